@@ -1,32 +1,53 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    io::{self, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use tokio::time::{sleep, Instant};
+use ring::signature::Ed25519KeyPair;
+use tokio::{
+    sync::RwLock,
+    time::{sleep, Instant},
+};
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
 use crate::{
-    error::Client as ClientError, persistence::ClientState, server::Server, settings::Settings,
+    error::Client as ClientError,
+    persistence::{self, ClientPersistentState, ClientState},
+    relay::Relay,
+    server::{Credentials, Server},
+    settings::Settings,
 };
 
-use std::{fs::File, io::Read};
-
 use distd_core::{
-    chunk_storage::{fs_storage::FsStorage, node_stream::receiver, ChunkStorage},
-    hash::Hash,
-    item::Item,
-    metadata::Item as ItemMetadata,
+    attributes::Attributes,
+    chunk_storage::{
+        fs_storage::FsStorage,
+        negotiation::Negotiation,
+        node_stream::{receiver, sender},
+        ChunkStorage, Node, Outline,
+    },
+    chunker::{Chunking, FastCdc},
+    directory::Manifest,
+    hash::{chunk_hash, Hash, TreeBuilder},
+    item::{Item, Kind as ItemKind},
+    metadata::{Item as ItemMetadata, Server as ServerMetadata},
+    peer::{self, Seeder},
+    signature::{PublicKey, DEFAULT_REALM},
 };
 
+/// How many times an interrupted transfer is resumed before giving up
+const RESUME_ATTEMPTS: u32 = 5;
+
 #[derive(Debug)]
 pub struct RegisterError;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Client<T>
 where
     T: ChunkStorage,
@@ -38,20 +59,35 @@ where
     /// Associated server
     pub server: Server,
 
-    /// Storage, implementing `ChunkStorage`
-    pub storage: T,
+    /// Storage, implementing `ChunkStorage`, shared with the peers we serve
+    pub storage: Arc<RwLock<T>>,
 
     pub settings: Arc<Settings>,
 
     pub state: Arc<ClientState>,
 }
 
+impl<T> Clone for Client<T>
+where
+    T: ChunkStorage,
+{
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            server: self.server.clone(),
+            storage: self.storage.clone(),
+            settings: self.settings.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
 impl<T> Client<T>
 where
     T: ChunkStorage,
 {
     pub async fn new(
-        server_public_key: &[u8; 32],
+        server_public_key: &PublicKey,
         storage: T,
         settings: Settings,
         mut state: ClientState,
@@ -61,6 +97,10 @@ where
             .client_uuid
             .as_ref()
             .and_then(|uuid_str| Uuid::from_str(uuid_str).ok());
+        let credentials = Credentials {
+            key_pair: Arc::new(persistence::load_or_generate_key()?),
+            api_key: settings.client.api_key.clone(),
+        };
 
         // Wait for server connection to get client uid
         let server = loop {
@@ -69,6 +109,8 @@ where
                 &settings.client.name,
                 client_uuid,
                 server_public_key,
+                settings.client.realm.clone(),
+                credentials.clone(),
             )
             .await
             {
@@ -84,8 +126,14 @@ where
         state.persistent.client_uuid = Some(server.client_uuid().to_string());
         state.persistent.commit().unwrap();
 
-        // Check for missing paths on server
-        let items = server.metadata().await.items;
+        // Let the server know what we already hold, so that transfers only carry what we miss and other clients
+        // may get it from us
+        if !server.advertise(&storage.nodes(), true).await? {
+            tracing::debug!("Server doesn't know some of the chunks we hold");
+        }
+
+        // Check for missing paths and feeds on server
+        let ServerMetadata { items, feeds, .. } = server.metadata().await;
         let server_paths: Vec<&PathBuf> = items.keys().collect();
         let missing: Vec<&PathBuf> = settings
             .client
@@ -105,57 +153,190 @@ where
             // It probably shouldn't exit in this case
             //return Err(ClientError::MissingItem);
         }
+        for feed in state
+            .persistent
+            .subscriptions
+            .iter()
+            .filter(|name| !feeds.contains_key(*name))
+        {
+            tracing::warn!("Subscribed feed '{feed}' could not be found in server");
+        }
 
         Ok(Self {
             name: String::from(&settings.client.name),
             server,
-            storage,
+            storage: Arc::new(RwLock::new(storage)),
             settings: Arc::new(settings),
             state: Arc::new(state),
         })
     }
 
+    #[must_use]
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Paths to keep in sync, the ones in settings along with every item of subscribed and relayed feeds
+    ///
+    /// Subscriptions are read again from the persistent state, to pick up feeds subscribed to while running.
+    fn synced_paths(&self, metadata: &ServerMetadata) -> BTreeSet<PathBuf> {
+        let mut paths: BTreeSet<PathBuf> = self.settings.client.sync.iter().cloned().collect();
+        let subscriptions = ClientPersistentState::default().subscriptions;
+        for name in subscriptions.iter().chain(&self.settings.relay.feeds) {
+            if let Some(feed) = metadata.feeds.get(name) {
+                paths.extend(feed.paths.keys().cloned());
+            } else {
+                tracing::debug!("Subscribed feed '{name}' not found on server");
+            }
+        }
+        paths
+    }
+
+    /// Publish the content of `file` as the item at `path`, uploading only the nodes the server is missing
+    ///
+    /// A directory is published as a whole, as a directory item (see `distd_core::directory`).
+    /// The file is chunked locally, with the same chunking of the latest revision at `path` if any, so that a
+    /// slightly changed file only costs the changed chunks. Only hashes are kept meanwhile, the chunks to upload
+    /// are read again from the file as they are sent. Mode, owner, mtime and extended attributes of the file
+    /// are carried by the item, to be applied by clients installing it.
+    /// Returns the metadata of the published item, whose revision is assigned by the server.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn publish(
+        &self,
+        file: &Path,
+        path: PathBuf,
+        name: String,
+        description: Option<String>,
+        feed: Option<String>,
+    ) -> Result<ItemMetadata, ClientError> {
+        let now = Instant::now();
+
+        // Same default chunking the server uses for the items it creates
+        let chunking = self
+            .server
+            .metadata()
+            .await
+            .item(&path, None)
+            .map_or(Chunking::FastCdc(FastCdc::default()), |item| item.chunking);
+
+        let (mut content, root, kind) = Content::outline(file, &chunking).await?;
+        let mut metadata = Item::new(name, path, 0, description, chunking, &root).metadata;
+        metadata.attributes = Attributes::capture(file).map_err(ClientError::Io)?;
+        metadata.kind = kind;
+
+        let hashes = Vec::from_iter(root.all_hashes());
+        let missing = self.server.missing(&hashes).await?;
+        let held: Vec<Hash> = hashes
+            .into_iter()
+            .filter(|hash| !missing.contains(hash))
+            .collect();
+        tracing::debug!(
+            "Server is missing {} nodes out of {}",
+            missing.len(),
+            missing.len() + held.len()
+        );
+
+        // Chunks are read again as they are sent, a failed read ends the upload short
+        let owner_key = self.owner_key()?;
+        let failure = Arc::new(Mutex::new(None));
+        let nodes = tokio_stream::iter(root.find_negotiated_diff(&held, &missing)).map_while({
+            let missing: HashSet<Hash> = missing.into_iter().collect();
+            let failure = failure.clone();
+            move |node| {
+                content
+                    .fill(node, &missing)
+                    .map_err(|e| *failure.lock().unwrap() = Some(e))
+                    .ok()
+            }
+        });
+        let payloads = sender(nodes, 32, Duration::new(0, 4800));
+        let published = self
+            .server
+            .publish(&metadata, feed, owner_key.as_ref(), payloads)
+            .await;
+        let failure = failure.lock().unwrap().take();
+        if let Some(e) = failure {
+            return Err(ClientError::Io(e));
+        }
+        let item = published?;
+
+        tracing::info!(
+            "Published {} v{}, {} bytes after {:.4}s",
+            item.name,
+            item.revision,
+            item.size(),
+            now.elapsed().as_secs_f32()
+        );
+        Ok(item)
+    }
+
+    /// Key pair of the realm owner, if configured
+    #[allow(clippy::result_large_err)]
+    fn owner_key(&self) -> Result<Option<Ed25519KeyPair>, ClientError> {
+        let Some(path) = &self.settings.client.owner_key else {
+            return Ok(None);
+        };
+        let invalid = || ClientError::OwnerKey(path.to_string_lossy().into());
+        let pkcs8 = std::fs::read(path).map_err(|_| invalid())?;
+        Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map(Some)
+            .map_err(|_| invalid())
+    }
 }
 
 impl Client<FsStorage> {
-    pub async fn get(&mut self, target: &Path, path: &Path) -> Result<Item, ClientError> {
-        tracing::debug!("sync: {target:?} {path:?}");
-        let mut buf = vec![];
-
-        let path = self.storage.path(path);
-
-        // If it exists read into buffer
-        if path.exists() {
-            tracing::trace!("File exists");
-            File::open(&path)
-                .and_then(|mut f| f.read_to_end(&mut buf))
-                .map_err(ClientError::Io)?;
-        }
+    /// Get an item from the server, at a specific revision or the latest one if `revision` is `None`
+    pub async fn get(
+        &mut self,
+        target: &Path,
+        path: &Path,
+        revision: Option<u32>,
+    ) -> Result<Item, ClientError> {
+        tracing::debug!("sync: {target:?} {path:?} {revision:?}");
+        let path = self.storage.read().await.path(path);
 
         let server_metadata = self.server.metadata().await;
         let item_metadata = server_metadata
-            .items
-            .get(target)
+            .item(target, revision)
             .ok_or(ClientError::FileNotFound(target.to_string_lossy().into()))?;
 
-        let _item = self.storage.create_item(
-            item_metadata.name.clone(),
-            path.clone(),
-            item_metadata.revision,
-            item_metadata.description.clone(),
-            buf.clone().into(),
-        );
+        // Files already there are indexed, files of directories too, so that only what they lack is transferred
+        let files = match &item_metadata.kind {
+            ItemKind::File => vec![path.clone()],
+            ItemKind::Directory(manifest) => manifest
+                .files()
+                .map(|(file, _, _)| path.join(file))
+                .collect(),
+        };
+        for file in files.into_iter().filter(|file| file.is_file()) {
+            tracing::trace!("Indexing existing {file:?}");
+            let indexed = self
+                .storage
+                .write()
+                .await
+                .index_file(
+                    item_metadata.name.clone(),
+                    file,
+                    item_metadata.revision,
+                    item_metadata.description.clone(),
+                    item_metadata.chunking,
+                )
+                .await
+                .inspect_err(|e| tracing::warn!("Cannot index existing file: {e}"));
+            if let Ok(item) = indexed {
+                self.server
+                    .advertise(&Vec::from_iter(item.all_hashes()), false)
+                    .await?;
+            }
+        }
 
-        self.update(item_metadata).await
+        self.update(item_metadata, None).await
     }
 }
 
 impl<T> Client<T>
 where
-    T: ChunkStorage + Send + 'static,
+    T: ChunkStorage + Send + Sync + 'static,
 {
     /// Transfer a diff from the server
     ///
@@ -166,7 +347,8 @@ where
         target: ItemMetadata,
         request_version: Option<u32>,
         from_version: Option<u32>,
-        from: &[Hash],
+        from: Option<&[Hash]>,
+        missing: &[Hash],
     ) -> Result<Item, ClientError> {
         let stream = self
             .server
@@ -175,42 +357,158 @@ where
                 request_version,
                 from_version,
                 from,
+                missing,
             )
             .await?;
 
-        let stream = stream.map(|x| x.unwrap().payload); // FIXME unwraps
+        // A broken stream ends the transfer, chunks received so far are kept by the storage and the next
+        // attempt only asks for what's left
+        let interrupted = Arc::new(Mutex::new(None));
+        let stream = stream.map_while({
+            let interrupted = interrupted.clone();
+            move |x| match x {
+                Ok(tree) => Some(tree.payload),
+                Err(status) => {
+                    *interrupted.lock().unwrap() = Some(status);
+                    None
+                }
+            }
+        });
         let stream = receiver(stream, 32, Duration::from_nanos(4800));
 
+        let res = self
+            .storage
+            .write()
+            .await
+            .receive_item(target, stream)
+            .await;
+        let interrupted = interrupted.lock().unwrap().take();
+        res.map_err(|e| interrupted.map_or(ClientError::Core(e), ClientError::TransferInterrupted))
+    }
+
+    /// Find out with the server which subtrees of `target` we already hold, walking its tree top-down
+    async fn negotiate(&self, target: &ItemMetadata) -> Result<Negotiation, ClientError> {
+        let item_path = target.path.to_string_lossy().into_owned();
+        let mut negotiation = Negotiation::default();
+        let mut expand = vec![];
+        loop {
+            let frontier = self
+                .server
+                .negotiate(item_path.clone(), Some(target.revision), &expand)
+                .await?;
+            expand = negotiation.step(&*self.storage.read().await, frontier);
+            if expand.is_empty() {
+                break;
+            }
+        }
+        tracing::debug!(
+            "Negotiated {} held and {} missing subtrees of {}",
+            negotiation.have.len(),
+            negotiation.missing.len(),
+            target.root.hash
+        );
+        Ok(negotiation)
+    }
+
+    /// Get `target` from peers, if any holds it and they provide it in time
+    ///
+    /// Peers are the ones tracked by the server, followed by the ones in settings. None of them is asked without a
+    /// peer token from the server, as they would ignore us.
+    async fn fetch_from_peers(&self, target: &ItemMetadata) -> Option<Item> {
+        let (mut peers, token) = self
+            .server
+            .peers(&target.root.hash)
+            .await
+            .inspect_err(|e| tracing::debug!("Cannot get peers from server: {e}"))
+            .ok()?;
+        for peer in &self.settings.peer.peers {
+            if !peers.contains(peer) {
+                peers.push(*peer);
+            }
+        }
+        if peers.is_empty() {
+            return None;
+        }
+
+        let timeout = Duration::from_secs(self.settings.peer.timeout);
+        let nodes = match &target.kind {
+            ItemKind::Directory(manifest) => {
+                let (root, chunking) = (&target.root, &target.chunking);
+                peer::fetch_directory(root, manifest, chunking, &peers, &token, timeout).await
+            }
+            ItemKind::File => {
+                peer::fetch(&target.root, &target.chunking, &peers, &token, timeout).await
+            }
+        };
+        let nodes = nodes
+            .inspect_err(|e| tracing::info!("Cannot get {} from peers: {e}", target.root.hash))
+            .ok()?;
         self.storage
-            .receive_item(
-                target.name,
-                target.path,
-                target.revision,
-                target.description,
-                stream,
-            )
+            .write()
+            .await
+            .receive_item(target.clone(), tokio_stream::iter(nodes))
             .await
-            .map_err(ClientError::Core)
+            .inspect_err(|e| tracing::warn!("Cannot store {} from peers: {e}", target.root.hash))
+            .ok()
     }
 
-    async fn update(&mut self, new_item_metadata: &ItemMetadata) -> Result<Item, ClientError> {
+    /// Update an item to the revision described by `new_item_metadata`
+    ///
+    /// If `from_version` is provided the server computes the diff from that revision of the item, which is
+    /// expected to be already stored locally, otherwise what we already hold is negotiated with the server.
+    pub(crate) async fn update(
+        &mut self,
+        new_item_metadata: &ItemMetadata,
+        from_version: Option<u32>,
+    ) -> Result<Item, ClientError> {
         tracing::info!(
-            "Updating item '{}' at '{}'",
+            "Updating item '{}' at '{}' to v{}",
             new_item_metadata.name,
-            new_item_metadata.path.to_string_lossy()
+            new_item_metadata.path.to_string_lossy(),
+            new_item_metadata.revision,
         );
         let now = Instant::now();
 
-        let from = self.storage.chunks(); // FIXME this could get very very large
-
-        let item = self
-            .transfer_diff(
-                // FIXME pass item versions
-                new_item_metadata.clone(),
-                None,
-                None,
-                &from,
-            )
+        let mut from_version = from_version;
+        let mut attempt = 0;
+        // Peers are tried first, the server being the fallback
+        let item = match self.fetch_from_peers(new_item_metadata).await {
+            Some(item) => item,
+            None => loop {
+                let negotiation = if from_version.is_some() {
+                    Negotiation::default()
+                } else {
+                    self.negotiate(new_item_metadata).await?
+                };
+
+                let res = self
+                    .transfer_diff(
+                        new_item_metadata.clone(),
+                        Some(new_item_metadata.revision),
+                        from_version,
+                        from_version.is_none().then_some(&negotiation.have[..]),
+                        &negotiation.missing,
+                    )
+                    .await;
+                match res {
+                    Err(
+                        e @ (ClientError::TransferInterrupted(_) | ClientError::ServerRequest(_)),
+                    ) if attempt < RESUME_ATTEMPTS => {
+                        attempt += 1;
+                        tracing::warn!(
+                            "{e}, resuming in {} seconds ({attempt}/{RESUME_ATTEMPTS})",
+                            self.server.timeout.as_secs()
+                        );
+                        sleep(self.server.timeout).await;
+                        // The previous revision may have been partially overwritten, negotiate what we hold instead
+                        from_version = None;
+                    }
+                    res => break res?,
+                }
+            },
+        };
+        self.server
+            .advertise(&Vec::from_iter(item.all_hashes()), false)
             .await?;
 
         tracing::info!(
@@ -225,44 +523,172 @@ where
     }
 
     /// Main client loop
+    ///
+    /// Keeps every synced item at its latest revision, or at the revision it is pinned to in settings.
+    /// Synced items are the ones in settings and the ones in subscribed feeds, as feeds change on the server.
+    /// Changes are picked up as soon as the server pushes them.
+    /// Held items are served to other clients if `peer.listen` is set, the server handing us out as a peer as long as
+    /// we keep sending heartbeats. Clients in a lower tier are served as the server would if `relay.listen` is set.
     pub async fn client_loop(mut self) -> Result<(), ClientError> {
         tokio::spawn(self.server.clone().fetch_loop());
 
-        let mut latest: HashMap<PathBuf, Hash> = HashMap::default();
+        let mut peer_port = None;
+        if let Some(listen) = self.settings.peer.listen {
+            let realm = self.server.realm().unwrap_or(DEFAULT_REALM);
+            let seeder = Seeder::bind(listen, self.storage.clone(), self.server.pub_key, realm)
+                .await
+                .map_err(|e| ClientError::Core(e.into()))?;
+            peer_port = Some(
+                seeder
+                    .local_addr()
+                    .map_err(|e| ClientError::Core(e.into()))?
+                    .port(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = seeder.serve().await {
+                    tracing::error!("Stopped serving peers: {e}");
+                }
+            });
+        }
+        tokio::spawn(self.server.clone().heartbeat_loop(peer_port));
+
+        if let Some(listen) = self.settings.relay.listen {
+            let relay = Relay::new(self.clone());
+            tokio::spawn(async move {
+                if let Err(e) = relay.serve(listen).await {
+                    tracing::error!("Stopped relaying: {e}");
+                }
+            });
+        }
+
+        // Revisions we currently hold of each synced item
+        let mut latest: HashMap<PathBuf, ItemMetadata> = HashMap::default();
 
         loop {
-            tokio::time::sleep(self.server.timeout).await;
-            let items = self.server.metadata().await.items;
-            for path in &self.settings.client.sync.clone() {
-                if latest.get(path)
-                    == self
-                        .server
-                        .metadata()
-                        .await
-                        .items
-                        .get(path)
-                        .map(|i| &i.root.hash)
-                {
+            // Sync as soon as metadata changes, checking again every `timeout` anyway
+            tokio::time::timeout(self.server.timeout, self.server.updated())
+                .await
+                .ok();
+            let metadata = self.server.metadata().await;
+            let paths = self.synced_paths(&metadata);
+
+            // Items not synced anymore, e.g. removed from a feed, are left on disk as they are
+            latest.retain(|path, _| paths.contains(path));
+
+            for path in &paths {
+                let pinned = self.settings.client.pinned(path);
+                let target = metadata
+                    .item(path, pinned)
+                    .ok_or(ClientError::MissingItem)?; //TODO should fail on missing on server or sync other files anyway?
+
+                let held = latest.get(path);
+                if held.map(|i| &i.root.hash) == Some(&target.root.hash) {
                     continue;
                 }
 
-                tracing::debug!("Syncing '{}'", path.to_string_lossy());
-                let old_item = items.get(path).ok_or(ClientError::Storage)?; //TODO should fail on missing on server or sync other files anyway?
-                let item = self.update(old_item).await?;
-                latest.insert(path.clone(), *item.root());
+                tracing::debug!("Syncing '{}' ({pinned:?})", path.to_string_lossy());
+                let item = self.update(target, held.map(|i| i.revision)).await?;
+                latest.insert(path.clone(), item.metadata);
             }
         }
     }
 }
 
+/// Content of an item being published, left in its files and read again only for the chunks to upload
+struct Content {
+    /// Files the content is made of, one after the other, with the offset of each in the content
+    files: Vec<(PathBuf, u64)>,
+
+    outline: Outline,
+
+    /// File last read, with its index in `files`
+    open: Option<(usize, fs::File)>,
+}
+
+impl Content {
+    /// Compute the tree of the file or directory at `path`, returning it along with the kind of item it makes
+    async fn outline(
+        path: &Path,
+        chunking: &Chunking,
+    ) -> Result<(Self, Arc<Node>, ItemKind), ClientError> {
+        let mut outline = Outline::default();
+        let (root, kind, files) = if path.is_dir() {
+            let (manifest, root) =
+                Manifest::insert(&mut outline, path, chunking).map_err(ClientError::Core)?;
+            let mut offset = 0;
+            let files = manifest
+                .files()
+                .map(|(file, root, _)| {
+                    offset += root.size;
+                    (path.join(file), offset - root.size)
+                })
+                .collect();
+            (root, ItemKind::Directory(manifest), files)
+        } else {
+            let reader = tokio::fs::File::open(path).await.map_err(ClientError::Io)?;
+            let mut builder = TreeBuilder::new(&mut outline, chunking);
+            builder
+                .read_from_async(reader)
+                .await
+                .map_err(ClientError::Core)?;
+            let root = builder.finish().map_err(ClientError::Core)?;
+            (root, ItemKind::File, vec![(path.to_path_buf(), 0)])
+        };
+        let content = Self {
+            files,
+            outline,
+            open: None,
+        };
+        Ok((content, root, kind))
+    }
+
+    /// `node` along with its data if it's a chunk in `missing`, as it is otherwise
+    ///
+    /// Chunks are checked against their hash, to fail if files changed since the tree was computed.
+    fn fill(&mut self, node: Arc<Node>, missing: &HashSet<Hash>) -> io::Result<Arc<Node>> {
+        let Node::Skipped { hash, .. } = node.as_ref() else {
+            return Ok(node);
+        };
+        let located = self.outline.locate(hash);
+        let Some((offset, size)) = located.filter(|_| missing.contains(hash)) else {
+            return Ok(node);
+        };
+
+        // The last file starting before the chunk has it, empty files before it start at the same offset
+        let index = self
+            .files
+            .partition_point(|(_, start)| *start <= offset)
+            .saturating_sub(1);
+        let (path, start) = &self.files[index];
+        if self.open.as_ref().is_none_or(|(open, _)| *open != index) {
+            self.open = Some((index, fs::File::open(path)?));
+        }
+        let (_, file) = self.open.as_mut().expect("file just opened");
+        let mut data = vec![0; usize::try_from(size).map_err(io::Error::other)?];
+        file.seek(SeekFrom::Start(offset - start))?;
+        file.read_exact(&mut data)?;
+        if chunk_hash(&data) != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' changed while publishing", path.to_string_lossy()),
+            ));
+        }
+        Ok(Arc::new(Node::Stored {
+            hash: *hash,
+            data: Arc::new(data),
+        }))
+    }
+}
+
 pub mod cli {
     use std::{env, path::PathBuf, str::FromStr};
 
     use distd_core::chunk_storage::fs_storage::FsStorage;
+    use distd_core::signature::decode_public_key;
 
     use crate::client::Client;
-    use crate::error::Client as ClientError;
-    use crate::persistence::ClientState;
+    use crate::error::{Client as ClientError, ServerRequest};
+    use crate::persistence::{state_path, update_subscriptions, ClientState};
     use crate::settings::Settings;
 
     pub async fn main() -> Result<(), ClientError> {
@@ -284,19 +710,29 @@ pub mod cli {
 
         tracing::debug!("Running \"{cmd}\" {cmd_args:?}");
 
+        // Subscriptions only change the persistent state, they don't need a server or to wait for a running client
+        match cmd.as_str() {
+            "subscribe" => return subscribe(&cmd_args[..]),
+            "unsubscribe" => return unsubscribe(&cmd_args[..]),
+            _ => {}
+        }
+
         let state = ClientState::default();
 
         let Ok(storage_root) = PathBuf::from_str(&settings.fsstorage.root);
-        let storage = FsStorage::new(storage_root);
+        let mut storage = FsStorage::new(storage_root);
+        storage.install = settings.fsstorage.install;
+        storage.ownership = settings.fsstorage.ownership.clone();
         //let storage = HashMapStorage::default(); // use this for benchmarking in order to avoid potential fs-related bottlenecks
-        let client = Client::new(&[0u8; 32], storage, settings, state).await?;
+        let server_public_key = decode_public_key(&settings.server.public_key)
+            .map_err(|_| ClientError::ServerRequest(ServerRequest::BadPubKey))?;
+        let client = Client::new(&server_public_key, storage, settings, state).await?;
 
         match cmd.as_str() {
             "start" => client.client_loop().await,
             "get" => get(client, &cmd_args[..]).await,
             // TODO add "sync: to explicitly request syncing of items subscripted to?
-            "publish" => todo!(),
-            "subscribe" => todo!(),
+            "publish" => publish(client, &cmd_args[..]).await,
             _ => {
                 tracing::error!("Invalid command specified");
                 Err(ClientError::InvalidCmd(cmd))
@@ -305,24 +741,114 @@ pub mod cli {
         .inspect_err(|e| tracing::error!("Fatal: {e}"))
     }
 
+    /// Publish a local file, as `publish <local-file> <item-path> [--name <name>] [--description <description>]
+    /// [--feed <feed>]`
+    ///
+    /// The item name defaults to the file name of `item-path`. A local directory is published as a directory item.
+    async fn publish(client: Client<FsStorage>, args: &[String]) -> Result<(), ClientError> {
+        let invalid = || ClientError::InvalidArgs(args.to_owned());
+        let [file, path, options @ ..] = args else {
+            return Err(invalid());
+        };
+
+        let (mut name, mut description, mut feed) = (None, None, None);
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = options.next().cloned().ok_or_else(invalid)?;
+            match option.as_str() {
+                "--name" => name = Some(value),
+                "--description" => description = Some(value),
+                "--feed" => feed = Some(value),
+                _ => return Err(invalid()),
+            }
+        }
+
+        if let Some(feed) = &feed {
+            if !client.server.metadata().await.feeds.contains_key(feed) {
+                return Err(ClientError::MissingFeed(feed.clone()));
+            }
+        }
+
+        let Ok(file) = PathBuf::from_str(file);
+        let Ok(path) = PathBuf::from_str(path);
+        let name = name
+            .or_else(|| path.file_name().map(|x| x.to_string_lossy().into()))
+            .ok_or_else(invalid)?;
+
+        client
+            .publish(&file, path, name, description, feed)
+            .await
+            .map(|_| ())
+    }
+
+    /// Subscribe to feeds, as `subscribe <feed>...`
+    ///
+    /// Items in subscribed feeds are kept in sync by `start`, a running client picks up new subscriptions too.
+    /// Feeds are not looked up on the server here, the client skips the ones it doesn't find there.
+    #[allow(clippy::result_large_err)]
+    fn subscribe(args: &[String]) -> Result<(), ClientError> {
+        if args.is_empty() {
+            return Err(ClientError::InvalidArgs(args.to_owned()));
+        }
+
+        update_subscriptions(&state_path(), |subscriptions| {
+            subscriptions.extend(args.iter().cloned());
+        })?;
+        tracing::info!("Subscribed to {args:?}");
+        Ok(())
+    }
+
+    /// Unsubscribe from feeds, as `unsubscribe <feed>...`
+    ///
+    /// Items already synced are left where they are.
+    #[allow(clippy::result_large_err)]
+    fn unsubscribe(args: &[String]) -> Result<(), ClientError> {
+        if args.is_empty() {
+            return Err(ClientError::InvalidArgs(args.to_owned()));
+        }
+
+        update_subscriptions(&state_path(), |subscriptions| {
+            for name in args {
+                if !subscriptions.remove(name) {
+                    tracing::warn!("Not subscribed to '{name}'");
+                }
+            }
+        })
+    }
+
+    /// Get an item, as `get <target>[@<revision>] [<path>]`
     async fn get(mut client: Client<FsStorage>, args: &[String]) -> Result<(), ClientError> {
         let first = args
             .first()
             .ok_or(ClientError::InvalidArgs(args.to_owned()))?;
 
+        // An optional revision may be specified after the target path
+        let (first, revision) = match first.rsplit_once('@') {
+            Some((target, revision)) => (
+                target,
+                Some(
+                    revision
+                        .parse::<u32>()
+                        .map_err(|_| ClientError::InvalidArgs(args.to_owned()))?,
+                ),
+            ),
+            None => (first.as_str(), None),
+        };
+
         let (target, path) = match args.len() {
             1 => (first, first),
             2 => (
                 first,
                 args.get(1)
-                    .ok_or(ClientError::InvalidArgs(args.to_owned()))?,
+                    .ok_or(ClientError::InvalidArgs(args.to_owned()))?
+                    .as_str(),
             ),
             _ => return Err(ClientError::InvalidArgs(args.to_owned())),
         };
 
         let Ok(path) = PathBuf::from_str(path);
-        let Ok(target) = PathBuf::from_str(target.as_str());
+        let Ok(target) = PathBuf::from_str(target);
 
-        client.get(&target, &path).await.map(|_| ())
+        client.get(&target, &path, revision).await.map(|_| ())
     }
 }
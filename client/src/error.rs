@@ -2,7 +2,7 @@ use std::str::Utf8Error;
 
 use config::ConfigError;
 use distd_core::{
-    error::InvalidParameter, GrpcError, TransportError,
+    error::InvalidParameter, signature::Error as SignatureError, GrpcError, TransportError,
 };
 use thiserror::Error;
 
@@ -38,6 +38,15 @@ pub enum ServerRequest {
     #[error("Server didn't return a valid Uuid")]
     BadUuid,
 
+    #[error("Server didn't return a session token")]
+    MissingToken,
+
+    #[error("Server didn't return a valid challenge")]
+    BadChallenge,
+
+    #[error("API key is not valid in request metadata")]
+    BadApiKey,
+
     #[error("Cannot decode UTF-8 string from server response")]
     Uuid(#[from] uuid::Error),
 
@@ -47,8 +56,17 @@ pub enum ServerRequest {
     #[error("Cannot connect to server")]
     Connection(#[from] ServerConnection),
 
+    #[error("Server returned an invalid hash")]
+    BadHash,
+
     #[error("Invalid format for provided server public key")]
     BadPubKey,
+
+    #[error("Cannot verify server signature: {0}")]
+    Signature(#[from] SignatureError),
+
+    #[error("Metadata update to generation {generation} doesn't follow generation {held:?}")]
+    OutOfOrder { generation: u64, held: Option<u64> },
 }
 
 #[derive(Error, Debug)]
@@ -95,6 +113,18 @@ pub enum Client {
     #[error("User specified item doesn't exist on server")]
     MissingItem,
 
+    #[error("Feed doesn't exist on server: \"{0}\"")]
+    MissingFeed(String),
+
+    #[error("Cannot load realm owner key from \"{0}\"")]
+    OwnerKey(String),
+
+    #[error("Cannot load or generate client key pair")]
+    Key,
+
+    #[error("Transfer interrupted: {0}")]
+    TransferInterrupted(GrpcError),
+
     #[error("Error reported from core: '{0}'")]
     Core(#[from] distd_core::error::Error),
 }
@@ -1,15 +1,37 @@
+use std::sync::{Arc, RwLock};
+
+use distd_core::auth::TOKEN_METADATA;
 use distd_core::tonic;
-use distd_core::tonic::{metadata::MetadataValue, service::Interceptor, Status};
+use distd_core::tonic::{
+    metadata::{AsciiMetadataValue, BinaryMetadataValue},
+    service::Interceptor,
+    Status,
+};
+
+/// Metadata carrying the API key, as the server expects it
+pub(crate) const API_KEY_METADATA: &str = "authorization";
 
-#[derive(Debug)]
+/// Adds our API key, if any, and our session token, once we have one, to every request
+#[derive(Debug, Clone, Default)]
 pub struct DistdGrpcClient {
-    pub uuid: MetadataValue<distd_core::tonic::metadata::Binary>
+    /// Shared by every clone of the interceptor, so that a renewed token is used right away
+    pub token: Arc<RwLock<Option<BinaryMetadataValue>>>,
+
+    /// API key granting more than what clients may do, e.g. publishing, as a bearer credential
+    pub api_key: Option<AsciiMetadataValue>,
 }
 
 impl Interceptor for DistdGrpcClient {
     fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
         tracing::trace!("request: {request:?}");
-        request.metadata_mut().insert_bin("x-uuid-bin", self.uuid.clone());
+        if let Some(token) = self.token.read().unwrap().clone() {
+            request.metadata_mut().insert_bin(TOKEN_METADATA, token);
+        }
+        if let Some(api_key) = &self.api_key {
+            request
+                .metadata_mut()
+                .insert(API_KEY_METADATA, api_key.clone());
+        }
         Ok(request)
     }
 }
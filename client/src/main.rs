@@ -9,6 +9,7 @@ pub mod grpc;
 pub mod server;
 pub mod settings;
 pub mod persistence;
+pub mod relay;
 
 pub use error::Client as ClientError;
 
@@ -1,44 +1,116 @@
 use std::{
+    collections::BTreeSet,
+    fs::File,
     io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
     path::{Path, PathBuf},
     process::exit,
     str::FromStr,
 };
 
+use distd_core::feed::Name as FeedName;
+use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
 use serde::{Deserialize, Serialize};
 
-use crate::settings::cache_dir;
+use crate::{error::Client as ClientError, settings::cache_dir};
 
 #[inline]
 #[must_use] pub fn state_path() -> PathBuf {
     cache_dir().join(format!("{}_state.json", env!("CARGO_PKG_NAME")))
 }
 
+#[inline]
+#[must_use]
+pub fn key_path() -> PathBuf {
+    cache_dir().join(format!("{}.pkcs8", env!("CARGO_PKG_NAME")))
+}
+
+/// Load the client key pair, generating and storing a new one on first start
+///
+/// The server binds the client uuid to this key pair: losing it means registering as a new client.
+#[allow(clippy::result_large_err)]
+pub fn load_or_generate_key() -> Result<Ed25519KeyPair, ClientError> {
+    let path = key_path();
+    let pkcs8 = if path.exists() {
+        std::fs::read(&path).map_err(|_| ClientError::Key)?
+    } else {
+        tracing::info!("Generating new client key pair");
+        let pkcs8 =
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| ClientError::Key)?;
+        // Private key, only readable by the owner
+        File::options()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(pkcs8.as_ref()))
+            .map_err(|_| ClientError::Key)?;
+        pkcs8.as_ref().to_vec()
+    };
+    Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| ClientError::Key)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientPersistentState {
     /// Client Uuid assigned to client from server, as a string
     pub client_uuid: Option<String>,
+
+    /// Feeds subscribed to, every item in them is kept in sync
+    #[serde(default)]
+    pub subscriptions: BTreeSet<FeedName>,
 }
 
 impl ClientPersistentState {
     #[must_use] pub fn commit(&self) -> Option<()> {
-        std::fs::File::create(state_path())
+        self.store(&state_path())
+    }
+
+    /// Load the state stored at `path`, an empty one if there is none
+    #[must_use]
+    pub fn load(path: &Path) -> Self {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or(ClientPersistentState {
+                client_uuid: None,
+                subscriptions: BTreeSet::new(),
+            })
+    }
+
+    /// Store the state at `path`, through a temporary file so that a running client never reads a partial one
+    #[must_use]
+    pub fn store(&self, path: &Path) -> Option<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::File::create(&tmp)
             .map(std::io::BufWriter::new)
             .ok()
-            .and_then(|file| serde_json::to_writer(file, self).ok())
+            .and_then(|file| serde_json::to_writer(file, self).ok())?;
+        std::fs::rename(tmp, path).ok()
     }
 }
 
 impl Default for ClientPersistentState {
     fn default() -> Self {
-        std::fs::File::open(state_path())
-            .map(std::io::BufReader::new)
-            .ok()
-            .and_then(|file| serde_json::from_reader(file).ok())
-            .unwrap_or(ClientPersistentState { client_uuid: None })
+        Self::load(&state_path())
     }
 }
 
+/// Apply `f` to the subscriptions stored at `path`, returning what it returns
+///
+/// Only the subscriptions are changed, the rest of the state is the one of the running client, if any: no pid-lock
+/// is taken, the running client reads subscriptions again on every sync.
+#[allow(clippy::result_large_err)]
+pub fn update_subscriptions<F, R>(path: &Path, f: F) -> Result<R, ClientError>
+where
+    F: FnOnce(&mut BTreeSet<FeedName>) -> R,
+{
+    let mut state = ClientPersistentState::load(path);
+    let res = f(&mut state.subscriptions);
+    state.store(path).ok_or(ClientError::Storage)?;
+    Ok(res)
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientPid {
     /// The path to the lock file.
@@ -53,9 +125,11 @@ impl ClientPid {
     }
 }
 
-impl Default for ClientPid {
-    fn default() -> Self {
-        let pid_path = cache_dir().join(format!("{}_pid", env!("CARGO_PKG_NAME")));
+impl ClientPid {
+    /// Take the pid-lock at `pid_path`, exiting if another client holds it
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)]
+    pub fn lock(pid_path: PathBuf) -> Self {
         tracing::debug!("Pidfile: {}", pid_path.to_string_lossy());
 
         // FIXME linux-specific and fragile
@@ -83,6 +157,13 @@ impl Default for ClientPid {
         Self { pid_path }
     }
 }
+
+impl Default for ClientPid {
+    fn default() -> Self {
+        Self::lock(cache_dir().join(format!("{}_pid", env!("CARGO_PKG_NAME"))))
+    }
+}
+
 impl Drop for ClientPid {
     fn drop(&mut self) {
         Self::pidfile_cleanup(&self.pid_path);
@@ -96,3 +177,33 @@ pub struct ClientState {
     /// Persistent state of the client
     pub persistent: ClientPersistentState,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{update_subscriptions, ClientPersistentState, ClientPid};
+
+    #[test]
+    fn subscribe_while_running() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        // A running client, holding its pid-lock and a registered uuid
+        let _pid = ClientPid::lock(dir.join("pid"));
+        let running = ClientPersistentState {
+            client_uuid: Some("uuid".into()),
+            subscriptions: BTreeSet::new(),
+        };
+        running.store(&path).unwrap();
+
+        update_subscriptions(&path, |feeds| feeds.extend(["a".into(), "b".into()])).unwrap();
+        assert!(update_subscriptions(&path, |feeds| feeds.remove("a")).unwrap());
+
+        // Read again by the running client on its next sync, the rest of its state untouched
+        let state = ClientPersistentState::load(&path);
+        assert_eq!(state.subscriptions, BTreeSet::from(["b".into()]));
+        assert_eq!(state.client_uuid, running.client_uuid);
+    }
+}
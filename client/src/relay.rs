@@ -0,0 +1,619 @@
+//! Relay mode, serving clients in a lower tier as the server would
+//!
+//! A relay runs the `Distd` gRPC service on top of its own storage, so that the clients of a datacenter get items
+//! from a relay close to them rather than from the server. Metadata is signed by the server and can't be changed, it
+//! is passed on as the server sends it: clients of a relay are configured with the server public key as usual, and a
+//! relay may itself be the upstream of another relay.
+//!
+//! Items of `relay.feeds` are kept in sync ahead of requests, any other item is fetched from upstream when first
+//! requested, negotiating what's missing as for any update. Publishing and feed management are left to the server.
+//!
+//! Downstream clients authenticate with the relay as they would with the server, their session tokens are signed
+//! with the relay key pair. Every request but the ones to register needs such a token, see `SessionInterceptor`.
+//! Registering needs one of the API keys in `relay.api_keys` if any is set, the ones of the server mean nothing here.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use distd_core::auth::{self, Challenges, TOKEN_METADATA};
+use distd_core::chunk_storage::negotiation;
+use distd_core::chunk_storage::node_stream::sender;
+use distd_core::chunk_storage::ChunkStorage;
+use distd_core::hash::Hash;
+use distd_core::metadata::Item as ItemMetadata;
+use distd_core::proto::distd_server::{Distd, DistdServer};
+use distd_core::proto::{
+    Acknowledge, AuthChallenge, ChallengeRequest, ClientKeepAlive, ClientRegister, DiffFrontier,
+    DiffProbe, EnumAcknowledge, FeedItem, FeedRef, FeedRename, Hashes, ItemRequest, ItemUpload,
+    MetadataUpdate, PeerList, PeersRequest, PublishedItem, SerializedFeed, SerializedTree,
+    ServerMetadata, SessionToken, Subtree, TokenRequest, UpdatesRequest,
+};
+use distd_core::signature::{PublicKey, DEFAULT_REALM};
+use distd_core::tonic::{self, service::Interceptor, Request, Response, Status, Streaming};
+use ring::signature::KeyPair;
+use tokio::sync::{Mutex, RwLock};
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::client::Client;
+use crate::error::{Client as ClientError, ServerRequest};
+use crate::grpc::API_KEY_METADATA;
+
+type TreeStream = Pin<Box<dyn Stream<Item = Result<SerializedTree, Status>> + Send>>;
+type UpdatesStream = Pin<Box<dyn Stream<Item = Result<MetadataUpdate, Status>> + Send>>;
+
+/// Time session tokens issued to downstream clients are valid for
+const TOKEN_TTL: Duration = Duration::from_mins(15);
+
+/// `Distd` service of a client relaying the server to lower tiers
+#[derive(Debug)]
+pub struct Relay<T>
+where
+    T: ChunkStorage,
+{
+    /// Our own client, storage and upstream connection included
+    client: Client<T>,
+
+    /// Hashes advertised by each downstream client
+    holdings: Arc<RwLock<HashMap<Uuid, HashSet<Hash>>>>,
+
+    /// Taken while fetching the item with each root from upstream, so that concurrent requests for it fetch it once
+    fetching: Arc<Mutex<HashMap<Hash, Arc<Mutex<()>>>>>,
+
+    /// Challenges handed out to downstream clients
+    challenges: Arc<Challenges>,
+
+    /// Hashes of the API keys downstream clients may register with, so that lookups don't leak keys through timing
+    api_keys: Arc<HashSet<blake3::Hash>>,
+}
+
+impl<T> Clone for Relay<T>
+where
+    T: ChunkStorage,
+{
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            holdings: self.holdings.clone(),
+            fetching: self.fetching.clone(),
+            challenges: self.challenges.clone(),
+            api_keys: self.api_keys.clone(),
+        }
+    }
+}
+
+impl<T> Relay<T>
+where
+    T: ChunkStorage + Send + Sync + 'static,
+{
+    #[must_use]
+    pub fn new(client: Client<T>) -> Self {
+        let api_keys = client
+            .settings
+            .relay
+            .api_keys
+            .iter()
+            .map(|key| blake3::hash(key.as_bytes()))
+            .collect();
+        Self {
+            client,
+            holdings: Arc::default(),
+            fetching: Arc::default(),
+            challenges: Arc::default(),
+            api_keys: Arc::new(api_keys),
+        }
+    }
+
+    /// Serve downstream clients on `addr`, until the server fails
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), ClientError> {
+        tracing::info!("Relaying server to clients on {addr}");
+        let interceptor = SessionInterceptor {
+            public_key: self.public_key()?,
+        };
+        tonic::transport::Server::builder()
+            .add_service(DistdServer::with_interceptor(self, interceptor))
+            .serve(addr)
+            .await
+            .map_err(|e| ServerRequest::Transport(e).into())
+    }
+
+    /// Metadata of the item at `path` and `revision`, the latest one if `None`, as last received from upstream
+    async fn target(&self, path: &Path, revision: Option<u32>) -> Result<ItemMetadata, Status> {
+        self.client
+            .server
+            .metadata()
+            .await
+            .item(path, revision)
+            .cloned()
+            .ok_or(Status::not_found("Missing item path or revision"))
+    }
+
+    /// Make sure the whole tree of `target` is in storage, fetching what's missing from upstream
+    async fn hold(&self, target: &ItemMetadata) -> Result<(), Status> {
+        let root = &target.root.hash;
+        if self.client.storage.read().await.contains(root) {
+            return Ok(());
+        }
+        let lock = self.fetching.lock().await.entry(*root).or_default().clone();
+        let fetching = lock.lock().await;
+        let res = self.fetch_upstream(target).await;
+        drop(fetching);
+
+        // Nobody else waiting for the same root, as they take the lock out of the map first
+        let mut locks = self.fetching.lock().await;
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(root);
+        }
+        res
+    }
+
+    /// Update storage to `target` from upstream, unless an earlier request for it just did
+    async fn fetch_upstream(&self, target: &ItemMetadata) -> Result<(), Status> {
+        if self.client.storage.read().await.contains(&target.root.hash) {
+            return Ok(());
+        }
+
+        tracing::info!(
+            "Fetching '{}' v{} for downstream clients",
+            target.path.to_string_lossy(),
+            target.revision
+        );
+        self.client
+            .clone()
+            .update(target, None)
+            .await
+            .map_err(|e| Status::unavailable(format!("Cannot get item from upstream: {e}")))?;
+        Ok(())
+    }
+
+    /// Issue a session token to the downstream client `uuid`
+    #[allow(clippy::result_large_err)]
+    fn session(&self, uuid: Uuid) -> Result<SessionToken, Status> {
+        let (token, expires) = auth::issue(self.client.server.key_pair(), uuid, TOKEN_TTL)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(SessionToken { token, expires })
+    }
+
+    /// Public key of the relay key pair, session tokens are signed with
+    #[allow(clippy::result_large_err)]
+    fn public_key(&self) -> Result<PublicKey, ClientError> {
+        self.client
+            .server
+            .key_pair()
+            .public_key()
+            .as_ref()
+            .try_into()
+            .map_err(|_| ClientError::Key)
+    }
+}
+
+#[derive(Clone)]
+struct DownstreamExtension {
+    uuid: Uuid,
+}
+
+/// Checks the session token of requests carrying one, issued by the relay to a downstream client
+///
+/// Requests without a token are let through, for clients to register: every other request is refused later on,
+/// see `downstream_uuid`.
+#[derive(Debug, Clone)]
+struct SessionInterceptor {
+    public_key: PublicKey,
+}
+
+impl Interceptor for SessionInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(token) = request.metadata().get_bin(TOKEN_METADATA) {
+            let token = token
+                .to_bytes()
+                .map_err(|_| Status::unauthenticated("Invalid metadata"))?;
+            let claims = auth::verify(&self.public_key, &token)
+                .map_err(|e| Status::unauthenticated(e.to_string()))?;
+            request
+                .extensions_mut()
+                .insert(DownstreamExtension { uuid: claims.uuid });
+        }
+        Ok(request)
+    }
+}
+
+/// Uuid of the downstream client issuing `request`, as authenticated by `SessionInterceptor`
+#[allow(clippy::result_large_err)]
+fn downstream_uuid<R>(request: &Request<R>) -> Result<Uuid, Status> {
+    request
+        .extensions()
+        .get::<DownstreamExtension>()
+        .map(|ext| ext.uuid)
+        .ok_or(Status::unauthenticated("Missing session token"))
+}
+
+/// Check the API key of `request` against the hashes of the configured `api_keys`, it may have none if none is
+#[allow(clippy::result_large_err)]
+fn check_api_key<R>(api_keys: &HashSet<blake3::Hash>, request: &Request<R>) -> Result<(), Status> {
+    let Some(credentials) = request.metadata().get(API_KEY_METADATA) else {
+        return if api_keys.is_empty() {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("Missing API key"))
+        };
+    };
+    let key = credentials
+        .to_str()
+        .ok()
+        .and_then(|credentials| credentials.strip_prefix("Bearer "))
+        .ok_or(Status::unauthenticated("Invalid metadata"))?;
+    if api_keys.contains(&blake3::hash(key.trim().as_bytes())) {
+        Ok(())
+    } else {
+        Err(Status::unauthenticated("Unknown API key"))
+    }
+}
+
+/// Parse raw hashes from a request, silently dropping malformed ones
+fn parse_hashes(hashes: Vec<Vec<u8>>) -> Vec<Hash> {
+    hashes
+        .into_iter()
+        .filter_map(|v| v.try_into().ok())
+        .map(Hash::from_bytes)
+        .collect()
+}
+
+/// Map an error talking to upstream to a status for downstream clients
+fn upstream_status(e: &ServerRequest) -> Status {
+    match e {
+        ServerRequest::Grpc(status) => status.clone(),
+        _ => Status::unavailable(format!("Upstream unreachable: {e}")),
+    }
+}
+
+#[tonic::async_trait]
+impl<T> Distd for Relay<T>
+where
+    T: ChunkStorage + Send + Sync + 'static,
+{
+    type TreeTransferStream = TreeStream;
+    type UpdatesStream = UpdatesStream;
+
+    async fn challenge(
+        &self,
+        _request: Request<ChallengeRequest>,
+    ) -> Result<Response<AuthChallenge>, Status> {
+        let nonce = self
+            .challenges
+            .issue()
+            .map_err(|e| Status::resource_exhausted(e.to_string()))?;
+        Ok(Response::new(AuthChallenge {
+            nonce: nonce.to_vec(),
+        }))
+    }
+
+    async fn register(
+        &self,
+        request: Request<ClientRegister>,
+    ) -> Result<Response<ServerMetadata>, Status> {
+        check_api_key(&self.api_keys, &request)?;
+        let addr = request.remote_addr();
+        let inner = request.into_inner();
+        let public_key: PublicKey = inner
+            .public_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| Status::unauthenticated("Bad client public key"))?;
+        self.challenges
+            .redeem(&inner.nonce, &public_key, &inner.signature)
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+
+        // We only see the realm we registered into upstream
+        if inner.realm.as_deref() != self.client.server.realm() {
+            return Err(Status::permission_denied(format!(
+                "Only relaying realm '{}'",
+                self.client.server.realm().unwrap_or(DEFAULT_REALM)
+            )));
+        }
+
+        // Our state isn't persisted, uuids are derived from the client key so that they're the same after a restart
+        let uuid = Uuid::new_v5(&self.client.server.client_uuid(), &public_key);
+        tracing::info!("Downstream client \"{}\"@{addr:?}: {uuid}", inner.name);
+        self.holdings.write().await.entry(uuid).or_default();
+        let session = self.session(uuid)?;
+
+        let metadata = self
+            .client
+            .server
+            .fetch()
+            .await
+            .map_err(|e| upstream_status(&e))?;
+        Ok(Response::new(ServerMetadata {
+            uuid: Some(uuid.as_bytes().to_vec()),
+            session: Some(session),
+            ..metadata
+        }))
+    }
+
+    async fn renew_token(
+        &self,
+        request: Request<TokenRequest>,
+    ) -> Result<Response<SessionToken>, Status> {
+        let uuid = downstream_uuid(&request)?;
+        Ok(Response::new(self.session(uuid)?))
+    }
+
+    async fn fetch(
+        &self,
+        request: Request<ClientKeepAlive>,
+    ) -> Result<Response<ServerMetadata>, Status> {
+        downstream_uuid(&request)?;
+        let metadata = self
+            .client
+            .server
+            .fetch()
+            .await
+            .map_err(|e| upstream_status(&e))?;
+        Ok(Response::new(metadata))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<ClientKeepAlive>,
+    ) -> Result<Response<Acknowledge>, Status> {
+        downstream_uuid(&request)?;
+        Ok(Response::new(Acknowledge {
+            ack: EnumAcknowledge::AckOk.into(),
+        }))
+    }
+
+    /// Updates are signed by the server, each downstream stream is a stream from upstream passed on as it is
+    async fn updates(
+        &self,
+        request: Request<UpdatesRequest>,
+    ) -> Result<Response<UpdatesStream>, Status> {
+        downstream_uuid(&request)?;
+        let stream = self
+            .client
+            .server
+            .updates(request.into_inner().generation)
+            .await
+            .map_err(|e| upstream_status(&e))?;
+        Ok(Response::new(Box::pin(stream) as Self::UpdatesStream))
+    }
+
+    async fn adv_hashes(&self, request: Request<Hashes>) -> Result<Response<Acknowledge>, Status> {
+        let uuid = downstream_uuid(&request)?;
+        let inner = request.into_inner();
+        let hashes = parse_hashes(inner.hashes);
+
+        let mut holdings = self.holdings.write().await;
+        let held = holdings.entry(uuid).or_default();
+        if inner.replace {
+            held.clear();
+        }
+        held.extend(hashes);
+        Ok(Response::new(Acknowledge {
+            ack: EnumAcknowledge::AckOk.into(),
+        }))
+    }
+
+    async fn tree_transfer(
+        &self,
+        request: Request<ItemRequest>,
+    ) -> Result<Response<TreeStream>, Status> {
+        let uuid = downstream_uuid(&request)?;
+        let inner = request.into_inner();
+        let path = PathBuf::from(&inner.item_path);
+        let target = self.target(&path, inner.request_version).await?;
+        self.hold(&target).await?;
+
+        // We may not hold the revision to diff from anymore, or never did, what the client advertised is used then
+        let from_root = match inner.from_version {
+            Some(revision) => Some(self.target(&path, Some(revision)).await?.root.hash),
+            None => None,
+        };
+        let storage = self.client.storage.read().await;
+        let from: Vec<Hash> = if let Some(from) = from_root.and_then(|root| storage.get(&root)) {
+            from.all_hashes().into_iter().collect()
+        } else if let Some(hashes) = inner.hashes {
+            parse_hashes(hashes.hashes)
+        } else {
+            self.holdings
+                .read()
+                .await
+                .get(&uuid)
+                .map(|held| held.iter().copied().collect())
+                .unwrap_or_default()
+        };
+        let missing = parse_hashes(inner.missing.unwrap_or_default().hashes);
+
+        let nodes = storage
+            .get(&target.root.hash)
+            .ok_or(Status::not_found("tree not found"))?
+            .find_negotiated_diff(&from, &missing);
+        drop(storage);
+        tracing::debug!("Relaying {} to {uuid}", target.root.hash);
+
+        let stream = sender(tokio_stream::iter(nodes), 32, Duration::new(0, 4800))
+            .map(|payload| SerializedTree { payload })
+            .map(Ok);
+        Ok(Response::new(Box::pin(stream) as Self::TreeTransferStream))
+    }
+
+    async fn negotiate(
+        &self,
+        request: Request<DiffProbe>,
+    ) -> Result<Response<DiffFrontier>, Status> {
+        downstream_uuid(&request)?;
+        let inner = request.into_inner();
+        let path = PathBuf::from(&inner.item_path);
+
+        let subtrees = if inner.expand.is_empty() {
+            // First round, the client is about to ask for the item
+            let target = self.target(&path, inner.request_version).await?;
+            self.hold(&target).await?;
+            let root = self
+                .client
+                .storage
+                .read()
+                .await
+                .get(&target.root.hash)
+                .ok_or(Status::not_found("tree not found"))?;
+            vec![(*root.hash(), root.children().is_none())]
+        } else {
+            negotiation::expand(
+                &*self.client.storage.read().await,
+                &parse_hashes(inner.expand),
+            )
+        };
+
+        Ok(Response::new(DiffFrontier {
+            subtrees: subtrees
+                .into_iter()
+                .map(|(hash, leaf)| Subtree {
+                    hash: hash.as_bytes().to_vec(),
+                    leaf,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn missing(&self, request: Request<Hashes>) -> Result<Response<Hashes>, Status> {
+        downstream_uuid(&request)?;
+        let hashes = parse_hashes(request.into_inner().hashes);
+        let storage = self.client.storage.read().await;
+        Ok(Response::new(Hashes {
+            hashes: hashes
+                .into_iter()
+                .filter(|hash| !storage.contains(hash))
+                .map(|hash| hash.as_bytes().to_vec())
+                .collect(),
+            replace: false,
+        }))
+    }
+
+    /// Downstream clients get items from the relay, no peer is handed out
+    async fn peers(&self, request: Request<PeersRequest>) -> Result<Response<PeerList>, Status> {
+        downstream_uuid(&request)?;
+        Ok(Response::new(PeerList::default()))
+    }
+
+    async fn publish(
+        &self,
+        _request: Request<Streaming<ItemUpload>>,
+    ) -> Result<Response<PublishedItem>, Status> {
+        Err(not_relayed())
+    }
+
+    async fn create_feed(
+        &self,
+        _request: Request<FeedRef>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        Err(not_relayed())
+    }
+
+    async fn rename_feed(
+        &self,
+        _request: Request<FeedRename>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        Err(not_relayed())
+    }
+
+    async fn delete_feed(
+        &self,
+        _request: Request<FeedRef>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        Err(not_relayed())
+    }
+
+    async fn add_to_feed(
+        &self,
+        _request: Request<FeedItem>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        Err(not_relayed())
+    }
+
+    async fn remove_from_feed(
+        &self,
+        _request: Request<FeedItem>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        Err(not_relayed())
+    }
+}
+
+/// Changes are made on the server only, relays just pass them on
+fn not_relayed() -> Status {
+    Status::unimplemented("Not available on relays, ask the server")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use distd_core::auth::{self, TOKEN_METADATA};
+    use distd_core::signature::PublicKey;
+    use distd_core::tonic::{metadata::BinaryMetadataValue, service::Interceptor, Code, Request};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use uuid::Uuid;
+
+    use super::{check_api_key, downstream_uuid, SessionInterceptor, API_KEY_METADATA};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn with_token(key_pair: &Ed25519KeyPair, uuid: Uuid) -> Request<()> {
+        let (token, _) = auth::issue(key_pair, uuid, Duration::from_mins(1)).unwrap();
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert_bin(TOKEN_METADATA, BinaryMetadataValue::from_bytes(&token));
+        request
+    }
+
+    #[test]
+    fn session_required() {
+        let relay = key_pair();
+        let mut interceptor = SessionInterceptor {
+            public_key: PublicKey::try_from(relay.public_key().as_ref()).unwrap(),
+        };
+        let uuid = Uuid::new_v4();
+
+        // Let through to register, refused by anything else
+        let request = interceptor.call(Request::new(())).unwrap();
+        let status = downstream_uuid(&request).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let request = interceptor.call(with_token(&relay, uuid)).unwrap();
+        assert_eq!(downstream_uuid(&request).unwrap(), uuid);
+
+        // Tokens issued by someone else, e.g. the server, are not ours
+        let status = interceptor.call(with_token(&key_pair(), uuid)).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn api_key_required() {
+        let with_key = |key: &str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert(API_KEY_METADATA, format!("Bearer {key}").parse().unwrap());
+            request
+        };
+
+        // Anyone may register without configured keys, but not with a key we don't know
+        let none = HashSet::new();
+        assert!(check_api_key(&none, &Request::new(())).is_ok());
+        assert!(check_api_key(&none, &with_key("secret")).is_err());
+
+        let keys = HashSet::from([blake3::hash(b"secret")]);
+        assert!(check_api_key(&keys, &with_key("secret")).is_ok());
+        let status = check_api_key(&keys, &Request::new(())).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(check_api_key(&keys, &with_key("other")).is_err());
+    }
+}
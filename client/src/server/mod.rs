@@ -1,24 +1,48 @@
 //use std::{net::SocketAddr
 use crate::{error::ServerRequest, grpc::DistdGrpcClient};
 
-use std::{fmt::Debug, sync::Arc, time::Duration};
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use uuid::Uuid;
 
-use tokio::{sync::RwLock, time::Instant};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use tokio::{
+    sync::{Notify, RwLock},
+    time::Instant,
+};
+use tokio_stream::{Stream, StreamExt};
 
 //use ring::agreement::PublicKey;
 
 use distd_core::{
+    auth,
     error::InvalidParameter,
     hash::Hash,
-    metadata::Server as ServerMetadata,
-    proto::{distd_client::DistdClient, Hashes, SerializedTree},
-    tonic::{service::interceptor::InterceptedService, transport::Channel, Streaming},
-    utils::grpc::uuid_to_metadata,
+    metadata::{Item as ItemMetadata, Server as ServerMetadata, Update},
+    proto::{
+        distd_client::DistdClient, ChallengeRequest, ClientKeepAlive, ClientRegister, DiffProbe,
+        EnumAcknowledge, Hashes, ItemUpload, MetadataUpdate, PeersRequest, SerializedTree,
+        ServerMetadata as SignedMetadata, SessionToken, TokenRequest, UpdatesRequest,
+    },
+    signature::{self, Error as SignatureError, PublicKey, RealmChange, DEFAULT_REALM},
+    tonic::{
+        metadata::BinaryMetadataValue, service::interceptor::InterceptedService,
+        transport::Channel, Streaming,
+    },
     version::VERSION,
     Request,
 };
 
+/// Time between heartbeats, well within the time the server waits for them before expiring peers
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Session tokens are renewed this long before they expire
+const TOKEN_RENEWAL: Duration = Duration::from_mins(1);
+
 /// Shared server-related data to be kept behind an async lock
 #[derive(Debug)]
 struct SharedServer {
@@ -28,10 +52,26 @@ struct SharedServer {
     /// last time metadata was fetched from server
     pub last_update: Instant,
 
+    /// generation of `metadata`, if following updates pushed by the server
+    pub generation: Option<u64>,
+
+    /// expiry of the session token, in seconds since the Unix epoch
+    pub token_expires: Option<u64>,
+
     /// client for gRPC requests to server
     pub grpc_client: distd_core::Client<InterceptedService<Channel, DistdGrpcClient>>,
 }
 
+/// What a client proves who it is and what it may do with
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    /// Client Ed25519 key pair, the client is bound to it when first registering
+    pub key_pair: Arc<Ed25519KeyPair>,
+
+    /// API key configured on the server, needed to publish and manage feeds
+    pub api_key: Option<String>,
+}
+
 /// Server representation used by clients
 #[derive(Debug, Clone)]
 pub struct Server {
@@ -43,7 +83,7 @@ pub struct Server {
     pub url: String,
 
     /// server Ed25519 public key
-    pub pub_key: [u8; 32], // TODO Check this
+    pub pub_key: PublicKey,
 
     /// Client Uuid assigned to client from server
     client_uuid: Option<Uuid>,
@@ -51,11 +91,23 @@ pub struct Server {
     /// Client name
     client_name: String,
 
+    /// Realm registered into, the server default one if not set
+    realm: Option<String>,
+
+    /// Client Ed25519 key pair, proving who we are when registering
+    key_pair: Arc<Ed25519KeyPair>,
+
+    /// Interceptor adding the session token to requests
+    session: DistdGrpcClient,
+
     /// Shared data
     shared: Arc<RwLock<SharedServer>>,
 
     /// Elapsed time between server fetches
     pub timeout: Duration,
+
+    /// Notified whenever metadata changes
+    updated: Arc<Notify>,
 }
 
 impl Server {
@@ -63,8 +115,10 @@ impl Server {
     ///
     /// # Arguments
     /// * `url` - server url
-    /// * `pub_key` - client public key, TODO
+    /// * `pub_key` - server Ed25519 public key, used to verify metadata and items
     /// * `client_name` - client name
+    /// * `realm` - realm to register into, the server default one if `None`
+    /// * `credentials` - client key pair and API key, if any
     /// * `timeout` - timeout for server fetches, TODO
     ///
     /// # Returns
@@ -75,6 +129,7 @@ impl Server {
     /// * `ServerRequest::Request` - if the request fails
     /// * `ServerRequest::Utf8` - if the response is not valid utf8
     /// * `ServerRequest::Uuid` - if the response is not a valid uuid
+    /// * `ServerRequest::Signature` - if server metadata is not signed with `pub_key`
     ///
     /// # Panics
     /// * If the url does not have a scheme or authority
@@ -83,9 +138,19 @@ impl Server {
         //pub_key: &PublicKey,
         client_name: &str,
         client_uuid: Option<Uuid>,
-        pub_key: &[u8; 32],
+        pub_key: &PublicKey,
+        realm: Option<String>,
+        credentials: Credentials,
     ) -> Result<Self, ServerRequest> {
-        let grpc_client = Self::make_grpc_client(url, &Uuid::nil()).await?;
+        let session = DistdGrpcClient {
+            api_key: credentials
+                .api_key
+                .map(|key| format!("Bearer {key}").try_into())
+                .transpose()
+                .map_err(|_| ServerRequest::BadApiKey)?,
+            ..DistdGrpcClient::default()
+        };
+        let grpc_client = Self::make_grpc_client(url, session.clone()).await?;
         tracing::debug!("Connected to server");
 
         let timeout = Duration::new(5, 0); // TODO make this configurable
@@ -98,12 +163,18 @@ impl Server {
             url: url.to_string(),
             client_uuid,
             client_name: client_name.to_string(),
+            realm,
+            key_pair: credentials.key_pair,
+            session,
             shared: Arc::new(RwLock::new(SharedServer {
                 metadata: ServerMetadata::default(),
                 grpc_client,
                 last_update: Instant::now(),
+                generation: None,
+                token_expires: None,
             })),
             timeout,
+            updated: Arc::default(),
         };
         server.register().await?;
         server.fetch().await?;
@@ -111,6 +182,18 @@ impl Server {
         Ok(server)
     }
 
+    /// Get the realm we're registered into, `None` for the server default one
+    #[must_use]
+    pub fn realm(&self) -> Option<&str> {
+        self.realm.as_deref()
+    }
+
+    /// Get the client key pair
+    #[must_use]
+    pub fn key_pair(&self) -> &Ed25519KeyPair {
+        &self.key_pair
+    }
+
     /// Get the client uuid
     #[must_use] pub fn client_uuid(&self) -> Uuid {
         self.client_uuid.unwrap_or(Uuid::nil())
@@ -118,32 +201,55 @@ impl Server {
 
     async fn make_grpc_client(
         url: &str,
-        uuid: &Uuid,
+        session: DistdGrpcClient,
     ) -> Result<DistdClient<InterceptedService<Channel, DistdGrpcClient>>, ServerRequest> {
         tracing::debug!("Connecting to server at {url}");
         let grpc_channel = distd_core::tonic::transport::Channel::from_shared(url.to_string())
             .map_err(InvalidParameter::Uri)?
             .connect()
             .await?;
-        Ok(distd_core::Client::with_interceptor(
-            grpc_channel,
-            DistdGrpcClient {
-                uuid: uuid_to_metadata(uuid),
-            },
-        ))
+        Ok(distd_core::Client::with_interceptor(grpc_channel, session))
     }
 
     /// Register a new client
     pub async fn register(&mut self) -> Result<Uuid, ServerRequest> {
+        let uuid = self.authenticate().await?;
+        self.client_uuid = Some(uuid);
+        Ok(uuid)
+    }
+
+    /// Register with our uuid, if any, answering a challenge with our key pair to get a new session token
+    ///
+    /// Returns the uuid assigned by the server.
+    async fn authenticate(&self) -> Result<Uuid, ServerRequest> {
         let mut shared = self.shared.write().await;
 
+        tracing::trace!("Starting `Challenge` request");
+        let nonce = shared
+            .grpc_client
+            .challenge(Request::new(ChallengeRequest {}))
+            .await?
+            .into_inner()
+            .nonce;
+        let signature = auth::answer(
+            &self.key_pair,
+            nonce
+                .as_slice()
+                .try_into()
+                .map_err(|_| ServerRequest::BadChallenge)?,
+        );
+
         tracing::trace!("Starting `Register` request");
         let res = shared
             .grpc_client
-            .register(Request::new(distd_core::proto::ClientRegister {
+            .register(Request::new(ClientRegister {
                 name: self.client_name.to_string(),
                 version: VERSION.to_string(),
                 uuid: self.client_uuid.map(|uuid| uuid.as_bytes().to_vec()),
+                realm: self.realm.clone(),
+                public_key: self.key_pair.public_key().as_ref().to_vec(),
+                signature,
+                nonce,
             }))
             .await?
             .into_inner();
@@ -154,13 +260,55 @@ impl Server {
         let uuid = Uuid::from_bytes(uuid);
         tracing::info!("Got uuid '{uuid:?}' from server");
 
-        // Update client_uuid and create a new gRPC connection setting it in the metadata
-        self.client_uuid = Some(uuid);
-        shared.grpc_client = Self::make_grpc_client(&self.url, &self.client_uuid()).await?;
-
+        let session = res.session.ok_or(ServerRequest::MissingToken)?;
+        self.set_session(&mut shared, &session)?;
         Ok(uuid)
     }
 
+    /// Renew the session token, registering again if the server doesn't, e.g. as it already expired
+    pub async fn renew_token(&self) -> Result<(), ServerRequest> {
+        tracing::debug!("Renewing session token");
+        {
+            let mut shared = self.shared.write().await;
+            match shared
+                .grpc_client
+                .renew_token(Request::new(TokenRequest {}))
+                .await
+            {
+                Ok(session) => return self.set_session(&mut shared, session.get_ref()),
+                Err(e) => tracing::warn!("Cannot renew session token: {}", e.message()),
+            }
+        }
+        self.authenticate().await.map(|_| ())
+    }
+
+    /// Use `session` for the following requests
+    #[allow(clippy::result_large_err)]
+    fn set_session(
+        &self,
+        shared: &mut SharedServer,
+        session: &SessionToken,
+    ) -> Result<(), ServerRequest> {
+        if session.token.is_empty() {
+            return Err(ServerRequest::MissingToken);
+        }
+        *self.session.token.write().unwrap() =
+            Some(BinaryMetadataValue::from_bytes(&session.token));
+        shared.token_expires = Some(session.expires);
+        Ok(())
+    }
+
+    /// Wait until the session token is about to expire, forever if we don't have one
+    async fn token_expiring(&self) {
+        let Some(expires) = self.shared.read().await.token_expires else {
+            return std::future::pending().await;
+        };
+        let left = (SystemTime::UNIX_EPOCH + Duration::from_secs(expires))
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        tokio::time::sleep(left.saturating_sub(TOKEN_RENEWAL)).await;
+    }
+
     /// Get the server metadata
     pub async fn metadata(&self) -> ServerMetadata {
         self.shared.read().await.metadata.clone()
@@ -171,8 +319,20 @@ impl Server {
         self.shared.read().await.last_update
     }
 
+    /// Wait for metadata to change
+    ///
+    /// A change happened while nobody was waiting is reported right away.
+    pub async fn updated(&self) {
+        self.updated.notified().await;
+    }
+
     /// Fetch metadata from server
-    async fn fetch(&self) -> Result<(), ServerRequest> {
+    ///
+    /// Returns the metadata as signed by the server, for relays to pass it on to their own clients.
+    ///
+    /// # Panics
+    /// * If the client is not registered yet
+    pub async fn fetch(&self) -> Result<SignedMetadata, ServerRequest> {
         tracing::trace!("Starting `Fetch` request");
 
         let mut shared = self.shared.write().await;
@@ -182,39 +342,192 @@ impl Server {
         //distd_core::AcknowledgeRequest::new(distd_core::proto::EnumAcknowledge::AckOk);
         let res = shared
             .grpc_client
-            .fetch(Request::new(distd_core::proto::ClientKeepAlive {}))
+            .fetch(Request::new(ClientKeepAlive::default()))
             .await?
             .into_inner();
         tracing::trace!("Parsed `Fetch` response");
 
-        let new_metadata = bitcode::deserialize(&res.serialized)?;
+        // Refuse anything not coming from the server we trust
+        let signature = res.signature.as_ref().ok_or(SignatureError::Missing)?;
+        signature::verify(&self.pub_key, &res.serialized, signature)?;
+        let new_metadata: ServerMetadata = bitcode::deserialize(&res.serialized)?;
+        new_metadata.verify_items(&self.pub_key)?;
         shared.last_update = Instant::now();
 
         if shared.metadata != new_metadata {
             shared.metadata = new_metadata;
             tracing::trace!("New metadata: {:?}", shared.metadata);
+            self.updated.notify_one();
         }
 
+        Ok(res)
+    }
+
+    /// Stream of the metadata updates pushed by the server, starting from `generation` if we hold it
+    ///
+    /// Updates are not checked nor applied, see `follow_updates`.
+    pub async fn updates(
+        &self,
+        generation: Option<u64>,
+    ) -> Result<Streaming<MetadataUpdate>, ServerRequest> {
+        let mut shared = self.shared.write().await;
+        Ok(shared
+            .grpc_client
+            .updates(Request::new(UpdatesRequest { generation }))
+            .await?
+            .into_inner())
+    }
+
+    /// Follow the metadata updates pushed by the server, applying them as they come
+    ///
+    /// Returns when the stream ends or breaks, updates applied so far are kept.
+    async fn follow_updates(&self) -> Result<(), ServerRequest> {
+        let generation = self.shared.read().await.generation;
+        tracing::trace!("Following updates from generation {generation:?}");
+        let mut stream = self.updates(generation).await?;
+
+        while let Some(res) = stream.message().await? {
+            // Refuse anything not coming from the server we trust
+            let signature = res.signature.ok_or(SignatureError::Missing)?;
+            signature::verify(&self.pub_key, &res.serialized, &signature)?;
+            let update: Update = bitcode::deserialize(&res.serialized)?;
+            update.verify_items(&self.pub_key)?;
+
+            let mut shared = self.shared.write().await;
+            // Signed updates may still be replayed, by a relay or anyone in between
+            if !update.follows(shared.generation) {
+                return Err(ServerRequest::OutOfOrder {
+                    generation: update.generation,
+                    held: shared.generation,
+                });
+            }
+            shared.last_update = Instant::now();
+            shared.generation = Some(update.generation);
+            if !update.is_empty() {
+                tracing::trace!("Metadata update: {update:?}");
+                shared.metadata.apply(update);
+                self.updated.notify_one();
+            }
+        }
         Ok(())
     }
 
+    /// Advertise to the server the hashes of chunks and trees we hold
+    ///
+    /// If `replace` is set these replace anything advertised before, otherwise they're added to it.
+    /// Returns whether the server recognized every provided hash.
+    pub async fn advertise(&self, hashes: &[Hash], replace: bool) -> Result<bool, ServerRequest> {
+        tracing::trace!("Advertising {} hashes, replace: {replace}", hashes.len());
+        let mut shared = self.shared.write().await;
+
+        let ack = shared
+            .grpc_client
+            .adv_hashes(Request::new(Hashes {
+                hashes: hashes.iter().map(|x| x.as_bytes().to_vec()).collect(),
+                replace,
+            }))
+            .await?
+            .into_inner();
+
+        Ok(ack.ack() == EnumAcknowledge::AckOk)
+    }
+
+    /// Let the server know we're alive, and the UDP port we serve held items to peers on if any
+    pub async fn heartbeat(&self, peer_port: Option<u16>) -> Result<(), ServerRequest> {
+        tracing::trace!("Sending heartbeat, peer port: {peer_port:?}");
+        let mut shared = self.shared.write().await;
+
+        shared
+            .grpc_client
+            .heartbeat(Request::new(ClientKeepAlive {
+                peer_port: peer_port.map(u32::from),
+            }))
+            .await?;
+        Ok(())
+    }
+
+    /// Live peers holding the whole item with `root`, as tracked by the server, best candidates first, along with
+    /// the peer token they open channels for
+    ///
+    /// Peers holding just part of the item are left out as they don't serve it yet.
+    pub async fn peers(&self, root: &Hash) -> Result<(Vec<SocketAddr>, Vec<u8>), ServerRequest> {
+        let mut shared = self.shared.write().await;
+
+        let peers = shared
+            .grpc_client
+            .peers(Request::new(PeersRequest {
+                root: root.as_bytes().to_vec(),
+                limit: None,
+            }))
+            .await?
+            .into_inner();
+
+        let token = peers.token.ok_or(ServerRequest::MissingToken)?;
+        let peers = peers
+            .peers
+            .into_iter()
+            .filter(|peer| peer.complete)
+            .filter_map(|peer| peer.addr.parse().ok())
+            .collect();
+        Ok((peers, token))
+    }
+
+    /// Run a negotiation round for an item, see `Negotiation`
+    ///
+    /// With an empty `expand` the server returns the item root, otherwise the children of the given subtrees.
+    pub async fn negotiate(
+        &self,
+        item_path: String,
+        request_version: Option<u32>,
+        expand: &[Hash],
+    ) -> Result<Vec<(Hash, bool)>, ServerRequest> {
+        let mut shared = self.shared.write().await;
+
+        let frontier = shared
+            .grpc_client
+            .negotiate(Request::new(DiffProbe {
+                item_path,
+                request_version,
+                expand: expand.iter().map(|x| x.as_bytes().to_vec()).collect(),
+            }))
+            .await?
+            .into_inner();
+
+        frontier
+            .subtrees
+            .into_iter()
+            .map(|subtree| {
+                let hash: [u8; 32] = subtree
+                    .hash
+                    .try_into()
+                    .map_err(|_| ServerRequest::BadHash)?;
+                Ok((Hash::from_bytes(hash), subtree.leaf))
+            })
+            .collect()
+    }
+
     // TODO diff may optionally be computed client-side
     /// Transfer chunks from server, computing diff from local data
+    ///
+    /// If `from` is `None` the server computes the diff from the hashes we advertised with `advertise`,
+    /// `missing` are the subtrees a negotiation found we lack.
     pub async fn transfer_diff(
         &self,
         item_path: String,
         request_version: Option<u32>,
         from_version: Option<u32>,
-        from: &[Hash],
+        from: Option<&[Hash]>,
+        missing: &[Hash],
     ) -> Result<Streaming<SerializedTree>, ServerRequest> {
         tracing::trace!("Preparing transfer/diff request: target: '{item_path}', {from_version:?}->{request_version:?}, {from:?}");
         let mut shared = self.shared.write().await;
 
-        // comma separated list of hashes
-        let from = from
-            .iter()
-            .map(|x| x.as_bytes().to_vec())
-            .collect::<Vec<Vec<u8>>>();
+        let to_proto = |hashes: &[Hash]| Hashes {
+            hashes: hashes.iter().map(|x| x.as_bytes().to_vec()).collect(),
+            replace: false,
+        };
+        let from = from.map(to_proto);
+        let missing = (!missing.is_empty()).then(|| to_proto(missing));
 
         Ok(shared
             .grpc_client
@@ -222,26 +535,138 @@ impl Server {
                 item_path,
                 request_version,
                 from_version,
-                hashes: Some(Hashes { hashes: from }),
+                hashes: from,
+                missing,
             }))
             .await?
             .into_inner())
     }
 
-    /// Fetch metadata from server in a loop
+    /// Hashes among `hashes` the server doesn't hold
+    pub async fn missing(&self, hashes: &[Hash]) -> Result<Vec<Hash>, ServerRequest> {
+        let mut shared = self.shared.write().await;
+
+        let missing = shared
+            .grpc_client
+            .missing(Request::new(Hashes {
+                hashes: hashes.iter().map(|x| x.as_bytes().to_vec()).collect(),
+                replace: false,
+            }))
+            .await?
+            .into_inner();
+
+        missing
+            .hashes
+            .into_iter()
+            .map(|hash| {
+                let hash: [u8; 32] = hash.try_into().map_err(|_| ServerRequest::BadHash)?;
+                Ok(Hash::from_bytes(hash))
+            })
+            .collect()
+    }
+
+    /// Publish an item described by `metadata`, uploading the serialized nodes in `payloads`
+    ///
+    /// The server assigns the revision, and adds the item to `feed` if provided.
+    /// Realms with an owner only accept items signed with `owner_key`, the key pair of their owner, see `RealmChange`:
+    /// the signature is made for a challenge nonce asked to the server first.
+    /// Returns the metadata of the published item, as signed by the server.
+    pub async fn publish<S>(
+        &self,
+        metadata: &ItemMetadata,
+        feed: Option<String>,
+        owner_key: Option<&Ed25519KeyPair>,
+        payloads: S,
+    ) -> Result<ItemMetadata, ServerRequest>
+    where
+        S: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        let change = RealmChange::Publish {
+            realm: self.realm().unwrap_or(DEFAULT_REALM),
+            path: &metadata.path,
+            root: &metadata.root.hash,
+            feed: feed.as_deref(),
+        };
+        // The upload must start right away, the nonce signed along is only good for a short while
+        let mut grpc_client = self.shared.read().await.grpc_client.clone();
+        let owner_signature = match owner_key {
+            Some(key) => {
+                let nonce = grpc_client
+                    .challenge(Request::new(ChallengeRequest {}))
+                    .await?
+                    .into_inner()
+                    .nonce;
+                Some(change.sign(key, &nonce)?)
+            }
+            None => None,
+        };
+        let first = ItemUpload {
+            owner_signature,
+            metadata: Some(bitcode::serialize(metadata)?),
+            feed,
+            payload: vec![],
+        };
+        let uploads = tokio_stream::once(first).chain(payloads.map(|payload| ItemUpload {
+            metadata: None,
+            feed: None,
+            payload,
+            owner_signature: None,
+        }));
+
+        // The upload may take a while, the client is cloned not to keep other requests waiting for it
+        let published = grpc_client
+            .publish(Request::new(uploads))
+            .await?
+            .into_inner();
+
+        let item: ItemMetadata = bitcode::deserialize(&published.serialized)?;
+        item.verify(&self.pub_key)?;
+        Ok(item)
+    }
+
+    /// Send a heartbeat every `HEARTBEAT_INTERVAL`, for the server to keep handing us out as a peer
+    pub async fn heartbeat_loop(self, peer_port: Option<u16>) {
+        loop {
+            if let Err(e) = self.heartbeat(peer_port).await {
+                tracing::warn!("Cannot send heartbeat: {e}");
+            }
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        }
+    }
+
+    /// Keep metadata up to date, following the updates pushed by the server, and our session token valid
+    ///
+    /// Whenever the stream ends or breaks it's established again after `timeout`, then the server either
+    /// resumes from the generation we hold or sends everything again.
+    /// The stream is also dropped and established again right away when the session token is renewed.
     pub async fn fetch_loop(self) {
         loop {
+            let res = tokio::select! {
+                res = self.follow_updates() => res,
+                () = self.token_expiring() => match self.renew_token().await {
+                    Ok(()) => continue,
+                    Err(e) => Err(e),
+                },
+            };
             tokio::time::sleep(self.timeout).await;
-            if self.fetch().await.is_err() {
-                // try to re-establish connection to server
-                if let Ok(client) = Self::make_grpc_client(&self.url, &self.client_uuid()).await {
-                    tracing::info!("Connected to server");
-                    self.shared.write().await.grpc_client = client;
-                } else {
-                    tracing::warn!(
-                        "Cannot connect to server, retrying in {} seconds",
-                        self.timeout.as_secs()
-                    );
+            match res {
+                Ok(()) => tracing::debug!("Metadata updates stream ended"),
+                Err(ServerRequest::Signature(e)) => {
+                    tracing::error!("Refusing server metadata: {e}");
+                }
+                Err(_) => {
+                    // try to re-establish connection to server
+                    if let Ok(client) =
+                        Self::make_grpc_client(&self.url, self.session.clone()).await
+                    {
+                        tracing::info!("Connected to server");
+                        self.shared.write().await.grpc_client = client;
+                    } else {
+                        tracing::warn!(
+                            "Cannot connect to server, retrying in {} seconds",
+                            self.timeout.as_secs()
+                        );
+                    }
                 }
             }
         }
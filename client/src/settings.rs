@@ -1,6 +1,11 @@
 use config::{Config, Environment, File};
+use distd_core::attributes::Ownership;
+use distd_core::chunk_storage::fs_storage::Install;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
 use crate::error::Client as ClientError;
 
@@ -10,6 +15,9 @@ pub use distd_core::utils::settings::{cache_dir, config_dir};
 #[allow(unused)]
 pub struct Server {
     pub url: String,
+
+    /// Base64-encoded Ed25519 public key of the server, as logged by the server on startup
+    pub public_key: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,13 +42,98 @@ pub struct FsStorage {
 
     /// The path to the root of the storage directory.
     pub root: String,
+
+    /// How updated items are installed, atomically unless set to `in-place`
+    #[serde(default)]
+    pub install: Install,
+
+    /// Owner of installed items, the user running the client unless set to `preserve` the one carried by items or
+    /// to `override` with the given `user` and `group`
+    #[serde(default)]
+    pub ownership: Ownership,
+}
+
+/// An item to be kept at a specific revision instead of following the latest one
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pin {
+    pub path: PathBuf,
+    pub revision: u32,
 }
 
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 pub struct Client {
     pub name: String,
+
+    /// Items to keep in sync, on top of the ones in subscribed feeds
+    #[serde(default)]
     pub sync: Vec<PathBuf>,
+
+    /// Synced items pinned to a specific revision, may be used to roll back an item
+    #[serde(default)]
+    pub pin: Vec<Pin>,
+
+    /// Realm to register into, the server default one if not set
+    #[serde(default)]
+    pub realm: Option<String>,
+
+    /// PKCS#8 file with the Ed25519 key pair of the realm owner, needed to publish into owned realms
+    #[serde(default)]
+    pub owner_key: Option<PathBuf>,
+
+    /// API key granting the publisher role on the server, needed to publish and manage feeds
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+impl Client {
+    /// Revision an item is pinned to, if any
+    #[must_use]
+    pub fn pinned(&self, path: &Path) -> Option<u32> {
+        self.pin
+            .iter()
+            .find(|pin| pin.path == path)
+            .map(|pin| pin.revision)
+    }
+}
+
+/// Chunk exchange with other clients, e.g. the ones in the same site
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Peer {
+    /// Address to serve held items to other clients on, nothing is served if not set
+    pub listen: Option<SocketAddr>,
+
+    /// Clients to get items from before falling back to the server
+    pub peers: Vec<SocketAddr>,
+
+    /// Seconds to wait for peers to provide an item before falling back to the server
+    pub timeout: u64,
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            peers: vec![],
+            timeout: 60,
+        }
+    }
+}
+
+/// Serving clients in a lower tier, as a server would
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Relay {
+    /// Address to serve the gRPC service on, we don't act as a relay if not set
+    pub listen: Option<SocketAddr>,
+
+    /// Feeds whose items are kept in sync ahead of requests, other items are fetched when first requested
+    pub feeds: Vec<String>,
+
+    /// API keys downstream clients must register with, sent as `Authorization: Bearer <key>`; anyone may register
+    /// if there's none, but keys that aren't configured are refused either way
+    pub api_keys: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -51,6 +144,12 @@ pub struct Settings {
     pub server: Server,
     pub log: Log,
     pub client: Client,
+
+    #[serde(default)]
+    pub peer: Peer,
+
+    #[serde(default)]
+    pub relay: Relay,
 }
 
 impl Settings {
@@ -0,0 +1,478 @@
+//! File attributes carried by items: POSIX mode, ownership, modification time and extended attributes
+//!
+//! Attributes are captured from the published file, and applied to the installed one by storages writing to the
+//! filesystem. Ownership is only applied if the installing side asks for it, see `Ownership`, as users and groups
+//! of the publisher may mean nothing on the client.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, Permissions},
+    io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Mode bits granting privileges of the file owner or group to whoever runs the file
+const SET_ID_BITS: u32 = 0o6000;
+
+/// Extended attributes left out of captures, as they're up to the host policy rather than to the file
+const HOST_XATTRS: &[&str] = &["security.selinux"];
+
+/// Extended attributes granting privileges to whoever runs the file, file capabilities, or only meant for
+/// privileged processes, the `trusted` namespace: like setuid and setgid bits, only applied along with the owner
+const PRIVILEGED_XATTRS: &[&str] = &["security.capability"];
+const PRIVILEGED_NAMESPACE: &str = "trusted.";
+
+/// Attributes of an item file, every one of them optional
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    /// Permission bits, including setuid, setgid and sticky ones
+    pub mode: Option<u32>,
+
+    pub owner: Option<Owner>,
+
+    /// Last modification time
+    pub mtime: Option<SystemTime>,
+
+    /// Extended attributes, by name
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+/// Owner of a file, by id and by name if known to the publisher
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+/// What to do with the owner carried by items on install
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ownership {
+    /// Files are owned by whoever installs them, setuid and setgid bits are dropped along with file capabilities and
+    /// `trusted.*` extended attributes
+    #[default]
+    Ignore,
+
+    /// Files get the owner carried by the item, by name if known here or else by id
+    Preserve,
+
+    /// Files get the given user and group, by name, whatever the item carries
+    Override {
+        user: Option<String>,
+        group: Option<String>,
+    },
+}
+
+impl Attributes {
+    /// Whether there is no attribute at all
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Capture the attributes of the file at `path`
+    ///
+    /// Extended attributes are left empty if the filesystem doesn't support them.
+    pub fn capture(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let xattrs = match xattr::list(path) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => BTreeMap::new(),
+            xattrs => xattrs?,
+        };
+        Ok(Self {
+            mode: Some(metadata.mode() & 0o7777),
+            owner: Some(Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                user: names::user(metadata.uid()),
+                group: names::group(metadata.gid()),
+            }),
+            mtime: metadata.modified().ok(),
+            xattrs: xattrs
+                .into_iter()
+                .filter(|(name, _)| !HOST_XATTRS.contains(&name.as_str()))
+                .collect(),
+        })
+    }
+
+    /// Apply the attributes to the file at `path`, with the owner chosen by `ownership`
+    ///
+    /// Extended attributes that cannot be set, not being supported by the filesystem or needing privileges we
+    /// don't have, are skipped with a warning. Failing to change the owner is an error instead, as it was asked for.
+    /// The owner is changed first, as it clears file capabilities and setuid and setgid bits, and the mode last, as
+    /// it may not let us write to the file anymore.
+    pub fn apply(&self, path: &Path, ownership: &Ownership) -> io::Result<()> {
+        let (uid, gid) = self.owner(ownership)?;
+        if uid.is_some() || gid.is_some() {
+            std::os::unix::fs::chown(path, uid, gid)?;
+        }
+        for (name, value) in self.xattrs(ownership) {
+            match xattr::set(path, name, value) {
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTSUP | libc::EPERM)) => {
+                    tracing::warn!("Cannot set {name} on '{}': {e}", path.to_string_lossy());
+                }
+                res => res?,
+            }
+        }
+        if let Some(mtime) = self.mtime {
+            File::open(path)?.set_modified(mtime)?;
+        }
+        if let Some(mode) = self.mode {
+            let mode = match ownership {
+                Ownership::Ignore => mode & !SET_ID_BITS,
+                _ => mode,
+            };
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Extended attributes to give to installed files, privileged ones only if the owner is applied too
+    fn xattrs<'a>(
+        &'a self,
+        ownership: &'a Ownership,
+    ) -> impl Iterator<Item = (&'a String, &'a Vec<u8>)> + 'a {
+        self.xattrs.iter().filter(move |(name, _)| {
+            *ownership != Ownership::Ignore
+                || !(PRIVILEGED_XATTRS.contains(&name.as_str())
+                    || name.starts_with(PRIVILEGED_NAMESPACE))
+        })
+    }
+
+    /// User and group ids to give to installed files, if any
+    fn owner(&self, ownership: &Ownership) -> io::Result<(Option<u32>, Option<u32>)> {
+        let unknown = |what, name: &str| {
+            io::Error::new(io::ErrorKind::NotFound, format!("Unknown {what} '{name}'"))
+        };
+        match (ownership, &self.owner) {
+            (Ownership::Preserve, Some(owner)) => Ok((
+                Some(
+                    owner
+                        .user
+                        .as_deref()
+                        .and_then(names::uid)
+                        .unwrap_or(owner.uid),
+                ),
+                Some(
+                    owner
+                        .group
+                        .as_deref()
+                        .and_then(names::gid)
+                        .unwrap_or(owner.gid),
+                ),
+            )),
+            (Ownership::Override { user, group }, _) => Ok((
+                user.as_deref()
+                    .map(|user| names::uid(user).ok_or_else(|| unknown("user", user)))
+                    .transpose()?,
+                group
+                    .as_deref()
+                    .map(|group| names::gid(group).ok_or_else(|| unknown("group", group)))
+                    .transpose()?,
+            )),
+            _ => Ok((None, None)),
+        }
+    }
+}
+
+/// Lookups in the user and group databases
+mod names {
+    use std::ffi::{CStr, CString};
+    use std::mem::MaybeUninit;
+    use std::ptr;
+
+    use libc::{c_char, c_int, group, passwd, size_t};
+
+    /// Run a reentrant database lookup, growing its buffer as needed, and extract what's needed from the entry
+    fn lookup<T, R>(
+        call: impl Fn(*mut T, *mut c_char, size_t, *mut *mut T) -> c_int,
+        extract: impl FnOnce(&T) -> R,
+    ) -> Option<R> {
+        let mut buf: Vec<c_char> = vec![0; 1024];
+        loop {
+            let mut entry = MaybeUninit::<T>::uninit();
+            let mut found = ptr::null_mut();
+            match call(
+                entry.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                ptr::addr_of_mut!(found),
+            ) {
+                libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+                // Strings in the entry point into `buf`, still alive here
+                0 if !found.is_null() => return Some(extract(unsafe { &*found })),
+                _ => return None,
+            }
+        }
+    }
+
+    fn string(ptr: *const c_char) -> String {
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn user(uid: u32) -> Option<String> {
+        lookup(
+            |pwd, buf, len, found| unsafe { libc::getpwuid_r(uid, pwd, buf, len, found) },
+            |pwd: &passwd| string(pwd.pw_name),
+        )
+    }
+
+    pub fn group(gid: u32) -> Option<String> {
+        lookup(
+            |grp, buf, len, found| unsafe { libc::getgrgid_r(gid, grp, buf, len, found) },
+            |grp: &group| string(grp.gr_name),
+        )
+    }
+
+    pub fn uid(user: &str) -> Option<u32> {
+        let user = CString::new(user).ok()?;
+        lookup(
+            |pwd, buf, len, found| unsafe { libc::getpwnam_r(user.as_ptr(), pwd, buf, len, found) },
+            |pwd: &passwd| pwd.pw_uid,
+        )
+    }
+
+    pub fn gid(group: &str) -> Option<u32> {
+        let group = CString::new(group).ok()?;
+        lookup(
+            |grp, buf, len, found| unsafe {
+                libc::getgrnam_r(group.as_ptr(), grp, buf, len, found)
+            },
+            |grp: &group| grp.gr_gid,
+        )
+    }
+}
+
+/// Extended attributes, following symbolic links
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::collections::BTreeMap;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Run a call returning a value of variable length: first to get its length, then to fill a buffer that long
+    fn read(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let len = usize::try_from(call(ptr::null_mut(), 0))
+                .map_err(|_| io::Error::last_os_error())?;
+            let mut buf = vec![0u8; len];
+            if let Ok(len) = usize::try_from(call(buf.as_mut_ptr(), len)) {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            let e = io::Error::last_os_error();
+            // The value grew in between, try again
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Extended attributes of the file at `path`, the ones with names that aren't UTF-8 are skipped
+    pub fn list(path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let names = read(|buf, len| unsafe { libc::listxattr(path.as_ptr(), buf.cast(), len) })?;
+        names
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| Some((name, std::str::from_utf8(name).ok()?)))
+            .map(|(bytes, name)| {
+                let c_name = c_string(bytes)?;
+                let value = read(|buf, len| unsafe {
+                    libc::getxattr(path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+                })?;
+                Ok((name.to_owned(), value))
+            })
+            .collect()
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+        let res = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Extended attributes are only supported on Linux
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::path::Path;
+
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn list(_path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+        Ok(BTreeMap::new())
+    }
+
+    pub fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::time::{Duration, SystemTime};
+
+    use super::{Attributes, Owner, Ownership};
+
+    #[test]
+    fn capture_and_apply() {
+        let dir = std::env::temp_dir().join(format!("distd-attributes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        let target = dir.join("target");
+        fs::write(&source, b"source").unwrap();
+        fs::write(&target, b"target").unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o4751)).unwrap();
+        fs::File::open(&source)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        // Not every filesystem supports user extended attributes
+        let xattrs = super::xattr::set(&source, "user.distd", b"value").is_ok();
+
+        let attributes = Attributes::capture(&source).unwrap();
+        assert_eq!(attributes.mode, Some(0o4751));
+        assert_eq!(attributes.mtime, Some(mtime));
+        let owner = attributes.owner.clone().unwrap();
+        assert_eq!(owner.uid, fs::metadata(&source).unwrap().uid());
+        if xattrs {
+            assert_eq!(attributes.xattrs["user.distd"], b"value");
+        }
+
+        // Owned by whoever installs it, without the setuid bit
+        attributes.apply(&target, &Ownership::Ignore).unwrap();
+        let metadata = fs::metadata(&target).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o751);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        if xattrs {
+            assert_eq!(
+                Attributes::capture(&target).unwrap().xattrs,
+                attributes.xattrs
+            );
+        }
+
+        // The captured owner is ours, so it can be preserved
+        attributes.apply(&target, &Ownership::Preserve).unwrap();
+        assert_eq!(Attributes::capture(&target).unwrap(), attributes);
+
+        let unknown = Ownership::Override {
+            user: Some("distd-no-such-user".into()),
+            group: None,
+        };
+        assert!(attributes.apply(&target, &unknown).is_err());
+        assert!(Attributes::default().is_empty());
+        assert!(!attributes.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn privileged_xattrs_need_ownership() {
+        let attributes = Attributes {
+            xattrs: [
+                ("security.capability", b"cap".as_slice()),
+                ("trusted.overlay", b"overlay"),
+                ("user.distd", b"value"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.to_vec()))
+            .collect(),
+            ..Attributes::default()
+        };
+        let names = |ownership: &Ownership| {
+            attributes
+                .xattrs(ownership)
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&Ownership::Ignore), vec!["user.distd"]);
+        assert_eq!(
+            names(&Ownership::Preserve),
+            vec!["security.capability", "trusted.overlay", "user.distd"]
+        );
+
+        // Whatever our privileges, installing without the owner never sets them
+        let path = std::env::temp_dir().join(format!("distd-privileged-{}", std::process::id()));
+        fs::write(&path, b"target").unwrap();
+        attributes.apply(&path, &Ownership::Ignore).unwrap();
+        let applied = Attributes::capture(&path).unwrap().xattrs;
+        assert!(!applied.contains_key("security.capability"));
+        assert!(!applied.contains_key("trusted.overlay"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn capabilities_survive_ownership() {
+        // Only root may give files to someone else and set their capabilities
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        // Version 2 capabilities, with CAP_NET_BIND_SERVICE permitted and effective
+        let mut capability = vec![0u8; 20];
+        capability[..4].copy_from_slice(&0x0200_0001_u32.to_le_bytes());
+        capability[4..8].copy_from_slice(&(1_u32 << 10).to_le_bytes());
+        let attributes = Attributes {
+            mode: Some(0o755),
+            owner: Some(Owner {
+                uid: 65534,
+                gid: 65534,
+                user: None,
+                group: None,
+            }),
+            xattrs: [("security.capability".to_owned(), capability.clone())].into(),
+            ..Attributes::default()
+        };
+
+        let path = std::env::temp_dir().join(format!("distd-capability-{}", std::process::id()));
+        fs::write(&path, b"target").unwrap();
+        attributes.apply(&path, &Ownership::Preserve).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (65534, 65534));
+        assert_eq!(metadata.mode() & 0o7777, 0o755);
+        // Unless the filesystem doesn't support them at all
+        if let Some(applied) = Attributes::capture(&path)
+            .unwrap()
+            .xattrs
+            .get("security.capability")
+        {
+            assert_eq!(applied, &capability);
+        } else {
+            assert!(super::xattr::set(&path, "security.capability", &capability).is_err());
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! Client authentication with short-lived session tokens
+//!
+//! Every client holds an Ed25519 key pair, which it's bound to when it first registers. To register, and to prove
+//! it's the client it claims to be when registering again, a client signs a random challenge handed out by the
+//! server. It then gets a session token to be sent in the `TOKEN_METADATA` of every following request.
+//! Tokens are signed by the server and expire after a while: clients renew them before they do, or register again.
+//!
+//! A relay signs the tokens of its own clients with the key pair it answers challenges of its upstream with: both
+//! signed payloads are prefixed with their own domain, so that neither side can get a token out of an answer or the
+//! other way around.
+//!
+//! Clients getting items from other clients present them a peer token, issued by the server along with the peers and
+//! signed with the same key pair, under a domain of its own so that peers can't use it as a session token. It is
+//! bound to the realm of the client, peers serve the clients of their own realm only, see `crate::peer::Seeder`.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::Ed25519KeyPair;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::signature::{self, PublicKey};
+
+/// Binary gRPC metadata carrying the session token
+pub const TOKEN_METADATA: &str = "x-token-bin";
+
+/// Challenges not answered within this time are dropped
+pub const CHALLENGE_TTL: Duration = Duration::from_mins(1);
+
+/// Most challenges handed out within `CHALLENGE_TTL`, new ones are refused until the oldest expire
+pub const MAX_CHALLENGES: usize = 16 * 1024;
+
+/// Length of a challenge nonce
+pub const NONCE_LEN: usize = 32;
+
+const SIGNATURE_LEN: usize = 64;
+
+/// Prefixes of what's signed for challenges and for tokens, see the module documentation
+const CHALLENGE_DOMAIN: &[u8] = b"distd challenge\0";
+const TOKEN_DOMAIN: &[u8] = b"distd token\0";
+const PEER_DOMAIN: &[u8] = b"distd peer\0";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Malformed token")]
+    Malformed,
+
+    #[error("Token expired")]
+    Expired,
+
+    #[error("Unknown or expired challenge")]
+    UnknownChallenge,
+
+    #[error("Too many pending challenges, try again later")]
+    TooManyChallenges,
+
+    #[error("Client key was revoked")]
+    Revoked,
+
+    #[error("Bad signature: {0}")]
+    Signature(#[from] signature::Error),
+}
+
+/// What a session token grants, signed by its issuer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Client the token was issued to
+    pub uuid: Uuid,
+
+    /// Seconds since the Unix epoch after which the token is refused
+    pub expires: u64,
+}
+
+/// What a peer token grants, signed by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerClaims {
+    /// Client the token was issued to
+    pub uuid: Uuid,
+
+    /// Realm of the client, whose peers it may get items from
+    pub realm: String,
+
+    /// Seconds since the Unix epoch after which the token is refused
+    pub expires: u64,
+}
+
+/// Issue a token for `uuid` valid for `ttl`, signed with `key_pair`
+///
+/// Returns the token along with its expiry, in seconds since the Unix epoch.
+pub fn issue(
+    key_pair: &Ed25519KeyPair,
+    uuid: Uuid,
+    ttl: Duration,
+) -> Result<(Vec<u8>, u64), Error> {
+    let claims = Claims {
+        uuid,
+        expires: unix_time(SystemTime::now() + ttl),
+    };
+    Ok((seal(key_pair, TOKEN_DOMAIN, &claims)?, claims.expires))
+}
+
+/// Check that `token` was issued with the key pair of `public_key` and is not expired yet
+pub fn verify(public_key: &PublicKey, token: &[u8]) -> Result<Claims, Error> {
+    let claims: Claims = unseal(public_key, TOKEN_DOMAIN, token)?;
+    check_expiry(claims.expires)?;
+    Ok(claims)
+}
+
+/// Issue a peer token for `uuid` of `realm` valid for `ttl`, signed with `key_pair`, see `issue`
+pub fn issue_peer(
+    key_pair: &Ed25519KeyPair,
+    uuid: Uuid,
+    realm: &str,
+    ttl: Duration,
+) -> Result<(Vec<u8>, u64), Error> {
+    let claims = PeerClaims {
+        uuid,
+        realm: realm.to_string(),
+        expires: unix_time(SystemTime::now() + ttl),
+    };
+    Ok((seal(key_pair, PEER_DOMAIN, &claims)?, claims.expires))
+}
+
+/// Check that the peer `token` was issued with the key pair of `public_key` and is not expired yet
+pub fn verify_peer(public_key: &PublicKey, token: &[u8]) -> Result<PeerClaims, Error> {
+    let claims: PeerClaims = unseal(public_key, PEER_DOMAIN, token)?;
+    check_expiry(claims.expires)?;
+    Ok(claims)
+}
+
+/// Serialize `claims` followed by their signature under `domain`
+fn seal<C: Serialize>(
+    key_pair: &Ed25519KeyPair,
+    domain: &[u8],
+    claims: &C,
+) -> Result<Vec<u8>, Error> {
+    let mut token = bitcode::serialize(claims).map_err(|_| Error::Malformed)?;
+    let signature = key_pair.sign(&[domain, &token].concat());
+    token.extend_from_slice(signature.as_ref());
+    Ok(token)
+}
+
+/// Claims of `token` if it was sealed under `domain` with the key pair of `public_key`
+fn unseal<C: DeserializeOwned>(
+    public_key: &PublicKey,
+    domain: &[u8],
+    token: &[u8],
+) -> Result<C, Error> {
+    let split = token
+        .len()
+        .checked_sub(SIGNATURE_LEN)
+        .ok_or(Error::Malformed)?;
+    let (serialized, signature) = token.split_at(split);
+    signature::verify(public_key, &[domain, serialized].concat(), signature)?;
+    bitcode::deserialize(serialized).map_err(|_| Error::Malformed)
+}
+
+fn check_expiry(expires: u64) -> Result<(), Error> {
+    if unix_time(SystemTime::now()) >= expires {
+        return Err(Error::Expired);
+    }
+    Ok(())
+}
+
+/// Answer a challenge, proving we hold `key_pair`
+#[must_use]
+pub fn answer(key_pair: &Ed25519KeyPair, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+    key_pair
+        .sign(&[CHALLENGE_DOMAIN, nonce].concat())
+        .as_ref()
+        .to_vec()
+}
+
+/// Challenges handed out and not answered yet
+///
+/// Every challenge may be answered only once, within `CHALLENGE_TTL`. At most `MAX_CHALLENGES` are handed out
+/// within `CHALLENGE_TTL`, answered or not, so that requesting challenges can't grow memory without bounds.
+#[derive(Debug, Default)]
+pub struct Challenges {
+    pending: Mutex<Pending>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// Time each challenge not taken yet was handed out at
+    issued: HashMap<[u8; NONCE_LEN], Instant>,
+
+    /// Every challenge handed out within `CHALLENGE_TTL`, oldest first, for expired ones to be dropped in order
+    order: VecDeque<([u8; NONCE_LEN], Instant)>,
+}
+
+impl Challenges {
+    /// Hand out a new random challenge, unless `MAX_CHALLENGES` were handed out within `CHALLENGE_TTL`
+    ///
+    /// # Panics
+    /// If the system RNG fails, which is not something we can recover from
+    pub fn issue(&self) -> Result<[u8; NONCE_LEN], Error> {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        while let Some((nonce, issued)) = pending.order.front().copied() {
+            if now.duration_since(issued) < CHALLENGE_TTL {
+                break;
+            }
+            pending.order.pop_front();
+            pending.issued.remove(&nonce);
+        }
+        if pending.order.len() >= MAX_CHALLENGES {
+            return Err(Error::TooManyChallenges);
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system RNG failure");
+        pending.issued.insert(nonce, now);
+        pending.order.push_back((nonce, now));
+        Ok(nonce)
+    }
+
+    /// Check that `signature` of the challenge `nonce` was made with the key pair of `public_key`
+    ///
+    /// The challenge is consumed, whether the answer is right or not.
+    ///
+    /// # Panics
+    /// If the lock on pending challenges is poisoned
+    pub fn redeem(
+        &self,
+        nonce: &[u8],
+        public_key: &PublicKey,
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        let nonce = self.take(nonce)?;
+        Ok(signature::verify(
+            public_key,
+            &[CHALLENGE_DOMAIN, &nonce].concat(),
+            signature,
+        )?)
+    }
+
+    /// Take the challenge `nonce` if it was handed out and not taken yet, within `CHALLENGE_TTL`
+    ///
+    /// Nonces of signed realm changes are taken this way, see `signature::RealmChange`.
+    ///
+    /// # Panics
+    /// If the lock on pending challenges is poisoned
+    pub fn take(&self, nonce: &[u8]) -> Result<[u8; NONCE_LEN], Error> {
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| Error::UnknownChallenge)?;
+        let issued = self
+            .pending
+            .lock()
+            .unwrap()
+            .issued
+            .remove(&nonce)
+            .ok_or(Error::UnknownChallenge)?;
+        if issued.elapsed() >= CHALLENGE_TTL {
+            return Err(Error::UnknownChallenge);
+        }
+        Ok(nonce)
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn key_pair() -> (Ed25519KeyPair, PublicKey) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = key_pair.public_key().as_ref().try_into().unwrap();
+        (key_pair, public_key)
+    }
+
+    #[test]
+    fn issue_and_verify_tokens() {
+        let (key_pair, public_key) = key_pair();
+        let uuid = Uuid::new_v4();
+        let (token, expires) = issue(&key_pair, uuid, Duration::from_mins(1)).unwrap();
+        assert_eq!(verify(&public_key, &token), Ok(Claims { uuid, expires }));
+
+        let (_, other) = self::key_pair();
+        assert!(matches!(verify(&other, &token), Err(Error::Signature(_))));
+        let mut forged = token.clone();
+        forged[0] ^= 1;
+        assert!(verify(&public_key, &forged).is_err());
+        assert_eq!(verify(&public_key, &token[..10]), Err(Error::Malformed));
+
+        let (stale, _) = issue(&key_pair, uuid, Duration::ZERO).unwrap();
+        assert_eq!(verify(&public_key, &stale), Err(Error::Expired));
+    }
+
+    #[test]
+    fn signatures_are_domain_separated() {
+        let (key_pair, public_key) = key_pair();
+        let challenges = Challenges::default();
+
+        // An answer is no signature of the bare nonce, nor of a token
+        let nonce = challenges.issue().unwrap();
+        let signature = answer(&key_pair, &nonce);
+        assert!(signature::verify(&public_key, &nonce, &signature).is_err());
+        let forged = [nonce.as_slice(), &signature].concat();
+        assert!(verify(&public_key, &forged).is_err());
+
+        // A token signature is no answer of a challenge
+        let (token, _) = issue(&key_pair, Uuid::new_v4(), Duration::from_mins(1)).unwrap();
+        let (serialized, signature) = token.split_at(token.len() - SIGNATURE_LEN);
+        assert!(signature::verify(&public_key, serialized, signature).is_err());
+        assert!(challenges
+            .redeem(&nonce, &public_key, &answer(&key_pair, &nonce))
+            .is_ok());
+
+        // Peers can't pass a peer token off as a session token, nor the other way around
+        let uuid = Uuid::new_v4();
+        let (token, expires) = issue_peer(&key_pair, uuid, "team", Duration::from_mins(1)).unwrap();
+        let claims = verify_peer(&public_key, &token).unwrap();
+        assert_eq!(
+            claims,
+            PeerClaims {
+                uuid,
+                realm: "team".into(),
+                expires
+            }
+        );
+        assert!(verify(&public_key, &token).is_err());
+        let (session, _) = issue(&key_pair, uuid, Duration::from_mins(1)).unwrap();
+        assert!(verify_peer(&public_key, &session).is_err());
+        let (stale, _) = issue_peer(&key_pair, uuid, "team", Duration::ZERO).unwrap();
+        assert_eq!(verify_peer(&public_key, &stale), Err(Error::Expired));
+    }
+
+    #[test]
+    fn challenges_are_single_use() {
+        let (key_pair, public_key) = key_pair();
+        let challenges = Challenges::default();
+        let nonce = challenges.issue().unwrap();
+        let signature = answer(&key_pair, &nonce);
+
+        let (_, other) = self::key_pair();
+        assert!(challenges.redeem(&nonce, &other, &signature).is_err());
+        // Consumed by the failed attempt
+        assert_eq!(
+            challenges.redeem(&nonce, &public_key, &signature),
+            Err(Error::UnknownChallenge)
+        );
+
+        let nonce = challenges.issue().unwrap();
+        let signature = answer(&key_pair, &nonce);
+        assert_eq!(challenges.redeem(&nonce, &public_key, &signature), Ok(()));
+        assert_eq!(
+            challenges.redeem(&nonce, &public_key, &signature),
+            Err(Error::UnknownChallenge)
+        );
+    }
+
+    #[test]
+    fn challenges_are_capped() {
+        let challenges = Challenges::default();
+        let first = challenges.issue().unwrap();
+        for _ in 1..MAX_CHALLENGES {
+            challenges.issue().unwrap();
+        }
+        assert_eq!(challenges.issue(), Err(Error::TooManyChallenges));
+        // Taking one doesn't make room, as they're handed out for a while anyway
+        challenges.take(&first).unwrap();
+        assert_eq!(challenges.issue(), Err(Error::TooManyChallenges));
+
+        // Expired ones do
+        let mut pending = challenges.pending.lock().unwrap();
+        for (_, issued) in pending.order.iter_mut().take(10) {
+            *issued = issued.checked_sub(CHALLENGE_TTL).unwrap();
+        }
+        let expired = pending.order[1].0;
+        drop(pending);
+        assert!(challenges.issue().is_ok());
+        assert_eq!(challenges.take(&expired), Err(Error::UnknownChallenge));
+        assert_eq!(
+            challenges.pending.lock().unwrap().order.len(),
+            MAX_CHALLENGES - 9
+        );
+    }
+}
@@ -1 +1,94 @@
+//! Benchmarks, run with `cargo +nightly bench -p distd_core`
+//!
+//! Hashing and storage insertion are run on `rayon` pools of different sizes, throughput should scale with the
+//! number of threads up to the number of cores. Chunking is the default fixed-size one unless stated otherwise.
 
+#[cfg(test)]
+mod hash {
+    extern crate test;
+
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use rayon::ThreadPoolBuilder;
+    use test::{black_box, Bencher};
+
+    use crate::chunk_storage::{hashmap_storage::HashMapStorage, ChunkStorage};
+    use crate::chunker::{Chunking, FastCdc};
+    use crate::hash::hash_with;
+
+    const SIZE: usize = 16 * 1024 * 1024;
+
+    fn data() -> Vec<u8> {
+        let mut data = vec![0u8; SIZE];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        data
+    }
+
+    /// Run `f` on a pool of `threads` threads, all the available ones if 0
+    fn on_pool<F>(b: &mut Bencher, threads: usize, mut f: F)
+    where
+        F: FnMut() + Send,
+    {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        b.bytes = SIZE as u64;
+        pool.install(|| b.iter(&mut f));
+    }
+
+    fn hash_tree(b: &mut Bencher, threads: usize, chunking: Chunking) {
+        let data = data();
+        on_pool(b, threads, || {
+            black_box(hash_with(&data, &chunking));
+        });
+    }
+
+    fn insert(b: &mut Bencher, threads: usize) {
+        let data = bytes::Bytes::from(data());
+        let chunking = Chunking::default();
+        on_pool(b, threads, || {
+            let mut storage = HashMapStorage::default();
+            black_box(storage.insert_with(data.clone(), &chunking));
+        });
+    }
+
+    #[bench]
+    fn hash_1_thread(b: &mut Bencher) {
+        hash_tree(b, 1, Chunking::default());
+    }
+
+    #[bench]
+    fn hash_2_threads(b: &mut Bencher) {
+        hash_tree(b, 2, Chunking::default());
+    }
+
+    #[bench]
+    fn hash_4_threads(b: &mut Bencher) {
+        hash_tree(b, 4, Chunking::default());
+    }
+
+    #[bench]
+    fn hash_all_threads(b: &mut Bencher) {
+        hash_tree(b, 0, Chunking::default());
+    }
+
+    #[bench]
+    fn hash_fastcdc_1_thread(b: &mut Bencher) {
+        hash_tree(b, 1, Chunking::FastCdc(FastCdc::default()));
+    }
+
+    #[bench]
+    fn hash_fastcdc_all_threads(b: &mut Bencher) {
+        hash_tree(b, 0, Chunking::FastCdc(FastCdc::default()));
+    }
+
+    #[bench]
+    fn insert_1_thread(b: &mut Bencher) {
+        insert(b, 1);
+    }
+
+    #[bench]
+    fn insert_all_threads(b: &mut Bencher) {
+        insert(b, 0);
+    }
+}
@@ -2,19 +2,30 @@ use std::{collections::HashSet, path::PathBuf, sync::Arc};
 
 use bytes::Bytes;
 pub use node::Node;
+pub use outline::Outline;
+pub use staging::Staging;
+pub use stats::Stats;
+use tokio::io::AsyncRead;
 use tokio_stream::{Stream, StreamExt};
 
+use crate::chunker::{Chunker, Chunking};
 use crate::error::Error;
-use crate::hash::{hash, Hash, HashTreeCapable};
+use crate::hash::{chunk_hash, chunk_hashes, Hash, HashTreeCapable, TreeBuilder};
 use crate::{
     hash::merge_hashes,
     item::{Item, Name as ItemName},
+    metadata::Item as ItemMetadata,
 };
 
 pub mod fs_storage;
+pub mod gc;
 pub mod hashmap_storage;
+pub mod negotiation;
 pub mod node;
 pub mod node_stream;
+pub mod outline;
+pub mod staging;
+pub mod stats;
 
 #[cfg(feature = "redb")]
 pub mod redb;
@@ -37,6 +48,21 @@ pub enum StorageError {
 
     #[error("Cannot reconstruct tree from storage")]
     TreeReconstruct,
+
+    #[error("Hash mismatch for node, expected {expected} but got {got}")]
+    HashMismatch { expected: Hash, got: Hash },
+
+    #[error("Size of node {0} doesn't match the size of its children")]
+    SizeMismatch(Hash),
+
+    #[error("Reconstructed tree has root {got}, expected {expected}")]
+    RootMismatch { expected: Hash, got: Hash },
+
+    #[error("Node {0} was neither received nor found in storage")]
+    MissingNode(Hash),
+
+    #[error("Invalid directory entry '{0}'")]
+    InvalidEntry(PathBuf),
 }
 
 /// Defines a backend used to store hashes and chunks ad key-value pairs
@@ -47,20 +73,55 @@ pub trait ChunkStorage: HashTreeCapable<Arc<Node>, Error> {
 
     fn chunks(&self) -> Vec<Hash>;
 
+    /// Hashes of every node in storage, both chunks and links
+    fn nodes(&self) -> Vec<Hash>;
+
+    /// Whether the (sub-)tree with root `hash` is available in the storage
+    fn contains(&self, hash: &Hash) -> bool {
+        self.get(hash).is_some()
+    }
+
+    /// Node counts and chunk bytes, maintained as nodes are stored and removed
+    fn stats(&self) -> Stats;
+
     /// Allocated size for all chunks, in bytes
     /// This only counts actual chunks size, excluding any auxiliary structure used by storage backend/adapter
-    fn size(&self) -> u64;
+    fn size(&self) -> u64 {
+        self.stats().chunk_bytes
+    }
 
-    //fn drop(hash: Hash); // TODO
+    /// Remove a single node from storage, returning the chunk bytes freed or None if it wasn't there
+    ///
+    /// Nodes linking to it are left untouched, use `collect_garbage` to only remove unreachable nodes.
+    fn remove_node(&mut self, hash: &Hash) -> Option<u64>;
+
+    /// Remove every node not reachable from `roots`, only reporting what would be removed if `dry_run` is set
+    fn collect_garbage(&mut self, roots: &[Hash], dry_run: bool) -> gc::Report {
+        gc::collect(self, roots, dry_run)
+    }
 
     fn insert_chunk(&mut self, chunk: &[u8]) -> Option<Arc<Node>> {
-        let hash = hash(chunk);
+        let hash = chunk_hash(chunk);
         tracing::trace!("Insert chunk {hash}, {} bytes", chunk.len());
 
         self.store_chunk(hash, chunk)
             .inspect(|x| assert!(x.hash() == &hash))
     }
 
+    /// Insert `chunks` one after the other, their hashes being computed in parallel beforehand
+    fn insert_chunks(&mut self, chunks: &[&[u8]]) -> Result<Vec<Arc<Node>>, Error> {
+        chunks
+            .iter()
+            .zip(chunk_hashes(chunks))
+            .map(|(chunk, hash)| {
+                tracing::trace!("Insert chunk {hash}, {} bytes", chunk.len());
+                Ok(self
+                    .store_chunk(hash, chunk)
+                    .ok_or(StorageError::ChunkInsertError)?)
+            })
+            .collect()
+    }
+
     fn link(&mut self, left: Arc<Node>, right: Arc<Node>) -> Option<Arc<Node>> {
         let hash = merge_hashes(left.hash(), right.hash());
         tracing::trace!("Link {} {} → {}", left.hash(), right.hash(), hash);
@@ -73,7 +134,59 @@ pub trait ChunkStorage: HashTreeCapable<Arc<Node>, Error> {
     where
         Self: Sized,
     {
-        self.compute_tree(data.as_ref()).ok()
+        self.insert_with(data, &Chunking::default())
+    }
+
+    /// Insert bytes into the storage, split with `chunker`, returning the associated hash tree
+    fn insert_with<C>(&mut self, data: Bytes, chunker: &C) -> Option<Arc<Node>>
+    where
+        Self: Sized,
+        C: Chunker + Sync,
+    {
+        let mut builder = TreeBuilder::new(self, chunker);
+        builder.update(&data).ok()?;
+        builder.finish().ok()
+    }
+
+    /// Insert the data read from `reader` into the storage, split with `chunker`, returning the associated hash tree
+    ///
+    /// Data is inserted as it's read, only a few chunks of it are buffered whatever its size.
+    fn insert_reader<R, C>(
+        &mut self,
+        reader: R,
+        chunker: &C,
+    ) -> impl std::future::Future<Output = Result<Arc<Node>, Error>> + Send
+    where
+        Self: Sized + Send,
+        R: AsyncRead + Unpin + Send,
+        C: Chunker + Sync,
+    {
+        async move {
+            let mut builder = TreeBuilder::new(self, chunker);
+            builder.read_from_async(reader).await?;
+            builder.finish()
+        }
+    }
+
+    /// Same as `insert_reader`, with the data coming from `stream` piece by piece
+    fn insert_stream<S, B, C>(
+        &mut self,
+        mut stream: S,
+        chunker: &C,
+    ) -> impl std::future::Future<Output = Result<Arc<Node>, Error>> + Send
+    where
+        Self: Sized + Send,
+        S: Stream<Item = std::io::Result<B>> + Unpin + Send,
+        B: AsRef<[u8]>,
+        C: Chunker + Sync,
+    {
+        async move {
+            let mut builder = TreeBuilder::new(self, chunker);
+            while let Some(data) = stream.next().await {
+                builder.update(data?.as_ref())?;
+            }
+            builder.finish()
+        }
     }
 
     /// Create a new Item from its metadata and Bytes
@@ -85,12 +198,20 @@ pub trait ChunkStorage: HashTreeCapable<Arc<Node>, Error> {
         revision: u32,
         description: Option<String>,
         file: Bytes,
+        chunking: Chunking,
     ) -> Option<Item>
     where
         Self: Sized,
     {
-        let hash_tree = self.insert(file)?;
-        Some(Item::new(name, path, revision, description, &hash_tree))
+        let hash_tree = self.insert_with(file, &chunking)?;
+        Some(Item::new(
+            name,
+            path,
+            revision,
+            description,
+            chunking,
+            &hash_tree,
+        ))
     }
 
     /// Build a new Item from its metadata and root node
@@ -100,43 +221,40 @@ pub trait ChunkStorage: HashTreeCapable<Arc<Node>, Error> {
         path: PathBuf,
         revision: u32,
         description: Option<String>,
+        chunking: Chunking,
         root: Arc<Node>,
     ) -> Option<Item>
     where
         Self: Sized,
     {
-        Some(Item::new(name, path, revision, description, &root))
+        Some(Item::new(
+            name,
+            path,
+            revision,
+            description,
+            chunking,
+            &root,
+        ))
     }
 
-    /// Build a new Item from its metadata and a streaming of nodes
+    /// Build a new Item from its expected metadata and a streaming of nodes
+    ///
+    /// Every node is verified before being stored, and the reconstructed tree must have the root hash in `target`.
     fn receive_item<T>(
         &mut self,
-        name: ItemName,
-        path: PathBuf,
-        revision: u32,
-        description: Option<String>,
+        target: ItemMetadata,
         mut stream: T,
-        //) -> Result<Item, crate::error::Error>
     ) -> impl std::future::Future<Output = Result<Item, crate::error::Error>> + Send
     where
         Self: Sized + Send,
         T: Stream<Item = Node> + std::marker::Unpin + Send,
     {
         async move {
-            let mut n = None; // final node
-            let mut i = 0; // node counter
+            let mut staging = Staging::default();
             while let Some(node) = stream.next().await {
-                n = Some(
-                    self.try_fill_in(&node)
-                        .ok_or(StorageError::TreeReconstruct)?,
-                );
-                i += 1;
+                staging.receive(self, &node)?;
             }
-
-            let n = n.ok_or(StorageError::TreeReconstruct)?;
-            tracing::trace!("Reconstructed {i} nodes with {} bytes total", n.size());
-
-            Ok(Item::new(name, path, revision, description, &n))
+            Ok(staging.into_item(target)?)
         }
     }
 
@@ -154,17 +272,34 @@ pub trait ChunkStorage: HashTreeCapable<Arc<Node>, Error> {
             .into()
     }
 
-    /// Take ownership of an `OwnedHashTreeNode` and try to fill in any `Skipped` nodes
-    fn try_fill_in(&mut self, tree: &Node) -> Option<Arc<Node>> {
+    /// Verify a `Node` received from a possibly untrusted source and store it, filling in any `Skipped` node
+    ///
+    /// Children of a `Parent` must be already in storage, or be provided along with it.
+    fn try_fill_in(&mut self, tree: &Node) -> Result<Arc<Node>, StorageError> {
         tracing::trace!("Filling {}", tree.hash());
-        Some(match tree {
-            Node::Stored { hash, data } => self.store_chunk(*hash, data)?,
+        tree.verify()?;
+        match tree {
+            Node::Stored { hash, data } => self
+                .store_chunk(*hash, data)
+                .ok_or(StorageError::ChunkInsertError),
             Node::Parent { left, right, .. } => {
                 let l = self.try_fill_in(left)?;
                 let r = self.try_fill_in(right)?;
-                self.link(l, r)?
+                self.link(l, r).ok_or(StorageError::LinkCreation)
             }
-            Node::Skipped { hash, .. } => self.get(hash)?,
+            Node::Skipped { hash, .. } => self.get(hash).ok_or(StorageError::MissingNode(*hash)),
+        }
+    }
+}
+
+/// Check that a received tree is the one we were expecting
+pub(crate) fn check_root(target: &ItemMetadata, root: &Node) -> Result<(), StorageError> {
+    if root.hash() == &target.root.hash {
+        Ok(())
+    } else {
+        Err(StorageError::RootMismatch {
+            expected: target.root.hash,
+            got: *root.hash(),
         })
     }
 }
@@ -178,9 +313,15 @@ mod tests {
     use super::*;
 
     use bytes::{Bytes, BytesMut};
-    use rand::{self, RngCore};
+    use futures::executor::block_on;
+    use rand::{self, rngs::StdRng, RngCore, SeedableRng};
 
-    use crate::{chunks::CHUNK_SIZE, hash::hash};
+    use crate::{
+        chunk_storage::hashmap_storage::HashMapStorage,
+        chunker::FastCdc,
+        chunks::CHUNK_SIZE,
+        hash::{compute_tree, hash, hash_with, merge_hashes},
+    };
 
     pub fn single_chunk_insertion<S>(s: &mut S)
     where
@@ -255,6 +396,146 @@ mod tests {
         }
     }
 
+    /// Content-defined chunking keeps deduplicating data after a small insertion
+    pub fn content_defined_deduplication<S>(s: &mut S)
+    where
+        S: ChunkStorage,
+    {
+        let chunker = Chunking::FastCdc(FastCdc::default());
+        let mut data = vec![0u8; CHUNK_SIZE * 12];
+        StdRng::seed_from_u64(7).fill_bytes(&mut data);
+
+        let root = s.insert_with(Bytes::from(data.clone()), &chunker).unwrap();
+        assert_eq!(root.hash(), &hash_with(&data, &chunker));
+        assert_eq!(root.clone_data(), data);
+        let size = s.size();
+
+        // prepend a few bytes, only the first chunk(s) should change
+        let mut shifted = b"some header".to_vec();
+        shifted.extend_from_slice(&data);
+        let root = s
+            .insert_with(Bytes::from(shifted.clone()), &chunker)
+            .unwrap();
+        assert_eq!(root.clone_data(), shifted);
+        assert!(s.size() - size <= 2 * chunker.max_size() as u64);
+    }
+
+    /// Received nodes are verified, as well as the resulting root
+    pub fn receive_item_verification<S>(s: &mut S)
+    where
+        S: ChunkStorage + Send,
+    {
+        let mut data = vec![0u8; CHUNK_SIZE * 3 + 100];
+        StdRng::seed_from_u64(11).fill_bytes(&mut data);
+
+        let mut source = HashMapStorage::default();
+        let item = source
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                Bytes::from(data.clone()),
+                Chunking::default(),
+            )
+            .unwrap();
+        let root = source.get(item.root()).unwrap();
+        let nodes: Vec<Node> = root.find_diff(&[]).map(|n| (*n).clone()).collect();
+
+        // Tampered data is refused
+        let mut tampered = nodes.clone();
+        let stored = tampered
+            .iter_mut()
+            .find(|n| matches!(n, Node::Stored { .. }))
+            .unwrap();
+        if let Node::Stored { data, .. } = stored {
+            *data = Arc::new(b"something else".to_vec());
+        }
+        let res = block_on(s.receive_item(item.metadata.clone(), tokio_stream::iter(tampered)));
+        assert!(matches!(
+            res,
+            Err(Error::Storage(StorageError::HashMismatch { .. }))
+        ));
+
+        // A valid tree that isn't the expected one is refused
+        let mut other = item.metadata.clone();
+        other.root.hash = hash(b"another root");
+        let res = block_on(s.receive_item(other, tokio_stream::iter(nodes.clone())));
+        assert!(matches!(
+            res,
+            Err(Error::Storage(StorageError::RootMismatch { .. }))
+        ));
+
+        let received =
+            block_on(s.receive_item(item.metadata.clone(), tokio_stream::iter(nodes))).unwrap();
+        assert_eq!(received.root(), item.root());
+        assert_eq!(s.get(item.root()).unwrap().clone_data(), data);
+    }
+
+    /// Stats are kept up to date as nodes are stored, deduplicated and removed
+    pub fn storage_stats<S>(s: &mut S)
+    where
+        S: ChunkStorage,
+    {
+        assert_eq!(s.stats(), Stats::default());
+
+        // Three identical chunks, linked by two parents
+        let root = s.insert(Bytes::from(vec![3u8; CHUNK_SIZE * 3])).unwrap();
+        let stats = Stats {
+            chunks: 1,
+            links: 2,
+            chunk_bytes: CHUNK_SIZE as u64,
+        };
+        assert_eq!(s.stats(), stats);
+        assert_eq!(s.size(), CHUNK_SIZE as u64);
+        assert_eq!(s.stats().nodes(), root.all_hashes().len() as u64);
+
+        // Inserting the same data again changes nothing
+        s.insert(Bytes::from(vec![3u8; CHUNK_SIZE * 3])).unwrap();
+        assert_eq!(s.stats(), stats);
+
+        s.collect_garbage(&[], false);
+        assert_eq!(s.stats(), Stats::default());
+    }
+
+    /// Only nodes reachable from the given roots survive a collection
+    pub fn garbage_collection<S>(s: &mut S)
+    where
+        S: ChunkStorage,
+    {
+        let mut kept = vec![0u8; CHUNK_SIZE * 3 + 100];
+        StdRng::seed_from_u64(13).fill_bytes(&mut kept);
+        let mut dropped = vec![0u8; CHUNK_SIZE * 2];
+        StdRng::seed_from_u64(17).fill_bytes(&mut dropped);
+
+        let kept = s.insert(Bytes::from(kept)).unwrap();
+        let dropped = s.insert(Bytes::from(dropped)).unwrap();
+        let size = s.size();
+
+        // Nothing is removed on a dry run
+        let report = s.collect_garbage(&[*kept.hash()], true);
+        assert!(report.dry_run);
+        assert_eq!(report.bytes, dropped.size());
+        assert_eq!(report.nodes, dropped.all_hashes().len());
+        assert!(s.contains(dropped.hash()));
+        assert_eq!(s.size(), size);
+
+        let report = s.collect_garbage(&[*kept.hash()], false);
+        assert!(!report.dry_run);
+        assert_eq!(report.bytes, dropped.size());
+        assert!(!s.contains(dropped.hash()));
+        assert_eq!(s.size(), size - dropped.size());
+        assert_eq!(s.get(kept.hash()).unwrap().clone_data(), kept.clone_data());
+
+        // Everything left is reachable
+        assert_eq!(
+            s.collect_garbage(&[*kept.hash()], false),
+            gc::Report::default()
+        );
+        assert_eq!(s.collect_garbage(&[], false).bytes, kept.size());
+        assert!(s.nodes().is_empty());
+    }
+
     pub fn storage_2mb<S>(s: &mut S)
     where
         S: ChunkStorage,
@@ -274,12 +555,62 @@ mod tests {
         }
     }
 
+    /// Chunks hashed in parallel are stored in order, making the tree computed on a single thread
+    pub fn parallel_insertion<S>(s: &mut S)
+    where
+        S: ChunkStorage + Send,
+    {
+        let mut data = vec![0u8; CHUNK_SIZE * 9 + 17];
+        StdRng::seed_from_u64(5).fill_bytes(&mut data);
+        let chunking = Chunking::FastCdc(FastCdc::default());
+        let pool = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+        };
+
+        let root = pool(4)
+            .install(|| s.insert_with(Bytes::from(data.clone()), &chunking))
+            .unwrap();
+        let expected = pool(1)
+            .install(|| {
+                compute_tree(
+                    |x| -> Result<Hash, std::convert::Infallible> { Ok(chunk_hash(x)) },
+                    |l, r| Ok(merge_hashes(l, r)),
+                    &data,
+                    &chunking,
+                )
+            })
+            .unwrap();
+        assert_eq!(root.hash(), &expected);
+        assert_eq!(root.clone_data(), data);
+        assert_eq!(s.size(), data.len() as u64);
+    }
+
     macro_rules! chunk_storage_tests {
         ($t:ty, $builder:ident) => {
             crate::chunk_storage::tests::chunk_storage_tests!($t, single_chunk_insertion, $builder);
-            crate::chunk_storage::tests::chunk_storage_tests!($t, multiple_chunks_insertion, $builder);
+            crate::chunk_storage::tests::chunk_storage_tests!(
+                $t,
+                multiple_chunks_insertion,
+                $builder
+            );
             crate::chunk_storage::tests::chunk_storage_tests!($t, chunks_deduplication, $builder);
+            crate::chunk_storage::tests::chunk_storage_tests!(
+                $t,
+                content_defined_deduplication,
+                $builder
+            );
+            crate::chunk_storage::tests::chunk_storage_tests!(
+                $t,
+                receive_item_verification,
+                $builder
+            );
+            crate::chunk_storage::tests::chunk_storage_tests!($t, storage_stats, $builder);
+            crate::chunk_storage::tests::chunk_storage_tests!($t, garbage_collection, $builder);
             crate::chunk_storage::tests::chunk_storage_tests!($t, storage_2mb, $builder);
+            crate::chunk_storage::tests::chunk_storage_tests!($t, parallel_insertion, $builder);
             // ... any more tests go here ...
         };
         ($t:ty, $name:ident, $builder:ident) => {
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
     fs::{self, create_dir_all, remove_file, File},
     io::{BufWriter, Read, Seek, Write},
     path::{Path, PathBuf},
@@ -11,15 +11,19 @@ use serde::{Deserialize, Serialize};
 use tokio_stream::{Stream, StreamExt};
 
 use crate::{
-    chunk_storage::StorageError,
+    attributes::{Attributes, Ownership},
+    chunk_storage::{check_root, gc, StorageError},
+    chunker::{Chunker, Chunking},
     chunks::{ChunkInfo, CHUNK_SIZE},
+    directory::{EntryKind, Manifest},
     error::{Error, InvalidParameter},
-    hash::{hash as do_hash, Hash, HashTreeCapable},
-    item::{Item, Name as ItemName},
+    hash::{chunk_hash, chunk_hashes, Hash, HashTreeCapable, TreeBuilder},
+    item::{Item, Kind as ItemKind, Name as ItemName},
+    metadata::Item as ItemMetadata,
     utils::settings::cache_dir,
 };
 
-use super::{ChunkStorage, Node};
+use super::{ChunkStorage, Node, Stats};
 
 pub fn open_file(path: &Path) -> Result<File, Error> {
     File::options()
@@ -141,6 +145,61 @@ impl InFileChunk {
     }
 }
 
+/// How received items are written to their path
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Install {
+    /// Assembled in a staging file next to the target, then renamed over it once verified and flushed to disk, so
+    /// that the target always holds a whole revision: the previous one until the new one is in place
+    #[default]
+    Atomic,
+
+    /// Written straight at the target, saving the space of a second copy, the target is torn if interrupted
+    InPlace,
+}
+
+/// Staging file of `path` for atomic installs, in the same directory so that it can be renamed over `path`
+fn staging_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".distd-staging");
+    path.with_file_name(name)
+}
+
+/// Where the content of a received item goes, file by file
+///
+/// Items are laid out as their files one after the other, a single one for file items, see `directory`.
+struct Layout {
+    files: Vec<Placement>,
+}
+
+/// A file of a received item
+struct Placement {
+    /// Offset of the file content in the item
+    start: u64,
+    len: u64,
+    /// Where the file is written to, a staging file or `path` itself
+    dest: PathBuf,
+    /// Where the file is installed
+    path: PathBuf,
+    attributes: Attributes,
+}
+
+impl Layout {
+    /// File and offset within it of the item content at `offset`, if the item has any file
+    ///
+    /// Chunks never span files, as files are whole subtrees of the item. The empty chunk of empty files may end up in
+    /// any file, which changes nothing.
+    fn locate(&self, offset: u64) -> Option<(&Path, u64)> {
+        let i = self
+            .files
+            .partition_point(|file| file.start + file.len <= offset)
+            .min(self.files.len().checked_sub(1)?);
+        let file = &self.files[i];
+        Some((&file.dest, offset - file.start))
+    }
+}
+
 /// Storage keeping files in the filesystem instead of stored chunks indipendently
 ///
 /// It is useful to actually install files in the filesystem if the root is set to `/`
@@ -169,6 +228,14 @@ pub struct FsStorage {
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     handles_map: HashMap<PathBuf, Handle>,
+
+    /// How received items are written, not persisted as it's up to the configuration
+    #[serde(skip)]
+    pub install: Install,
+
+    /// Owner given to received items, not persisted either
+    #[serde(skip)]
+    pub ownership: Ownership,
 }
 
 impl FsStorage {
@@ -233,10 +300,20 @@ impl FsStorage {
             let mut already_processed = HashMap::new();
             let mut old_links = s.links.clone();
             while !old_links.is_empty() {
+                let before = old_links.len();
                 for n in old_links.clone().values() {
                     node_relink(&mut s, &mut already_processed, n)
                         .map(|n| old_links.remove(n.hash()));
                 }
+                // Links whose chunks cannot be read anymore (e.g. an interrupted transfer or a file changed on
+                // disk) are dropped, they'll be transferred again when needed
+                if old_links.len() == before {
+                    tracing::warn!("Dropping {before} links that cannot be restored");
+                    for hash in old_links.keys() {
+                        s.links.remove(hash);
+                    }
+                    break;
+                }
             }
 
             // return the recreated storage
@@ -278,13 +355,19 @@ impl FsStorage {
     }
 
     /// Persist data to the filesystem
+    ///
+    /// Written to a temporary file renamed over the previous one, so that a crash never leaves a truncated index
+    /// that `FsStorage::new` cannot load.
     fn persist(&self) -> Result<(), Error> {
         let buf = bitcode::serialize(&self)
             .inspect_err(|e| tracing::error!("{}", e))
             .map_err(InvalidParameter::from)?;
-        fs::File::create(&self.persistance_path)
-            .inspect_err(|e| tracing::error!("{}", e))?
-            .write_all(&buf)?;
+        let mut tmp = self.persistance_path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = fs::File::create(&tmp).inspect_err(|e| tracing::error!("{}", e))?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.persistance_path)?;
         Ok(())
     }
 
@@ -353,13 +436,21 @@ impl FsStorage {
         Ok(())
     }
 
-    /// Pre-allocate space for `Bytes` in the filesystem at a path
-    pub fn pre_allocate_bytes(&mut self, path: &Path, data: &[u8]) -> Result<(), Error> {
+    /// Pre-allocate space for `Bytes` in the filesystem at a path, splitting them with `chunker`
+    pub fn pre_allocate_bytes<C>(
+        &mut self,
+        path: &Path,
+        data: &[u8],
+        chunker: &C,
+    ) -> Result<(), Error>
+    where
+        C: Chunker,
+    {
         tracing::debug!("Preallocating {} bytes at {path:?}", data.len());
-        let chunks = data
-            .chunks(CHUNK_SIZE)
+        let chunks = chunker
+            .chunks(data)
             .map(|chunk| ChunkInfo {
-                hash: do_hash(chunk),
+                hash: chunk_hash(chunk),
                 size: chunk.len() as u64,
             })
             .collect::<Vec<ChunkInfo>>();
@@ -384,6 +475,245 @@ impl FsStorage {
         Ok(())
     }
 
+    /// Create an item from the file already at `path`, reading it piece by piece
+    ///
+    /// The file is left untouched, its chunks are recorded where they lie. Unlike `create_item`, only a few chunks
+    /// of the file are kept in memory at once, whatever its size.
+    pub async fn index_file(
+        &mut self,
+        name: ItemName,
+        path: PathBuf,
+        revision: u32,
+        description: Option<String>,
+        chunking: Chunking,
+    ) -> Result<Item, Error> {
+        tracing::debug!("Index item {name} with path {path:?}");
+        let path = self.path(&path);
+        let file = tokio::fs::File::open(&path).await?;
+        let mut indexer = Indexer {
+            storage: self,
+            path: &path,
+            offset: 0,
+        };
+        let mut builder = TreeBuilder::new(&mut indexer, &chunking);
+        builder.read_from_async(file).await?;
+        let hash_tree = builder.finish()?;
+
+        let item = Item::new(name, path, revision, description, chunking, &hash_tree);
+        tracing::debug!("Indexed item: {item}");
+        self.items.insert(item.clone());
+        self.persist()?;
+        Ok(item)
+    }
+
+    /// Write an already stored (sub-)tree at `offset` in the item laid out as `layout`, returning the offset right
+    /// after it
+    ///
+    /// This is used for `Skipped` nodes of received items, which we already have somewhere in the filesystem.
+    fn copy_stored(&mut self, layout: &Layout, hash: &Hash, mut offset: u64) -> Result<u64, Error> {
+        let node = self.get(hash).ok_or(StorageError::MissingNode(*hash))?;
+        for (info, data) in node
+            .flatten_with_sizes()
+            .into_iter()
+            .zip(node.flatten_iter())
+        {
+            // Data is read back from files, make sure nothing changed it in the meantime
+            let got = chunk_hash(&data);
+            if got != info.hash {
+                return Err(StorageError::HashMismatch {
+                    expected: info.hash,
+                    got,
+                }
+                .into());
+            }
+            if let Some((path, offset)) = layout.locate(offset) {
+                self.pre_allocate_chunk(path, &info, offset)?;
+            }
+            self.store_chunk(info.hash, &data)
+                .ok_or(StorageError::ChunkInsertError)?;
+            offset += info.size;
+        }
+        Ok(offset)
+    }
+
+    /// Swap the fully written staging file `staging`, `len` bytes long, in place of `path`
+    ///
+    /// The staging file gets `attributes` and is flushed to disk before being renamed over `path`, permissions of
+    /// the file it replaces are kept unless `attributes` carry a mode. Chunks of the previous file are forgotten, the
+    /// ones of the staging file are at `path` from then on.
+    fn install_staged(
+        &mut self,
+        staging: &Path,
+        path: &Path,
+        len: u64,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        // Writes are flushed as they're made, handles are dropped so that nothing writes to the replaced file
+        self.handles_map.remove(staging);
+        self.handles_map.remove(path);
+
+        // Empty files of directories may have no chunk of their own, so nothing created their staging file
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(staging)?;
+        file.set_len(len)?;
+        if let Ok(previous) = fs::metadata(path) {
+            file.set_permissions(previous.permissions())?;
+        }
+        attributes.apply(staging, &self.ownership)?;
+        file.sync_all()?;
+        fs::rename(staging, path)?;
+        // Make the rename itself durable
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        tracing::debug!("Installed '{}'", path.to_string_lossy());
+
+        self.data.retain(|_, ifc| ifc.path != path);
+        for (_, ifcs) in self.data.iter_all_mut() {
+            for ifc in ifcs.iter_mut().filter(|ifc| ifc.path == staging) {
+                ifc.path = path.to_path_buf();
+            }
+        }
+        Ok(())
+    }
+
+    /// Files `target` is written to, as installed at `path`
+    fn layout(&self, target: &ItemMetadata, path: &Path) -> Result<Layout, Error> {
+        let dest = |path: &Path| match self.install {
+            Install::Atomic => staging_path(path),
+            Install::InPlace => path.to_path_buf(),
+        };
+        let files = match &target.kind {
+            ItemKind::File => vec![Placement {
+                start: 0,
+                len: target.root.size,
+                dest: dest(path),
+                path: path.to_path_buf(),
+                attributes: target.attributes.clone(),
+            }],
+            ItemKind::Directory(manifest) => {
+                manifest.check(&target.root)?;
+                let mut start = 0;
+                manifest
+                    .files()
+                    .map(|(file, root, attributes)| {
+                        let path = path.join(file);
+                        let placement = Placement {
+                            start,
+                            len: root.size,
+                            dest: dest(&path),
+                            path,
+                            attributes: attributes.clone(),
+                        };
+                        start += root.size;
+                        placement
+                    })
+                    .collect()
+            }
+        };
+        Ok(Layout { files })
+    }
+
+    /// Make room for the directory item at `path`, creating its directories and removing whatever is in the way of
+    /// its entries
+    fn prepare_directory(&mut self, path: &Path, manifest: &Manifest) -> Result<(), Error> {
+        if fs::symlink_metadata(path).is_ok_and(|existing| !existing.is_dir()) {
+            self.remove_path(path)?;
+        }
+        create_dir_all(path)?;
+        for entry in &manifest.entries {
+            let full = path.join(&entry.path);
+            let fits = fs::symlink_metadata(&full)
+                .ok()
+                .map(|existing| match entry.kind {
+                    EntryKind::File { .. } => existing.is_file(),
+                    EntryKind::Directory => existing.is_dir(),
+                    EntryKind::Symlink { .. } => existing.is_symlink(),
+                });
+            if fits == Some(false) {
+                self.remove_path(&full)?;
+            }
+            if entry.kind == EntryKind::Directory && fits != Some(true) {
+                fs::create_dir(&full)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Complete the directory item at `path` once its files are installed
+    ///
+    /// Symbolic links are swapped in like files, entries not in `manifest` are removed and directories get their
+    /// attributes, `attributes` being the ones of `path` itself.
+    fn finish_directory(
+        &mut self,
+        path: &Path,
+        manifest: &Manifest,
+        attributes: &Attributes,
+    ) -> Result<(), Error> {
+        for entry in &manifest.entries {
+            let EntryKind::Symlink { target } = &entry.kind else {
+                continue;
+            };
+            let full = path.join(&entry.path);
+            if fs::read_link(&full).is_ok_and(|current| &current == target) {
+                continue;
+            }
+            let staging = staging_path(&full);
+            // Left over by an interrupted install
+            fs::remove_file(&staging).ok();
+            std::os::unix::fs::symlink(target, &staging)?;
+            fs::rename(&staging, &full)?;
+        }
+
+        let keep = manifest
+            .entries
+            .iter()
+            .map(|entry| path.join(&entry.path))
+            .collect();
+        self.remove_extraneous(path, &keep)?;
+
+        // Deepest first, as changes within a directory change its mtime
+        for entry in manifest.entries.iter().rev() {
+            if entry.kind == EntryKind::Directory {
+                entry
+                    .attributes
+                    .apply(&path.join(&entry.path), &self.ownership)?;
+            }
+        }
+        attributes.apply(path, &self.ownership)?;
+        Ok(())
+    }
+
+    /// Remove whatever is under `dir` and not in `keep`
+    fn remove_extraneous(&mut self, dir: &Path, keep: &HashSet<PathBuf>) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !keep.contains(&path) {
+                tracing::debug!("Removing '{}'", path.to_string_lossy());
+                self.remove_path(&path)?;
+            } else if fs::symlink_metadata(&path)?.is_dir() {
+                self.remove_extraneous(&path, keep)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove whatever is at `path`, forgetting the chunks of the files under it
+    fn remove_path(&mut self, path: &Path) -> Result<(), Error> {
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            remove_file(path)?;
+        }
+        self.handles_map
+            .retain(|handle, _| !handle.starts_with(path));
+        self.data.retain(|_, ifc| !ifc.path.starts_with(path));
+        Ok(())
+    }
+
     /// Remove references to file from `FsStorage`, doesn't actually delete the file from filesystem
     pub fn remove(&mut self, item: Item) -> Result<(), Error> {
         let path = self.item_path(&item)?;
@@ -425,8 +755,32 @@ impl ChunkStorage for FsStorage {
         self.links.get(hash).cloned().or(self.get_data(hash))
     }
 
-    fn size(&self) -> u64 {
-        0 // TODO
+    fn contains(&self, hash: &Hash) -> bool {
+        // Avoid reading chunks back from files just to know whether we have them
+        self.links.contains_key(hash)
+            || self.data.get_vec(hash).is_some_and(|ifcs| {
+                ifcs.iter()
+                    .any(|ifc| ifc.populated.load(std::sync::atomic::Ordering::Relaxed))
+            })
+    }
+
+    /// Computed from in-memory bookkeeping, files are never read
+    ///
+    /// Chunks not yet written to any file are not counted.
+    fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            links: self.links.len() as u64,
+            ..Default::default()
+        };
+        for (_, ifcs) in self.data.iter_all() {
+            if let Some(ifc) = ifcs
+                .iter()
+                .find(|ifc| ifc.populated.load(std::sync::atomic::Ordering::Relaxed))
+            {
+                stats.add_chunk(ifc.info.size);
+            }
+        }
+        stats
     }
 
     /// Insert chunk into storage, requires an item to have been created with the appropriate chunks to be preallocate
@@ -434,8 +788,13 @@ impl ChunkStorage for FsStorage {
         let infile_chunks = self.data.get_vec_mut(&hash)?;
         for infile_chunk in infile_chunks {
             tracing::trace!("infile chunk {infile_chunk:?}");
+            // Handles are not persisted, files of items restored from a previous run are opened here
+            let handle = match self.handles_map.entry(infile_chunk.path.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(Handle::new(&infile_chunk.path).ok()?),
+            };
             infile_chunk
-                .write(&hash, chunk, self.handles_map.get_mut(&infile_chunk.path)?)
+                .write(&hash, chunk, handle)
                 .inspect(|()| {
                     tracing::trace!(
                         "Written infile chunk {hash} to {}",
@@ -485,6 +844,7 @@ impl ChunkStorage for FsStorage {
         revision: u32,
         description: Option<String>,
         file: bytes::Bytes,
+        chunking: Chunking,
     ) -> Option<Item>
     where
         Self: Sized,
@@ -493,11 +853,11 @@ impl ChunkStorage for FsStorage {
         // respect storage root
         let path = self.path(&path);
         create_dir_all(path.parent()?).ok()?;
-        self.pre_allocate_bytes(&path, &file).ok()?;
+        self.pre_allocate_bytes(&path, &file, &chunking).ok()?;
         tracing::info!("Preallocated on disk {:?}", path);
 
-        let hash_tree = self.insert(file)?;
-        let item = Item::new(name, path, revision, description, &hash_tree);
+        let hash_tree = self.insert_with(file, &chunking)?;
+        let item = Item::new(name, path, revision, description, chunking, &hash_tree);
         tracing::debug!("New item: {item}");
 
         self.items.insert(item.clone());
@@ -512,6 +872,7 @@ impl ChunkStorage for FsStorage {
         path: PathBuf,
         revision: u32,
         description: Option<String>,
+        chunking: Chunking,
         root: Arc<Node>,
     ) -> Option<Item>
     where
@@ -523,7 +884,7 @@ impl ChunkStorage for FsStorage {
         self.pre_allocate(&path, &root.flatten_with_sizes()).ok()?;
         tracing::info!("Preallocated on disk {:?}", path);
 
-        let item = Item::new(name, path, revision, description, &root);
+        let item = Item::new(name, path, revision, description, chunking, &root);
         tracing::debug!("New item: {item}");
 
         self.persist().ok()?;
@@ -531,23 +892,29 @@ impl ChunkStorage for FsStorage {
         Some(item)
     }
 
-    /// Build a new Item from its metadata and a streaming of nodes
+    /// Build a new Item from its expected metadata and a streaming of nodes, writing it to the filesystem
+    ///
+    /// Every node is verified before being written, `Skipped` nodes are copied from data already in storage.
+    /// The item is written as set by `install`: with atomic installs nothing changes at its path until all of it
+    /// is received and checked against the expected root, an interrupted transfer is resumed in the staging file.
+    /// Directory items are installed file by file, the local directory matching the manifest once done.
     async fn receive_item<T>(
         &mut self,
-        name: ItemName,
-        path: PathBuf,
-        revision: u32,
-        description: Option<String>,
+        target: ItemMetadata,
         mut stream: T,
-        //) -> Result<Item, crate::error::Error>
     ) -> Result<Item, crate::error::Error>
     where
         Self: Sized,
         T: Stream<Item = Node> + std::marker::Unpin,
     {
-        let path = self.path(&path);
+        let path = self.path(&target.path);
+        let layout = self.layout(&target, &path)?;
 
         tracing::trace!("Receiving item at '{}'", path.to_string_lossy());
+        create_dir_all(path.parent().unwrap_or(&path))?;
+        if let ItemKind::Directory(manifest) = &target.kind {
+            self.prepare_directory(&path, manifest)?;
+        }
         let mut i = 0; // node counter
         let mut o = 0u64; // offset counter
 
@@ -555,26 +922,67 @@ impl ChunkStorage for FsStorage {
         let mut last: Option<Arc<Node>> = None; // final node
 
         while let Some(node) = stream.next().await {
-            if let s_n @ Node::Stored { .. } = &node {
-                tracing::trace!(
-                    "Preallocating {} bytes in {}@'{}'",
-                    s_n.size(),
-                    o,
-                    path.to_string_lossy()
-                );
-                self.pre_allocate_chunk(&path, &s_n.chunk_info(), o)?;
-                o += s_n.size(); // FIXME this may be incorrect, should be passed along with the chunk
+            // Check before touching the filesystem
+            node.verify()?;
+            match (&node, layout.locate(o)) {
+                (s_n @ Node::Stored { .. }, Some((dest, offset))) => {
+                    tracing::trace!(
+                        "Preallocating {} bytes in {}@'{}'",
+                        s_n.size(),
+                        offset,
+                        dest.to_string_lossy()
+                    );
+                    self.pre_allocate_chunk(dest, &s_n.chunk_info(), offset)?;
+                    o += s_n.size();
+                }
+                // Directories without files are made of an empty chunk, with nowhere to be written
+                (Node::Stored { .. }, None) => {
+                    last = Some(Arc::new(node));
+                    i += 1;
+                    continue;
+                }
+                (Node::Skipped { hash, .. }, _) => o = self.copy_stored(&layout, hash, o)?,
+                (Node::Parent { .. }, _) => {}
             }
-            last = self.try_fill_in(&node);
+            last = Some(self.try_fill_in(&node)?);
             i += 1;
         }
 
         let last = last.ok_or(StorageError::TreeReconstruct)?;
         tracing::info!("Reconstructed {i} nodes with {} bytes total", last.size());
+        check_root(&target, &last)?;
+
+        for file in &layout.files {
+            if file.dest == file.path {
+                // Drop any trailing data from a previous, longer, revision
+                File::options()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&file.path)?
+                    .set_len(file.len)?;
+                file.attributes.apply(&file.path, &self.ownership)?;
+            } else {
+                self.install_staged(&file.dest, &file.path, file.len, &file.attributes)?;
+            }
+        }
+        if let ItemKind::Directory(manifest) = &target.kind {
+            self.finish_directory(&path, manifest, &target.attributes)?;
+        }
 
         self.persist()?;
 
-        Ok(Item::new(name, path, revision, description, &last))
+        let mut item = Item::new(
+            target.name,
+            path,
+            target.revision,
+            target.description,
+            target.chunking,
+            &last,
+        );
+        item.metadata.attributes = target.attributes;
+        item.metadata.kind = target.kind;
+        Ok(item)
     }
 
     /// Get a Vec of all chunks' hashes in storage
@@ -582,6 +990,37 @@ impl ChunkStorage for FsStorage {
         self.data.keys().copied().collect()
     }
 
+    fn nodes(&self) -> Vec<Hash> {
+        self.data.keys().chain(self.links.keys()).copied().collect()
+    }
+
+    /// Forget a node, files are left untouched as their chunks may still be read by others
+    ///
+    /// This is not persisted on its own, `collect_garbage` persists once done.
+    fn remove_node(&mut self, hash: &Hash) -> Option<u64> {
+        if self.links.remove(hash).is_some() {
+            return Some(0);
+        }
+        self.data.remove(hash).map(|ifcs| {
+            ifcs.iter()
+                .find(|ifc| ifc.populated.load(std::sync::atomic::Ordering::Relaxed))
+                .map_or(0, |ifc| ifc.info.size)
+        })
+    }
+
+    /// Like the default implementation, but always keeping nodes of the items in storage
+    fn collect_garbage(&mut self, roots: &[Hash], dry_run: bool) -> gc::Report {
+        let mut roots = roots.to_vec();
+        roots.extend(self.items.iter().map(|item| *item.root()));
+        let report = gc::collect(self, &roots, dry_run);
+        if !dry_run {
+            self.persist()
+                .inspect_err(|e| tracing::error!("Cannot persist storage after collection: {e}"))
+                .ok();
+        }
+        report
+    }
+
     /*
     fn insert(&self, data: bytes::Bytes) -> Option<Arc<Node>>
         where
@@ -598,6 +1037,10 @@ impl HashTreeCapable<Arc<Node>, crate::error::Error> for FsStorage {
             .ok_or(StorageError::ChunkInsertError)?)
     }
 
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<Arc<Node>>, crate::error::Error> {
+        self.insert_chunks(leaves)
+    }
+
     fn merge(&mut self, l: &Arc<Node>, r: &Arc<Node>) -> Result<Arc<Node>, crate::error::Error> {
         Ok(self
             .link(l.clone(), r.clone())
@@ -605,6 +1048,61 @@ impl HashTreeCapable<Arc<Node>, crate::error::Error> for FsStorage {
     }
 }
 
+/// Records the chunks of a file already in place, see `FsStorage::index_file`
+struct Indexer<'a> {
+    storage: &'a mut FsStorage,
+    path: &'a Path,
+
+    /// Offset of the next chunk in the file
+    offset: u64,
+}
+
+impl Indexer<'_> {
+    /// Record the next chunk of the file, with its hash
+    fn record(&mut self, hash: Hash, data: &[u8]) -> Result<Arc<Node>, Error> {
+        let info = ChunkInfo {
+            hash,
+            size: data.len() as u64,
+        };
+        self.storage
+            .pre_allocate_chunk(self.path, &info, self.offset)?;
+        // The chunk is already in the file, there's nothing to write
+        if let Some(ifc) = self.storage.data.get_vec(&info.hash).and_then(|ifcs| {
+            ifcs.iter()
+                .find(|ifc| ifc.path == self.path && ifc.offset == self.offset)
+        }) {
+            ifc.populated
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+        self.offset += info.size;
+        Ok(Arc::new(Node::Stored {
+            hash: info.hash,
+            data: Arc::new(data.to_vec()),
+        }))
+    }
+}
+
+impl HashTreeCapable<Arc<Node>, crate::error::Error> for Indexer<'_> {
+    fn func(&mut self, data: &[u8]) -> Result<Arc<Node>, crate::error::Error> {
+        self.record(chunk_hash(data), data)
+    }
+
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<Arc<Node>>, crate::error::Error> {
+        leaves
+            .iter()
+            .zip(chunk_hashes(leaves))
+            .map(|(leaf, hash)| self.record(hash, leaf))
+            .collect()
+    }
+
+    fn merge(&mut self, l: &Arc<Node>, r: &Arc<Node>) -> Result<Arc<Node>, crate::error::Error> {
+        Ok(self
+            .storage
+            .link(l.clone(), r.clone())
+            .ok_or(StorageError::LinkCreation)?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -841,6 +1339,381 @@ mod tests {
         }
     }
 
+    #[test]
+    fn fs_storage_receive_update() {
+        use crate::chunk_storage::hashmap_storage::HashMapStorage;
+        use futures::executor::block_on;
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        let tempdir = temp_path();
+        let mut storage = FsStorage::new(tempdir.clone());
+
+        let mut old = vec![0u8; CHUNK_SIZE * 3];
+        StdRng::seed_from_u64(3).fill_bytes(&mut old);
+        let (c0, c1) = (&old[..CHUNK_SIZE], &old[CHUNK_SIZE..CHUNK_SIZE * 2]);
+        // new third chunk, first chunk repeated after it, plus a shorter tail
+        let new = [c0, c1, &[7u8; CHUNK_SIZE], c0, &[8u8; 100]].concat();
+
+        let mut source = HashMapStorage::default();
+        let new_item = source
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                1,
+                None,
+                new.clone().into(),
+                Chunking::default(),
+            )
+            .unwrap();
+        let old_item = storage
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                old.clone().into(),
+                Chunking::default(),
+            )
+            .unwrap();
+
+        let nodes: Vec<Node> = source
+            .get(new_item.root())
+            .unwrap()
+            .find_diff(&old_item.chunks.iter().map(|c| c.hash).collect::<Vec<_>>())
+            .map(|n| (*n).clone())
+            .collect();
+        assert!(nodes.iter().any(|n| matches!(n, Node::Skipped { .. })));
+
+        let received =
+            block_on(storage.receive_item(new_item.metadata.clone(), tokio_stream::iter(nodes)))
+                .unwrap();
+        assert_eq!(received.root(), new_item.root());
+        assert_eq!(std::fs::read(tempdir.join("item")).unwrap(), new);
+    }
+
+    #[tokio::test]
+    async fn fs_storage_index_file() {
+        use crate::chunk_storage::hashmap_storage::HashMapStorage;
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        let tempdir = temp_path();
+        let mut storage = FsStorage::new(tempdir.clone());
+        let mut data = vec![0u8; CHUNK_SIZE * 3 + 100];
+        StdRng::seed_from_u64(4).fill_bytes(&mut data);
+        std::fs::create_dir_all(&tempdir).unwrap();
+        std::fs::write(tempdir.join("item"), &data).unwrap();
+
+        let item = storage
+            .index_file(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                Chunking::default(),
+            )
+            .await
+            .unwrap();
+        let expected = HashMapStorage::default()
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                data.clone().into(),
+                Chunking::default(),
+            )
+            .unwrap();
+        assert_eq!(item.root(), expected.root());
+        assert_eq!(item.chunks, expected.chunks);
+
+        // Chunks are served from the file, which is left as it was
+        assert!(storage.contains(item.root()));
+        let last = storage.get(&item.chunks[3].hash).unwrap();
+        assert_eq!(last.size(), 100);
+        assert_eq!(std::fs::read(tempdir.join("item")).unwrap(), data);
+    }
+
+    #[test]
+    fn fs_storage_resume_interrupted() {
+        use crate::chunk_storage::{
+            hashmap_storage::HashMapStorage,
+            negotiation::{expand, Negotiation},
+        };
+        use futures::executor::block_on;
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        let tempdir = temp_path();
+        let mut data = vec![0u8; CHUNK_SIZE * 8 + 100];
+        StdRng::seed_from_u64(5).fill_bytes(&mut data);
+
+        let mut source = HashMapStorage::default();
+        let item = source
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                data.clone().into(),
+                Chunking::default(),
+            )
+            .unwrap();
+        let root = source.get(item.root()).unwrap();
+        let stored = |nodes: &[Node]| {
+            nodes
+                .iter()
+                .filter(|n| matches!(n, Node::Stored { .. }))
+                .count()
+        };
+
+        // The stream breaks half-way
+        let nodes: Vec<Node> = root.clone().find_diff(&[]).map(|n| (*n).clone()).collect();
+        let total = stored(&nodes);
+        let half = nodes.len() / 2;
+        let mut storage = FsStorage::new(tempdir.clone());
+        assert!(block_on(storage.receive_item(
+            item.metadata.clone(),
+            tokio_stream::iter(nodes[..half].to_vec())
+        ))
+        .is_err());
+        drop(storage);
+
+        // Progress is restored and only what's left is transferred
+        let mut storage = FsStorage::new(tempdir.clone());
+        let mut negotiation = Negotiation::default();
+        let mut frontier = vec![(*item.root(), false)];
+        loop {
+            let next = negotiation.step(&storage, frontier);
+            if next.is_empty() {
+                break;
+            }
+            frontier = expand(&source, &next);
+        }
+        let nodes: Vec<Node> = root
+            .find_negotiated_diff(&negotiation.have, &negotiation.missing)
+            .map(|n| (*n).clone())
+            .collect();
+        assert!(stored(&nodes) < total);
+
+        let received =
+            block_on(storage.receive_item(item.metadata.clone(), tokio_stream::iter(nodes)))
+                .unwrap();
+        assert_eq!(received.root(), item.root());
+        assert_eq!(std::fs::read(tempdir.join("item")).unwrap(), data);
+
+        // Links whose data is gone are dropped instead of blocking the restore
+        std::fs::remove_file(tempdir.join("item")).unwrap();
+        let storage = FsStorage::new(tempdir);
+        assert!(!storage.contains(item.root()));
+    }
+
+    #[test]
+    fn fs_storage_atomic_install() {
+        use crate::chunk_storage::hashmap_storage::HashMapStorage;
+        use futures::executor::block_on;
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = temp_path();
+        let mut storage = FsStorage::new(tempdir.clone());
+        let mut old = vec![0u8; CHUNK_SIZE * 4];
+        StdRng::seed_from_u64(7).fill_bytes(&mut old);
+        let mut new = vec![0u8; CHUNK_SIZE * 6 + 100];
+        StdRng::seed_from_u64(8).fill_bytes(&mut new);
+
+        storage
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                old.clone().into(),
+                Chunking::default(),
+            )
+            .unwrap();
+        let live = tempdir.join("item");
+        fs::set_permissions(&live, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut source = HashMapStorage::default();
+        let item = source
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                1,
+                None,
+                new.clone().into(),
+                Chunking::default(),
+            )
+            .unwrap();
+        let nodes: Vec<Node> = source
+            .get(item.root())
+            .unwrap()
+            .find_diff(&[])
+            .map(|n| (*n).clone())
+            .collect();
+
+        // An interrupted transfer leaves the previous revision untouched
+        let half = nodes[..nodes.len() / 2].to_vec();
+        assert!(
+            block_on(storage.receive_item(item.metadata.clone(), tokio_stream::iter(half)))
+                .is_err()
+        );
+        assert_eq!(fs::read(&live).unwrap(), old);
+
+        block_on(storage.receive_item(item.metadata.clone(), tokio_stream::iter(nodes.clone())))
+            .unwrap();
+        assert_eq!(fs::read(&live).unwrap(), new);
+        assert_eq!(
+            fs::metadata(&live).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert!(!staging_path(&live).exists());
+        assert_eq!(storage.get(item.root()).unwrap().clone_data(), new);
+
+        // Attributes carried by the item win over the ones of the replaced file
+        let mut metadata = item.metadata.clone();
+        metadata.revision = 2;
+        metadata.attributes.mode = Some(0o640);
+        metadata.attributes.mtime = Some(std::time::SystemTime::UNIX_EPOCH);
+        let received = block_on(storage.receive_item(metadata, tokio_stream::iter(nodes))).unwrap();
+        let installed = fs::metadata(&live).unwrap();
+        assert_eq!(installed.permissions().mode() & 0o777, 0o640);
+        assert_eq!(
+            installed.modified().unwrap(),
+            std::time::SystemTime::UNIX_EPOCH
+        );
+        assert_eq!(received.metadata.attributes.mode, Some(0o640));
+        assert_eq!(fs::read(&live).unwrap(), new);
+    }
+
+    #[test]
+    fn fs_storage_directory_sync() {
+        use crate::chunk_storage::hashmap_storage::HashMapStorage;
+        use crate::chunker::FixedSize;
+        use crate::directory::Manifest;
+        use futures::executor::block_on;
+
+        let chunking = Chunking::Fixed(FixedSize { size: 1024 });
+        let source = temp_path();
+        fs::create_dir_all(source.join("bin")).unwrap();
+        fs::create_dir_all(source.join("empty")).unwrap();
+        fs::write(source.join("bin/app"), vec![1u8; 3000]).unwrap();
+        fs::write(source.join("data"), vec![2u8; 5000]).unwrap();
+        fs::write(source.join("gone"), vec![3u8; 100]).unwrap();
+        fs::write(source.join("none"), b"").unwrap();
+        std::os::unix::fs::symlink("bin/app", source.join("link")).unwrap();
+
+        let publish = |revision| {
+            let mut published = HashMapStorage::default();
+            let (manifest, root) = Manifest::insert(&mut published, &source, &chunking).unwrap();
+            let mut item = Item::new(
+                "dir".into(),
+                PathBuf::from("dir"),
+                revision,
+                None,
+                chunking,
+                &root,
+            );
+            item.metadata.kind = ItemKind::Directory(manifest);
+            (item.metadata, root)
+        };
+
+        let tempdir = temp_path();
+        let mut storage = FsStorage::new(tempdir.clone());
+        let (metadata, root) = publish(0);
+        let nodes: Vec<Node> = root.find_diff(&[]).map(|n| (*n).clone()).collect();
+        block_on(storage.receive_item(metadata, tokio_stream::iter(nodes))).unwrap();
+
+        let dir = tempdir.join("dir");
+        assert_eq!(fs::read(dir.join("bin/app")).unwrap(), vec![1u8; 3000]);
+        assert_eq!(fs::read(dir.join("data")).unwrap(), vec![2u8; 5000]);
+        assert!(fs::read(dir.join("none")).unwrap().is_empty());
+        assert!(dir.join("empty").is_dir());
+        assert_eq!(
+            fs::read_link(dir.join("link")).unwrap(),
+            Path::new("bin/app")
+        );
+
+        // Only what changed is sent, and the local directory ends up matching the new revision
+        fs::write(source.join("data"), vec![4u8; 5000]).unwrap();
+        fs::remove_file(source.join("gone")).unwrap();
+        fs::remove_dir(source.join("empty")).unwrap();
+        fs::write(source.join("empty"), vec![1u8; 3000]).unwrap();
+        fs::write(dir.join("local"), b"not in the item").unwrap();
+        let (metadata, root) = publish(1);
+        let held: Vec<Hash> = storage.nodes();
+        let missing: Vec<Hash> = root
+            .all_hashes()
+            .into_iter()
+            .filter(|hash| !storage.contains(hash))
+            .collect();
+        let nodes: Vec<Node> = root
+            .find_negotiated_diff(&held, &missing)
+            .map(|n| (*n).clone())
+            .collect();
+        assert!(nodes.iter().any(|n| matches!(n, Node::Skipped { .. })));
+        let item = block_on(storage.receive_item(metadata, tokio_stream::iter(nodes))).unwrap();
+
+        assert_eq!(fs::read(dir.join("data")).unwrap(), vec![4u8; 5000]);
+        assert_eq!(fs::read(dir.join("empty")).unwrap(), vec![1u8; 3000]);
+        assert_eq!(fs::read(dir.join("bin/app")).unwrap(), vec![1u8; 3000]);
+        assert!(!dir.join("gone").exists());
+        assert!(!dir.join("local").exists());
+        assert_eq!(
+            fs::read_dir(&dir).unwrap().count(),
+            5 // bin, data, empty, link, none
+        );
+        assert_eq!(
+            storage.get(item.root()).unwrap().clone_data(),
+            [vec![1u8; 3000], vec![4u8; 5000], vec![1u8; 3000]].concat()
+        );
+    }
+
+    #[test]
+    fn fs_storage_garbage_collection() {
+        let tempdir = temp_path();
+        let mut storage = FsStorage::new(tempdir.clone());
+        let kept = storage
+            .create_item(
+                "kept".into(),
+                PathBuf::from("kept"),
+                0,
+                None,
+                vec![1u8; CHUNK_SIZE * 3].into(),
+                Chunking::default(),
+            )
+            .unwrap();
+        let removed = storage
+            .create_item(
+                "removed".into(),
+                PathBuf::from("removed"),
+                0,
+                None,
+                vec![2u8; CHUNK_SIZE * 3].into(),
+                Chunking::default(),
+            )
+            .unwrap();
+
+        assert_eq!(storage.stats().chunk_bytes, 2 * CHUNK_SIZE as u64);
+
+        // Items in storage are always kept
+        assert_eq!(storage.collect_garbage(&[], true).nodes, 0);
+
+        storage.remove(removed.clone()).unwrap();
+        let report = storage.collect_garbage(&[], false);
+        assert!(report.nodes > 0);
+        assert_eq!(storage.stats().chunk_bytes, CHUNK_SIZE as u64);
+        assert!(!storage.contains(removed.root()));
+        assert!(storage.contains(kept.root()));
+        drop(storage);
+
+        let storage = FsStorage::new(tempdir);
+        assert!(!storage.contains(removed.root()));
+        assert_eq!(
+            storage.get(kept.root()).unwrap().clone_data(),
+            vec![1u8; CHUNK_SIZE * 3]
+        );
+    }
+
     #[test]
     fn fs_storage_persistance_10x() {
         // repeated test to check determinism
@@ -0,0 +1,82 @@
+//! Mark-and-sweep garbage collection
+//!
+//! Every node reachable from a set of roots, usually the roots of the item revisions still retained, is marked.
+//! All the other nodes in the storage are then swept, or just accounted for on a dry run.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash::Hash;
+
+use super::{ChunkStorage, Node};
+
+/// Outcome of a garbage collection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    /// Number of nodes (chunks and links) that are, or would be on a dry run, removed
+    pub nodes: usize,
+
+    /// Chunk bytes that are, or would be on a dry run, reclaimed
+    pub bytes: u64,
+
+    /// Whether nothing was actually removed
+    pub dry_run: bool,
+}
+
+/// Hashes of every node reachable from `roots`
+///
+/// Roots missing from storage are ignored.
+pub fn mark<S>(storage: &S, roots: &[Hash]) -> HashSet<Hash>
+where
+    S: ChunkStorage + ?Sized,
+{
+    let mut marked = HashSet::new();
+    for root in roots {
+        if marked.contains(root) {
+            continue;
+        }
+        if let Some(node) = storage.get(root) {
+            marked.extend(node.all_hashes());
+        }
+    }
+    marked
+}
+
+/// Remove every node not reachable from `roots`, only reporting what would be removed if `dry_run` is set
+pub fn collect<S>(storage: &mut S, roots: &[Hash], dry_run: bool) -> Report
+where
+    S: ChunkStorage + ?Sized,
+{
+    let marked = mark(storage, roots);
+    let mut report = Report {
+        dry_run,
+        ..Default::default()
+    };
+
+    for hash in storage.nodes() {
+        if marked.contains(&hash) {
+            continue;
+        }
+        let bytes = if dry_run {
+            storage.get(&hash).map(|node| match node.as_ref() {
+                Node::Stored { data, .. } => data.len() as u64,
+                Node::Parent { .. } | Node::Skipped { .. } => 0,
+            })
+        } else {
+            storage.remove_node(&hash)
+        };
+        if let Some(bytes) = bytes {
+            report.nodes += 1;
+            report.bytes += bytes;
+        }
+    }
+
+    tracing::info!(
+        "Garbage collection{}: {} nodes, {} bytes",
+        if dry_run { " (dry run)" } else { "" },
+        report.nodes,
+        report.bytes
+    );
+    report
+}
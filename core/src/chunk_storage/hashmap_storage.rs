@@ -4,12 +4,13 @@ use std::sync::Arc;
 use crate::chunk_storage::ChunkStorage;
 use crate::hash::{Hash, HashTreeCapable};
 
-use super::{Node, StorageError};
+use super::{Node, Stats, StorageError};
 
 /// Dead simple in-memory global storage
 #[derive(Debug, Default, Clone)]
 pub struct HashMapStorage {
     data: HashMap<Hash, Arc<Node>>,
+    stats: Stats,
 }
 
 impl ChunkStorage for HashMapStorage {
@@ -22,6 +23,7 @@ impl ChunkStorage for HashMapStorage {
         if let Some(raw_chunk) = self.data.get(&hash) {
             return Some(raw_chunk.clone());
         }
+        self.stats.add_chunk(chunk.len() as u64);
         self.data
             .try_insert(
                 hash,
@@ -44,8 +46,11 @@ impl ChunkStorage for HashMapStorage {
         );
         */
         let size = left.size() + right.size();
-        self.data.get(&hash).cloned().or(self
-            .data
+        if let Some(link) = self.data.get(&hash) {
+            return Some(link.clone());
+        }
+        self.stats.links += 1;
+        self.data
             .try_insert(
                 hash,
                 Arc::new(Node::Parent {
@@ -56,21 +61,32 @@ impl ChunkStorage for HashMapStorage {
                 }),
             )
             .ok()
-            .map(|x| x.clone()))
+            .map(|x| x.clone())
     }
 
     fn chunks(&self) -> Vec<Hash> {
         self.data.keys().copied().collect()
     }
 
-    fn size(&self) -> u64 {
-        self.data
-            .values()
-            .map(|x| match &**x {
-                Node::Stored { data, .. } => data.len() as u64,
-                Node::Parent { .. } | Node::Skipped { .. } => 0,
-            })
-            .sum()
+    fn nodes(&self) -> Vec<Hash> {
+        self.data.keys().copied().collect()
+    }
+
+    fn remove_node(&mut self, hash: &Hash) -> Option<u64> {
+        self.data.remove(hash).map(|x| match &*x {
+            Node::Stored { data, .. } => {
+                self.stats.remove_chunk(data.len() as u64);
+                data.len() as u64
+            }
+            Node::Parent { .. } | Node::Skipped { .. } => {
+                self.stats.links = self.stats.links.saturating_sub(1);
+                0
+            }
+        })
+    }
+
+    fn stats(&self) -> Stats {
+        self.stats
     }
 }
 
@@ -81,6 +97,10 @@ impl HashTreeCapable<Arc<Node>, crate::error::Error> for HashMapStorage {
             .ok_or(StorageError::ChunkInsertError)?)
     }
 
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<Arc<Node>>, crate::error::Error> {
+        self.insert_chunks(leaves)
+    }
+
     fn merge(&mut self, l: &Arc<Node>, r: &Arc<Node>) -> Result<Arc<Node>, crate::error::Error> {
         Ok(self
             .link(l.clone(), r.clone())
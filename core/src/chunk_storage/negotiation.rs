@@ -0,0 +1,149 @@
+//! Top-down diff negotiation
+//!
+//! Instead of sending every hash it holds, a receiver walks the hash-tree of the item it wants starting from the
+//! root, one level per round: subtrees it already has are kept as is, the others are expanded into their children
+//! on the next round. The result is the smallest set of subtrees the receiver has, along with the parents it lacks,
+//! to be passed to `Node::find_negotiated_diff`.
+
+use crate::hash::Hash;
+
+use super::ChunkStorage;
+
+/// Receiver side state of a diff negotiation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Negotiation {
+    /// Roots of subtrees we already hold
+    pub have: Vec<Hash>,
+
+    /// Parents we don't hold, these must never be skipped by the sender
+    pub missing: Vec<Hash>,
+}
+
+impl Negotiation {
+    /// Sort the `(hash, is_leaf)` subtrees of a frontier received from the sender
+    ///
+    /// Returns the parents to expand on the next round, an empty result means the negotiation is over.
+    pub fn step<S, I>(&mut self, storage: &S, frontier: I) -> Vec<Hash>
+    where
+        S: ChunkStorage,
+        I: IntoIterator<Item = (Hash, bool)>,
+    {
+        let mut expand = vec![];
+        for (hash, leaf) in frontier {
+            if storage.contains(&hash) {
+                self.have.push(hash);
+            } else if !leaf {
+                self.missing.push(hash);
+                expand.push(hash);
+            }
+        }
+        expand
+    }
+}
+
+/// Sender side of a negotiation round, get the `(hash, is_leaf)` children of every parent in `hashes`
+///
+/// Unknown hashes and leaves are ignored.
+pub fn expand<S>(storage: &S, hashes: &[Hash]) -> Vec<(Hash, bool)>
+where
+    S: ChunkStorage,
+{
+    hashes
+        .iter()
+        .filter_map(|hash| storage.get(hash))
+        .filter_map(|node| node.children().map(|(l, r)| [l.clone(), r.clone()]))
+        .flatten()
+        .map(|child| (*child.hash(), child.children().is_none()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use crate::{
+        chunk_storage::{hashmap_storage::HashMapStorage, Node, StorageError},
+        chunker::Chunking,
+        chunks::CHUNK_SIZE,
+        error::Error,
+    };
+
+    use super::*;
+
+    #[test]
+    fn negotiated_transfer() {
+        let mut data = vec![0u8; CHUNK_SIZE * 3 + 100];
+        StdRng::seed_from_u64(7).fill_bytes(&mut data);
+
+        let mut source = HashMapStorage::default();
+        let item = source
+            .create_item(
+                "item".into(),
+                PathBuf::from("item"),
+                0,
+                None,
+                Bytes::from(data.clone()),
+                Chunking::default(),
+            )
+            .unwrap();
+
+        // The local storage holds the first two chunks, but not the subtree linking them
+        let mut local = HashMapStorage::default();
+        local.insert_chunk(&data[..CHUNK_SIZE]).unwrap();
+        local
+            .insert_chunk(&data[CHUNK_SIZE..CHUNK_SIZE * 2])
+            .unwrap();
+
+        let mut negotiation = Negotiation::default();
+        let mut frontier = vec![(*item.root(), false)];
+        let mut rounds = 0;
+        loop {
+            let next = negotiation.step(&local, frontier);
+            if next.is_empty() {
+                break;
+            }
+            frontier = expand(&source, &next);
+            rounds += 1;
+        }
+        assert_eq!(rounds, 2);
+        assert_eq!(negotiation.have.len(), 2);
+        assert_eq!(negotiation.missing.len(), 3);
+
+        let root = source.get(item.root()).unwrap();
+
+        // Plain `find_diff` assumes we hold the parent of the two chunks
+        let nodes: Vec<Node> = root
+            .clone()
+            .find_diff(&negotiation.have)
+            .map(|n| (*n).clone())
+            .collect();
+        let res = block_on(
+            local
+                .clone()
+                .receive_item(item.metadata.clone(), tokio_stream::iter(nodes)),
+        );
+        assert!(matches!(
+            res,
+            Err(Error::Storage(StorageError::MissingNode(_)))
+        ));
+
+        let nodes: Vec<Node> = root
+            .find_negotiated_diff(&negotiation.have, &negotiation.missing)
+            .map(|n| (*n).clone())
+            .collect();
+        let stored = nodes
+            .iter()
+            .filter(|n| matches!(n, Node::Stored { .. }))
+            .count();
+        assert_eq!(stored, 2);
+
+        let received =
+            block_on(local.receive_item(item.metadata.clone(), tokio_stream::iter(nodes))).unwrap();
+        assert_eq!(received.root(), item.root());
+        assert_eq!(local.get(item.root()).unwrap().clone_data(), data);
+    }
+}
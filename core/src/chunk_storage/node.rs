@@ -4,8 +4,9 @@ use std::{collections::HashSet, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 
+use crate::chunk_storage::StorageError;
 use crate::chunks::{ChunkInfo, CHUNK_SIZE_U64};
-use crate::hash::Hash;
+use crate::hash::{chunk_hash, merge_hashes, Hash};
 use crate::utils::serde::nodes::{deserialize_arc_node, serialize_arc_node};
 
 /// Arc reference to a raw byte chunk
@@ -358,6 +359,33 @@ impl Node {
         }
     }
 
+    /// Check the node hash against its content
+    ///
+    /// `Stored` data is re-hashed, while `Parent` hash and size are checked against its children ones.
+    /// `Skipped` nodes cannot be verified on their own.
+    pub fn verify(&self) -> Result<(), StorageError> {
+        let (expected, got) = match self {
+            Self::Stored { hash, data } => (*hash, chunk_hash(data)),
+            Self::Parent {
+                hash,
+                size,
+                left,
+                right,
+            } => {
+                if *size != left.size() + right.size() {
+                    return Err(StorageError::SizeMismatch(*hash));
+                }
+                (*hash, merge_hashes(left.hash(), right.hash()))
+            }
+            Self::Skipped { .. } => return Ok(()),
+        };
+        if expected == got {
+            Ok(())
+        } else {
+            Err(StorageError::HashMismatch { expected, got })
+        }
+    }
+
     /// Get all unique hashes (`Stored` or `Parent`) referenced by the (sub-)tree, as an `HashMap`
     #[must_use]
     pub fn hash_map(self: Arc<Node>) -> HashMap<Hash, Arc<Node>> {
@@ -394,8 +422,24 @@ impl Node {
 
     /// Get all unique hashes (`Stored` or `Parent`) referenced by the (sub-)tree, as a `HashMap`
     pub fn find_diff(self: Arc<Node>, hashes: &[Hash]) -> impl Iterator<Item = Arc<Node>> {
-        let mut hashes = hashes.iter().copied().collect();
+        self.find_negotiated_diff(hashes, &[])
+    }
+
+    /// Like `find_diff`, but never skipping subtrees in `missing`
+    ///
+    /// `fill_hashes` assumes a parent is held whenever both its children are, which isn't true for a receiver
+    /// holding the same chunks in a different tree. With a top-down negotiation the receiver tells which
+    /// parents it lacks, these are sent as `Parent` so that it can rebuild them from their children.
+    pub fn find_negotiated_diff(
+        self: Arc<Node>,
+        hashes: &[Hash],
+        missing: &[Hash],
+    ) -> impl Iterator<Item = Arc<Node>> {
+        let mut hashes = hashes.iter().copied().collect::<HashSet<_>>();
         self.fill_hashes(&mut hashes);
+        for hash in missing {
+            hashes.remove(hash);
+        }
         NodeIterator::new_skipping(self, &hashes)
     }
 }
@@ -0,0 +1,109 @@
+//! Trees of content left where it is, holding hashes only
+//!
+//! An `Outline` computes the tree of some content with `TreeBuilder` without keeping any of it: leaves are
+//! `Node::Skipped`, and the outline remembers where each chunk is in the content so that it can be read again when
+//! actually needed, as when uploading the nodes a receiver is missing.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::error::Error;
+use crate::hash::{chunk_hash, chunk_hashes, merge_hashes, Hash, HashTreeCapable};
+
+use super::Node;
+
+/// Hash tree of some content, with the offset and size of each of its chunks in the content
+#[derive(Debug, Default)]
+pub struct Outline {
+    /// Offset and size of the chunks, the first occurrence for chunks found more than once
+    chunks: HashMap<Hash, (u64, u64)>,
+
+    /// Size of the content seen so far, the offset of the next chunk
+    offset: u64,
+}
+
+impl Outline {
+    /// Offset and size of the chunk with `hash` in the content, if the content has it
+    #[must_use]
+    pub fn locate(&self, hash: &Hash) -> Option<(u64, u64)> {
+        self.chunks.get(hash).copied()
+    }
+
+    /// Size of the content seen so far
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.offset
+    }
+
+    /// Record the next chunk of the content, with its hash
+    fn record(&mut self, hash: Hash, data: &[u8]) -> Arc<Node> {
+        let size = data.len() as u64;
+        self.chunks.entry(hash).or_insert((self.offset, size));
+        self.offset += size;
+        Arc::new(Node::Skipped { hash, size })
+    }
+}
+
+/// Leaves are `Node::Skipped`, parents link them as usual
+impl HashTreeCapable<Arc<Node>, Error> for Outline {
+    fn func(&mut self, data: &[u8]) -> Result<Arc<Node>, Error> {
+        Ok(self.record(chunk_hash(data), data))
+    }
+
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<Arc<Node>>, Error> {
+        Ok(leaves
+            .iter()
+            .zip(chunk_hashes(leaves))
+            .map(|(leaf, hash)| self.record(hash, leaf))
+            .collect())
+    }
+
+    fn merge(&mut self, l: &Arc<Node>, r: &Arc<Node>) -> Result<Arc<Node>, Error> {
+        Ok(Arc::new(Node::Parent {
+            hash: merge_hashes(l.hash(), r.hash()),
+            size: l.size() + r.size(),
+            left: l.clone(),
+            right: r.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::Outline;
+    use crate::chunk_storage::{hashmap_storage::HashMapStorage, ChunkStorage, Node};
+    use crate::chunker::{Chunking, FixedSize};
+    use crate::hash::{chunk_hash, TreeBuilder};
+
+    #[test]
+    fn outlined_tree() {
+        let mut data = vec![0u8; 20_000];
+        StdRng::seed_from_u64(5).fill_bytes(&mut data);
+        // Repeated chunks are located at their first occurrence
+        data.extend_from_within(..4096);
+        let chunking = Chunking::Fixed(FixedSize { size: 1024 });
+        let expected = HashMapStorage::default()
+            .insert_with(Bytes::from(data.clone()), &chunking)
+            .unwrap();
+
+        let mut outline = Outline::default();
+        let mut builder = TreeBuilder::new(&mut outline, &chunking);
+        builder.update(&data).unwrap();
+        let root = builder.finish().unwrap();
+        assert_eq!(root.chunk_info(), expected.chunk_info());
+        assert_eq!(root.all_hashes(), expected.all_hashes());
+        assert_eq!(outline.size(), data.len() as u64);
+
+        for chunk in expected.flatten_with_sizes() {
+            let (offset, size) = outline.locate(&chunk.hash).unwrap();
+            assert_eq!(size, chunk.size);
+            let range = usize::try_from(offset).unwrap()..usize::try_from(offset + size).unwrap();
+            assert_eq!(chunk_hash(&data[range]), chunk.hash);
+        }
+        assert!(root
+            .find_diff(&[])
+            .all(|node| !matches!(node.as_ref(), Node::Stored { .. })));
+    }
+}
@@ -4,13 +4,19 @@ use std::sync::Arc;
 use crate::chunk_storage::ChunkStorage;
 use crate::hash::{Hash, HashTreeCapable};
 
-use super::{Node, StorageError};
+use super::{Node, Stats, StorageError};
 
-use redb::{Database, Error, ReadTransaction, ReadableTable, TableDefinition};
+use redb::{
+    Database, Error, ReadTransaction, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
 
 const CHUNK_TABLE: TableDefinition<&[u8; 32], Vec<u8>> = TableDefinition::new("distd_chunks");
 const LINK_TABLE: TableDefinition<&[u8; 32], ([u8; 32], [u8; 32])> =
     TableDefinition::new("distd_links");
+/// Single row table keeping `Stats` as (chunks, links, `chunk_bytes`), updated along with the other tables
+const STATS_TABLE: TableDefinition<&str, (u64, u64, u64)> = TableDefinition::new("distd_stats");
+const STATS_KEY: &str = "stats";
 
 /// Dead simple in-memory global storage
 #[derive(Debug, Clone)]
@@ -21,7 +27,41 @@ pub struct RedbStorage {
 impl RedbStorage {
     pub fn new(db_path: &Path) -> Result<Self, Error> {
         let db = Database::create(db_path)?;
-        Ok(Self { db: Arc::new(db) })
+        let storage = Self { db: Arc::new(db) };
+        storage.init_stats()?;
+        Ok(storage)
+    }
+
+    /// Compute stats of a database created before they were kept, this is only done once
+    fn init_stats(&self) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        if write_txn.open_table(STATS_TABLE)?.is_empty()? {
+            let mut stats = Stats::default();
+            for v in write_txn.open_table(CHUNK_TABLE)?.iter()? {
+                stats.add_chunk(v?.1.value().len() as u64);
+            }
+            stats.links = write_txn.open_table(LINK_TABLE)?.len()?;
+            Self::update_stats(&write_txn, |s| *s = stats)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Update stats within `write_txn`
+    fn update_stats(write_txn: &WriteTransaction, f: impl FnOnce(&mut Stats)) -> Result<(), Error> {
+        let mut table = write_txn.open_table(STATS_TABLE)?;
+        let (chunks, links, chunk_bytes) = table
+            .get(STATS_KEY)?
+            .map(|guard| guard.value())
+            .unwrap_or_default();
+        let mut stats = Stats {
+            chunks,
+            links,
+            chunk_bytes,
+        };
+        f(&mut stats);
+        table.insert(STATS_KEY, (stats.chunks, stats.links, stats.chunk_bytes))?;
+        Ok(())
     }
 
     fn get_stored_node(&self, read_txn: &ReadTransaction, hash: &Hash) -> Option<Node> {
@@ -73,7 +113,13 @@ impl ChunkStorage for RedbStorage {
         let write_txn = self.db.begin_write().ok()?;
         {
             let mut table = write_txn.open_table(CHUNK_TABLE).ok()?;
-            table.insert(hash.as_bytes(), Vec::from(chunk)).ok()?;
+            let new = table
+                .insert(hash.as_bytes(), Vec::from(chunk))
+                .ok()?
+                .is_none();
+            if new {
+                Self::update_stats(&write_txn, |s| s.add_chunk(chunk.len() as u64)).ok()?;
+            }
         }
         write_txn.commit().ok()?;
         Some(Arc::new(Node::Stored {
@@ -87,12 +133,16 @@ impl ChunkStorage for RedbStorage {
         let write_txn = self.db.begin_write().ok()?;
         {
             let mut table = write_txn.open_table(LINK_TABLE).ok()?;
-            table
+            let new = table
                 .insert(
                     hash.as_bytes(),
                     (*left.hash().as_bytes(), *right.hash().as_bytes()),
                 )
-                .ok()?;
+                .ok()?
+                .is_none();
+            if new {
+                Self::update_stats(&write_txn, |s| s.links += 1).ok()?;
+            }
         }
         write_txn.commit().ok()?;
         Some(Arc::new(Node::Parent {
@@ -116,22 +166,65 @@ impl ChunkStorage for RedbStorage {
             .collect()
     }
 
-    fn size(&self) -> u64 {
-        fn get_size(redb_storage: &RedbStorage) -> Option<u64> {
-            Some(
-                redb_storage
-                    .db
-                    .begin_read()
-                    .ok()?
-                    .open_table(CHUNK_TABLE)
-                    .ok()?
-                    .iter()
-                    .ok()?
-                    .map(|v| v.unwrap().1.value().len() as u64)
-                    .sum(),
-            )
+    fn nodes(&self) -> Vec<Hash> {
+        fn get_links(redb_storage: &RedbStorage) -> Option<Vec<Hash>> {
+            redb_storage
+                .db
+                .begin_read()
+                .ok()?
+                .open_table(LINK_TABLE)
+                .ok()?
+                .iter()
+                .ok()?
+                .map(|v| v.ok().map(|v| Hash::from_bytes(*v.0.value())))
+                .collect()
         }
-        get_size(self).unwrap_or(0)
+        let mut nodes = self.chunks();
+        nodes.extend(get_links(self).unwrap_or_default());
+        nodes
+    }
+
+    fn remove_node(&mut self, hash: &Hash) -> Option<u64> {
+        let write_txn = self.db.begin_write().ok()?;
+        let removed = {
+            let mut chunks = write_txn.open_table(CHUNK_TABLE).ok()?;
+            let mut links = write_txn.open_table(LINK_TABLE).ok()?;
+            let chunk = chunks
+                .remove(hash.as_bytes())
+                .ok()?
+                .map(|guard| guard.value().len() as u64);
+            let link = links.remove(hash.as_bytes()).ok()?.map(|_| 0);
+            match (chunk, link) {
+                (Some(size), _) => Self::update_stats(&write_txn, |s| s.remove_chunk(size)).ok()?,
+                (None, Some(_)) => {
+                    Self::update_stats(&write_txn, |s| s.links = s.links.saturating_sub(1)).ok()?;
+                }
+                (None, None) => {}
+            }
+            chunk.or(link)
+        };
+        write_txn.commit().ok()?;
+        removed
+    }
+
+    fn stats(&self) -> Stats {
+        fn get_stats(redb_storage: &RedbStorage) -> Option<Stats> {
+            let (chunks, links, chunk_bytes) = redb_storage
+                .db
+                .begin_read()
+                .ok()?
+                .open_table(STATS_TABLE)
+                .ok()?
+                .get(STATS_KEY)
+                .ok()??
+                .value();
+            Some(Stats {
+                chunks,
+                links,
+                chunk_bytes,
+            })
+        }
+        get_stats(self).unwrap_or_default()
     }
 }
 
@@ -142,6 +235,10 @@ impl HashTreeCapable<Arc<Node>, crate::error::Error> for RedbStorage {
             .ok_or(StorageError::ChunkInsertError)?)
     }
 
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<Arc<Node>>, crate::error::Error> {
+        self.insert_chunks(leaves)
+    }
+
     fn merge(&mut self, l: &Arc<Node>, r: &Arc<Node>) -> Result<Arc<Node>, crate::error::Error> {
         Ok(self
             .link(l.clone(), r.clone())
@@ -163,4 +260,16 @@ mod tests {
     }
 
     crate::chunk_storage::tests::chunk_storage_tests!(RedbStorage, make_redb_storage);
+
+    #[test]
+    fn redb_stats_persistance() {
+        let p = SelfDeletingPath::new(random_path());
+        let mut storage = RedbStorage::new(&p).unwrap();
+        storage.insert(vec![1u8; 5000].into()).unwrap();
+        let stats = storage.stats();
+        assert_eq!(stats.chunk_bytes, 5000);
+        drop(storage);
+
+        assert_eq!(RedbStorage::new(&p).unwrap().stats(), stats);
+    }
 }
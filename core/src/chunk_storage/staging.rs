@@ -0,0 +1,215 @@
+//! Trees received a piece at a time, stored as they come so that storage only needs to be locked for each piece
+//!
+//! Nodes stored but not linked by a parent yet form the frontier of the tree being received: garbage collections
+//! running meanwhile must keep it, along with everything it links (see `Staging::frontier`). Once the whole tree is
+//! received its root is all that's left in the frontier.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::error::Error;
+use crate::hash::{chunk_hash, chunk_hashes, merge_hashes, Hash, HashTreeCapable};
+use crate::item::Item;
+use crate::metadata::Item as ItemMetadata;
+
+use super::{check_root, ChunkStorage, Node, StorageError};
+
+/// A tree being received, either computed from content with `TreeBuilder` or made of nodes sent by someone else
+#[derive(Debug, Default)]
+pub struct Staging {
+    /// Nodes computed but not stored yet, children before their parents
+    queue: Vec<Queued>,
+
+    /// Nodes stored but not linked by a parent yet, with how many times they are in the tree
+    frontier: HashMap<Hash, (Arc<Node>, usize)>,
+
+    /// Last node stored, the root once the whole tree is received
+    last: Option<Arc<Node>>,
+}
+
+#[derive(Debug)]
+enum Queued {
+    Chunk(Hash, Vec<u8>),
+    Link(Hash, Hash, Hash),
+}
+
+/// Trees are computed on hashes, chunks and links being queued until `Staging::commit`
+impl HashTreeCapable<Hash, Error> for Staging {
+    fn func(&mut self, data: &[u8]) -> Result<Hash, Error> {
+        let hash = chunk_hash(data);
+        self.queue.push(Queued::Chunk(hash, data.to_vec()));
+        Ok(hash)
+    }
+
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<Hash>, Error> {
+        let hashes = chunk_hashes(leaves);
+        self.queue.extend(
+            leaves
+                .iter()
+                .zip(&hashes)
+                .map(|(leaf, hash)| Queued::Chunk(*hash, leaf.to_vec())),
+        );
+        Ok(hashes)
+    }
+
+    fn merge(&mut self, l: &Hash, r: &Hash) -> Result<Hash, Error> {
+        let hash = merge_hashes(l, r);
+        self.queue.push(Queued::Link(hash, *l, *r));
+        Ok(hash)
+    }
+}
+
+impl Staging {
+    /// Store the chunks and links computed so far into `storage`
+    pub fn commit<S>(&mut self, storage: &mut S) -> Result<(), StorageError>
+    where
+        S: ChunkStorage + ?Sized,
+    {
+        for queued in std::mem::take(&mut self.queue) {
+            let node = match queued {
+                Queued::Chunk(hash, data) => storage
+                    .store_chunk(hash, &data)
+                    .ok_or(StorageError::ChunkInsertError)?,
+                Queued::Link(hash, left, right) => {
+                    let left = self.take(&left).ok_or(StorageError::MissingNode(left))?;
+                    let right = self.take(&right).ok_or(StorageError::MissingNode(right))?;
+                    storage
+                        .store_link(hash, left, right)
+                        .ok_or(StorageError::LinkCreation)?
+                }
+            };
+            self.add(node);
+        }
+        Ok(())
+    }
+
+    /// Verify a received node and store it into `storage`, see `ChunkStorage::try_fill_in`
+    ///
+    /// Nodes are expected children first, as sent by `Node::find_diff`: skipped children of a parent may be nodes
+    /// received earlier, or nodes already held.
+    pub fn receive<S>(&mut self, storage: &mut S, node: &Node) -> Result<(), StorageError>
+    where
+        S: ChunkStorage + ?Sized,
+    {
+        let stored = storage.try_fill_in(node)?;
+        if let Node::Parent { left, right, .. } = node {
+            self.take(left.hash());
+            self.take(right.hash());
+        }
+        self.add(stored);
+        Ok(())
+    }
+
+    /// Hashes of the nodes stored but not linked by a parent yet
+    pub fn frontier(&self) -> impl Iterator<Item = &Hash> {
+        self.frontier.keys()
+    }
+
+    /// Root of the tree, the last node stored
+    #[must_use]
+    pub fn root(&self) -> Option<Arc<Node>> {
+        self.last.clone()
+    }
+
+    /// Item with the received tree, which must have the root in `target`
+    pub fn into_item(self, target: ItemMetadata) -> Result<Item, StorageError> {
+        let root = self.last.ok_or(StorageError::TreeReconstruct)?;
+        tracing::trace!("Received tree with {} bytes total", root.size());
+        check_root(&target, &root)?;
+
+        let mut item = Item::new(
+            target.name,
+            target.path,
+            target.revision,
+            target.description,
+            target.chunking,
+            &root,
+        );
+        item.metadata.attributes = target.attributes;
+        item.metadata.kind = target.kind;
+        Ok(item)
+    }
+
+    fn add(&mut self, node: Arc<Node>) {
+        self.frontier
+            .entry(*node.hash())
+            .or_insert_with(|| (node.clone(), 0))
+            .1 += 1;
+        self.last = Some(node);
+    }
+
+    /// Take a node out of the frontier, as it's linked by a parent now
+    fn take(&mut self, hash: &Hash) -> Option<Arc<Node>> {
+        let (node, count) = self.frontier.get_mut(hash)?;
+        let node = node.clone();
+        *count -= 1;
+        if *count == 0 {
+            self.frontier.remove(hash);
+        }
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::Staging;
+    use crate::chunk_storage::{gc, hashmap_storage::HashMapStorage, ChunkStorage};
+    use crate::chunker::{Chunking, FixedSize};
+    use crate::chunks::CHUNK_SIZE;
+    use crate::hash::{Hash, TreeBuilder};
+    use crate::item::Item;
+
+    #[test]
+    fn staged_insertion() {
+        let mut data = vec![0u8; 20_000];
+        StdRng::seed_from_u64(3).fill_bytes(&mut data);
+        let chunking = Chunking::Fixed(FixedSize { size: 1024 });
+        let expected = HashMapStorage::default()
+            .insert_with(Bytes::from(data.clone()), &chunking)
+            .unwrap();
+
+        let mut storage = HashMapStorage::default();
+        let mut staging = Staging::default();
+        let mut builder = TreeBuilder::new(&mut staging, &chunking);
+        for piece in data.chunks(3000) {
+            builder.update(piece).unwrap();
+            builder.hasher().commit(&mut storage).unwrap();
+
+            // Whatever was stored so far is kept by a garbage collection through the frontier
+            let frontier: Vec<Hash> = builder.hasher().frontier().copied().collect();
+            let report = gc::collect(&mut storage, &frontier, false);
+            assert_eq!(report.nodes, 0);
+        }
+        let root = builder.finish().unwrap();
+        staging.commit(&mut storage).unwrap();
+
+        assert_eq!(&root, expected.hash());
+        assert_eq!(staging.frontier().collect::<Vec<_>>(), vec![&root]);
+        assert_eq!(staging.root().unwrap().clone_data(), data);
+        assert_eq!(storage.size(), expected.size());
+    }
+
+    #[test]
+    fn staged_reception() {
+        let mut source = HashMapStorage::default();
+        let mut data = vec![0u8; CHUNK_SIZE * 3 + 100];
+        StdRng::seed_from_u64(5).fill_bytes(&mut data);
+        let data = Bytes::from(data);
+        let root = source
+            .insert_with(data.clone(), &Chunking::default())
+            .unwrap();
+        let target = Item::new("x".into(), "x".into(), 0, None, Chunking::default(), &root);
+
+        let mut storage = HashMapStorage::default();
+        let mut staging = Staging::default();
+        for node in root.clone().find_diff(&[]) {
+            staging.receive(&mut storage, &node).unwrap();
+        }
+        assert_eq!(staging.frontier().collect::<Vec<_>>(), vec![root.hash()]);
+        let item = staging.into_item(target.metadata).unwrap();
+        assert_eq!(item.root(), root.hash());
+        assert_eq!(storage.get(root.hash()).unwrap().clone_data(), data);
+    }
+}
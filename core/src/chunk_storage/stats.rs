@@ -0,0 +1,174 @@
+//! Storage statistics
+//!
+//! `Stats` are maintained by every `ChunkStorage` backend as nodes come and go, while `Usage` is computed from items,
+//! telling how much deduplication saves across them.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::ChunkInfo;
+use crate::item::Item;
+
+/// Node counts and unique chunk bytes in a storage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Number of stored chunks
+    pub chunks: u64,
+
+    /// Number of links (parent nodes)
+    pub links: u64,
+
+    /// Bytes of unique chunks, excluding any auxiliary structure used by the backend
+    pub chunk_bytes: u64,
+}
+
+impl Stats {
+    /// Account for a newly stored chunk of `size` bytes
+    pub fn add_chunk(&mut self, size: u64) {
+        self.chunks += 1;
+        self.chunk_bytes += size;
+    }
+
+    /// Account for a removed chunk of `size` bytes
+    pub fn remove_chunk(&mut self, size: u64) {
+        self.chunks = self.chunks.saturating_sub(1);
+        self.chunk_bytes = self.chunk_bytes.saturating_sub(size);
+    }
+
+    /// Total number of nodes
+    #[must_use]
+    pub fn nodes(&self) -> u64 {
+        self.chunks + self.links
+    }
+}
+
+/// Logical and unique bytes of a set of items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemUsage {
+    /// Bytes the items take once installed
+    pub logical_bytes: u64,
+
+    /// Bytes of the unique chunks making the items
+    pub unique_bytes: u64,
+}
+
+impl ItemUsage {
+    /// Logical bytes per unique byte, 1 when nothing is deduplicated (or there's no data at all)
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.unique_bytes == 0 {
+            1.0
+        } else {
+            self.logical_bytes as f64 / self.unique_bytes as f64
+        }
+    }
+}
+
+/// Usage of a set of items, overall and for each path
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(flatten)]
+    pub total: ItemUsage,
+
+    /// Deduplication ratio of all the items together
+    pub dedup_ratio: f64,
+
+    /// Usage by item path, every revision at a path counting towards it
+    pub items: BTreeMap<PathBuf, ItemUsage>,
+}
+
+impl Usage {
+    /// Compute the usage of `items`
+    pub fn of<'a, I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Item>,
+    {
+        let mut chunks = HashSet::<ChunkInfo>::new();
+        let mut by_path = BTreeMap::<PathBuf, (u64, HashSet<ChunkInfo>)>::new();
+        for item in items {
+            let (logical, path_chunks) = by_path.entry(item.metadata.path.clone()).or_default();
+            *logical += item.size();
+            path_chunks.extend(item.chunks.iter().copied());
+            chunks.extend(item.chunks.iter().copied());
+        }
+
+        let unique_bytes = |chunks: &HashSet<ChunkInfo>| chunks.iter().map(|c| c.size).sum();
+        let items: BTreeMap<PathBuf, ItemUsage> = by_path
+            .into_iter()
+            .map(|(path, (logical_bytes, chunks))| {
+                (
+                    path,
+                    ItemUsage {
+                        logical_bytes,
+                        unique_bytes: unique_bytes(&chunks),
+                    },
+                )
+            })
+            .collect();
+        let total = ItemUsage {
+            logical_bytes: items.values().map(|u| u.logical_bytes).sum(),
+            unique_bytes: unique_bytes(&chunks),
+        };
+
+        Self {
+            total,
+            dedup_ratio: total.dedup_ratio(),
+            items,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{
+        chunk_storage::{hashmap_storage::HashMapStorage, ChunkStorage},
+        chunker::Chunking,
+        chunks::CHUNK_SIZE,
+    };
+
+    use super::*;
+
+    #[test]
+    fn items_usage() {
+        let mut storage = HashMapStorage::default();
+        let mut create = |path: &str, revision, data: Vec<u8>| {
+            storage
+                .create_item(
+                    path.into(),
+                    path.into(),
+                    revision,
+                    None,
+                    Bytes::from(data),
+                    Chunking::default(),
+                )
+                .unwrap()
+        };
+        let mut data = vec![0u8; CHUNK_SIZE * 2];
+        let a0 = create("a", 0, data.clone());
+        data[0] = 1;
+        let a1 = create("a", 1, data.clone());
+        let b0 = create("b", 0, data);
+
+        let usage = Usage::of([&a0, &a1, &b0]);
+        let size = CHUNK_SIZE as u64;
+        // Zeros chunk, and the one starting with 1
+        assert_eq!(usage.total.unique_bytes, 2 * size);
+        assert_eq!(usage.total.logical_bytes, 6 * size);
+        assert!((usage.dedup_ratio - 3.0).abs() < f64::EPSILON);
+        assert_eq!(
+            usage.items[&PathBuf::from("a")],
+            ItemUsage {
+                logical_bytes: 4 * size,
+                unique_bytes: 2 * size
+            }
+        );
+        assert_eq!(usage.items[&PathBuf::from("b")].unique_bytes, 2 * size);
+
+        assert!((Usage::of([]).dedup_ratio - 1.0).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,416 @@
+//! Chunking strategies, used to split data into the leaves of an hash-tree
+//!
+//! Fixed-size chunking is cheap, but inserting or removing a single byte shifts every following chunk boundary,
+//! making deduplication useless for most real-world updates.
+//! Content-defined chunking (`FastCDC`) places boundaries according to the data itself, so that boundaries
+//! re-synchronize shortly after any local modification.
+//!
+//! The `Chunking` enum is the serializable description of a chunker and its parameters, it is stored along with
+//! each item so that anyone can reproduce the same hash-tree.
+
+use rayon::{current_num_threads, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::CHUNK_SIZE;
+use crate::error::InvalidParameter;
+
+/// Largest chunk size accepted for items made by others, see `Chunking::check`
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Something able to split data into chunks
+pub trait Chunker {
+    /// Maximum size in bytes of a single chunk
+    fn max_size(&self) -> usize;
+
+    /// Minimum size in bytes of every chunk but the last one
+    fn min_size(&self) -> usize;
+
+    /// Length in bytes of the first chunk of `data`
+    ///
+    /// `data` is expected to be either at least `max_size()` bytes long or the trailing part of the input,
+    /// a shorter `data` is always considered to be the last chunk.
+    fn cut(&self, data: &[u8]) -> usize;
+
+    /// Iterate over the chunks of `data`
+    fn chunks<'a>(&'a self, data: &'a [u8]) -> Chunks<'a, Self>
+    where
+        Self: Sized,
+    {
+        Chunks {
+            chunker: self,
+            data,
+        }
+    }
+
+    /// Same chunks as `chunks`, with boundaries searched on every thread of the `rayon` pool
+    ///
+    /// Data is split into segments searched in parallel, each from its own start. Boundaries found in a segment are
+    /// the actual ones from the first one they have in common, which for content-defined chunking comes within a
+    /// few chunks: segments are stitched together searching again from the actual boundary up to that one.
+    fn par_chunks<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]>
+    where
+        Self: Sized + Sync,
+    {
+        let cut = |pos: usize| pos + self.cut(&data[pos..]).clamp(1, data.len() - pos);
+        let segment = (self.max_size().max(1) * 4).max(data.len().div_ceil(current_num_threads()));
+
+        // Ends of the chunks found from the start of each segment, up to the start of the next one
+        let found: Vec<Vec<usize>> = (0..data.len().div_ceil(segment))
+            .into_par_iter()
+            .map(|k| {
+                let end = ((k + 1) * segment).min(data.len());
+                let mut ends = vec![];
+                let mut pos = k * segment;
+                while pos < end {
+                    pos = cut(pos);
+                    ends.push(pos);
+                }
+                ends
+            })
+            .collect();
+
+        let mut ends = vec![];
+        let mut pos = 0;
+        for (k, found) in found.iter().enumerate() {
+            let end = ((k + 1) * segment).min(data.len());
+            while pos < end {
+                let synced = if pos == k * segment {
+                    Some(0)
+                } else {
+                    found.binary_search(&pos).ok().map(|i| i + 1)
+                };
+                if let Some(i) = synced {
+                    ends.extend_from_slice(&found[i..]);
+                    pos = ends.last().copied().unwrap_or(pos);
+                    break;
+                }
+                pos = cut(pos);
+                ends.push(pos);
+            }
+        }
+
+        let mut start = 0;
+        ends.into_iter()
+            .map(|end| {
+                let chunk = &data[start..end];
+                start = end;
+                chunk
+            })
+            .collect()
+    }
+}
+
+/// Iterator over chunks of some data, as returned by `Chunker::chunks`
+pub struct Chunks<'a, C> {
+    chunker: &'a C,
+    data: &'a [u8],
+}
+
+impl<'a, C> Iterator for Chunks<'a, C>
+where
+    C: Chunker,
+{
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        // Always make progress, even with a misbehaving chunker
+        let len = self.chunker.cut(self.data).clamp(1, self.data.len());
+        let (chunk, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(chunk)
+    }
+}
+
+/// Fixed-size chunking, every chunk but the last one is exactly `size` bytes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct FixedSize {
+    pub size: u32,
+}
+
+impl Default for FixedSize {
+    #[allow(clippy::cast_possible_truncation)]
+    fn default() -> Self {
+        Self {
+            size: CHUNK_SIZE as u32,
+        }
+    }
+}
+
+impl Chunker for FixedSize {
+    fn max_size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn min_size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        data.len().min(self.max_size())
+    }
+}
+
+/// Gear table used by the rolling hash, 256 pseudo-random values generated with splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Content-defined chunking using the `FastCDC` gear-based rolling hash, with normalized chunking
+///
+/// Chunks are at least `min_size` and at most `max_size` bytes, with sizes distributed around `avg_size`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct FastCdc {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for FastCdc {
+    #[allow(clippy::cast_possible_truncation)]
+    fn default() -> Self {
+        Self {
+            min_size: (CHUNK_SIZE / 4) as u32,
+            avg_size: CHUNK_SIZE as u32,
+            max_size: (CHUNK_SIZE * 4) as u32,
+        }
+    }
+}
+
+impl FastCdc {
+    /// Mask with the `bits` most significant bits set
+    ///
+    /// With the gear hash higher bits depend on a larger window of preceding bytes, so they're the ones we check
+    const fn mask(bits: u32) -> u64 {
+        if bits == 0 {
+            0
+        } else {
+            u64::MAX << (64 - bits)
+        }
+    }
+}
+
+impl Chunker for FastCdc {
+    fn max_size(&self) -> usize {
+        self.max_size as usize
+    }
+
+    fn min_size(&self) -> usize {
+        self.min_size.min(self.max_size) as usize
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        let min = self.min_size as usize;
+        let max = data.len().min(self.max_size as usize);
+        if max <= min {
+            return max;
+        }
+        let normal = max.min(self.avg_size as usize);
+
+        // Normalized chunking: harder to cut before the average size, easier after
+        let bits = self.avg_size.max(2).ilog2();
+        let mask_small = Self::mask((bits + 1).min(64));
+        let mask_large = Self::mask(bits - 1);
+
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(max).skip(min) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < normal { mask_small } else { mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        max
+    }
+}
+
+/// Serializable description of the chunker used to compute an hash-tree, along with its parameters
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Chunking {
+    Fixed(FixedSize),
+    FastCdc(FastCdc),
+}
+
+impl Default for Chunking {
+    /// Fixed-size chunking with `CHUNK_SIZE`, the same used by `hash::hash`
+    fn default() -> Self {
+        Self::Fixed(FixedSize::default())
+    }
+}
+
+impl Chunking {
+    /// Check that parameters are within sane limits, as chunks are held in memory whole
+    ///
+    /// Chunks must be between 1 byte and `MAX_CHUNK_SIZE`, and `FastCDC` sizes in order.
+    pub fn check(&self) -> Result<(), InvalidParameter> {
+        let sane = match self {
+            Self::Fixed(c) => (1..=MAX_CHUNK_SIZE).contains(&c.size),
+            Self::FastCdc(c) => {
+                c.min_size <= c.avg_size
+                    && c.avg_size <= c.max_size
+                    && (1..=MAX_CHUNK_SIZE).contains(&c.max_size)
+            }
+        };
+        if sane {
+            Ok(())
+        } else {
+            Err(InvalidParameter::Generic {
+                expected: format!("chunks of 1 to {MAX_CHUNK_SIZE} bytes"),
+                got: format!("{self:?}"),
+            })
+        }
+    }
+}
+
+impl Chunker for Chunking {
+    fn max_size(&self) -> usize {
+        match self {
+            Self::Fixed(c) => c.max_size(),
+            Self::FastCdc(c) => c.max_size(),
+        }
+    }
+
+    fn min_size(&self) -> usize {
+        match self {
+            Self::Fixed(c) => c.min_size(),
+            Self::FastCdc(c) => c.min_size(),
+        }
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        match self {
+            Self::Fixed(c) => c.cut(data),
+            Self::FastCdc(c) => c.cut(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use crate::hash::hash;
+
+    use super::*;
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(42).fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn fixed_size_chunks() {
+        let data = random_data(CHUNK_SIZE * 3 + 17);
+        let chunker = FixedSize::default();
+        let chunks: Vec<&[u8]> = chunker.chunks(&data).collect();
+        let expected: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn empty_data_has_no_chunks() {
+        assert_eq!(FixedSize::default().chunks(&[]).count(), 0);
+        assert_eq!(FastCdc::default().chunks(&[]).count(), 0);
+    }
+
+    #[test]
+    fn fastcdc_bounds() {
+        let data = random_data(CHUNK_SIZE * 20);
+        let chunker = FastCdc::default();
+        let chunks: Vec<&[u8]> = chunker.chunks(&data).collect();
+
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+        // all but the last chunk respect the bounds
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= chunker.min_size as usize);
+            assert!(chunk.len() <= chunker.max_size as usize);
+        }
+        // the rolling hash actually cuts somewhere, instead of always reaching max_size
+        assert!(chunks.iter().any(|c| c.len() < chunker.max_size as usize));
+    }
+
+    #[test]
+    fn sane_chunking() {
+        assert!(Chunking::default().check().is_ok());
+        assert!(Chunking::FastCdc(FastCdc::default()).check().is_ok());
+        assert!(Chunking::Fixed(FixedSize { size: 0 }).check().is_err());
+        assert!(Chunking::Fixed(FixedSize { size: u32::MAX })
+            .check()
+            .is_err());
+        let unordered = FastCdc {
+            min_size: 2048,
+            avg_size: 1024,
+            max_size: 4096,
+        };
+        assert!(Chunking::FastCdc(unordered).check().is_err());
+    }
+
+    #[test]
+    fn fastcdc_is_deterministic() {
+        let data = random_data(CHUNK_SIZE * 8);
+        let chunker = Chunking::FastCdc(FastCdc::default());
+        let a: Vec<&[u8]> = chunker.chunks(&data).collect();
+        let b: Vec<&[u8]> = chunker.chunks(&data).collect();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn fastcdc_resynchronizes_after_insertion() {
+        let data = random_data(CHUNK_SIZE * 20);
+        let mut shifted = data.clone();
+        shifted.insert(1000, 0xAA);
+
+        let chunker = FastCdc::default();
+        let original: HashSet<_> = chunker.chunks(&data).map(hash).collect();
+        let modified: Vec<_> = chunker.chunks(&shifted).map(hash).collect();
+
+        let shared = modified.iter().filter(|h| original.contains(h)).count();
+        // only the chunks around the insertion point are expected to change
+        assert!(shared + 2 >= modified.len());
+
+        // while with fixed-size chunking nothing is shared anymore
+        let fixed = FixedSize::default();
+        let original: HashSet<_> = fixed.chunks(&data).map(hash).collect();
+        assert!(!fixed
+            .chunks(&shifted)
+            .map(hash)
+            .any(|h| original.contains(&h)));
+    }
+
+    #[test]
+    fn parallel_chunks() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let fastcdc = Chunking::FastCdc(FastCdc {
+            min_size: 256,
+            avg_size: 1024,
+            max_size: 4096,
+        });
+        let fixed = Chunking::Fixed(FixedSize { size: 1000 });
+        for len in [0, 1, 255, 5000, 200_000] {
+            let data = random_data(len);
+            for chunker in [&fastcdc, &fixed] {
+                let expected: Vec<&[u8]> = chunker.chunks(&data).collect();
+                assert_eq!(pool.install(|| chunker.par_chunks(&data)), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Directory items, whole trees of files, directories and symbolic links
+//!
+//! The content of a directory item is the content of its files one after the other, in the order of its manifest.
+//! Each file is chunked on its own and its hash tree is a subtree of the item one, the trees of files being merged
+//! pairwise up to the item root: files with the same content are stored once, and unchanged files are subtrees
+//! already held from previous revisions.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attributes::Attributes,
+    chunk_storage::{Node, StorageError},
+    chunker::Chunking,
+    chunks::ChunkInfo,
+    error::Error,
+    hash::{hash_roots, HashTreeCapable, TreeBuilder},
+};
+
+/// Entries of a directory item, parents before their children
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+/// A file, directory or symbolic link in a directory item
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path relative to the item path
+    pub path: PathBuf,
+
+    pub kind: EntryKind,
+
+    /// Attributes of files and directories, symbolic links have none
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// A regular file, with the root and size of its hash tree
+    File {
+        root: ChunkInfo,
+    },
+
+    Directory,
+
+    /// A symbolic link pointing to `target`, which is not checked in any way
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+impl Manifest {
+    /// Relative paths and hash trees of the files, in the order their content has in the item
+    pub fn files(&self) -> impl Iterator<Item = (&Path, &ChunkInfo, &Attributes)> {
+        self.entries.iter().filter_map(|entry| match &entry.kind {
+            EntryKind::File { root } => Some((entry.path.as_path(), root, &entry.attributes)),
+            _ => None,
+        })
+    }
+
+    /// Total size of the files
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.files().map(|(_, root, _)| root.size).sum()
+    }
+
+    /// Root hash of the item, the one of the trees of its files merged pairwise
+    #[must_use]
+    pub fn root_hash(&self) -> crate::hash::Hash {
+        hash_roots(self.files().map(|(_, root, _)| root.hash).collect())
+    }
+
+    /// Check that the manifest describes an item with `root`, and that it can be laid out safely
+    ///
+    /// Paths must be relative and go down from the item path only, each one listed once and after its parent
+    /// directory, so that nothing is ever written outside the item or through a symbolic link.
+    pub fn check(&self, root: &ChunkInfo) -> Result<(), StorageError> {
+        let mut directories = HashSet::from([Path::new("")]);
+        let mut seen = HashSet::new();
+        for entry in &self.entries {
+            let invalid = || StorageError::InvalidEntry(entry.path.clone());
+            let normal = entry
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            let parent = entry.path.parent().ok_or_else(invalid)?;
+            if !normal || !directories.contains(parent) || !seen.insert(entry.path.as_path()) {
+                return Err(invalid());
+            }
+            if entry.kind == EntryKind::Directory {
+                directories.insert(entry.path.as_path());
+            }
+        }
+
+        let got = self.root_hash();
+        if got != root.hash {
+            return Err(StorageError::RootMismatch {
+                expected: root.hash,
+                got,
+            });
+        }
+        if self.size() != root.size {
+            return Err(StorageError::SizeMismatch(root.hash));
+        }
+        Ok(())
+    }
+
+    /// Insert the content of the directory at `dir` into `storage`, each file split with `chunking`
+    ///
+    /// Returns the manifest of the directory along with the root of the item tree. Attributes of entries are
+    /// captured along the way, the ones of `dir` itself are up to the item. Any hasher works in place of a storage,
+    /// an `Outline` only computes the tree.
+    pub fn insert<S>(
+        storage: &mut S,
+        dir: &Path,
+        chunking: &Chunking,
+    ) -> Result<(Self, Arc<Node>), Error>
+    where
+        S: HashTreeCapable<Arc<Node>, Error>,
+    {
+        let mut manifest = Self::default();
+        let mut roots = vec![];
+        manifest.insert_dir(storage, dir, Path::new(""), chunking, &mut roots)?;
+        let root = if roots.is_empty() {
+            storage.func(&[])?
+        } else {
+            storage.merge_all(roots)?
+        };
+        Ok((manifest, root))
+    }
+
+    /// Add the entries in `dir`, at `prefix` in the item, sorted by name
+    fn insert_dir<S>(
+        &mut self,
+        storage: &mut S,
+        dir: &Path,
+        prefix: &Path,
+        chunking: &Chunking,
+        roots: &mut Vec<Arc<Node>>,
+    ) -> Result<(), Error>
+    where
+        S: HashTreeCapable<Arc<Node>, Error>,
+    {
+        let mut children = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        children.sort();
+
+        for name in children {
+            let full = dir.join(&name);
+            let path = prefix.join(&name);
+            let file_type = fs::symlink_metadata(&full)?.file_type();
+            if file_type.is_symlink() {
+                self.entries.push(Entry {
+                    path,
+                    kind: EntryKind::Symlink {
+                        target: fs::read_link(&full)?,
+                    },
+                    attributes: Attributes::default(),
+                });
+            } else if file_type.is_dir() {
+                self.entries.push(Entry {
+                    path: path.clone(),
+                    kind: EntryKind::Directory,
+                    attributes: Attributes::capture(&full)?,
+                });
+                self.insert_dir(storage, &full, &path, chunking, roots)?;
+            } else if file_type.is_file() {
+                let mut builder = TreeBuilder::new(storage, chunking);
+                builder.read_from(fs::File::open(&full)?)?;
+                let root = builder.finish()?;
+                self.entries.push(Entry {
+                    path,
+                    kind: EntryKind::File {
+                        root: root.chunk_info(),
+                    },
+                    attributes: Attributes::capture(&full)?,
+                });
+                roots.push(root);
+            } else {
+                tracing::warn!(
+                    "Skipping '{}', not a file, directory or link",
+                    full.to_string_lossy()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{Entry, EntryKind, Manifest};
+    use crate::attributes::Attributes;
+    use crate::chunk_storage::{hashmap_storage::HashMapStorage, ChunkStorage};
+    use crate::chunker::{Chunking, FixedSize};
+    use crate::utils::testing::temp_path;
+
+    #[test]
+    fn directory_manifest() {
+        let dir = temp_path();
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("bin/app"), vec![1u8; 3000]).unwrap();
+        fs::write(dir.join("a"), vec![1u8; 3000]).unwrap();
+        fs::write(dir.join("z"), b"").unwrap();
+        std::os::unix::fs::symlink("bin/app", dir.join("link")).unwrap();
+
+        let mut storage = HashMapStorage::default();
+        let chunking = Chunking::Fixed(FixedSize { size: 1024 });
+        let (manifest, root) = Manifest::insert(&mut storage, &dir, &chunking).unwrap();
+        let paths: Vec<_> = manifest.entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(
+            paths,
+            ["a", "bin", "bin/app", "empty", "link", "z"].map(PathBuf::from)
+        );
+        assert_eq!(
+            manifest.entries[4].kind,
+            EntryKind::Symlink {
+                target: "bin/app".into()
+            }
+        );
+
+        // The item is made of its files, each a subtree of it
+        assert_eq!(root.size(), 6000);
+        assert_eq!(manifest.root_hash(), *root.hash());
+        let files: Vec<_> = manifest.files().map(|(path, _, _)| path).collect();
+        assert_eq!(files, ["a", "bin/app", "z"].map(Path::new));
+        let (_, app, _) = manifest.files().nth(1).unwrap();
+        assert!(root.all_hashes().contains(&app.hash));
+        assert!(storage.get(&app.hash).is_some());
+        assert!(manifest.check(&root.chunk_info()).is_ok());
+
+        // Entries must stay within the item, and not go through links
+        let with = |path: &str| {
+            let mut manifest = manifest.clone();
+            manifest.entries.push(Entry {
+                path: path.into(),
+                kind: EntryKind::Directory,
+                attributes: Attributes::default(),
+            });
+            manifest.check(&root.chunk_info())
+        };
+        assert!(with("other").is_ok());
+        for bad in ["../escape", "/abs", "link/x", "missing/x", "bin", ""] {
+            assert!(with(bad).is_err(), "{bad}");
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
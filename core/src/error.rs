@@ -3,7 +3,7 @@ use std::str::Utf8Error;
 use thiserror::Error;
 use tonic::metadata::errors::{InvalidMetadataValue, InvalidMetadataValueBytes};
 
-use crate::{chunk_storage::StorageError, GrpcError, TransportError};
+use crate::{chunk_storage::StorageError, peer::PeerError, GrpcError, TransportError};
 
 /// Generic `distd_core` error
 #[derive(Error, Debug)]
@@ -25,6 +25,9 @@ pub enum Error {
 
     #[error("Storage error: '{0}'")]
     Storage(#[from] StorageError),
+
+    #[error("Peer error: '{0}'")]
+    Peer(#[from] PeerError),
 }
 
 /// Invalid parameter error
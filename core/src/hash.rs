@@ -1,6 +1,11 @@
 use std::convert::Infallible;
+use std::io::{self, Read};
+use std::marker::PhantomData;
 
-use crate::chunks::CHUNK_SIZE;
+use rayon::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::chunker::{Chunker, Chunking};
 
 #[must_use]
 #[inline]
@@ -16,6 +21,15 @@ pub fn merge_hashes(left: &blake3_hash::Hash, right: &blake3_hash::Hash) -> blak
     .into()
 }
 
+/// Hash of a single chunk, i.e. of a leaf of the hash-tree
+///
+/// Chunks may be larger than `CHUNK_SIZE` (e.g. with content-defined chunking), they're still hashed as a whole
+#[must_use]
+#[inline]
+pub fn chunk_hash(data: &[u8]) -> blake3_hash::Hash {
+    blake3::hash(data).into()
+}
+
 /// Trait to abstract away the structure used to compute hash-tree
 pub trait HashTreeCapable<T, E>
 where
@@ -24,32 +38,56 @@ where
     fn func(&mut self, data: &[u8]) -> Result<T, E>;
     fn merge(&mut self, l: &T, r: &T) -> Result<T, E>;
 
+    /// Results of `func` for each of `leaves`, in order
+    ///
+    /// This is where leaves may be processed in parallel, e.g. hashing them on every core before storing them one
+    /// after the other.
+    fn func_many(&mut self, leaves: &[&[u8]]) -> Result<Vec<T>, E> {
+        leaves.iter().map(|leaf| self.func(leaf)).collect()
+    }
+
+    /// Compute the hash-tree of `data`, using default fixed-size chunking
     fn compute_tree(&mut self, data: &[u8]) -> Result<T, E>
     where
         Self: Sized,
     {
-        if data.len() <= CHUNK_SIZE {
-            return self.func(data);
-        }
-
-        // pre-allocate partials vec
-        let mut partials: Vec<T> = Vec::with_capacity(data.len() / CHUNK_SIZE + 1);
+        self.compute_tree_with(data, &Chunking::default())
+    }
 
+    /// Compute the hash-tree of `data`, splitting it into leaves with `chunker`
+    fn compute_tree_with<C>(&mut self, data: &[u8], chunker: &C) -> Result<T, E>
+    where
+        Self: Sized,
+        C: Chunker,
+    {
         // Compute single chunks results
-        for chunk in data.chunks(CHUNK_SIZE) {
-            partials.push(self.func(chunk)?);
+        let leaves: Vec<&[u8]> = chunker.chunks(data).collect();
+
+        // Empty data still has an hash
+        if leaves.is_empty() {
+            return self.func(data);
         }
 
+        let partials = self.func_many(&leaves)?;
+        self.merge_all(partials)
+    }
+
+    /// Merge `partials` pairwise, level by level, up to a single root
+    ///
+    /// # Panics
+    /// If `partials` is empty
+    fn merge_all(&mut self, mut partials: Vec<T>) -> Result<T, E> {
         while partials.len() > 1 {
             // to is the destination position, i the first result position
             for (to, i) in (0..partials.len() - 1).step_by(2).enumerate() {
                 partials[to] = self.merge(&partials[i], &partials[i + 1])?;
             }
 
-            // if there's an element remaining put it in last position
+            // if there's an element remaining move it right after the merged ones
             if partials.len() % 2 != 0 {
-                partials.swap_remove(partials.len() / 2 + 1);
-                partials.truncate(partials.len() / 2 + 1);
+                let last = partials.len() - 1;
+                partials.swap(last / 2, last);
+                partials.truncate(last / 2 + 1);
             } else {
                 partials.truncate(partials.len() / 2);
             }
@@ -58,6 +96,171 @@ where
     }
 }
 
+/// Size of the reads done by `TreeBuilder::read_from` and `TreeBuilder::read_from_async`
+const READ_SIZE: usize = 64 * 1024;
+
+/// Largest chunks in a batch of data split at once by `TreeBuilder`
+const BATCH_CHUNKS: usize = 8;
+
+/// Incremental computation of hash-trees, for data coming in pieces
+///
+/// Leaves are cut as soon as there are `BATCH_CHUNKS` of the largest chunks of data, to be found and processed in
+/// parallel with `func_many`, and merged as soon as they complete a subtree: only that much data and one subtree
+/// root per level are kept. The result is the tree `HashTreeCapable::compute_tree_with` gives for the whole data at once.
+pub struct TreeBuilder<'a, H, T, E, C> {
+    hasher: &'a mut H,
+    chunker: &'a C,
+
+    /// Data not split into leaves yet, shorter than a batch between updates
+    pending: Vec<u8>,
+
+    /// Roots of the complete subtrees not merged yet, along with their height, highest first
+    subtrees: Vec<(u32, T)>,
+
+    error: PhantomData<fn() -> E>,
+}
+
+impl<'a, H, T, E, C> TreeBuilder<'a, H, T, E, C>
+where
+    H: HashTreeCapable<T, E>,
+    E: std::error::Error,
+    C: Chunker + Sync,
+{
+    pub fn new(hasher: &'a mut H, chunker: &'a C) -> Self {
+        Self {
+            hasher,
+            chunker,
+            pending: Vec::new(),
+            subtrees: Vec::new(),
+            error: PhantomData,
+        }
+    }
+
+    /// The hasher leaves and subtrees are computed with, e.g. to flush what it computed so far
+    pub fn hasher(&mut self) -> &mut H {
+        self.hasher
+    }
+
+    /// Add `data` after the one already added
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), E> {
+        let batch = self.chunker.max_size().max(1) * BATCH_CHUNKS;
+
+        // Complete the pending data first, cutting leaves out of it until it's used up
+        while !self.pending.is_empty() {
+            let missing = batch.saturating_sub(self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..missing]);
+            data = &data[missing..];
+            if self.pending.len() < batch {
+                return Ok(());
+            }
+            let pending = std::mem::take(&mut self.pending);
+            let len = self.split(&pending)?;
+            self.pending = pending;
+            self.pending.drain(..len);
+        }
+
+        // Then straight from `data`, without copying it
+        if data.len() >= batch {
+            let len = self.split(data)?;
+            data = &data[len..];
+        }
+        self.pending.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Add everything `reader` yields, until its end
+    pub fn read_from<R>(&mut self, mut reader: R) -> Result<(), E>
+    where
+        R: Read,
+        E: From<io::Error>,
+    {
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.update(&buf[..n])?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Add everything `reader` yields, until its end
+    pub async fn read_from_async<R>(&mut self, mut reader: R) -> Result<(), E>
+    where
+        R: AsyncRead + Unpin,
+        E: From<io::Error>,
+    {
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            match reader.read(&mut buf).await? {
+                0 => return Ok(()),
+                n => self.update(&buf[..n])?,
+            }
+        }
+    }
+
+    /// Split the remaining data and merge every subtree, returning the root of the tree
+    pub fn finish(mut self) -> Result<T, E> {
+        let pending = std::mem::take(&mut self.pending);
+        let leaves: Vec<&[u8]> = self.chunker.chunks(&pending).collect();
+        self.add(&leaves)?;
+
+        // Empty data still has an hash
+        let Some((_, mut root)) = self.subtrees.pop() else {
+            return self.hasher.func(&[]);
+        };
+        // Leftover subtrees are merged from the right, as `merge_all` carries the odd ones up
+        while let Some((_, left)) = self.subtrees.pop() {
+            root = self.hasher.merge(&left, &root)?;
+        }
+        Ok(root)
+    }
+
+    /// Add the leaves of `data` whose boundary can be found, returning their length
+    ///
+    /// A chunk boundary can only be found with `max_size` bytes at hand, unless data is over: leaves starting later
+    /// may not be the ones of the whole data.
+    fn split(&mut self, data: &[u8]) -> Result<usize, E> {
+        let max = self.chunker.max_size().max(1);
+        let mut leaves = self.chunker.par_chunks(data);
+        let (mut len, mut kept) = (0, 0);
+        for leaf in &leaves {
+            if data.len() - len < max {
+                break;
+            }
+            len += leaf.len();
+            kept += 1;
+        }
+        leaves.truncate(kept);
+        self.add(&leaves)?;
+        Ok(len)
+    }
+
+    /// Add `leaves`, in order
+    fn add(&mut self, leaves: &[&[u8]]) -> Result<(), E> {
+        for node in self.hasher.func_many(leaves)? {
+            self.push(node)?;
+        }
+        Ok(())
+    }
+
+    /// Add a leaf, merging it with the subtrees it completes
+    fn push(&mut self, mut node: T) -> Result<(), E> {
+        let mut height = 0;
+        while let Some((h, left)) = self.subtrees.pop() {
+            if h != height {
+                self.subtrees.push((h, left));
+                break;
+            }
+            node = self.hasher.merge(&left, &node)?;
+            height += 1;
+        }
+        self.subtrees.push((height, node));
+        Ok(())
+    }
+}
+
 /// Wrapper to allow dynamic dispatch
 struct DynHashTreeCapable<Func, Merge, T, E>
 where
@@ -88,24 +291,72 @@ where
 ///
 /// Used to abstract away the structure used to compute hash-tree, because storage may be doing almost the same
 /// thing but inserting nodes in the process
-pub fn compute_tree<Func, Merge, T, E>(func: Func, merge: Merge, data: &[u8]) -> Result<T, E>
+pub fn compute_tree<Func, Merge, T, E, C>(
+    func: Func,
+    merge: Merge,
+    data: &[u8],
+    chunker: &C,
+) -> Result<T, E>
 where
     Func: FnMut(&[u8]) -> Result<T, E>,
     Merge: FnMut(&T, &T) -> Result<T, E>,
     E: std::error::Error,
+    C: Chunker,
 {
-    DynHashTreeCapable { func, merge }.compute_tree(data)
+    DynHashTreeCapable { func, merge }.compute_tree_with(data, chunker)
+}
+
+/// Root hash of a tree with the subtrees with root `roots` as leaves, as merged by `HashTreeCapable::merge_all`
+///
+/// Without subtrees it's the hash of empty data.
+#[must_use]
+pub fn hash_roots(roots: Vec<blake3_hash::Hash>) -> blake3_hash::Hash {
+    if roots.is_empty() {
+        return chunk_hash(&[]);
+    }
+    let Ok(root) = DynHashTreeCapable {
+        func: |x: &[u8]| -> Result<blake3_hash::Hash, Infallible> { Ok(chunk_hash(x)) },
+        merge: |l: &blake3_hash::Hash, r: &blake3_hash::Hash| Ok(merge_hashes(l, r)),
+    }
+    .merge_all(roots);
+    root
 }
 
 /// Hashing function. Uses BLAKE3 but without Subtree-freeness
 #[must_use]
 pub fn hash(data: &[u8]) -> blake3_hash::Hash {
-    compute_tree(
-        |x| -> Result<blake3_hash::Hash, Infallible> { Ok(blake3::hash(x).into()) },
-        |l, r| Ok(merge_hashes(l, r)),
-        data,
-    )
-    .unwrap()
+    hash_with(data, &Chunking::default())
+}
+
+/// Same as `hash`, but splitting data into leaves with `chunker`
+///
+/// Leaves are found and hashed in parallel, then each level of the tree is merged in parallel, on the `rayon` pool.
+#[must_use]
+pub fn hash_with<C>(data: &[u8], chunker: &C) -> blake3_hash::Hash
+where
+    C: Chunker + Sync,
+{
+    let mut partials = chunk_hashes(&chunker.par_chunks(data));
+
+    // Same pairing as `HashTreeCapable::merge_all`, an odd last node is carried to the next level
+    while partials.len() > 1 {
+        partials = partials
+            .par_chunks(2)
+            .map(|pair| match pair {
+                [left, right] => merge_hashes(left, right),
+                [node] => *node,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    // Empty data still has an hash
+    partials.pop().unwrap_or_else(|| chunk_hash(data))
+}
+
+/// Hashes of `chunks`, in order, computed in parallel on the `rayon` pool
+#[must_use]
+pub fn chunk_hashes(chunks: &[&[u8]]) -> Vec<blake3_hash::Hash> {
+    chunks.par_iter().map(|chunk| chunk_hash(chunk)).collect()
 }
 
 pub use blake3_hash::Hash;
@@ -264,11 +515,18 @@ pub mod blake3_hash {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::Infallible;
+
+    use rand::Rng;
+
+    use crate::chunker::{Chunking, FastCdc, FixedSize};
     use crate::chunks::CHUNK_SIZE;
-    use crate::hash::merge_hashes;
+    use crate::hash::{chunk_hash, merge_hashes};
 
     use super::hash;
+    use super::hash_with;
     use super::Hash;
+    use super::{DynHashTreeCapable, TreeBuilder};
 
     #[test]
     fn blake3_one_chunk() {
@@ -289,4 +547,61 @@ mod tests {
 
         assert_eq!(hash(&data), h);
     }
+
+    #[test]
+    /// With an odd number of leaves the last one is carried over and merged at the end
+    fn odd_number_of_chunks() {
+        let mut data = vec![0u8; CHUNK_SIZE * 2 + 10];
+        data[CHUNK_SIZE..].fill(1);
+        data[CHUNK_SIZE * 2..].fill(2);
+
+        let h0 = Hash::from(blake3::hash(&data[..CHUNK_SIZE]));
+        let h1 = Hash::from(blake3::hash(&data[CHUNK_SIZE..CHUNK_SIZE * 2]));
+        let h2 = Hash::from(blake3::hash(&data[CHUNK_SIZE * 2..]));
+
+        assert_eq!(hash(&data), merge_hashes(&merge_hashes(&h0, &h1), &h2));
+    }
+
+    #[test]
+    fn hash_with_chunkers() {
+        let data = vec![7u8; CHUNK_SIZE * 5];
+        assert_eq!(hash(&data), hash_with(&data, &Chunking::default()));
+        assert_eq!(
+            hash(&data),
+            hash_with(&data, &Chunking::Fixed(FixedSize::default()))
+        );
+        // same data but cut differently
+        assert_ne!(hash(&data), hash_with(&data, &FixedSize { size: 1024 }));
+    }
+
+    #[test]
+    /// Data added in pieces of any size gives the same tree as all at once
+    fn tree_builder() {
+        let mut data = vec![0u8; 200_000];
+        rand::thread_rng().fill(&mut data[..]);
+
+        for chunking in [
+            Chunking::Fixed(FixedSize { size: 1024 }),
+            Chunking::FastCdc(FastCdc::default()),
+        ] {
+            for len in [0, 1, 1023, 1024, 1025, 33 * 1024 + 5, 200_000] {
+                let data = &data[..len];
+                for piece in [1, 1000, 4096, 200_000] {
+                    let mut hasher = DynHashTreeCapable {
+                        func: |x: &[u8]| -> Result<Hash, Infallible> { Ok(chunk_hash(x)) },
+                        merge: |l: &Hash, r: &Hash| Ok(merge_hashes(l, r)),
+                    };
+                    let mut builder = TreeBuilder::new(&mut hasher, &chunking);
+                    for part in data.chunks(piece) {
+                        builder.update(part).unwrap();
+                    }
+                    assert_eq!(
+                        builder.finish().unwrap(),
+                        hash_with(data, &chunking),
+                        "{len} bytes in pieces of {piece}, {chunking:?}"
+                    );
+                }
+            }
+        }
+    }
 }
@@ -7,7 +7,7 @@
 //! - 2. Uses BLAKE3 instead of SHA1 or SHA25
 //! - 3. No `info_hash`, no encoding. We'll use a binary format and the server will sign it
 //! - 4. We're calling them "chunks" instead of "pieces", because I like it more this way
-//! - 5. An item contains a single file. Msgpack serialization is cheap. Just use tar if you need to :)
+//! - 5. An item contains a single file, or a whole directory tree (see `directory`) listed in a manifest
 //!
 //!```json
 //!"name": "Update for some file",
@@ -17,7 +17,7 @@
 //!"created": 1375363666,
 //!"created_by": "distd 0.1.0",
 //!"format": "1"
-//!"chunk_size": 16384,
+//!"chunking": { "FastCdc": { "min_size": 65536, "avg_size": 262144, "max_size": 1048576 } },
 //!"chunks": [
 //!  "8a468d4f30b20645981364d3b77499f0d3dc999d25960cdfc5da8e836ce51b9d",
 //!  "36875eae0dba363968a1e2f12d6be4aff5d737d0cca2d12351ccf182531a8613",
@@ -37,8 +37,11 @@ use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
 
+use crate::attributes::Attributes;
 use crate::chunk_storage::Node;
+use crate::chunker::Chunking;
 use crate::chunks::ChunkInfo;
+use crate::directory::Manifest;
 use crate::metadata::Item as ItemMetadata;
 use crate::unique_name::UniqueName;
 
@@ -49,6 +52,17 @@ pub enum Format {
     V1 = 1,
 }
 
+/// What an item is made of
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub enum Kind {
+    /// A single file
+    #[default]
+    File,
+
+    /// A directory tree, the content of its files being the content of the item
+    Directory(Manifest),
+}
+
 /// Item representation
 ///
 /// This is bothe the format used over-the-wire to communicate from client to server, as well as the internal format
@@ -77,6 +91,7 @@ impl Item {
         path: PathBuf,
         revision: u32,
         description: Option<String>,
+        chunking: Chunking,
         hash_tree: &Arc<Node>,
     ) -> Self {
         let now = SystemTime::now();
@@ -87,10 +102,14 @@ impl Item {
                 revision,
                 path,
                 root: hash_tree.chunk_info(),
+                chunking,
                 created: now,
                 updated: now,
                 created_by: env!("CARGO_PKG_VERSION").to_owned(),
+                signature: None,
                 format: Format::V1,
+                attributes: Attributes::default(),
+                kind: Kind::File,
             },
             chunks: hash_tree.flatten_with_sizes(),
             hashes: hash_tree.all_hashes_with_sizes(),
@@ -98,11 +117,13 @@ impl Item {
     }
 
     /// Make a new Item without adding it to a storage
+    #[allow(clippy::too_many_arguments)]
     pub fn make(
         name: Name,
         path: PathBuf,
         revision: u32,
         description: Option<String>,
+        chunking: Chunking,
         root: ChunkInfo,
         chunks: Vec<ChunkInfo>,
         hashes: HashSet<ChunkInfo>,
@@ -115,10 +136,14 @@ impl Item {
                 revision,
                 path,
                 root,
+                chunking,
                 created: now,
                 updated: now,
                 created_by: env!("CARGO_PKG_VERSION").to_owned(),
+                signature: None,
                 format: Format::V1,
+                attributes: Attributes::default(),
+                kind: Kind::File,
             },
             chunks,
             hashes,
@@ -146,6 +171,16 @@ impl Item {
         self.hashes.difference(&other.hashes).copied().collect()
     }
 
+    /// Hashes of every chunk and subtree of the item, as advertised to the server
+    #[must_use]
+    pub fn all_hashes(&self) -> HashSet<crate::hash::Hash> {
+        self.chunks
+            .iter()
+            .chain(self.hashes.iter())
+            .map(|x| x.hash)
+            .collect()
+    }
+
     #[inline]
     #[must_use] pub fn root(&self) -> &crate::hash::Hash {
         &self.metadata.root.hash
@@ -208,6 +243,7 @@ pub mod tests {
                 0,
                 None,
                 Bytes::from_static(b""),
+                Chunking::default(),
             )
     }
 
@@ -224,6 +260,7 @@ pub mod tests {
                 0,
                 Some("Some description for the larger item".to_string()),
                 Bytes::from_static(&[VALUE; SIZE]),
+                Chunking::default(),
             )
     }
 
@@ -253,6 +290,7 @@ pub mod tests {
             random_path_subdir(),
             0,
             Some("Some description for the larger item".to_string()),
+            Chunking::default(),
             chunk,
             vec![chunk],
             HashSet::from_iter(vec![chunk]),
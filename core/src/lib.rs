@@ -7,14 +7,19 @@
 #![feature(hash_extract_if)]
 #![feature(test)]
 
+pub mod attributes;
+pub mod auth;
 pub mod chunk_storage;
+pub mod chunker;
 pub mod chunks;
+pub mod directory;
 pub mod error;
 pub mod feed;
 pub mod hash;
 pub mod item;
 pub mod metadata;
 pub mod peer;
+pub mod signature;
 pub mod unique_name;
 pub mod utils;
 pub mod version;
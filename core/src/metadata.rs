@@ -1,12 +1,20 @@
 //! Common metadata exchanged between server and client
 
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf, time::SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use crate::{
+    attributes::Attributes,
+    chunker::Chunking,
     chunks::ChunkInfo,
     feed::{Feed, Name as FeedName},
-    item::{Format as ItemFormat, Name as ItemName},
+    item::{Format as ItemFormat, Kind as ItemKind, Name as ItemName},
+    signature::{self, Error as SignatureError, PublicKey, Signature},
     utils::serde::BitcodeSerializable,
     version::Version,
 };
@@ -19,12 +27,153 @@ pub struct Server {
     pub version: Version,
     // Feed map
     pub feeds: HashMap<FeedName, Feed>,
-    // Item map
+    // Item map, latest revision of each item
     pub items: HashMap<PathBuf, Item>,
+    /// Every revision of each item, by path and revision number
+    pub revisions: HashMap<PathBuf, BTreeMap<u32, Item>>,
+}
+
+impl Server {
+    /// Verify the server signature of every item, including all revisions
+    pub fn verify_items(&self, public_key: &PublicKey) -> Result<(), SignatureError> {
+        self.items
+            .values()
+            .chain(self.revisions.values().flat_map(BTreeMap::values))
+            .try_for_each(|item| item.verify(public_key))
+    }
+
+    /// Get the item at `path`, at a specific revision or the latest one if `revision` is `None`
+    #[must_use]
+    pub fn item(&self, path: &Path, revision: Option<u32>) -> Option<&Item> {
+        match revision {
+            Some(revision) => self.revisions.get(path)?.get(&revision),
+            None => self.items.get(path),
+        }
+    }
+
+    /// Changes turning `self` into `newer`
+    ///
+    /// Generations are left unset, to be set by whoever tracks them.
+    #[must_use]
+    pub fn diff(&self, newer: &Self) -> Update {
+        Update {
+            version: newer.version,
+            items: newer
+                .revisions
+                .iter()
+                .filter(|(path, revisions)| self.revisions.get(*path) != Some(revisions))
+                .map(|(path, revisions)| (path.clone(), revisions.clone()))
+                .collect(),
+            removed_items: self
+                .revisions
+                .keys()
+                .filter(|path| !newer.revisions.contains_key(*path))
+                .cloned()
+                .collect(),
+            feeds: newer
+                .feeds
+                .iter()
+                .filter(|(name, feed)| self.feeds.get(*name) != Some(feed))
+                .map(|(name, feed)| (name.clone(), feed.clone()))
+                .collect(),
+            removed_feeds: self
+                .feeds
+                .keys()
+                .filter(|name| !newer.feeds.contains_key(*name))
+                .cloned()
+                .collect(),
+            ..Update::default()
+        }
+    }
+
+    /// Update replacing any metadata it is applied to with `self`
+    #[must_use]
+    pub fn snapshot(&self) -> Update {
+        Update {
+            full: true,
+            ..Self::default().diff(self)
+        }
+    }
+
+    /// Apply the changes in `update`
+    pub fn apply(&mut self, update: Update) {
+        if update.full {
+            *self = Self::default();
+        }
+        self.version = update.version;
+        for path in &update.removed_items {
+            self.items.remove(path);
+            self.revisions.remove(path);
+        }
+        for (path, revisions) in update.items {
+            match revisions.values().next_back() {
+                Some(latest) => self.items.insert(path.clone(), latest.clone()),
+                None => self.items.remove(&path),
+            };
+            self.revisions.insert(path, revisions);
+        }
+        for name in &update.removed_feeds {
+            self.feeds.remove(name);
+        }
+        self.feeds.extend(update.feeds);
+    }
 }
 
 impl BitcodeSerializable<'_, Server> for Server {}
 
+/// Changes to server metadata, pushed to clients as they happen
+///
+/// Items are replaced as a whole along with every revision, and so are feeds.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Update {
+    /// Generation of the metadata once the update is applied
+    pub generation: u64,
+    /// Generation of the metadata the update applies to, none for full updates
+    pub base: Option<u64>,
+    /// Whether the update replaces everything instead of changing it
+    pub full: bool,
+    pub version: Version,
+    /// Every revision of items added or changed, by path
+    pub items: HashMap<PathBuf, BTreeMap<u32, Item>>,
+    /// Paths of removed items
+    pub removed_items: Vec<PathBuf>,
+    /// Feeds added or changed
+    pub feeds: HashMap<FeedName, Feed>,
+    /// Names of removed feeds
+    pub removed_feeds: Vec<FeedName>,
+}
+
+impl Update {
+    /// Whether applying the update changes nothing
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        !self.full
+            && self.items.is_empty()
+            && self.removed_items.is_empty()
+            && self.feeds.is_empty()
+            && self.removed_feeds.is_empty()
+    }
+
+    /// Whether the update may be applied to metadata at generation `held`
+    ///
+    /// Generations only go forward, and changes only apply to the generation they were computed from: updates
+    /// replayed or out of order are refused.
+    #[must_use]
+    pub fn follows(&self, held: Option<u64>) -> bool {
+        held.is_none_or(|held| self.generation > held) && (self.full || self.base == held)
+    }
+
+    /// Verify the server signature of every item in the update
+    pub fn verify_items(&self, public_key: &PublicKey) -> Result<(), SignatureError> {
+        self.items
+            .values()
+            .flat_map(BTreeMap::values)
+            .try_for_each(|item| item.verify(public_key))
+    }
+}
+
+impl BitcodeSerializable<'_, Update> for Update {}
+
 /// A compact subset of the fields in an Item
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Item {
@@ -38,6 +187,8 @@ pub struct Item {
     pub path: PathBuf,
     /// BLAKE3 root hash of the file
     pub root: ChunkInfo,
+    /// Chunker (and its parameters) used to compute the hash-tree
+    pub chunking: Chunking,
     /// Creation `SystemTime`
     pub created: SystemTime,
     /// Last time the item has been updated
@@ -46,7 +197,14 @@ pub struct Item {
     pub created_by: String,
     /// format used
     pub format: ItemFormat,
-    //signature: Signature,
+    /// Server signature of root hash, size, path, revision, attributes and kind, see `Item::signed_data`
+    pub signature: Option<Signature>,
+    /// File attributes captured when publishing, applied on install
+    #[serde(default)]
+    pub attributes: Attributes,
+    /// Single file or directory tree, with the manifest of its entries
+    #[serde(default)]
+    pub kind: ItemKind,
 }
 
 impl std::hash::Hash for Item {
@@ -61,6 +219,46 @@ impl Item {
     pub fn size(&self) -> u64 {
         self.root.size
     }
+
+    /// Data covered by the server signature: root hash, size, path, revision, attributes and kind of the item
+    ///
+    /// The bitcode serialization of `SignedItem`, with the path as its raw bytes.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the item cannot be serialized
+    pub fn signed_data(&self) -> Result<Vec<u8>, SignatureError> {
+        let signed = SignedItem {
+            domain: SignedItem::DOMAIN,
+            root: &self.root,
+            revision: self.revision,
+            path: self.path.as_os_str().as_bytes(),
+            attributes: &self.attributes,
+            kind: &self.kind,
+        };
+        bitcode::serialize(&signed).map_err(|_| SignatureError::Unsignable)
+    }
+
+    /// Verify the server signature of the item
+    pub fn verify(&self, public_key: &PublicKey) -> Result<(), SignatureError> {
+        let signature = self.signature.as_ref().ok_or(SignatureError::Missing)?;
+        signature::verify(public_key, &self.signed_data()?, signature)
+    }
+}
+
+/// What the server signs of an item, tagged so that it can't be taken for any other signed data
+#[derive(Serialize)]
+struct SignedItem<'a> {
+    domain: &'static str,
+    root: &'a ChunkInfo,
+    revision: u32,
+    path: &'a [u8],
+    attributes: &'a Attributes,
+    kind: &'a ItemKind,
+}
+
+impl SignedItem<'_> {
+    const DOMAIN: &'static str = "distd item";
 }
 
 impl BitcodeSerializable<'_, Item> for Item {}
@@ -74,6 +272,8 @@ pub struct Clients {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeSet;
+
     use crate::hash::Hash;
 
     use super::*;
@@ -89,10 +289,14 @@ mod tests {
                 hash: Hash::from_bytes([0; 32]),
                 size: 0,
             },
+            chunking: Chunking::default(),
             created: SystemTime::now(),
             updated: SystemTime::now(),
             created_by: "distd".to_string(),
             format: ItemFormat::V1,
+            signature: None,
+            attributes: Attributes::default(),
+            kind: ItemKind::File,
         };
     }
 
@@ -107,10 +311,14 @@ mod tests {
                 hash: Hash::from_bytes([0; 32]),
                 size: 0,
             },
+            chunking: Chunking::default(),
             created: SystemTime::now(),
             updated: SystemTime::now(),
             created_by: "distd".to_string(),
             format: ItemFormat::V1,
+            signature: None,
+            attributes: Attributes::default(),
+            kind: ItemKind::File,
         };
         let item2 = item.clone();
         assert_eq!(item, item2);
@@ -124,13 +332,196 @@ mod tests {
                 hash: Hash::from_bytes([0; 32]),
                 size: 0,
             },
+            chunking: Chunking::default(),
             created: SystemTime::now(),
             updated: SystemTime::now(),
             created_by: "distd".to_string(),
             format: ItemFormat::V1,
+            signature: None,
+            attributes: Attributes::default(),
+            kind: ItemKind::File,
         };
         assert_ne!(item, item3);
 
     }
 
+    #[test]
+    fn server_item_revisions() {
+        let item = |revision| Item {
+            name: "An item".to_string(),
+            description: None,
+            revision,
+            path: PathBuf::from("path/to/item"),
+            root: ChunkInfo {
+                hash: Hash::from_bytes([0; 32]),
+                size: 0,
+            },
+            chunking: Chunking::default(),
+            created: SystemTime::now(),
+            updated: SystemTime::now(),
+            created_by: "distd".to_string(),
+            format: ItemFormat::V1,
+            signature: None,
+            attributes: Attributes::default(),
+            kind: ItemKind::File,
+        };
+        let path = PathBuf::from("path/to/item");
+        let server = Server {
+            items: HashMap::from([(path.clone(), item(1))]),
+            revisions: HashMap::from([(
+                path.clone(),
+                BTreeMap::from([(0, item(0)), (1, item(1))]),
+            )]),
+            ..Server::default()
+        };
+
+        assert_eq!(server.item(&path, Some(0)).map(|i| i.revision), Some(0));
+        assert_eq!(server.item(&path, None).map(|i| i.revision), Some(1));
+        assert!(server.item(&path, Some(2)).is_none());
+        assert!(server.item(&PathBuf::from("missing"), None).is_none());
+    }
+
+    #[test]
+    fn server_update() {
+        let item = |path: &str, revision| Item {
+            name: "An item".to_string(),
+            description: None,
+            revision,
+            path: PathBuf::from(path),
+            root: ChunkInfo {
+                hash: Hash::from_bytes([u8::try_from(revision).unwrap(); 32]),
+                size: 0,
+            },
+            chunking: Chunking::default(),
+            created: SystemTime::UNIX_EPOCH,
+            updated: SystemTime::UNIX_EPOCH,
+            created_by: "distd".to_string(),
+            format: ItemFormat::V1,
+            signature: None,
+            attributes: Attributes::default(),
+            kind: ItemKind::File,
+        };
+        let server = |items: &[(&str, u32)], feeds: &[&str]| {
+            let mut server = Server::default();
+            for (path, latest) in items {
+                server.items.insert(path.into(), item(path, *latest));
+                server.revisions.insert(
+                    path.into(),
+                    (0..=*latest).map(|r| (r, item(path, r))).collect(),
+                );
+            }
+            for name in feeds {
+                server.feeds.insert((*name).to_string(), Feed::new(name));
+            }
+            server
+        };
+
+        let old = server(&[("a", 0), ("b", 1), ("c", 0)], &["f", "g"]);
+        let new = server(&[("a", 0), ("b", 2), ("d", 0)], &["f", "h"]);
+        let update = old.diff(&new);
+        assert_eq!(
+            update.items.keys().collect::<BTreeSet<_>>(),
+            BTreeSet::from([&PathBuf::from("b"), &PathBuf::from("d")])
+        );
+        assert_eq!(update.removed_items, vec![PathBuf::from("c")]);
+        assert_eq!(update.feeds.keys().collect::<Vec<_>>(), vec!["h"]);
+        assert_eq!(update.removed_feeds, vec!["g".to_string()]);
+
+        let mut applied = old.clone();
+        applied.apply(update);
+        assert_eq!(applied, new);
+        assert!(new.diff(&applied).is_empty());
+
+        let mut applied = old;
+        applied.apply(new.snapshot());
+        assert_eq!(applied, new);
+        assert!(!new.snapshot().is_empty());
+
+        // Updates apply to the generation they were made from, and never go back
+        let diff = Update {
+            generation: 3,
+            base: Some(2),
+            ..Update::default()
+        };
+        assert!(diff.follows(Some(2)));
+        assert!(!diff.follows(Some(1)) && !diff.follows(Some(3)) && !diff.follows(None));
+        let full = Update {
+            generation: 3,
+            ..new.snapshot()
+        };
+        assert!(full.follows(None) && full.follows(Some(1)));
+        assert!(!full.follows(Some(3)) && !full.follows(Some(4)));
+    }
+
+    #[test]
+    fn item_signature() {
+        use ring::{
+            rand::SystemRandom,
+            signature::{Ed25519KeyPair, KeyPair},
+        };
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key: PublicKey = key_pair.public_key().as_ref().try_into().unwrap();
+
+        let mut item = Item {
+            name: "An item".to_string(),
+            description: None,
+            revision: 3,
+            path: PathBuf::from("path/to/item"),
+            root: ChunkInfo {
+                hash: Hash::from_bytes([1; 32]),
+                size: 42,
+            },
+            chunking: Chunking::default(),
+            created: SystemTime::now(),
+            updated: SystemTime::now(),
+            created_by: "distd".to_string(),
+            format: ItemFormat::V1,
+            signature: None,
+            attributes: Attributes::default(),
+            kind: ItemKind::File,
+        };
+        assert_eq!(item.verify(&public_key), Err(SignatureError::Missing));
+
+        item.signature = Some(
+            key_pair
+                .sign(&item.signed_data().unwrap())
+                .as_ref()
+                .to_vec(),
+        );
+        assert_eq!(item.verify(&public_key), Ok(()));
+
+        // Any change to signed fields is detected
+        let mut tampered = item.clone();
+        tampered.root.hash = Hash::from_bytes([2; 32]);
+        assert_eq!(tampered.verify(&public_key), Err(SignatureError::Invalid));
+        let mut tampered = item.clone();
+        tampered.revision = 4;
+        assert_eq!(tampered.verify(&public_key), Err(SignatureError::Invalid));
+        let mut tampered = item.clone();
+        tampered.attributes.mode = Some(0o4755);
+        assert_eq!(tampered.verify(&public_key), Err(SignatureError::Invalid));
+        let mut tampered = item.clone();
+        tampered.kind = ItemKind::Directory(crate::directory::Manifest::default());
+        assert_eq!(tampered.verify(&public_key), Err(SignatureError::Invalid));
+
+        // Paths are signed as they are, non-UTF-8 ones included
+        let with_path = |path: &[u8]| {
+            let mut item = item.clone();
+            item.path = PathBuf::from(std::ffi::OsStr::from_bytes(path));
+            item.signed_data().unwrap()
+        };
+        assert_ne!(with_path(b"a\xfe"), with_path(b"a\xff"));
+
+        let server = Server {
+            items: HashMap::from([(item.path.clone(), item.clone())]),
+            revisions: HashMap::from([(item.path.clone(), BTreeMap::from([(3, tampered)]))]),
+            ..Server::default()
+        };
+        assert_eq!(
+            server.verify_items(&public_key),
+            Err(SignatureError::Invalid)
+        );
+    }
 }
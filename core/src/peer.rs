@@ -1 +1,68 @@
-// TODO
+//! Peer-to-peer chunk exchange, speaking a subset of PPSPP (RFC 7574) over UDP
+//!
+//! Every item is a swarm, identified by the root hash of its hash-tree. Peers holding an item serve its chunks
+//! straight from their `ChunkStorage` through a `Seeder`, while `leecher::fetch` downloads a whole item from a set
+//! of peers, verifying every chunk against the root hash before it is handed to storage. Directory items are
+//! downloaded file by file instead, every file being the swarm of its own root, see `leecher::fetch_directory`.
+//!
+//! Implemented messages are HANDSHAKE, HAVE, REQUEST, DATA, INTEGRITY and ACK, with Merkle integrity protection
+//! (using the BLAKE3 hash-tree of items) and 32-bit chunk ranges addressing, where chunks are the leaves of the
+//! hash-tree. Departures from the RFC:
+//! - Chunks are usually larger than a datagram, so DATA carries a fragment of a single chunk, REQUEST asks for one
+//!   fragment and both carry the offset of the fragment within the chunk
+//! - Chunks have variable sizes (e.g. with content-defined chunking), the size of a chunk is sent with every DATA
+//! - Hash-trees are not balanced full trees: leftover nodes are moved up a level as they are, see
+//!   `integrity::Tree`. The number of chunks is then needed to know the shape of the tree, peers holding an item send
+//!   it in the HANDSHAKE with the `CHUNKS` option
+//! - There's no congestion control, a leecher keeps a fixed window of outstanding requests per peer
+//! - No peer exchange, peers are provided by the caller
+//! - Channels are only opened for clients of the realm of the seeder, with a peer token issued by the server sent in
+//!   the HANDSHAKE with the `PEER_TOKEN` option, see `seeder`
+
+use thiserror::Error;
+
+pub mod integrity;
+pub mod leecher;
+pub mod message;
+pub mod seeder;
+
+pub use leecher::{fetch, fetch_directory};
+pub use seeder::Seeder;
+
+/// Size in bytes of the fragments of chunks carried by DATA messages
+pub const FRAGMENT_SIZE: usize = 8 * 1024;
+
+/// Outstanding fragment requests a leecher keeps per peer, and most fragments a seeder sends for a single datagram
+pub(crate) const WINDOW: usize = 16;
+
+/// Maximum size of a datagram we expect to receive
+pub(crate) const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// Peer-to-peer error
+#[derive(Error, Debug)]
+pub enum PeerError {
+    #[error("Socket error: '{0}'")]
+    Io(#[from] std::io::Error),
+
+    #[error("Malformed datagram: {0}")]
+    Malformed(&'static str),
+
+    #[error("Unsupported {0}")]
+    Unsupported(&'static str),
+
+    #[error("No peer available for swarm {0}")]
+    NoPeers(crate::hash::Hash),
+
+    #[error("Timed out with {missing} of {chunks} chunks missing")]
+    Incomplete { missing: usize, chunks: usize },
+
+    #[error("Chunks received from peers don't make up the expected item")]
+    Mismatch,
+}
+
+/// Microseconds since the UNIX epoch, as sent in DATA messages
+pub(crate) fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |t| u64::try_from(t.as_micros()).unwrap_or(u64::MAX))
+}
@@ -0,0 +1,168 @@
+//! Merkle integrity protection of chunks
+//!
+//! Hash-trees are built merging nodes pairwise, level by level, with any leftover node moved up a level unchanged
+//! (see `HashTreeCapable::compute_tree_with`). Node `i` at level `k` then covers chunks from `i << k` up to
+//! `(i + 1) << k`, clipped to the number of chunks, so that the number of chunks is enough to know which uncle
+//! hashes are needed to verify a chunk against the root hash.
+
+use crate::hash::{merge_hashes, Hash};
+
+use super::message::ChunkRange;
+
+/// Every level of the hash-tree of an item, from the leaves up to the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl Tree {
+    /// Rebuild the tree from the hashes of its leaves, in order
+    ///
+    /// # Panics
+    ///
+    /// Panics if `leaves` is empty, every item has at least one chunk
+    #[must_use]
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        assert!(!leaves.is_empty(), "hash-trees have at least one leaf");
+        let mut levels = vec![leaves];
+        while let Some(level) = levels.last().filter(|l| l.len() > 1) {
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [l, r] => merge_hashes(l, r),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Hash {
+        &self.levels[self.levels.len() - 1][0]
+    }
+
+    #[must_use]
+    pub fn leaves(&self) -> &[Hash] {
+        &self.levels[0]
+    }
+
+    /// Number of chunks
+    #[must_use]
+    pub fn chunks(&self) -> u32 {
+        u32::try_from(self.levels[0].len()).unwrap_or(u32::MAX)
+    }
+
+    /// Uncle hashes needed to verify `chunk` against the root, along with the chunks they cover
+    #[must_use]
+    pub fn uncles(&self, chunk: u32) -> Vec<(ChunkRange, Hash)> {
+        let chunks = self.chunks();
+        let mut index = chunk;
+        let mut uncles = vec![];
+        for (level, hashes) in self.levels.iter().enumerate() {
+            if let Some(uncle) = hashes.get((index ^ 1) as usize) {
+                uncles.push((covered(chunks, level, index ^ 1), *uncle));
+            }
+            index /= 2;
+        }
+        uncles
+    }
+}
+
+/// Chunks covered by node `index` at `level` of a tree with `chunks` leaves
+#[must_use]
+pub fn covered(chunks: u32, level: usize, index: u32) -> ChunkRange {
+    let start = u64::from(index) << level;
+    let end = ((u64::from(index) + 1) << level).min(u64::from(chunks)) - 1;
+    #[allow(clippy::cast_possible_truncation)]
+    ChunkRange {
+        start: start as u32,
+        end: end as u32,
+    }
+}
+
+/// Verify the `hash` of `chunk` against `root`, in a tree of `chunks` leaves
+///
+/// `uncles` must hold every uncle hash of the chunk, as returned by `Tree::uncles`, others are ignored.
+#[must_use]
+pub fn verify(
+    root: &Hash,
+    chunks: u32,
+    chunk: u32,
+    hash: Hash,
+    uncles: &[(ChunkRange, Hash)],
+) -> bool {
+    if chunk >= chunks {
+        return false;
+    }
+    let mut hash = hash;
+    let mut index = chunk;
+    let mut len = chunks;
+    let mut level = 0;
+    while len > 1 {
+        let sibling = index ^ 1;
+        if sibling < len {
+            let range = covered(chunks, level, sibling);
+            let Some((_, uncle)) = uncles.iter().find(|(r, _)| *r == range) else {
+                return false;
+            };
+            hash = if sibling > index {
+                merge_hashes(&hash, uncle)
+            } else {
+                merge_hashes(uncle, &hash)
+            };
+        }
+        index /= 2;
+        len = len.div_ceil(2);
+        level += 1;
+    }
+    hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chunker::{Chunking, FixedSize},
+        hash::{chunk_hash, hash_with},
+    };
+
+    use super::*;
+
+    #[test]
+    fn tree_matches_hash_tree() {
+        let chunking = Chunking::Fixed(FixedSize { size: 16 });
+        for len in [1, 16, 17, 48, 80, 100, 16 * 7, 16 * 9 + 1] {
+            let data: Vec<u8> = (0..len).map(|i: u32| i.to_le_bytes()[0]).collect();
+            let leaves: Vec<Hash> = data.chunks(16).map(chunk_hash).collect();
+            let tree = Tree::new(leaves.clone());
+            let root = hash_with(&data, &chunking);
+            assert_eq!(tree.root(), &root, "{len} bytes");
+
+            let chunks = tree.chunks();
+            for (i, leaf) in (0..).zip(&leaves) {
+                let uncles = tree.uncles(i);
+                assert!(
+                    verify(&root, chunks, i, *leaf, &uncles),
+                    "chunk {i} of {len} bytes"
+                );
+
+                // Any other chunk count, or any other position, doesn't verify
+                assert!(!verify(&root, chunks + 1, i, *leaf, &uncles) || chunks == 1);
+                assert!(!verify(&root, chunks, (i + 1) % chunks, *leaf, &uncles) || chunks == 1);
+                assert!(!verify(&root, chunks, i, chunk_hash(b"other"), &uncles));
+            }
+        }
+    }
+
+    #[test]
+    fn covered_ranges() {
+        assert_eq!(covered(5, 0, 4), ChunkRange::single(4));
+        assert_eq!(covered(5, 1, 1), ChunkRange { start: 2, end: 3 });
+        // Leftover nodes cover just what's left
+        assert_eq!(covered(5, 1, 2), ChunkRange::single(4));
+        assert_eq!(covered(5, 2, 1), ChunkRange::single(4));
+        assert_eq!(covered(5, 3, 0), ChunkRange { start: 0, end: 4 });
+    }
+}
@@ -0,0 +1,547 @@
+//! Downloading a whole item from a set of peers
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    chunk_storage::Node,
+    chunker::{Chunker, Chunking},
+    chunks::ChunkInfo,
+    directory::Manifest,
+    hash::{chunk_hash, merge_hashes, Hash},
+};
+
+use super::{
+    integrity,
+    message::{ChunkRange, Datagram, Message, Options},
+    seeder::random_channel,
+    PeerError, FRAGMENT_SIZE, MAX_DATAGRAM, WINDOW,
+};
+
+/// Requests not answered within this time are sent again
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Peers not answering this many times in a row are given up
+const MAX_FAILURES: u32 = 8;
+
+/// A chunk being downloaded from a peer
+struct Job {
+    chunk: u32,
+
+    /// Size of the chunk, known with the first fragment
+    length: Option<u32>,
+    data: Vec<u8>,
+    received: Vec<bool>,
+
+    /// Uncle hashes sent along with the first fragment
+    uncles: Vec<(ChunkRange, Hash)>,
+
+    /// Offsets of outstanding fragment requests, with the time they were sent
+    requested: HashMap<u32, Instant>,
+}
+
+impl Job {
+    fn new(chunk: u32) -> Self {
+        Self {
+            chunk,
+            length: None,
+            data: vec![],
+            received: vec![],
+            uncles: vec![],
+            requested: HashMap::new(),
+        }
+    }
+
+    /// Offsets of the fragments still to be received
+    fn wanted(&self) -> Vec<u32> {
+        if self.length.is_none() {
+            return vec![0];
+        }
+        self.received
+            .iter()
+            .enumerate()
+            .filter(|(_, received)| !**received)
+            .filter_map(|(i, _)| u32::try_from(i * FRAGMENT_SIZE).ok())
+            .collect()
+    }
+}
+
+/// A peer we're downloading from
+struct Remote {
+    addr: SocketAddr,
+
+    /// Our end of the channel
+    channel: u32,
+    peer_channel: Option<u32>,
+    have: Vec<ChunkRange>,
+    handshake_sent: Option<Instant>,
+    failures: u32,
+    job: Option<Job>,
+    closed: bool,
+}
+
+struct Download {
+    root: ChunkInfo,
+
+    /// Peer token sent with every HANDSHAKE
+    token: Vec<u8>,
+
+    /// Chunking of the item, bounding the number and size of chunks peers can make us allocate
+    chunking: Chunking,
+    chunks: Option<u32>,
+    leaves: Vec<Option<Node>>,
+    missing: usize,
+    remotes: Vec<Remote>,
+}
+
+/// Download the item with `root`, split with `chunking`, from `peers`, giving up after `timeout`
+///
+/// Peers only open a channel for a HANDSHAKE with a `token` issued by the server, see `crate::auth::issue_peer`.
+/// Every chunk is verified against the root hash, the number of chunks is taken from the first peer telling it.
+/// Returns the nodes of the whole hash-tree, leaves first, ready to be passed to `ChunkStorage::receive_item`.
+pub async fn fetch(
+    root: &ChunkInfo,
+    chunking: &Chunking,
+    peers: &[SocketAddr],
+    token: &[u8],
+    timeout: Duration,
+) -> Result<Vec<Node>, PeerError> {
+    let bind: SocketAddr = match peers.first() {
+        Some(SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        _ => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+
+    let mut download = Download {
+        root: *root,
+        token: token.to_vec(),
+        chunking: *chunking,
+        chunks: None,
+        leaves: vec![],
+        missing: 0,
+        remotes: peers
+            .iter()
+            .filter(|addr| addr.is_ipv4() == bind.is_ipv4())
+            .map(|addr| Remote {
+                addr: *addr,
+                channel: random_channel(),
+                peer_channel: None,
+                have: vec![],
+                handshake_sent: None,
+                failures: 0,
+                job: None,
+                closed: false,
+            })
+            .collect(),
+    };
+
+    let res = download.run(&socket, Instant::now() + timeout).await;
+    download.close(&socket).await;
+    res
+}
+
+/// Download the directory item with `root` and `manifest`, split with `chunking`, from `peers`, giving up after
+/// `timeout`
+///
+/// The tree of a directory item is made of the trees of its files (see `crate::directory`), not of its content
+/// chunked as a whole: each file is fetched as a swarm of its own, the one of its root, which peers holding the item
+/// hold too. Returns the nodes of the whole hash-tree, as `fetch` does.
+pub async fn fetch_directory(
+    root: &ChunkInfo,
+    manifest: &Manifest,
+    chunking: &Chunking,
+    peers: &[SocketAddr],
+    token: &[u8],
+    timeout: Duration,
+) -> Result<Vec<Node>, PeerError> {
+    let files: Vec<ChunkInfo> = manifest.files().map(|(_, root, _)| *root).collect();
+    // Without files the item is a single empty chunk
+    if files.is_empty() {
+        return fetch(root, chunking, peers, token, timeout).await;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut fetched: HashMap<Hash, Vec<Node>> = HashMap::new();
+    let mut nodes = vec![];
+    for file in &files {
+        // Files with the same content are fetched once, but their nodes are needed at each place
+        if let Entry::Vacant(entry) = fetched.entry(file.hash) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            entry.insert(fetch(file, chunking, peers, token, left).await?);
+        }
+        nodes.extend(fetched[&file.hash].iter().cloned());
+    }
+
+    if merge_levels(files, &mut nodes) == Some(*root) {
+        Ok(nodes)
+    } else {
+        Err(PeerError::Mismatch)
+    }
+}
+
+impl Download {
+    async fn run(&mut self, socket: &UdpSocket, deadline: Instant) -> Result<Vec<Node>, PeerError> {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let mut out = vec![];
+        loop {
+            if self.chunks.is_some() && self.missing == 0 {
+                return self.nodes();
+            }
+            if self.remotes.iter().all(|r| r.closed) {
+                return Err(PeerError::NoPeers(self.root.hash));
+            }
+            for (addr, datagram) in self.poll() {
+                datagram.encode_into(&mut out);
+                socket.send_to(&out, addr).await?;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(match self.chunks {
+                    Some(_) => PeerError::Incomplete {
+                        missing: self.missing,
+                        chunks: self.leaves.len(),
+                    },
+                    None => PeerError::NoPeers(self.root.hash),
+                });
+            }
+            match timeout_at(
+                deadline.min(now + REQUEST_TIMEOUT / 4),
+                socket.recv_from(&mut buf),
+            )
+            .await
+            {
+                Ok(Ok((len, from))) => match Datagram::decode(&buf[..len]) {
+                    Ok(datagram) => {
+                        if let Some(ack) = self.handle(datagram, from) {
+                            ack.encode_into(&mut out);
+                            socket.send_to(&out, from).await?;
+                        }
+                    }
+                    Err(e) => tracing::debug!("Dropping datagram from {from}: {e}"),
+                },
+                // Some platforms report unreachable peers on the next receive
+                Ok(Err(e))
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                    ) => {}
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Let every peer we're still talking to know we're done
+    async fn close(&mut self, socket: &UdpSocket) {
+        for remote in self.remotes.iter().filter(|r| !r.closed) {
+            if let Some(peer_channel) = remote.peer_channel {
+                let close = Datagram::new(
+                    peer_channel,
+                    vec![Message::Handshake {
+                        channel: 0,
+                        options: Options::default(),
+                    }],
+                );
+                socket.send_to(&close.encode(), remote.addr).await.ok();
+            }
+        }
+    }
+
+    /// Give up a peer, the chunk it was sending is left to the others
+    fn give_up(&mut self, i: usize, reason: &str) {
+        let remote = &mut self.remotes[i];
+        tracing::debug!("Giving up peer {}: {reason}", remote.addr);
+        remote.closed = true;
+        remote.job = None;
+    }
+
+    /// Handshakes and requests to send
+    fn poll(&mut self) -> Vec<(SocketAddr, Datagram)> {
+        let now = Instant::now();
+        let mut assigned: HashSet<u32> = self
+            .remotes
+            .iter()
+            .filter_map(|r| r.job.as_ref().map(|job| job.chunk))
+            .collect();
+
+        let mut out = vec![];
+        for i in 0..self.remotes.len() {
+            let remote = &mut self.remotes[i];
+            if remote.closed {
+                continue;
+            }
+
+            let Some(peer_channel) = remote.peer_channel else {
+                if remote
+                    .handshake_sent
+                    .is_some_and(|t| now.duration_since(t) < REQUEST_TIMEOUT)
+                {
+                    continue;
+                }
+                if remote.failures >= MAX_FAILURES {
+                    self.give_up(i, "no handshake");
+                    continue;
+                }
+                if remote.handshake_sent.is_some() {
+                    remote.failures += 1;
+                }
+                remote.handshake_sent = Some(now);
+                out.push((
+                    remote.addr,
+                    Datagram::new(
+                        0,
+                        vec![Message::Handshake {
+                            channel: remote.channel,
+                            options: Options {
+                                swarm: Some(self.root.hash),
+                                chunks: self.chunks,
+                                token: Some(self.token.clone()),
+                            },
+                        }],
+                    ),
+                ));
+                continue;
+            };
+            let Some(chunks) = self.chunks else {
+                continue;
+            };
+
+            if remote.job.is_none() {
+                let next = (0..chunks).find(|c| {
+                    self.leaves[*c as usize].is_none()
+                        && !assigned.contains(c)
+                        && remote.have.iter().any(|r| r.contains(*c))
+                });
+                if let Some(chunk) = next {
+                    assigned.insert(chunk);
+                    remote.job = Some(Job::new(chunk));
+                }
+            }
+            let Some(job) = remote.job.as_mut() else {
+                continue;
+            };
+
+            let before = job.requested.len();
+            job.requested
+                .retain(|_, t| now.duration_since(*t) < REQUEST_TIMEOUT);
+            if job.requested.len() < before {
+                remote.failures += 1;
+                if remote.failures >= MAX_FAILURES {
+                    self.give_up(i, "requests timed out");
+                    continue;
+                }
+            }
+
+            let free = WINDOW.saturating_sub(job.requested.len());
+            let offsets: Vec<u32> = job
+                .wanted()
+                .into_iter()
+                .filter(|offset| !job.requested.contains_key(offset))
+                .take(free)
+                .collect();
+            if offsets.is_empty() {
+                continue;
+            }
+            let range = ChunkRange::single(job.chunk);
+            let messages = offsets
+                .into_iter()
+                .map(|offset| {
+                    job.requested.insert(offset, now);
+                    Message::Request { range, offset }
+                })
+                .collect();
+            out.push((remote.addr, Datagram::new(peer_channel, messages)));
+        }
+        out
+    }
+
+    /// Handle a datagram from a peer, returning the ACK to send if a chunk was completed
+    fn handle(&mut self, datagram: Datagram, from: SocketAddr) -> Option<Datagram> {
+        let i = self
+            .remotes
+            .iter()
+            .position(|r| r.channel == datagram.channel && r.addr == from && !r.closed)?;
+
+        let mut uncles = vec![];
+        for message in datagram.messages {
+            match message {
+                Message::Handshake { channel: 0, .. } => {
+                    self.give_up(i, "channel closed");
+                    return None;
+                }
+                Message::Handshake { channel, options } => {
+                    if options.swarm.is_some_and(|swarm| swarm != self.root.hash) {
+                        self.give_up(i, "wrong swarm");
+                        return None;
+                    }
+                    match (self.chunks, options.chunks) {
+                        (None, Some(chunks)) => {
+                            // Every chunk but the last has at least the minimum size, an empty item has a single one
+                            let min_size = self.chunking.min_size().max(1) as u64;
+                            if chunks == 0
+                                || u64::from(chunks) > self.root.size.div_ceil(min_size).max(1)
+                            {
+                                self.give_up(i, "implausible number of chunks");
+                                return None;
+                            }
+                            self.chunks = Some(chunks);
+                            self.leaves = vec![None; chunks as usize];
+                            self.missing = chunks as usize;
+                        }
+                        (Some(ours), Some(theirs)) if ours != theirs => {
+                            self.give_up(i, "different number of chunks");
+                            return None;
+                        }
+                        _ => {}
+                    }
+                    let remote = &mut self.remotes[i];
+                    remote.peer_channel = Some(channel);
+                    remote.failures = 0;
+                }
+                Message::Have(range) => self.remotes[i].have.push(range),
+                Message::Integrity { range, hash } => uncles.push((range, hash)),
+                Message::Data {
+                    range,
+                    offset,
+                    length,
+                    timestamp,
+                    data,
+                } => return self.receive(i, range.start, offset, length, timestamp, &data, uncles),
+                Message::Ack { .. } | Message::Request { .. } => {}
+            }
+        }
+        None
+    }
+
+    /// Store a fragment, verifying the chunk once complete
+    #[allow(clippy::too_many_arguments)]
+    fn receive(
+        &mut self,
+        i: usize,
+        chunk: u32,
+        offset: u32,
+        length: u32,
+        timestamp: u64,
+        data: &[u8],
+        uncles: Vec<(ChunkRange, Hash)>,
+    ) -> Option<Datagram> {
+        let chunks = self.chunks?;
+        let remote = &mut self.remotes[i];
+        let job = remote.job.as_mut().filter(|job| job.chunk == chunk)?;
+        // Duplicated or late fragments
+        job.requested.remove(&offset)?;
+        remote.failures = 0;
+
+        match job.length {
+            None if u64::from(length) <= self.root.size
+                && length as usize <= self.chunking.max_size() =>
+            {
+                job.length = Some(length);
+                job.data = vec![0; length as usize];
+                job.received = vec![false; (length as usize).div_ceil(FRAGMENT_SIZE).max(1)];
+                job.uncles = uncles;
+            }
+            Some(known) if known == length => {}
+            _ => {
+                self.give_up(i, "bad chunk length");
+                return None;
+            }
+        }
+
+        let start = offset as usize;
+        let end = (start + FRAGMENT_SIZE).min(length as usize);
+        let fragment = start / FRAGMENT_SIZE;
+        if fragment * FRAGMENT_SIZE != start || start > end || data.len() != end - start {
+            self.give_up(i, "bad fragment");
+            return None;
+        }
+        job.data[start..end].copy_from_slice(data);
+        job.received[fragment] = true;
+        if job.received.contains(&false) {
+            return None;
+        }
+
+        let job = remote.job.take()?;
+        let hash = chunk_hash(&job.data);
+        if !integrity::verify(&self.root.hash, chunks, chunk, hash, &job.uncles) {
+            tracing::warn!("Chunk {chunk} from {} doesn't verify", remote.addr);
+            self.give_up(i, "integrity check failed");
+            return None;
+        }
+        let peer_channel = remote.peer_channel?;
+        tracing::trace!("Got chunk {chunk} from {}", remote.addr);
+        self.leaves[chunk as usize] = Some(Node::Stored {
+            hash,
+            data: Arc::new(job.data),
+        });
+        self.missing -= 1;
+
+        Some(Datagram::new(
+            peer_channel,
+            vec![Message::Ack {
+                range: ChunkRange::single(chunk),
+                delay: super::timestamp().saturating_sub(timestamp),
+            }],
+        ))
+    }
+
+    /// Nodes of the whole tree, leaves first, with each parent following its children
+    fn nodes(&mut self) -> Result<Vec<Node>, PeerError> {
+        let mut nodes = vec![];
+        let mut level = vec![];
+        for node in std::mem::take(&mut self.leaves) {
+            let node = node.ok_or(PeerError::Mismatch)?;
+            level.push(node.chunk_info());
+            nodes.push(node);
+        }
+
+        // Leaves were verified one by one, but the number of chunks came from a peer
+        if merge_levels(level, &mut nodes) == Some(self.root) {
+            Ok(nodes)
+        } else {
+            Err(PeerError::Mismatch)
+        }
+    }
+}
+
+/// Merge `level` pairwise up to its root, as `HashTreeCapable::merge_all` does, pushing parents to `nodes`
+fn merge_levels(mut level: Vec<ChunkInfo>, nodes: &mut Vec<Node>) -> Option<ChunkInfo> {
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [l, r] => {
+                    let skipped = |c: &ChunkInfo| {
+                        Arc::new(Node::Skipped {
+                            hash: c.hash,
+                            size: c.size,
+                        })
+                    };
+                    let parent = Node::Parent {
+                        hash: merge_hashes(&l.hash, &r.hash),
+                        size: l.size + r.size,
+                        left: skipped(l),
+                        right: skipped(r),
+                    };
+                    let info = parent.chunk_info();
+                    nodes.push(parent);
+                    info
+                }
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level.first().copied()
+}
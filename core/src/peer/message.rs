@@ -0,0 +1,443 @@
+//! Wire format of PPSPP datagrams
+//!
+//! A datagram is made of the 32-bit destination channel followed by any number of messages, each one starting with
+//! its 1-byte type. All integers are big-endian. A DATA message, when present, is always the last one as its
+//! payload spans the rest of the datagram.
+
+use crate::hash::Hash;
+
+use super::PeerError;
+
+/// Protocol version, as sent in the HANDSHAKE
+pub const VERSION: u8 = 1;
+
+/// Merkle hash tree content integrity protection method
+const MERKLE_INTEGRITY: u8 = 1;
+
+/// BLAKE3 Merkle hash tree function, unassigned in the RFC
+const BLAKE3: u8 = 5;
+
+/// 32-bit chunk ranges addressing method
+const CHUNK_RANGES: u8 = 2;
+
+mod kind {
+    pub const HANDSHAKE: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const ACK: u8 = 2;
+    pub const HAVE: u8 = 3;
+    pub const INTEGRITY: u8 = 4;
+    pub const REQUEST: u8 = 8;
+}
+
+mod option {
+    pub const VERSION: u8 = 0;
+    pub const MIN_VERSION: u8 = 1;
+    pub const SWARM_ID: u8 = 2;
+    pub const INTEGRITY_METHOD: u8 = 3;
+    pub const HASH_FUNCTION: u8 = 4;
+    pub const ADDRESSING_METHOD: u8 = 6;
+    /// Number of chunks in the swarm, a distd extension
+    pub const CHUNKS: u8 = 10;
+    /// Peer token issued by the server, a distd extension
+    pub const PEER_TOKEN: u8 = 11;
+    pub const END: u8 = 255;
+}
+
+/// Inclusive range of chunks, i.e. leaves of the hash-tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl ChunkRange {
+    #[must_use]
+    pub fn single(chunk: u32) -> Self {
+        Self {
+            start: chunk,
+            end: chunk,
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, chunk: u32) -> bool {
+        (self.start..=self.end).contains(&chunk)
+    }
+}
+
+/// HANDSHAKE options we care about, the others are fixed by the supported subset of the protocol
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Swarm, i.e. root hash of the item, mandatory in the first HANDSHAKE of a channel
+    pub swarm: Option<Hash>,
+
+    /// Number of chunks of the item, sent by peers knowing it
+    pub chunks: Option<u32>,
+
+    /// Peer token of the client opening the channel, see `crate::auth::issue_peer`
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Open a channel, or close it if `channel` is 0
+    Handshake { channel: u32, options: Options },
+
+    /// A fragment of a chunk, `length` being the size of the whole chunk
+    Data {
+        range: ChunkRange,
+        offset: u32,
+        length: u32,
+        /// Microseconds since the UNIX epoch when the message was sent
+        timestamp: u64,
+        data: Vec<u8>,
+    },
+
+    /// Chunks received, with a one-way delay sample in microseconds
+    Ack { range: ChunkRange, delay: u64 },
+
+    /// Chunks available from the sender
+    Have(ChunkRange),
+
+    /// Hash of the node of the hash-tree covering `range`
+    Integrity { range: ChunkRange, hash: Hash },
+
+    /// Ask for the fragment at `offset` of each chunk in `range`
+    Request { range: ChunkRange, offset: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    /// Destination channel, 0 when opening a new one
+    pub channel: u32,
+    pub messages: Vec<Message>,
+}
+
+impl Datagram {
+    #[must_use]
+    pub fn new(channel: u32, messages: Vec<Message>) -> Self {
+        Self { channel, messages }
+    }
+
+    /// Serialize into `buf`, which is cleared first
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.extend(self.channel.to_be_bytes());
+        for message in &self.messages {
+            message.encode_into(buf);
+        }
+    }
+
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, PeerError> {
+        let mut reader = Reader(buf);
+        let channel = reader.u32()?;
+        let mut messages = vec![];
+        while !reader.0.is_empty() {
+            messages.push(Message::decode(&mut reader)?);
+        }
+        Ok(Self { channel, messages })
+    }
+}
+
+impl Message {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Handshake { channel, options } => {
+                buf.push(kind::HANDSHAKE);
+                buf.extend(channel.to_be_bytes());
+                buf.extend([option::VERSION, VERSION, option::MIN_VERSION, VERSION]);
+                if let Some(swarm) = options.swarm {
+                    buf.push(option::SWARM_ID);
+                    buf.extend(u16::try_from(swarm.as_bytes().len()).unwrap().to_be_bytes());
+                    buf.extend(swarm.as_bytes());
+                }
+                buf.extend([
+                    option::INTEGRITY_METHOD,
+                    MERKLE_INTEGRITY,
+                    option::HASH_FUNCTION,
+                    BLAKE3,
+                    option::ADDRESSING_METHOD,
+                    CHUNK_RANGES,
+                ]);
+                if let Some(chunks) = options.chunks {
+                    buf.push(option::CHUNKS);
+                    buf.extend(chunks.to_be_bytes());
+                }
+                if let Some(token) = &options.token {
+                    buf.push(option::PEER_TOKEN);
+                    buf.extend(u16::try_from(token.len()).unwrap().to_be_bytes());
+                    buf.extend(token);
+                }
+                buf.push(option::END);
+            }
+            Self::Data {
+                range,
+                offset,
+                length,
+                timestamp,
+                data,
+            } => {
+                buf.push(kind::DATA);
+                range.encode_into(buf);
+                buf.extend(offset.to_be_bytes());
+                buf.extend(length.to_be_bytes());
+                buf.extend(timestamp.to_be_bytes());
+                buf.extend(data);
+            }
+            Self::Ack { range, delay } => {
+                buf.push(kind::ACK);
+                range.encode_into(buf);
+                buf.extend(delay.to_be_bytes());
+            }
+            Self::Have(range) => {
+                buf.push(kind::HAVE);
+                range.encode_into(buf);
+            }
+            Self::Integrity { range, hash } => {
+                buf.push(kind::INTEGRITY);
+                range.encode_into(buf);
+                buf.extend(hash.as_bytes());
+            }
+            Self::Request { range, offset } => {
+                buf.push(kind::REQUEST);
+                range.encode_into(buf);
+                buf.extend(offset.to_be_bytes());
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, PeerError> {
+        Ok(match reader.u8()? {
+            kind::HANDSHAKE => Self::Handshake {
+                channel: reader.u32()?,
+                options: Options::decode(reader)?,
+            },
+            kind::DATA => Self::Data {
+                range: ChunkRange::decode(reader)?,
+                offset: reader.u32()?,
+                length: reader.u32()?,
+                timestamp: reader.u64()?,
+                data: reader.rest().to_vec(),
+            },
+            kind::ACK => Self::Ack {
+                range: ChunkRange::decode(reader)?,
+                delay: reader.u64()?,
+            },
+            kind::HAVE => Self::Have(ChunkRange::decode(reader)?),
+            kind::INTEGRITY => Self::Integrity {
+                range: ChunkRange::decode(reader)?,
+                hash: reader.hash()?,
+            },
+            kind::REQUEST => Self::Request {
+                range: ChunkRange::decode(reader)?,
+                offset: reader.u32()?,
+            },
+            _ => return Err(PeerError::Unsupported("message type")),
+        })
+    }
+}
+
+impl Options {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, PeerError> {
+        let mut options = Self::default();
+        loop {
+            match reader.u8()? {
+                option::VERSION | option::MIN_VERSION => {
+                    if reader.u8()? != VERSION {
+                        return Err(PeerError::Unsupported("protocol version"));
+                    }
+                }
+                option::SWARM_ID => {
+                    let len = reader.u16()?;
+                    if usize::from(len) != blake3::OUT_LEN {
+                        return Err(PeerError::Unsupported("swarm id"));
+                    }
+                    options.swarm = Some(reader.hash()?);
+                }
+                option::INTEGRITY_METHOD => {
+                    if reader.u8()? != MERKLE_INTEGRITY {
+                        return Err(PeerError::Unsupported(
+                            "content integrity protection method",
+                        ));
+                    }
+                }
+                option::HASH_FUNCTION => {
+                    if reader.u8()? != BLAKE3 {
+                        return Err(PeerError::Unsupported("Merkle hash tree function"));
+                    }
+                }
+                option::ADDRESSING_METHOD => {
+                    if reader.u8()? != CHUNK_RANGES {
+                        return Err(PeerError::Unsupported("chunk addressing method"));
+                    }
+                }
+                option::CHUNKS => options.chunks = Some(reader.u32()?),
+                option::PEER_TOKEN => {
+                    let len = reader.u16()?;
+                    options.token = Some(reader.bytes(len.into())?.to_vec());
+                }
+                option::END => return Ok(options),
+                _ => return Err(PeerError::Unsupported("handshake option")),
+            }
+        }
+    }
+}
+
+impl ChunkRange {
+    fn encode_into(self, buf: &mut Vec<u8>) {
+        buf.extend(self.start.to_be_bytes());
+        buf.extend(self.end.to_be_bytes());
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, PeerError> {
+        let range = Self {
+            start: reader.u32()?,
+            end: reader.u32()?,
+        };
+        if range.start > range.end {
+            return Err(PeerError::Malformed("empty chunk range"));
+        }
+        Ok(range)
+    }
+}
+
+/// Cursor over a received datagram
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], PeerError> {
+        let (bytes, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or(PeerError::Malformed("truncated message"))?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], PeerError> {
+        let (bytes, rest) = self
+            .0
+            .split_at_checked(len)
+            .ok_or(PeerError::Malformed("truncated message"))?;
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &[u8] {
+        std::mem::take(&mut self.0)
+    }
+
+    fn u8(&mut self) -> Result<u8, PeerError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PeerError> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, PeerError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, PeerError> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    fn hash(&mut self) -> Result<Hash, PeerError> {
+        Ok(Hash::from_bytes(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::hash;
+
+    use super::*;
+
+    #[test]
+    fn datagram_roundtrip() {
+        let range = ChunkRange { start: 2, end: 5 };
+        let datagram = Datagram::new(
+            42,
+            vec![
+                Message::Handshake {
+                    channel: 7,
+                    options: Options {
+                        swarm: Some(hash(b"swarm")),
+                        chunks: Some(6),
+                        token: Some(vec![4; 100]),
+                    },
+                },
+                Message::Have(range),
+                Message::Integrity {
+                    range,
+                    hash: hash(b"uncle"),
+                },
+                Message::Request { range, offset: 0 },
+                Message::Ack { range, delay: 12 },
+                Message::Data {
+                    range: ChunkRange::single(3),
+                    offset: 8,
+                    length: 100,
+                    timestamp: 1,
+                    data: vec![1, 2, 3],
+                },
+            ],
+        );
+        assert_eq!(Datagram::decode(&datagram.encode()).unwrap(), datagram);
+
+        // A bare handshake, closing the channel
+        let close = Datagram::new(
+            7,
+            vec![Message::Handshake {
+                channel: 0,
+                options: Options::default(),
+            }],
+        );
+        assert_eq!(Datagram::decode(&close.encode()).unwrap(), close);
+    }
+
+    #[test]
+    fn malformed_datagrams() {
+        assert!(matches!(
+            Datagram::decode(&[0, 0]),
+            Err(PeerError::Malformed(_))
+        ));
+
+        let mut buf = Datagram::new(1, vec![Message::Have(ChunkRange::single(0))]).encode();
+        buf.pop();
+        assert!(matches!(
+            Datagram::decode(&buf),
+            Err(PeerError::Malformed(_))
+        ));
+
+        // Unknown message type
+        assert!(matches!(
+            Datagram::decode(&[0, 0, 0, 1, 200]),
+            Err(PeerError::Unsupported(_))
+        ));
+
+        // Different hash function
+        let mut buf = Datagram::new(
+            0,
+            vec![Message::Handshake {
+                channel: 1,
+                options: Options::default(),
+            }],
+        )
+        .encode();
+        let i = buf.iter().rposition(|&b| b == BLAKE3).unwrap();
+        buf[i] = 0;
+        assert!(matches!(
+            Datagram::decode(&buf),
+            Err(PeerError::Unsupported(_))
+        ));
+    }
+}
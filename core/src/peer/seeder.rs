@@ -0,0 +1,337 @@
+//! Serving chunks of the items held in a `ChunkStorage` to other peers
+//!
+//! Only clients of our realm get anything: a channel is opened for a HANDSHAKE carrying a peer token issued by the
+//! server for the realm (see `crate::auth::issue_peer`), up to `MAX_CHANNELS` at once and `MAX_CLIENT_CHANNELS` per
+//! client. Other handshakes are ignored before looking at storage.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{net::UdpSocket, sync::RwLock};
+
+use uuid::Uuid;
+
+use crate::{
+    auth,
+    chunk_storage::{node::ArcChunk, ChunkStorage},
+    hash::Hash,
+    peer::{integrity::Tree, FRAGMENT_SIZE, MAX_DATAGRAM, WINDOW},
+    signature::PublicKey,
+};
+
+use super::{
+    message::{ChunkRange, Datagram, Message, Options},
+    PeerError,
+};
+
+/// Channels idle for longer than this are closed
+const CHANNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most channels open at once, handshakes beyond are ignored until some are closed or time out
+pub const MAX_CHANNELS: usize = 1024;
+
+/// Most channels open at once by a single client, a leecher opens one per swarm it's downloading
+pub const MAX_CLIENT_CHANNELS: usize = 16;
+
+/// A channel opened by a remote peer to one of our swarms
+struct Channel {
+    peer: SocketAddr,
+
+    /// Client the channel was opened by, as in its peer token
+    client: Uuid,
+    destination: u32,
+    tree: Arc<Tree>,
+    last_seen: Instant,
+
+    /// Last chunk read from storage, most requests are for the following fragments of the same chunk
+    cached: Option<(u32, ArcChunk)>,
+}
+
+/// Serves every item held in a `ChunkStorage`, each item being the swarm identified by its root hash
+pub struct Seeder<S> {
+    socket: UdpSocket,
+    storage: Arc<RwLock<S>>,
+    channels: HashMap<u32, Channel>,
+
+    /// Public key of the server, peer tokens are signed with
+    server: PublicKey,
+
+    /// Realm we serve the clients of
+    realm: String,
+}
+
+impl<S> Seeder<S>
+where
+    S: ChunkStorage + Send + Sync,
+{
+    /// Serve the items in `storage` on `addr` to clients of `realm` with a peer token signed by `server`
+    pub async fn bind(
+        addr: SocketAddr,
+        storage: Arc<RwLock<S>>,
+        server: PublicKey,
+        realm: &str,
+    ) -> Result<Self, PeerError> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            storage,
+            channels: HashMap::new(),
+            server,
+            realm: realm.to_string(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, PeerError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Serve peers forever
+    ///
+    /// Malformed datagrams and requests for swarms we don't have are ignored, only socket errors are returned.
+    pub async fn serve(mut self) -> Result<(), PeerError> {
+        tracing::info!("Serving peers on {}", self.socket.local_addr()?);
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+            match Datagram::decode(&buf[..len]) {
+                Ok(datagram) => {
+                    let replies = self.handle(datagram, from);
+                    let mut out = vec![];
+                    for reply in replies {
+                        reply.encode_into(&mut out);
+                        self.socket.send_to(&out, from).await?;
+                    }
+                }
+                Err(e) => tracing::debug!("Dropping datagram from {from}: {e}"),
+            }
+        }
+    }
+
+    fn handle(&mut self, datagram: Datagram, from: SocketAddr) -> Vec<Datagram> {
+        if datagram.channel == 0 {
+            return self.open(&datagram.messages, from).into_iter().collect();
+        }
+
+        let Some(channel) = self
+            .channels
+            .get_mut(&datagram.channel)
+            .filter(|c| c.peer == from)
+        else {
+            tracing::trace!("Unknown channel {} from {from}", datagram.channel);
+            return vec![];
+        };
+        channel.last_seen = Instant::now();
+
+        let mut replies = vec![];
+        for message in datagram.messages {
+            match message {
+                Message::Handshake { channel: 0, .. } => {
+                    tracing::debug!("Channel {} closed by {from}", datagram.channel);
+                    self.channels.remove(&datagram.channel);
+                    break;
+                }
+                // Replies are built in memory, a window of fragments is all a leecher asks for at once
+                Message::Request { range, offset } => {
+                    let end = range.end.min(channel.tree.chunks() - 1);
+                    for chunk in (range.start..=end).take(WINDOW.saturating_sub(replies.len())) {
+                        if let Some(reply) = Self::fragment(&self.storage, channel, chunk, offset) {
+                            replies.push(reply);
+                        }
+                    }
+                }
+                Message::Ack { range, delay } => {
+                    tracing::trace!("{from} got chunks {range:?}, delay {delay}us");
+                }
+                _ => {}
+            }
+        }
+        replies
+    }
+
+    /// Open a channel for the swarm in the HANDSHAKE, refusing it if we don't hold the whole item
+    fn open(&mut self, messages: &[Message], from: SocketAddr) -> Option<Datagram> {
+        let Some(Message::Handshake {
+            channel: destination,
+            options:
+                Options {
+                    swarm: Some(swarm),
+                    token: Some(token),
+                    ..
+                },
+        }) = messages.first()
+        else {
+            return None;
+        };
+        if *destination == 0 {
+            return None;
+        }
+        let client = self.admit(token, from)?;
+
+        let now = Instant::now();
+        self.channels
+            .retain(|_, c| now.duration_since(c.last_seen) < CHANNEL_TIMEOUT);
+
+        // Handshakes are sent again until answered, the channel opened for the first one is answered again
+        if let Some((id, channel)) = self.channels.iter().find(|(_, c)| {
+            c.peer == from && c.destination == *destination && c.tree.root() == swarm
+        }) {
+            return Some(accepted(*id, *destination, swarm, channel.tree.chunks()));
+        }
+        let opened = self.channels.values().filter(|c| c.client == client);
+        if self.channels.len() >= MAX_CHANNELS || opened.count() >= MAX_CLIENT_CHANNELS {
+            tracing::debug!("Too many open channels, ignoring handshake from {from}");
+            return None;
+        }
+
+        // Storage may be locked for writing for a long time, e.g. while receiving an item: peers are not waited
+        // for and will ask again
+        let Ok(storage) = self.storage.try_read() else {
+            tracing::trace!("Storage busy, ignoring handshake from {from}");
+            return None;
+        };
+        let Some(tree) = self.tree(&*storage, swarm) else {
+            tracing::debug!("{from} asked for unknown swarm {swarm}");
+            return Some(Datagram::new(
+                *destination,
+                vec![Message::Handshake {
+                    channel: 0,
+                    options: Options::default(),
+                }],
+            ));
+        };
+
+        let channel = loop {
+            let id = random_channel();
+            if !self.channels.contains_key(&id) {
+                break id;
+            }
+        };
+        tracing::debug!("Opening channel {channel} to {from} for swarm {swarm}");
+        drop(storage);
+        let chunks = tree.chunks();
+        self.channels.insert(
+            channel,
+            Channel {
+                peer: from,
+                client,
+                destination: *destination,
+                tree,
+                last_seen: now,
+                cached: None,
+            },
+        );
+        Some(accepted(channel, *destination, swarm, chunks))
+    }
+
+    /// Client the peer `token` sent by `from` was issued to, if it's valid and for our realm
+    fn admit(&self, token: &[u8], from: SocketAddr) -> Option<Uuid> {
+        match auth::verify_peer(&self.server, token) {
+            Ok(claims) if claims.realm == self.realm => Some(claims.uuid),
+            Ok(claims) => {
+                tracing::debug!("{from} has a peer token for realm '{}'", claims.realm);
+                None
+            }
+            Err(e) => {
+                tracing::debug!("Bad peer token from {from}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Tree of the item with root `swarm`, shared with open channels to the same swarm
+    fn tree(&self, storage: &S, swarm: &Hash) -> Option<Arc<Tree>> {
+        if let Some(channel) = self.channels.values().find(|c| c.tree.root() == swarm) {
+            return Some(channel.tree.clone());
+        }
+        let root = storage.get(swarm)?;
+        let leaves = root.flatten();
+        if leaves.is_empty() {
+            return None;
+        }
+        let tree = Tree::new(leaves);
+        (tree.root() == swarm).then(|| Arc::new(tree))
+    }
+
+    /// DATA message with the fragment at `offset` of `chunk`, preceded by its uncle hashes for the first fragment
+    fn fragment(
+        storage: &RwLock<S>,
+        channel: &mut Channel,
+        chunk: u32,
+        offset: u32,
+    ) -> Option<Datagram> {
+        let data = match &channel.cached {
+            Some((cached, data)) if *cached == chunk => data.clone(),
+            _ => {
+                let hash = channel.tree.leaves()[chunk as usize];
+                let data = storage.try_read().ok()?.get(&hash)?.stored_data()?;
+                channel.cached = Some((chunk, data.clone()));
+                data
+            }
+        };
+        let start = offset as usize;
+        if start > data.len() || (start == data.len() && start != 0) {
+            return None;
+        }
+        let end = data.len().min(start + FRAGMENT_SIZE);
+
+        let range = ChunkRange::single(chunk);
+        let mut messages = vec![];
+        if offset == 0 {
+            messages.extend(
+                channel
+                    .tree
+                    .uncles(chunk)
+                    .into_iter()
+                    .map(|(range, hash)| Message::Integrity { range, hash }),
+            );
+        }
+        messages.push(Message::Data {
+            range,
+            offset,
+            length: u32::try_from(data.len()).ok()?,
+            timestamp: super::timestamp(),
+            data: data[start..end].to_vec(),
+        });
+        Some(Datagram::new(channel.destination, messages))
+    }
+}
+
+/// HANDSHAKE answering the one of channel `destination`, from our end `channel` of the swarm with `chunks`
+fn accepted(channel: u32, destination: u32, swarm: &Hash, chunks: u32) -> Datagram {
+    Datagram::new(
+        destination,
+        vec![
+            Message::Handshake {
+                channel,
+                options: Options {
+                    swarm: Some(*swarm),
+                    chunks: Some(chunks),
+                    ..Options::default()
+                },
+            },
+            Message::Have(ChunkRange {
+                start: 0,
+                end: chunks - 1,
+            }),
+        ],
+    )
+}
+
+/// Random non-zero channel id
+pub(crate) fn random_channel() -> u32 {
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let rng = SystemRandom::new();
+    loop {
+        let mut bytes = [0u8; 4];
+        // The system RNG failing is not something we can recover from
+        rng.fill(&mut bytes).expect("system RNG failure");
+        let id = u32::from_be_bytes(bytes);
+        if id != 0 {
+            return id;
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Ed25519 signatures, used by clients to check that items and metadata actually come from the server
+//!
+//! The server signs both each item's metadata and the serialized `ServerMetadata` it sends to clients, clients
+//! verify them against the server public key they're configured with.
+//!
+//! Changes to a realm with an owner key are signed by its owner instead, see `RealmChange`.
+
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::hash::Hash;
+
+/// Raw Ed25519 signature bytes
+pub type Signature = Vec<u8>;
+
+/// Raw Ed25519 public key
+pub type PublicKey = [u8; 32];
+
+/// Length of an Ed25519 signature
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Missing signature")]
+    Missing,
+
+    #[error("Invalid signature")]
+    Invalid,
+
+    #[error("Invalid public key format")]
+    BadPublicKey,
+
+    #[error("Cannot serialize data to sign")]
+    Unsignable,
+
+    #[error("Unknown or expired nonce")]
+    Stale,
+}
+
+/// Verify `signature` of `data` with the Ed25519 `public_key`
+pub fn verify(public_key: &PublicKey, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(data, signature)
+        .map_err(|_| Error::Invalid)
+}
+
+/// Encode a public key as base64, as expected in configuration files
+#[must_use]
+pub fn encode_public_key(public_key: &[u8]) -> String {
+    STANDARD.encode(public_key)
+}
+
+/// Encode a challenge nonce as base64, as handed out to realm owners signing changes
+#[must_use]
+pub fn encode_nonce(nonce: &[u8]) -> String {
+    STANDARD.encode(nonce)
+}
+
+/// Decode a base64-encoded public key
+pub fn decode_public_key(encoded: &str) -> Result<PublicKey, Error> {
+    STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(Error::BadPublicKey)
+}
+
+/// Decode a base64-encoded signature
+pub fn decode_signature(encoded: &str) -> Result<Signature, Error> {
+    STANDARD.decode(encoded.trim()).map_err(|_| Error::Invalid)
+}
+
+/// Realm of clients not asking for a specific one
+pub const DEFAULT_REALM: &str = "default";
+
+/// A change to a realm, which the realm owner signs when the realm has an owner key
+///
+/// The signed data names the realm along with everything the change is about, so that a signature can't be replayed
+/// to another realm, path, content or feed. It also has a challenge nonce handed out by the server, which takes it
+/// only once and shortly after (see `auth::Challenges`), so that a signature can't be replayed later either.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum RealmChange<'a> {
+    /// Publish the item with `root` at `path`, into `feed` if any
+    Publish {
+        realm: &'a str,
+        path: &'a Path,
+        root: &'a Hash,
+        feed: Option<&'a str>,
+    },
+    CreateFeed {
+        realm: &'a str,
+        feed: &'a str,
+    },
+    RenameFeed {
+        realm: &'a str,
+        feed: &'a str,
+        new_name: &'a str,
+    },
+    DeleteFeed {
+        realm: &'a str,
+        feed: &'a str,
+    },
+    AddToFeed {
+        realm: &'a str,
+        feed: &'a str,
+        path: &'a Path,
+    },
+    RemoveFromFeed {
+        realm: &'a str,
+        feed: &'a str,
+        path: &'a Path,
+    },
+}
+
+/// Split an owner signature made by `RealmChange::sign` into the nonce and the Ed25519 signature
+#[must_use]
+pub fn split_nonce(signed: &[u8]) -> Option<(&[u8], &[u8])> {
+    let split = signed.len().checked_sub(SIGNATURE_LEN)?;
+    Some(signed.split_at(split))
+}
+
+impl RealmChange<'_> {
+    /// Data to sign, the bitcode serialization of the change along with `nonce`
+    ///
+    /// # Errors
+    ///
+    /// Fails for paths that are not valid UTF-8
+    pub fn signed_data(&self, nonce: &[u8]) -> Result<Vec<u8>, Error> {
+        bitcode::serialize(&(nonce, self)).map_err(|_| Error::Unsignable)
+    }
+
+    /// Sign the change with `key_pair`, for the challenge `nonce`
+    ///
+    /// The owner signature sent along with the change is the nonce followed by the Ed25519 signature.
+    pub fn sign(&self, key_pair: &Ed25519KeyPair, nonce: &[u8]) -> Result<Signature, Error> {
+        let mut signed = nonce.to_vec();
+        signed.extend_from_slice(key_pair.sign(&self.signed_data(nonce)?).as_ref());
+        Ok(signed)
+    }
+
+    /// Check that `signed`, as made by `sign`, comes from the key pair of `public_key`
+    ///
+    /// Returns the nonce the change was signed for, which is up to the caller to check.
+    pub fn verify<'s>(&self, public_key: &PublicKey, signed: &'s [u8]) -> Result<&'s [u8], Error> {
+        let (nonce, signature) = split_nonce(signed).ok_or(Error::Invalid)?;
+        verify(public_key, &self.signed_data(nonce)?, signature)?;
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::*;
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn public_key(key_pair: &Ed25519KeyPair) -> PublicKey {
+        key_pair.public_key().as_ref().try_into().unwrap()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let key_pair = key_pair();
+        let public_key = public_key(&key_pair);
+        let signature = key_pair.sign(b"some data");
+
+        assert_eq!(
+            verify(&public_key, b"some data", signature.as_ref()),
+            Ok(())
+        );
+        assert_eq!(
+            verify(&public_key, b"other data", signature.as_ref()),
+            Err(Error::Invalid)
+        );
+
+        let other = self::public_key(&self::key_pair());
+        assert_eq!(
+            verify(&other, b"some data", signature.as_ref()),
+            Err(Error::Invalid)
+        );
+    }
+
+    #[test]
+    fn realm_changes_are_bound() {
+        let key_pair = key_pair();
+        let public_key = public_key(&key_pair);
+        let root = crate::hash::hash(b"content");
+        let publish = |realm, path| RealmChange::Publish {
+            realm,
+            path: Path::new(path),
+            root: &root,
+            feed: None,
+        };
+        let nonce = [7u8; 32];
+        let signed = publish("r", "a").sign(&key_pair, &nonce).unwrap();
+
+        let verify =
+            |change: RealmChange<'_>| change.verify(&public_key, &signed).map(<[u8]>::to_vec);
+        assert_eq!(verify(publish("r", "a")), Ok(nonce.to_vec()));
+        assert_eq!(verify(publish("r", "b")), Err(Error::Invalid));
+        assert_eq!(verify(publish("other", "a")), Err(Error::Invalid));
+        let add = RealmChange::AddToFeed {
+            realm: "r",
+            feed: "f",
+            path: Path::new("a"),
+        };
+        assert_eq!(verify(add), Err(Error::Invalid));
+
+        // The nonce can't be swapped for another one
+        let mut swapped = signed.clone();
+        swapped[0] ^= 1;
+        assert_eq!(
+            publish("r", "a").verify(&public_key, &swapped),
+            Err(Error::Invalid)
+        );
+        assert_eq!(
+            publish("r", "a").verify(&public_key, &signed[..10]),
+            Err(Error::Invalid)
+        );
+    }
+
+    #[test]
+    fn public_key_encoding() {
+        let public_key = public_key(&key_pair());
+        let encoded = encode_public_key(&public_key);
+        assert_eq!(decode_public_key(&encoded), Ok(public_key));
+        assert_eq!(decode_public_key("not a key"), Err(Error::BadPublicKey));
+        assert_eq!(
+            decode_public_key(&encode_public_key(&[0u8; 16])),
+            Err(Error::BadPublicKey)
+        );
+    }
+}
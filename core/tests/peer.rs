@@ -0,0 +1,388 @@
+//! Loopback swarms, every peer running in the same process
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use tokio::{net::UdpSocket, sync::RwLock};
+use uuid::Uuid;
+
+use distd_core::{
+    auth,
+    chunk_storage::{hashmap_storage::HashMapStorage, ChunkStorage},
+    chunker::{Chunking, FixedSize},
+    directory::Manifest,
+    hash::Hash,
+    item::{Item, Kind as ItemKind},
+    peer::{
+        fetch, fetch_directory,
+        message::{ChunkRange, Datagram, Message, Options},
+        seeder::MAX_CLIENT_CHANNELS,
+        PeerError, Seeder,
+    },
+    signature::{PublicKey, DEFAULT_REALM},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Key pair of the server issuing peer tokens
+fn server_key() -> Ed25519KeyPair {
+    Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap()
+}
+
+/// Peer token for a new client of `realm`
+fn token(realm: &str) -> Vec<u8> {
+    let (token, _) = auth::issue_peer(&server_key(), Uuid::new_v4(), realm, TIMEOUT).unwrap();
+    token
+}
+
+fn random_data(len: usize, seed: u64) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+    data
+}
+
+fn create_item(storage: &mut HashMapStorage, path: &str, data: &[u8], chunk_size: u32) -> Item {
+    storage
+        .create_item(
+            path.into(),
+            PathBuf::from(path),
+            0,
+            None,
+            Bytes::from(data.to_vec()),
+            Chunking::Fixed(FixedSize { size: chunk_size }),
+        )
+        .unwrap()
+}
+
+/// Start a seeder on a random loopback port
+async fn seeder(storage: HashMapStorage) -> SocketAddr {
+    let server = PublicKey::try_from(server_key().public_key().as_ref()).unwrap();
+    let seeder = Seeder::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Arc::new(RwLock::new(storage)),
+        server,
+        DEFAULT_REALM,
+    )
+    .await
+    .unwrap();
+    let addr = seeder.local_addr().unwrap();
+    tokio::spawn(seeder.serve());
+    addr
+}
+
+/// Fetch `item` from `peers` and rebuild it in a new storage
+async fn fetch_item(item: &Item, peers: &[SocketAddr]) -> Result<Vec<u8>, PeerError> {
+    let metadata = &item.metadata;
+    let token = token(DEFAULT_REALM);
+    let nodes = fetch(&metadata.root, &metadata.chunking, peers, &token, TIMEOUT).await?;
+    let mut storage = HashMapStorage::default();
+    let received = storage
+        .receive_item(item.metadata.clone(), tokio_stream::iter(nodes))
+        .await
+        .unwrap();
+    assert_eq!(received.metadata.root, item.metadata.root);
+    Ok(storage.get(received.root()).unwrap().clone_data())
+}
+
+#[tokio::test]
+async fn fetch_from_multiple_peers() {
+    // Some chunks are repeated, and the last one is shorter
+    let mut a = random_data(300_000, 1);
+    a.extend(a[..40_000].to_vec());
+    let b = random_data(5_000, 2);
+
+    let mut peers = vec![];
+    let mut items = vec![];
+    for _ in 0..3 {
+        let mut storage = HashMapStorage::default();
+        items = vec![
+            create_item(&mut storage, "a", &a, 20_000),
+            create_item(&mut storage, "b", &b, 20_000),
+        ];
+        peers.push(seeder(storage).await);
+    }
+
+    // Every item is its own swarm
+    assert_eq!(fetch_item(&items[0], &peers).await.unwrap(), a);
+    assert_eq!(fetch_item(&items[1], &peers).await.unwrap(), b);
+}
+
+#[tokio::test]
+async fn fetch_directory_item() {
+    let dir = std::env::temp_dir().join(format!("distd-peer-directory-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let a = random_data(30_000, 8);
+    std::fs::write(dir.join("a"), &a).unwrap();
+    std::fs::write(dir.join("b"), &a).unwrap();
+    std::fs::write(dir.join("empty"), b"").unwrap();
+    std::fs::write(dir.join("sub/c"), random_data(7_000, 9)).unwrap();
+
+    let chunking = Chunking::Fixed(FixedSize { size: 4_000 });
+    let mut storage = HashMapStorage::default();
+    let (manifest, root) = Manifest::insert(&mut storage, &dir, &chunking).unwrap();
+    let mut item = Item::new("dir".into(), "dir".into(), 0, None, chunking, &root);
+    item.metadata.kind = ItemKind::Directory(manifest.clone());
+    let data = root.clone_data();
+    let peer = seeder(storage).await;
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // Files are merged into the item tree by pairs of files, not of chunks: the item root is no swarm
+    let metadata = &item.metadata;
+    let token = token(DEFAULT_REALM);
+    let res = fetch(&metadata.root, &chunking, &[peer], &token, TIMEOUT).await;
+    assert!(matches!(res, Err(PeerError::NoPeers(_))));
+
+    let nodes = fetch_directory(
+        &metadata.root,
+        &manifest,
+        &chunking,
+        &[peer],
+        &token,
+        TIMEOUT,
+    )
+    .await
+    .unwrap();
+    let mut storage = HashMapStorage::default();
+    let received = storage
+        .receive_item(metadata.clone(), tokio_stream::iter(nodes))
+        .await
+        .unwrap();
+    assert_eq!(received.metadata.root, metadata.root);
+    assert_eq!(storage.get(received.root()).unwrap().clone_data(), data);
+}
+
+#[tokio::test]
+async fn empty_item() {
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "empty", &[], 1024);
+    let peer = seeder(storage).await;
+    assert!(fetch_item(&item, &[peer]).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn skip_unusable_peers() {
+    let data = random_data(100_000, 3);
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &data, 10_000);
+
+    // A peer without the item, one that doesn't answer at all, and the one holding it
+    let empty = seeder(HashMapStorage::default()).await;
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let holder = seeder(storage).await;
+
+    let peers = [empty, silent.local_addr().unwrap(), holder];
+    assert_eq!(fetch_item(&item, &peers).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn no_peer_holds_the_item() {
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &random_data(1000, 4), 100);
+
+    let peers = [
+        seeder(HashMapStorage::default()).await,
+        seeder(HashMapStorage::default()).await,
+    ];
+    let metadata = &item.metadata;
+    let token = token(DEFAULT_REALM);
+    let res = fetch(&metadata.root, &metadata.chunking, &peers, &token, TIMEOUT).await;
+    assert!(matches!(res, Err(PeerError::NoPeers(hash)) if hash == metadata.root.hash));
+}
+
+/// A peer claiming to hold everything, sending garbage instead
+async fn liar(chunks: u32) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut remote_channel = 0;
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let datagram = Datagram::decode(&buf[..len]).unwrap();
+            let mut replies = vec![];
+            for message in datagram.messages {
+                match message {
+                    Message::Handshake { channel, options } if channel != 0 => {
+                        remote_channel = channel;
+                        replies.push(Message::Handshake {
+                            channel: 1,
+                            options: Options {
+                                swarm: options.swarm,
+                                chunks: Some(chunks),
+                                token: None,
+                            },
+                        });
+                        replies.push(Message::Have(ChunkRange {
+                            start: 0,
+                            end: chunks - 1,
+                        }));
+                    }
+                    Message::Request { range, offset: 0 } => replies.push(Message::Data {
+                        range,
+                        offset: 0,
+                        length: 100,
+                        timestamp: 0,
+                        data: vec![0xff; 100],
+                    }),
+                    _ => {}
+                }
+            }
+            for reply in replies {
+                let datagram = Datagram::new(remote_channel, vec![reply]);
+                socket.send_to(&datagram.encode(), from).await.unwrap();
+            }
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn corrupted_chunks_are_refetched() {
+    let data = random_data(50_000, 5);
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &data, 5_000);
+
+    // The liar answers first, getting the first chunk assigned
+    let peers = [liar(10).await, seeder(storage).await];
+    assert_eq!(fetch_item(&item, &peers).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn implausible_number_of_chunks() {
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &random_data(1000, 6), 100);
+
+    // Chunks of a byte would add up to the item size, chunking tells there are 10 at most
+    let peers = [liar(1000).await];
+    let metadata = &item.metadata;
+    let token = token(DEFAULT_REALM);
+    let res = fetch(&metadata.root, &metadata.chunking, &peers, &token, TIMEOUT).await;
+    assert!(matches!(res, Err(PeerError::NoPeers(_))));
+}
+
+#[tokio::test]
+async fn requests_are_capped() {
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &random_data(100_000, 7), 100);
+    let peer = seeder(storage).await;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let swarm = item.metadata.root.hash;
+    let channel = handshake(&socket, peer, 1, &swarm, Some(token(DEFAULT_REALM)))
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    // Every chunk at once, out of the 1000 only a window is sent back
+    let request = Message::Request {
+        range: ChunkRange {
+            start: 0,
+            end: u32::MAX,
+        },
+        offset: 0,
+    };
+    let requests = Datagram::new(channel, vec![request.clone(), request]);
+    socket.send_to(&requests.encode(), peer).await.unwrap();
+    let mut replies = 0;
+    while tokio::time::timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+        .await
+        .is_ok()
+    {
+        replies += 1;
+    }
+    assert_eq!(replies, 16);
+}
+
+/// Open channel `destination` to `peer` for `swarm`, returning the channel of the peer if it answers
+async fn handshake(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    destination: u32,
+    swarm: &Hash,
+    token: Option<Vec<u8>>,
+) -> Option<u32> {
+    let handshake = Datagram::new(
+        0,
+        vec![Message::Handshake {
+            channel: destination,
+            options: Options {
+                swarm: Some(*swarm),
+                chunks: None,
+                token,
+            },
+        }],
+    );
+    socket.send_to(&handshake.encode(), peer).await.unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+    let (len, _) = tokio::time::timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+        .await
+        .ok()?
+        .unwrap();
+    match Datagram::decode(&buf[..len]).unwrap().messages[0] {
+        Message::Handshake { channel, .. } => Some(channel),
+        _ => panic!("expected an handshake"),
+    }
+}
+
+#[tokio::test]
+async fn peer_token_required() {
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &random_data(1000, 10), 100);
+    let swarm = item.metadata.root.hash;
+    let peer = seeder(storage).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // No token, a token for another realm, or one signed by someone else
+    assert_eq!(handshake(&socket, peer, 1, &swarm, None).await, None);
+    let other_realm = Some(token("team"));
+    assert_eq!(handshake(&socket, peer, 2, &swarm, other_realm).await, None);
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+    let (forged, _) = auth::issue_peer(&key_pair, Uuid::new_v4(), DEFAULT_REALM, TIMEOUT).unwrap();
+    assert_eq!(
+        handshake(&socket, peer, 3, &swarm, Some(forged)).await,
+        None
+    );
+
+    assert!(
+        handshake(&socket, peer, 4, &swarm, Some(token(DEFAULT_REALM)))
+            .await
+            .is_some()
+    );
+}
+
+#[tokio::test]
+async fn channels_are_capped() {
+    let mut storage = HashMapStorage::default();
+    let item = create_item(&mut storage, "item", &random_data(1000, 11), 100);
+    let swarm = item.metadata.root.hash;
+    let peer = seeder(storage).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // Handshakes sent again get the same channel
+    let token = token(DEFAULT_REALM);
+    let first = handshake(&socket, peer, 1, &swarm, Some(token.clone())).await;
+    assert!(first.is_some());
+    assert_eq!(
+        handshake(&socket, peer, 1, &swarm, Some(token.clone())).await,
+        first
+    );
+
+    for destination in 2..=u32::try_from(MAX_CLIENT_CHANNELS).unwrap() {
+        assert!(
+            handshake(&socket, peer, destination, &swarm, Some(token.clone()))
+                .await
+                .is_some()
+        );
+    }
+    assert_eq!(
+        handshake(&socket, peer, 100, &swarm, Some(token)).await,
+        None
+    );
+
+    // Other clients still get theirs
+    let other = Some(self::token(DEFAULT_REALM));
+    assert!(handshake(&socket, peer, 101, &swarm, other).await.is_some());
+}
@@ -0,0 +1,327 @@
+//! Access control for the REST API and the gRPC service
+//!
+//! Requests carry one of the API keys configured on the server as `Authorization: Bearer <key>`, granting a role.
+//! Roles are ordered, each one may do whatever the ones below it do:
+//! - readers get metadata and chunks, and may register as clients
+//! - publishers may also publish items and manage feeds
+//! - admins may also list and revoke clients, create realms, and look at and collect garbage in storage
+//!
+//! Requests without a key get the `anonymous` role, readers unless configured otherwise. Clients sending their session
+//! token (see `auth`) are readers at least.
+//!
+//! Keys are bound to a realm, the default one unless configured otherwise, and requests made with them are refused in
+//! any other realm. So are requests without a key, bound to the default realm, and clients, bound to the realm they
+//! registered into. Only admin keys not bound to any realm may work in whichever realm they ask for, and on what's
+//! shared among realms: storage, garbage collection and the realms themselves.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::realm::{self, Name as RealmName};
+
+/// Header, and gRPC metadata, carrying the API key
+pub const HEADER: &str = "authorization";
+
+/// What a request is allowed to do, see the module documentation
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Nothing but asking for the server version
+    #[default]
+    None,
+    Reader,
+    Publisher,
+    Admin,
+}
+
+impl Role {
+    /// Check that the role grants what `required` does
+    pub fn check(self, required: Self) -> Result<(), Error> {
+        if self >= required {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(required))
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Reader => "reader",
+            Self::Publisher => "publisher",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("Unknown API key")]
+    UnknownKey,
+
+    #[error("Missing API key, {0} role needed")]
+    Unauthenticated(Role),
+
+    #[error("Not allowed, {0} role needed")]
+    Forbidden(Role),
+
+    #[error("Not allowed outside of realm '{0}'")]
+    OtherRealm(RealmName),
+}
+
+/// An API key configured on the server
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Who the key was handed out to, informative only
+    #[serde(default)]
+    pub name: String,
+
+    pub key: String,
+
+    pub role: Role,
+
+    /// Realm the key is bound to, the default one if missing but for admin keys, which may work in any realm
+    #[serde(default)]
+    pub realm: Option<RealmName>,
+}
+
+/// What a request is granted by its API key, or lack of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub role: Role,
+
+    /// Realm requests are bound to, `None` if they may work in any realm
+    pub realm: Option<RealmName>,
+}
+
+impl Grant {
+    /// Realm to work in, `requested` if any and allowed, the bound one or the default one otherwise
+    pub fn realm(&self, requested: Option<&RealmName>) -> Result<RealmName, Error> {
+        match (&self.realm, requested) {
+            (Some(bound), Some(requested)) if bound != requested => {
+                Err(Error::OtherRealm(bound.clone()))
+            }
+            (Some(realm), _) | (None, Some(realm)) => Ok(realm.clone()),
+            (None, None) => Ok(realm::DEFAULT.into()),
+        }
+    }
+
+    /// Check that requests may work on what's shared among realms, which only the ones bound to no realm may
+    pub fn global(&self) -> Result<(), Error> {
+        match &self.realm {
+            Some(bound) => Err(Error::OtherRealm(bound.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// API keys accepted by the server, along with the role of requests without one
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    /// Name of the holder of each key and what it grants, by hash so that lookups don't leak keys through timing
+    keys: HashMap<blake3::Hash, (String, Grant)>,
+
+    /// Role of requests without an API key
+    pub anonymous: Role,
+}
+
+impl Default for AccessControl {
+    /// No API key, anyone is a reader
+    fn default() -> Self {
+        Self::new(&[], Role::Reader)
+    }
+}
+
+impl AccessControl {
+    #[must_use]
+    pub fn new(keys: &[ApiKey], anonymous: Role) -> Self {
+        Self {
+            keys: keys
+                .iter()
+                .map(|key| {
+                    let hash = blake3::hash(key.key.as_bytes());
+                    let realm = match (&key.realm, key.role) {
+                        (None, Role::Admin) => None,
+                        (realm, _) => Some(realm.clone().unwrap_or_else(|| realm::DEFAULT.into())),
+                    };
+                    let grant = Grant {
+                        role: key.role,
+                        realm,
+                    };
+                    (hash, (key.name.clone(), grant))
+                })
+                .collect(),
+            anonymous,
+        }
+    }
+
+    /// What `credentials`, the value of the `HEADER` of a request if it has one, grant
+    pub fn grant(&self, credentials: Option<&str>) -> Result<Grant, Error> {
+        let Some(credentials) = credentials else {
+            return Ok(Grant {
+                role: self.anonymous,
+                realm: Some(realm::DEFAULT.into()),
+            });
+        };
+        let key = credentials
+            .strip_prefix("Bearer ")
+            .ok_or(Error::UnknownKey)?
+            .trim();
+        let (name, grant) = self
+            .keys
+            .get(&blake3::hash(key.as_bytes()))
+            .ok_or(Error::UnknownKey)?;
+        tracing::trace!("API key of '{name}' granting {} role", grant.role);
+        Ok(grant.clone())
+    }
+
+    /// Role granted by `credentials`, see `grant`
+    pub fn role(&self, credentials: Option<&str>) -> Result<Role, Error> {
+        self.grant(credentials).map(|grant| grant.role)
+    }
+
+    /// Check that `credentials` grant the `required` role, returning what they grant
+    pub fn authorize(&self, credentials: Option<&str>, required: Role) -> Result<Grant, Error> {
+        let grant = self.grant(credentials)?;
+        match grant.role.check(required) {
+            Err(_) if credentials.is_none() => Err(Error::Unauthenticated(required)),
+            res => res.map(|()| grant),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use distd_core::chunk_storage::hashmap_storage::HashMapStorage;
+    use tonic::service::Interceptor;
+    use tonic::Request;
+
+    use super::{AccessControl, ApiKey, Error, Role, HEADER};
+    use crate::grpc::TokenAuthInterceptor;
+    use crate::realm::{self, Name as RealmName};
+    use crate::Server;
+
+    fn key(key: &str, role: Role) -> ApiKey {
+        ApiKey {
+            name: String::new(),
+            key: key.into(),
+            role,
+            realm: None,
+        }
+    }
+
+    #[test]
+    fn roles_granted_by_keys() {
+        let access = AccessControl::new(
+            &[key("p", Role::Publisher), key("a", Role::Admin)],
+            Role::Reader,
+        );
+
+        assert_eq!(access.role(None), Ok(Role::Reader));
+        assert_eq!(access.role(Some("Bearer p")), Ok(Role::Publisher));
+        assert_eq!(access.role(Some("Bearer a")), Ok(Role::Admin));
+        assert_eq!(access.role(Some("Bearer x")), Err(Error::UnknownKey));
+        assert_eq!(access.role(Some("p")), Err(Error::UnknownKey));
+
+        let role = |credentials, required| access.authorize(credentials, required).map(|g| g.role);
+        assert_eq!(role(None, Role::Reader), Ok(Role::Reader));
+        assert_eq!(
+            role(None, Role::Publisher),
+            Err(Error::Unauthenticated(Role::Publisher))
+        );
+        assert_eq!(role(Some("Bearer p"), Role::Publisher), Ok(Role::Publisher));
+        assert_eq!(
+            role(Some("Bearer p"), Role::Admin),
+            Err(Error::Forbidden(Role::Admin))
+        );
+        assert_eq!(role(Some("Bearer a"), Role::Publisher), Ok(Role::Admin));
+
+        let closed = AccessControl::new(&[], Role::None);
+        assert!(closed.authorize(None, Role::None).is_ok());
+        assert!(closed.authorize(None, Role::Reader).is_err());
+    }
+
+    #[test]
+    fn keys_are_bound_to_realms() {
+        let other = RealmName::from("other");
+        let access = AccessControl::new(
+            &[
+                key("r", Role::Reader),
+                ApiKey {
+                    realm: Some(other.clone()),
+                    ..key("o", Role::Publisher)
+                },
+                key("a", Role::Admin),
+            ],
+            Role::Reader,
+        );
+        let realm = |credentials, requested| access.grant(credentials).unwrap().realm(requested);
+        let default = RealmName::from(realm::DEFAULT);
+
+        assert_eq!(realm(None, None), Ok(default.clone()));
+        assert_eq!(realm(None, Some(&default)), Ok(default.clone()));
+        assert_eq!(
+            realm(None, Some(&other)),
+            Err(Error::OtherRealm(default.clone()))
+        );
+        assert_eq!(
+            realm(Some("Bearer r"), Some(&other)),
+            Err(Error::OtherRealm(default.clone()))
+        );
+        assert_eq!(realm(Some("Bearer o"), None), Ok(other.clone()));
+        assert_eq!(
+            realm(Some("Bearer o"), Some(&default)),
+            Err(Error::OtherRealm(other.clone()))
+        );
+        assert_eq!(realm(Some("Bearer a"), None), Ok(default.clone()));
+        assert_eq!(realm(Some("Bearer a"), Some(&other)), Ok(other.clone()));
+
+        // Admin keys bound to a realm can't work on the whole server
+        let access = AccessControl::new(
+            &[
+                key("a", Role::Admin),
+                ApiKey {
+                    realm: Some(other.clone()),
+                    ..key("b", Role::Admin)
+                },
+            ],
+            Role::Reader,
+        );
+        let global = |credentials| access.grant(credentials).unwrap().global();
+        assert_eq!(global(Some("Bearer a")), Ok(()));
+        assert_eq!(global(Some("Bearer b")), Err(Error::OtherRealm(other)));
+        assert_eq!(global(None), Err(Error::OtherRealm(default)));
+    }
+
+    #[test]
+    fn grpc_requests_need_a_role() {
+        let server = Server::<HashMapStorage>::default();
+        let mut interceptor = TokenAuthInterceptor {
+            access: Arc::new(AccessControl::new(&[key("p", Role::Publisher)], Role::None)),
+            ..server.token_interceptor.clone()
+        };
+        let request = |credentials: Option<&str>| {
+            let mut request = Request::new(());
+            if let Some(credentials) = credentials {
+                request
+                    .metadata_mut()
+                    .insert(HEADER, credentials.parse().unwrap());
+            }
+            request
+        };
+
+        assert!(interceptor.call(request(None)).is_err());
+        assert!(interceptor.call(request(Some("Bearer x"))).is_err());
+        let request = interceptor.call(request(Some("Bearer p"))).unwrap();
+        assert_eq!(request.extensions().get::<Role>(), Some(&Role::Publisher));
+    }
+}
@@ -0,0 +1,214 @@
+//! Authenticating clients and handing out their session tokens, see `distd_core::auth`
+//!
+//! Clients are bound to the key they first registered with. The gRPC service only accepts the tokens of known
+//! clients, revoking a client drops it along with its tokens and its key is refused from then on.
+//!
+//! Revoking a client doesn't revoke the API key it registered with (see `crate::access`): whoever holds it can still
+//! register a new client with a new key pair, so the API key must be rotated as well if it leaked along with the
+//! client's key.
+
+use std::fmt::Debug;
+
+use distd_core::auth::{self, Error as AuthError};
+use distd_core::chunk_storage::ChunkStorage;
+use distd_core::signature::{self, PublicKey};
+use uuid::Uuid;
+
+use crate::client::Client;
+use crate::error::Server as ServerError;
+use crate::realm::Name as RealmName;
+use crate::Server;
+
+impl<T> Server<T>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    /// Check that the client holding `public_key` signed the challenge `nonce`, returning its key
+    pub async fn authenticate(
+        &self,
+        nonce: &[u8],
+        public_key: &[u8],
+        signature: &[u8],
+    ) -> Result<PublicKey, ServerError> {
+        let public_key: PublicKey = public_key
+            .try_into()
+            .map_err(|_| AuthError::Signature(signature::Error::BadPublicKey))?;
+        self.challenges.redeem(nonce, &public_key, signature)?;
+        if self.revoked.read().await.contains(&public_key) {
+            return Err(AuthError::Revoked.into());
+        }
+        Ok(public_key)
+    }
+
+    /// Issue a session token to the registered client `uuid`, valid for `token_ttl`
+    ///
+    /// Returns the token along with its expiry, in seconds since the Unix epoch.
+    ///
+    /// # Panics
+    /// If the lock on the uuids accepted by the interceptor is poisoned
+    pub fn issue_token(&self, uuid: &Uuid) -> Result<(Vec<u8>, u64), ServerError> {
+        if !self.token_interceptor.uuids.read().unwrap().contains(uuid) {
+            return Err(ServerError::UnknownClient(*uuid));
+        }
+        tracing::debug!("Issuing session token to {uuid}");
+        Ok(auth::issue(&self.key_pair, *uuid, self.token_ttl)?)
+    }
+
+    /// Issue a peer token to the client `uuid` of `realm`, valid for `token_ttl`, see `distd_core::auth::issue_peer`
+    pub fn issue_peer_token(&self, uuid: &Uuid, realm: &RealmName) -> Result<Vec<u8>, ServerError> {
+        let (token, _) = auth::issue_peer(&self.key_pair, *uuid, realm, self.token_ttl)?;
+        Ok(token)
+    }
+
+    /// Revoke the client `uuid` of `realm`, returning it as it was
+    ///
+    /// Its tokens are refused right away and its key cannot be used to register again. The API key it registered
+    /// with is left alone, it must be removed from `auth.api_keys` (and the server restarted) to keep its holder from
+    /// registering again. Clients of other realms are unknown here.
+    ///
+    /// # Panics
+    /// If the lock on the uuids accepted by the interceptor is poisoned
+    pub async fn revoke_client(
+        &self,
+        realm: &RealmName,
+        uuid: &Uuid,
+    ) -> Result<Client, ServerError> {
+        let client = {
+            let mut clients = self.clients.write().await;
+            if clients
+                .get(uuid)
+                .is_none_or(|client| client.realm != *realm)
+            {
+                return Err(ServerError::UnknownClient(*uuid));
+            }
+            let client = clients
+                .remove(uuid)
+                .ok_or(ServerError::UnknownClient(*uuid))?;
+            self.token_interceptor.uuids.write().unwrap().remove(uuid);
+            self.revoked.write().await.insert(client.public_key);
+            client
+        };
+        tracing::info!("Revoked client \"{}\" {uuid}", client.name);
+        self.persist().await?;
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use distd_core::auth::{self, TOKEN_METADATA};
+    use distd_core::chunk_storage::hashmap_storage::HashMapStorage;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use tonic::metadata::BinaryMetadataValue;
+    use tonic::service::Interceptor;
+    use tonic::Request;
+    use uuid::Uuid;
+
+    use crate::realm;
+    use crate::Server;
+
+    fn request(token: Option<&[u8]>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(token) = token {
+            request
+                .metadata_mut()
+                .insert_bin(TOKEN_METADATA, BinaryMetadataValue::from_bytes(token));
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn authenticate_and_revoke() {
+        let mut server = Server::<HashMapStorage>::default();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = key_pair.public_key().as_ref();
+
+        // Challenges must be signed with the key being registered, and are single-use
+        let nonce = server.challenges.issue().unwrap();
+        let signature = auth::answer(&key_pair, &nonce);
+        assert!(server
+            .authenticate(&nonce, &[0; 32], &signature)
+            .await
+            .is_err());
+        let nonce = server.challenges.issue().unwrap();
+        let signature = auth::answer(&key_pair, &nonce);
+        let public_key = server
+            .authenticate(&nonce, public_key, &signature)
+            .await
+            .unwrap();
+        assert!(server
+            .authenticate(&nonce, &public_key, &signature)
+            .await
+            .is_err());
+
+        let addr = ([127, 0, 0, 1], 1234).into();
+        let uuid = server
+            .register_client(
+                "c".into(),
+                addr,
+                None,
+                None,
+                realm::DEFAULT.into(),
+                public_key,
+            )
+            .await
+            .unwrap();
+        // Registering again needs the same key
+        assert!(server
+            .register_client(
+                "c".into(),
+                addr,
+                None,
+                Some(uuid),
+                realm::DEFAULT.into(),
+                [0; 32]
+            )
+            .await
+            .is_err());
+        assert!(server.issue_token(&Uuid::nil()).is_err());
+
+        let (token, _) = server.issue_token(&uuid).unwrap();
+        let mut interceptor = server.token_interceptor.clone();
+        assert!(interceptor.call(request(Some(&token))).is_ok());
+        // Requests without a token are let through, with a bad one they're not
+        assert!(interceptor.call(request(None)).is_ok());
+        assert!(interceptor.call(request(Some(b"forged"))).is_err());
+        server.token_ttl = Duration::ZERO;
+        let (expired, _) = server.issue_token(&uuid).unwrap();
+        assert!(interceptor.call(request(Some(&expired))).is_err());
+
+        // Not from another realm
+        assert!(server.revoke_client(&"other".into(), &uuid).await.is_err());
+        assert!(interceptor.call(request(Some(&token))).is_ok());
+
+        let default = realm::DEFAULT.into();
+        let client = server.revoke_client(&default, &uuid).await.unwrap();
+        assert_eq!(client.uuid, uuid);
+        assert!(interceptor.call(request(Some(&token))).is_err());
+        assert!(server.issue_token(&uuid).is_err());
+        assert!(server.revoke_client(&default, &uuid).await.is_err());
+
+        // The revoked key is refused from then on
+        let nonce = server.challenges.issue().unwrap();
+        let signature = auth::answer(&key_pair, &nonce);
+        assert!(server
+            .authenticate(&nonce, &public_key, &signature)
+            .await
+            .is_err());
+        assert!(server
+            .register_client(
+                "c".into(),
+                addr,
+                None,
+                None,
+                realm::DEFAULT.into(),
+                public_key
+            )
+            .await
+            .is_err());
+    }
+}
@@ -1,17 +1,22 @@
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::time::SystemTime;
 
-use serde::{ser::SerializeStruct, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use distd_core::hash::Hash;
+use distd_core::signature::PublicKey;
 use distd_core::unique_name::UniqueName;
 use distd_core::version::Version;
 
+use crate::realm::{self, Name as RealmName};
+
 pub type Name = UniqueName;
 
 /// Server-side client representation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Client {
     /// Client advertised name
     pub name: Name,
@@ -22,12 +27,31 @@ pub struct Client {
     /// Client UUID, assigned by server
     pub uuid: Uuid,
 
-    //realm: Option<Arc<Realm>>,
+    /// Realm the client registered into, the only one it has access to
+    #[serde(default = "default_realm")]
+    pub realm: RealmName,
+
+    /// Ed25519 public key the client registered with, see `distd_core::auth`
+    #[serde(default)]
+    pub public_key: PublicKey,
+
     /// Client version, optional
     pub version: Option<Version>,
 
     /// Last heartbeat time
     pub last_heartbeat: SystemTime,
+
+    /// Hashes of chunks and trees the client advertised to hold, see `Server::record_holdings`
+    #[serde(default)]
+    pub holdings: HashSet<Hash>,
+
+    /// UDP port the client serves held items to peers on, as last reported in a heartbeat
+    #[serde(default)]
+    pub peer_port: Option<u16>,
+}
+
+fn default_realm() -> RealmName {
+    realm::DEFAULT.into()
 }
 
 impl PartialEq for Client {
@@ -40,19 +64,3 @@ impl PartialOrd for Client {
         self.uuid.partial_cmp(&other.uuid)
     }
 }
-
-impl Serialize for Client {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("Client", 5)?;
-        state.serialize_field("name", &self.name)?;
-        state.serialize_field("addr", &self.addr)?;
-        state.serialize_field("uuid", &self.uuid.to_string())?;
-        state.serialize_field("version", &self.version)?;
-        state.serialize_field("last_heartbeat", &self.last_heartbeat)?;
-        state.end()
-    }
-}
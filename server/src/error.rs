@@ -1,4 +1,6 @@
+use config::ConfigError;
 use distd_core::{error::InvalidParameter, TransportError};
+use ring::error::KeyRejected;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -22,6 +24,57 @@ pub enum Server {
     #[error("unknown data store error")]
     UnknownDataStore,
 
+    #[error("Invalid config")]
+    InvalidConfig(#[from] ConfigError),
+
+    #[error("Cannot access persistent state")]
+    Io(#[from] std::io::Error),
+
+    #[error("Cannot (de)serialize persistent state")]
+    State(#[from] bitcode::Error),
+
+    #[error("Cannot generate server key pair")]
+    KeyGeneration,
+
+    #[error("Invalid server key pair: {0}")]
+    Key(KeyRejected),
+
+    #[error("Unknown client '{0}'")]
+    UnknownClient(uuid::Uuid),
+
+    #[error("Unknown feed '{0}'")]
+    UnknownFeed(String),
+
+    #[error("Feed '{0}' already exists")]
+    FeedExists(String),
+
+    #[error("Unknown item {0:?}")]
+    UnknownItem(std::path::PathBuf),
+
+    #[error("Unknown hash {0}")]
+    UnknownHash(distd_core::hash::Hash),
+
+    #[error("Unknown realm '{0}'")]
+    UnknownRealm(String),
+
+    #[error("Realm '{0}' already exists")]
+    RealmExists(String),
+
+    #[error("Not authorized to publish into realm '{0}': {1}")]
+    Unauthorized(String, distd_core::signature::Error),
+
+    #[error("Cannot sign: {0}")]
+    Signing(#[from] distd_core::signature::Error),
+
+    #[error("Cannot authenticate client: {0}")]
+    Authentication(#[from] distd_core::auth::Error),
+
+    #[error("Access denied: {0}")]
+    Access(#[from] crate::access::Error),
+
+    #[error("Cannot receive item: {0}")]
+    ItemReception(Box<distd_core::error::Error>),
+
     #[error("unknown server error")]
     Unknown,
 }
@@ -1,26 +1,35 @@
-use std::borrow::Borrow;
 use std::collections::HashSet;
 use std::fmt::Debug;
+use std::future::Future;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use distd_core::chunk_storage::node_stream::sender;
+use distd_core::auth;
+use distd_core::chunk_storage::negotiation;
+use distd_core::chunk_storage::node_stream::{receiver, sender};
 use distd_core::chunk_storage::ChunkStorage;
+use distd_core::feed::Feed;
 use distd_core::hash::Hash;
-use distd_core::proto::{self, EnumAcknowledge, ItemRequest, SerializedTree};
-use distd_core::utils::grpc::metadata_to_uuid;
+use distd_core::metadata::{Item as ItemMetadata, Update};
+use distd_core::proto::{
+    self, AuthChallenge, ChallengeRequest, DiffFrontier, DiffProbe, EnumAcknowledge, FeedItem,
+    FeedRef, FeedRename, ItemRequest, ItemUpload, MetadataUpdate, PeerInfo, PeerList, PeersRequest,
+    PublishedItem, SerializedFeed, SerializedTree, SessionToken, Subtree, TokenRequest,
+    UpdatesRequest,
+};
+use distd_core::signature::{PublicKey, RealmChange};
 use distd_core::utils::serde::BitcodeSerializable;
 use distd_core::utils::uuid::slice_to_uuid;
 use distd_core::version::Version;
-use tokio::sync::mpsc;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use tokio::sync::{mpsc, watch};
 use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 
-use tonic::metadata::MetadataValue;
 use tonic::service::Interceptor;
-use tonic::{Code, Request, Response, Status};
+use tonic::{Code, Request, Response, Status, Streaming};
 
 use distd_core::metadata::Server as ServerMetadataRepr;
 use distd_core::proto::{
@@ -28,135 +37,556 @@ use distd_core::proto::{
 };
 use uuid::Uuid;
 
+use crate::access::{self, AccessControl, Error as AccessError, Grant, Role};
 use crate::error::Server as ServerError;
+use crate::realm::{self, Name as RealmName};
+use crate::tracker::DEFAULT_PEERS;
 use crate::Server;
 
 #[derive(Clone)]
 struct ClientUuidExtension {
     pub uuid: Uuid,
+
+    /// Expiry of the session token, in seconds since the Unix epoch
+    pub expires: u64,
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct UuidAuthInterceptor {
-    pub uuids: Arc<RwLock<HashSet<MetadataValue<distd_core::tonic::metadata::Binary>>>>,
+impl ClientUuidExtension {
+    /// Time left before the session token expires
+    fn remaining(&self) -> Duration {
+        SystemTime::UNIX_EPOCH
+            .checked_add(Duration::from_secs(self.expires))
+            .map_or(Duration::MAX, |expires| {
+                expires
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+            })
+    }
 }
 
-impl Interceptor for UuidAuthInterceptor {
-    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
-        let uuid: MetadataValue<tonic::metadata::Binary>;
-        {
-            uuid = match request.borrow().metadata().get_bin("x-uuid-bin") {
-                Some(uuid) if self.uuids.read().unwrap().contains(uuid) => Ok(uuid),
-                _ => Err(Status::unauthenticated("Unauthenticated")),
-            }?
-            .clone();
+/// Checks the session token of requests carrying one, see `distd_core::auth`, and the role they're granted, see
+/// `access`
+///
+/// Requests without a token are let through, for clients to register and to get metadata of the default realm:
+/// the ones needing a client are refused later on, see `client_uuid`. Every request needs the reader role, which
+/// clients holding a token have, the ones needing more are refused later on, see `require`. The realm of requests is
+/// the one of their client, or the one their API key is bound to, see `Server::request_realm`.
+#[derive(Debug, Clone)]
+pub struct TokenAuthInterceptor {
+    /// Server public key, tokens are signed with the server key pair
+    pub public_key: PublicKey,
+
+    /// Clients whose tokens are accepted, revoked clients are removed
+    pub uuids: Arc<RwLock<HashSet<Uuid>>>,
+
+    /// API keys granting roles
+    pub access: Arc<AccessControl>,
+}
+
+impl TokenAuthInterceptor {
+    /// Accept tokens issued with `key_pair`, for no client yet
+    #[must_use]
+    pub fn new(key_pair: &Ed25519KeyPair) -> Self {
+        let mut public_key = PublicKey::default();
+        public_key.copy_from_slice(key_pair.public_key().as_ref());
+        Self {
+            public_key,
+            uuids: Arc::default(),
+            access: Arc::default(),
         }
+    }
+}
 
-        request.extensions_mut().insert(ClientUuidExtension {
-            uuid: metadata_to_uuid(&uuid)
-                //.map_err(|_| Status::unauthenticated("Unauthenticated"))?,
-                .map_err(|_| Status::unauthenticated("Invalid metadata"))?,
-        });
+impl Interceptor for TokenAuthInterceptor {
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        let credentials = request
+            .metadata()
+            .get(access::HEADER)
+            .map(|credentials| credentials.to_str())
+            .transpose()
+            .map_err(|_| Status::unauthenticated("Invalid metadata"))?;
+        let grant = self
+            .access
+            .grant(credentials)
+            .map_err(|e| status(&e.into()))?;
+        let mut role = grant.role;
 
+        if let Some(token) = request.metadata().get_bin(auth::TOKEN_METADATA) {
+            let token = token
+                .to_bytes()
+                .map_err(|_| Status::unauthenticated("Invalid metadata"))?;
+            let claims = auth::verify(&self.public_key, &token)
+                .map_err(|e| Status::unauthenticated(e.to_string()))?;
+            if !self.uuids.read().unwrap().contains(&claims.uuid) {
+                return Err(Status::unauthenticated("Unknown or revoked client"));
+            }
+            request.extensions_mut().insert(ClientUuidExtension {
+                uuid: claims.uuid,
+                expires: claims.expires,
+            });
+            role = role.max(Role::Reader);
+        }
+
+        if role < Role::Reader {
+            return Err(status(&AccessError::Unauthenticated(Role::Reader).into()));
+        }
+        request.extensions_mut().insert(role);
+        request.extensions_mut().insert(grant);
         Ok(request)
     }
 }
 
 impl<T> Server<T>
 where
-    T: ChunkStorage + Sync + Send + Default + Debug + 'static,
+    T: ChunkStorage + Sync + Send + Debug + 'static,
 {
     pub async fn make_grcp_service(self) -> Result<tonic::transport::server::Router, ServerError> {
-        let interceptor = self.uuid_interceptor.clone();
+        let interceptor = TokenAuthInterceptor {
+            access: self.access.clone(),
+            ..self.token_interceptor.clone()
+        };
         let svc = proto::distd_server::DistdServer::with_interceptor(self, interceptor);
 
         Ok(tonic::transport::Server::builder().add_service(svc))
     }
+
+    /// Serialize current metadata of `realm` and sign it, so that clients can check it comes from us
+    async fn signed_metadata(
+        &self,
+        realm: &RealmName,
+        uuid: Option<Vec<u8>>,
+    ) -> Result<ServerMetadata, Status> {
+        let metadata = self.metadata(realm).await.map_err(|e| status(&e))?.clone();
+        let serialized = ServerMetadataRepr::from(metadata)
+            .to_bitcode()
+            .map_err(|_| Status::new(Code::Internal, "Cannot serialize server metadata"))?;
+        Ok(ServerMetadata {
+            signature: Some(self.sign(&serialized)),
+            serialized,
+            uuid,
+            session: None,
+        })
+    }
+
+    /// Record a heartbeat from the client issuing `request`
+    async fn keep_alive(&self, request: &Request<ClientKeepAlive>) -> Result<(), Status> {
+        let uuid = client_uuid(request)?;
+        let peer_port = request
+            .get_ref()
+            .peer_port
+            .map(u16::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("Invalid peer port"))?;
+        self.heartbeat(&uuid, request.remote_addr(), peer_port)
+            .await
+            .map_err(|e| status(&e))
+    }
+
+    /// Serialize a metadata update and sign it, like `signed_metadata`
+    #[allow(clippy::result_large_err)]
+    fn signed_update(&self, update: Update) -> Result<MetadataUpdate, Status> {
+        let serialized = update
+            .to_bitcode()
+            .map_err(|_| Status::new(Code::Internal, "Cannot serialize metadata update"))?;
+        Ok(MetadataUpdate {
+            signature: Some(self.sign(&serialized)),
+            serialized,
+        })
+    }
+
+    /// Push metadata updates of `realm` to `tx` as they happen, until the receiving end is closed
+    ///
+    /// Unless the client already holds metadata at the current generation, a full snapshot is sent first.
+    /// Streams opened with a `session` token end with an error once it expires, or on the first change after its
+    /// client is revoked.
+    async fn push_updates(
+        self,
+        realm: RealmName,
+        seen: Option<u64>,
+        session: Option<ClientUuidExtension>,
+        tx: mpsc::Sender<Result<MetadataUpdate, Status>>,
+    ) {
+        let mut generations = self.generations();
+        let Ok((generation, mut last)) = self.current(&realm, &mut generations).await else {
+            return;
+        };
+        if seen != Some(generation) {
+            let snapshot = Update {
+                generation,
+                ..last.snapshot()
+            };
+            if tx.send(self.signed_update(snapshot)).await.is_err() {
+                return;
+            }
+        }
+
+        let mut base = generation;
+        let expiry = tokio::time::sleep(
+            session
+                .as_ref()
+                .map_or(Duration::ZERO, ClientUuidExtension::remaining),
+        );
+        tokio::pin!(expiry);
+        loop {
+            tokio::select! {
+                changed = generations.changed() => if changed.is_err() { break },
+                () = tx.closed() => break,
+                () = &mut expiry, if session.is_some() => {
+                    tx.send(Err(Status::unauthenticated("Token expired"))).await.ok();
+                    break;
+                }
+            }
+            if let Err(status) = self.check_session(session.as_ref()) {
+                tx.send(Err(status)).await.ok();
+                break;
+            }
+            let Ok((generation, current)) = self.current(&realm, &mut generations).await else {
+                break;
+            };
+            // Sent even if empty, for the client to keep track of the generation
+            let update = Update {
+                generation,
+                base: Some(base),
+                ..last.diff(&current)
+            };
+            last = current;
+            base = generation;
+            if tx.send(self.signed_update(update)).await.is_err() {
+                break;
+            }
+        }
+        tracing::trace!("Stopped pushing metadata updates");
+    }
+
+    /// Check that the `session` a stream was opened with still holds, its client not revoked nor its token expired
+    #[allow(clippy::result_large_err)]
+    fn check_session(&self, session: Option<&ClientUuidExtension>) -> Result<(), Status> {
+        let Some(session) = session else {
+            return Ok(());
+        };
+        if !self
+            .token_interceptor
+            .uuids
+            .read()
+            .unwrap()
+            .contains(&session.uuid)
+        {
+            return Err(Status::unauthenticated("Unknown or revoked client"));
+        }
+        if session.remaining().is_zero() {
+            return Err(Status::unauthenticated("Token expired"));
+        }
+        Ok(())
+    }
+
+    /// Current metadata of `realm`, along with its generation which is marked as seen
+    async fn current(
+        &self,
+        realm: &RealmName,
+        generations: &mut watch::Receiver<u64>,
+    ) -> Result<(u64, ServerMetadataRepr), ServerError> {
+        let metadata = self.metadata(realm).await?;
+        let generation = *generations.borrow_and_update();
+        Ok((generation, ServerMetadataRepr::from(metadata.clone())))
+    }
+
+    /// Realm of `request`, the one of the client issuing it or else the one its API key is bound to
+    ///
+    /// Requests of a client made with an API key bound to another realm are refused.
+    /// The returned future doesn't borrow `request`, whose body may not be `Sync`.
+    fn request_realm<R>(
+        &self,
+        request: &Request<R>,
+    ) -> impl Future<Output = Result<RealmName, Status>> + '_ {
+        let uuid = client_uuid(request).ok();
+        let keyed = request.metadata().contains_key(access::HEADER);
+        let grant = grant(request);
+        async move {
+            let Some(uuid) = uuid else {
+                return grant.realm(None).map_err(|e| status(&e.into()));
+            };
+            let realm = self.client_realm(&uuid).await.map_err(|e| status(&e))?;
+            if keyed {
+                grant.realm(Some(&realm)).map_err(|e| status(&e.into()))?;
+            }
+            Ok(realm)
+        }
+    }
+
+    /// Check that the owner of `realm`, if it has one, signed `change` with `signature`
+    async fn authorize(
+        &self,
+        realm: &RealmName,
+        change: &RealmChange<'_>,
+        signature: Option<&[u8]>,
+    ) -> Result<(), Status> {
+        self.authorize_change(realm, change, signature)
+            .await
+            .map_err(|e| status(&e))
+    }
+}
+
+/// Uuid of the client issuing `request`, as authenticated by `TokenAuthInterceptor`
+fn client_uuid<R>(request: &Request<R>) -> Result<Uuid, Status> {
+    request
+        .extensions()
+        .get::<ClientUuidExtension>()
+        .map(|ext| ext.uuid)
+        .ok_or(Status::unauthenticated("Missing session token"))
+}
+
+/// What `request` was granted by its API key, see `TokenAuthInterceptor`
+fn grant<R>(request: &Request<R>) -> Grant {
+    request
+        .extensions()
+        .get::<Grant>()
+        .cloned()
+        .unwrap_or_else(|| Grant {
+            role: Role::None,
+            realm: Some(realm::DEFAULT.into()),
+        })
+}
+
+/// Map an error of a client request to a gRPC status
+fn status(e: &ServerError) -> Status {
+    match e {
+        ServerError::UnknownFeed(_)
+        | ServerError::UnknownItem(_)
+        | ServerError::UnknownHash(_)
+        | ServerError::UnknownClient(_)
+        | ServerError::UnknownRealm(_) => Status::new(Code::NotFound, e.to_string()),
+        ServerError::FeedExists(_) | ServerError::RealmExists(_) => {
+            Status::new(Code::AlreadyExists, e.to_string())
+        }
+        ServerError::Unauthorized(..)
+        | ServerError::Access(AccessError::Forbidden(_) | AccessError::OtherRealm(_)) => {
+            Status::new(Code::PermissionDenied, e.to_string())
+        }
+        ServerError::Authentication(_)
+        | ServerError::Access(AccessError::UnknownKey | AccessError::Unauthenticated(_)) => {
+            Status::new(Code::Unauthenticated, e.to_string())
+        }
+        ServerError::ItemReception(_) => Status::new(Code::InvalidArgument, e.to_string()),
+        _ => Status::new(Code::Internal, e.to_string()),
+    }
+}
+
+/// Check that `request` was granted the `role` needed to make it, see `TokenAuthInterceptor`
+#[allow(clippy::result_large_err)]
+fn require<R>(request: &Request<R>, role: Role) -> Result<(), Status> {
+    request
+        .extensions()
+        .get::<Role>()
+        .copied()
+        .unwrap_or_default()
+        .check(role)
+        .map_err(|e| status(&e.into()))
+}
+
+/// Serialize a feed changed by a request
+#[allow(clippy::result_large_err)]
+fn serialized_feed(feed: Result<Feed, ServerError>) -> Result<Response<SerializedFeed>, Status> {
+    let feed = feed.map_err(|e| status(&e))?;
+    Ok(Response::new(SerializedFeed {
+        serialized: bitcode::serialize(&feed)
+            .map_err(|_| Status::new(Code::Internal, "Cannot serialize feed"))?,
+    }))
+}
+
+/// Parse raw hashes from a request, silently dropping malformed ones
+fn parse_hashes(hashes: Vec<Vec<u8>>) -> Vec<Hash> {
+    hashes
+        .into_iter()
+        .filter_map(|v| v.try_into().ok())
+        .map(Hash::from_bytes)
+        .collect()
 }
 
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<SerializedTree, Status>> + Send>>;
+type UpdatesStream = Pin<Box<dyn Stream<Item = Result<MetadataUpdate, Status>> + Send>>;
 
 #[tonic::async_trait]
 impl<T> Distd for Server<T>
 where
-    T: ChunkStorage + Sync + Send + Default + Debug + 'static,
+    T: ChunkStorage + Sync + Send + Debug + 'static,
 {
     type TreeTransferStream = ResponseStream;
+    type UpdatesStream = UpdatesStream;
+
+    async fn challenge(
+        &self,
+        _request: Request<ChallengeRequest>,
+    ) -> Result<Response<AuthChallenge>, Status> {
+        let nonce = self
+            .challenges
+            .issue()
+            .map_err(|e| Status::resource_exhausted(e.to_string()))?;
+        Ok(Response::new(AuthChallenge {
+            nonce: nonce.to_vec(),
+        }))
+    }
 
     async fn register(
         &self,
         request: Request<ClientRegister>,
     ) -> Result<Response<ServerMetadata>, Status> {
         let addr = request.remote_addr();
+        let grant = grant(&request);
         let inner = request.into_inner();
         let addr = addr.ok_or(Status::new(Code::Internal, "Invalid source address"))?;
+        let public_key = self
+            .authenticate(&inner.nonce, &inner.public_key, &inner.signature)
+            .await
+            .map_err(|e| status(&e))?;
+        // Clients are bound to the realm of the API key they register with
+        let realm = grant
+            .realm(inner.realm.as_ref())
+            .map_err(|e| status(&e.into()))?;
         let uuid = self
             .register_client(
                 inner.name,
                 addr,
                 Version::from_str(&inner.version).ok(),
                 inner.uuid.map(|x| slice_to_uuid(&x)),
+                realm.clone(),
+                public_key,
             )
             .await
             .map_err(|_| Status::new(Code::Internal, "Cannot assign new UUID"))?;
-        let serialized = ServerMetadataRepr::from(self.metadata.read().await.clone())
-            .to_bitcode()
-            .map_err(|_| Status::new(Code::Internal, "Cannot serialize server metadata"))?;
+        let (token, expires) = self.issue_token(&uuid).map_err(|e| status(&e))?;
         Ok(Response::new(ServerMetadata {
-            serialized,
-            uuid: Some(uuid.as_bytes().to_vec()),
+            session: Some(SessionToken { token, expires }),
+            ..self
+                .signed_metadata(&realm, Some(uuid.as_bytes().to_vec()))
+                .await?
         }))
     }
 
+    async fn renew_token(
+        &self,
+        request: Request<TokenRequest>,
+    ) -> Result<Response<SessionToken>, Status> {
+        let uuid = client_uuid(&request)?;
+        let (token, expires) = self.issue_token(&uuid).map_err(|e| status(&e))?;
+        Ok(Response::new(SessionToken { token, expires }))
+    }
+
     async fn fetch(
         &self,
-        _request: Request<ClientKeepAlive>,
+        request: Request<ClientKeepAlive>,
     ) -> Result<Response<ServerMetadata>, Status> {
-        let serialized = ServerMetadataRepr::from(self.metadata.read().await.clone())
-            .to_bitcode()
-            .map_err(|_| Status::new(Code::Internal, "Cannot serialize server metadata"))?;
-        Ok(Response::new(ServerMetadata {
-            serialized,
-            uuid: None, // TODO respond with the request uuid?
-        }))
+        // Metadata of the default realm is served to anyone, keep-alives only count for registered clients
+        if let Err(e) = self.keep_alive(&request).await {
+            tracing::debug!("Ignoring keep-alive: {}", e.message());
+        }
+        let realm = self.request_realm(&request).await?;
+        Ok(Response::new(
+            self.signed_metadata(&realm, None).await?, // TODO respond with the request uuid?
+        ))
     }
 
-    async fn adv_hashes(&self, _request: Request<Hashes>) -> Result<Response<Acknowledge>, Status> {
+    async fn heartbeat(
+        &self,
+        request: Request<ClientKeepAlive>,
+    ) -> Result<Response<Acknowledge>, Status> {
+        self.keep_alive(&request).await?;
         Ok(Response::new(Acknowledge {
-            ack: EnumAcknowledge::AckIgnored.into(),
+            ack: EnumAcknowledge::AckOk.into(),
         }))
     }
 
+    async fn updates(
+        &self,
+        request: Request<UpdatesRequest>,
+    ) -> Result<Response<UpdatesStream>, Status> {
+        let realm = self.request_realm(&request).await?;
+        // Checked again as metadata changes, streams may outlive the token and the client
+        let session = request.extensions().get::<ClientUuidExtension>().cloned();
+        let seen = request.into_inner().generation;
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(self.clone().push_updates(realm, seen, session, tx));
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::UpdatesStream
+        ))
+    }
+
+    async fn adv_hashes(&self, request: Request<Hashes>) -> Result<Response<Acknowledge>, Status> {
+        let uuid = client_uuid(&request)?;
+        let inner = request.into_inner();
+        let count = inner.hashes.len();
+        let hashes = parse_hashes(inner.hashes);
+        let well_formed = hashes.len() == count;
+        let all_known = self
+            .record_holdings(&uuid, hashes, inner.replace)
+            .await
+            .map_err(|e| Status::new(Code::NotFound, e.to_string()))?;
+
+        // Malformed or unknown hashes are dropped, tell the client something was off
+        let ack = if well_formed && all_known {
+            EnumAcknowledge::AckOk
+        } else {
+            EnumAcknowledge::AckConfused
+        };
+        Ok(Response::new(Acknowledge { ack: ack.into() }))
+    }
+
     async fn tree_transfer(
         &self,
         request: Request<ItemRequest>,
     ) -> Result<Response<ResponseStream>, Status> {
+        let uuid = client_uuid(&request)?;
+        let realm = self.request_realm(&request).await?;
         let inner = request.into_inner();
+        let path = PathBuf::from_str(&inner.item_path)
+            .map_err(|_| Status::new(Code::InvalidArgument, "Bad item path"))?;
 
-        let hash = {
-            let metadata = self.metadata.read().await;
-            *PathBuf::from_str(&inner.item_path)
-                .ok()
-                .and_then(|x| metadata.items.get(&x))
-                .ok_or(Status::new(
-                    Code::InvalidArgument,
-                    "Bad or missing item path",
-                ))?
-                .root()
+        let (hash, from_root) = {
+            let metadata = self.metadata(&realm).await.map_err(|e| status(&e))?;
+            let hash = *metadata
+                .item(&path, inner.request_version)
+                .ok_or(Status::new(Code::NotFound, "Missing item path or revision"))?
+                .root();
+            let from_root = inner
+                .from_version
+                .map(|revision| {
+                    metadata
+                        .item(&path, Some(revision))
+                        .map(|item| *item.root())
+                        .ok_or(Status::new(
+                            Code::NotFound,
+                            "Missing item revision to diff from",
+                        ))
+                })
+                .transpose()?;
+            (hash, from_root)
         };
 
-        tracing::debug!("Transfer {hash}");
+        tracing::debug!("Transfer {hash}, from {from_root:?}");
 
-        let from = inner.hashes.unwrap_or_default();
-        let from: Vec<Hash> = from
-            .hashes
-            .into_iter()
-            .flat_map(|v| {
-                v.try_into()
-                    .map_err(|_| Status::new(Code::InvalidArgument, "Bad BLAKE3 hash"))
-            })
-            .map(Hash::from_bytes)
-            .collect();
+        // When the client tells us which revision it already has, the diff is computed from its tree,
+        // otherwise we rely on the list of hashes the client provided
+        let from: Vec<Hash> = if let Some(from_root) = from_root {
+            self.storage
+                .read()
+                .await
+                .get(&from_root)
+                .ok_or(Status::new(Code::NotFound, "tree to diff from not found"))?
+                .all_hashes()
+                .into_iter()
+                .collect()
+        } else if let Some(hashes) = inner.hashes {
+            parse_hashes(hashes.hashes)
+        } else {
+            // Nothing provided, use whatever the client advertised through `AdvHashes`
+            self.clients
+                .read()
+                .await
+                .get(&uuid)
+                .map(|client| client.holdings.iter().copied().collect())
+                .unwrap_or_default()
+        };
+
+        let missing = parse_hashes(inner.missing.unwrap_or_default().hashes);
 
         let nodes = self
             .storage
@@ -164,7 +594,7 @@ where
             .await
             .get(&hash)
             .ok_or(Status::new(Code::NotFound, "tree not found"))?
-            .find_diff(&from)
+            .find_negotiated_diff(&from, &missing)
             .inspect(|hs| tracing::trace!("Transferring chunks: {hs}"));
         /*
             .map(|n| bitcode::serialize(&n))
@@ -208,4 +638,267 @@ where
             Box::pin(output_stream) as Self::TreeTransferStream
         ))
     }
+
+    async fn negotiate(
+        &self,
+        request: Request<DiffProbe>,
+    ) -> Result<Response<DiffFrontier>, Status> {
+        let realm = self.request_realm(&request).await?;
+        let inner = request.into_inner();
+        let path = PathBuf::from_str(&inner.item_path)
+            .map_err(|_| Status::new(Code::InvalidArgument, "Bad item path"))?;
+
+        let subtrees = if inner.expand.is_empty() {
+            // First round, start from the item root
+            let metadata = self.metadata(&realm).await.map_err(|e| status(&e))?;
+            let item = metadata
+                .item(&path, inner.request_version)
+                .ok_or(Status::new(Code::NotFound, "Missing item path or revision"))?;
+            vec![(*item.root(), item.chunks.len() == 1)]
+        } else {
+            let reachable = self.reachable(&realm).await.map_err(|e| status(&e))?;
+            let mut expand = parse_hashes(inner.expand);
+            expand.retain(|hash| reachable.contains(hash));
+            negotiation::expand(&*self.storage.read().await, &expand)
+        };
+
+        Ok(Response::new(DiffFrontier {
+            subtrees: subtrees
+                .into_iter()
+                .map(|(hash, leaf)| Subtree {
+                    hash: hash.as_bytes().to_vec(),
+                    leaf,
+                })
+                .collect(),
+        }))
+    }
+
+    /// Hashes of other realms are missing as well, their nodes being uploaded again if needed
+    async fn missing(&self, request: Request<Hashes>) -> Result<Response<Hashes>, Status> {
+        let realm = self.request_realm(&request).await?;
+        let hashes = parse_hashes(request.into_inner().hashes);
+        let reachable = self.reachable(&realm).await.map_err(|e| status(&e))?;
+        Ok(Response::new(Hashes {
+            hashes: hashes
+                .into_iter()
+                .filter(|hash| !reachable.contains(hash))
+                .map(|hash| hash.as_bytes().to_vec())
+                .collect(),
+            replace: false,
+        }))
+    }
+
+    async fn peers(&self, request: Request<PeersRequest>) -> Result<Response<PeerList>, Status> {
+        let uuid = client_uuid(&request)?;
+        let inner = request.into_inner();
+        let root: [u8; 32] = inner
+            .root
+            .try_into()
+            .map_err(|_| Status::invalid_argument("Malformed root hash"))?;
+        let limit = inner.limit.map_or(DEFAULT_PEERS, |limit| limit as usize);
+        let peers = self
+            .peers(&Hash::from_bytes(root), &uuid, limit)
+            .await
+            .map_err(|e| status(&e))?;
+        let realm = self.client_realm(&uuid).await.map_err(|e| status(&e))?;
+        let token = self
+            .issue_peer_token(&uuid, &realm)
+            .map_err(|e| status(&e))?;
+        Ok(Response::new(PeerList {
+            token: Some(token),
+            peers: peers
+                .into_iter()
+                .map(|peer| PeerInfo {
+                    addr: peer.addr.to_string(),
+                    complete: peer.complete,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn publish(
+        &self,
+        request: Request<Streaming<ItemUpload>>,
+    ) -> Result<Response<PublishedItem>, Status> {
+        require(&request, Role::Publisher)?;
+        let realm = self.request_realm(&request).await?;
+        let mut stream = request.into_inner();
+        let first = stream
+            .message()
+            .await?
+            .ok_or(Status::new(Code::InvalidArgument, "Empty upload"))?;
+        let metadata = first.metadata.unwrap_or_default();
+        let target: ItemMetadata = bitcode::deserialize(&metadata)
+            .map_err(|_| Status::new(Code::InvalidArgument, "Missing or bad item metadata"))?;
+        let change = RealmChange::Publish {
+            realm: &realm,
+            path: &target.path,
+            root: &target.root.hash,
+            feed: first.feed.as_deref(),
+        };
+        self.authorize(&realm, &change, first.owner_signature.as_deref())
+            .await?;
+        tracing::debug!(
+            "Receiving item '{}' in realm '{realm}'",
+            target.path.to_string_lossy()
+        );
+
+        // A broken upload just ends the stream, the tree then can't be rebuilt or won't match the expected root
+        let payloads = tokio_stream::once(first.payload)
+            .chain(stream.map_while(|upload| upload.ok().map(|upload| upload.payload)))
+            .filter(|payload| !payload.is_empty());
+        let nodes = receiver(Box::pin(payloads), 32, Duration::new(0, 4800));
+
+        let item = self
+            .receive_item(&realm, target, first.feed, Box::pin(nodes))
+            .await
+            .inspect_err(|e| tracing::warn!("Cannot publish uploaded item: {e}"))
+            .map_err(|e| status(&e))?;
+
+        Ok(Response::new(PublishedItem {
+            serialized: item
+                .metadata
+                .to_bitcode()
+                .map_err(|_| Status::new(Code::Internal, "Cannot serialize item metadata"))?,
+        }))
+    }
+
+    async fn create_feed(
+        &self,
+        request: Request<FeedRef>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        require(&request, Role::Publisher)?;
+        let realm = self.request_realm(&request).await?;
+        let inner = request.into_inner();
+        let change = RealmChange::CreateFeed {
+            realm: &realm,
+            feed: &inner.name,
+        };
+        self.authorize(&realm, &change, inner.owner_signature.as_deref())
+            .await?;
+        let feed = Feed::new(&inner.name);
+        serialized_feed(self.expose_feed(&realm, feed.clone()).await.map(|_| feed))
+    }
+
+    async fn rename_feed(
+        &self,
+        request: Request<FeedRename>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        require(&request, Role::Publisher)?;
+        let realm = self.request_realm(&request).await?;
+        let inner = request.into_inner();
+        let change = RealmChange::RenameFeed {
+            realm: &realm,
+            feed: &inner.name,
+            new_name: &inner.new_name,
+        };
+        self.authorize(&realm, &change, inner.owner_signature.as_deref())
+            .await?;
+        serialized_feed(Server::rename_feed(self, &realm, &inner.name, inner.new_name).await)
+    }
+
+    async fn delete_feed(
+        &self,
+        request: Request<FeedRef>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        require(&request, Role::Publisher)?;
+        let realm = self.request_realm(&request).await?;
+        let inner = request.into_inner();
+        let change = RealmChange::DeleteFeed {
+            realm: &realm,
+            feed: &inner.name,
+        };
+        self.authorize(&realm, &change, inner.owner_signature.as_deref())
+            .await?;
+        serialized_feed(self.remove_feed(&realm, &inner.name).await)
+    }
+
+    async fn add_to_feed(
+        &self,
+        request: Request<FeedItem>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        require(&request, Role::Publisher)?;
+        let realm = self.request_realm(&request).await?;
+        let inner = request.into_inner();
+        let path = PathBuf::from(inner.item_path);
+        let change = RealmChange::AddToFeed {
+            realm: &realm,
+            feed: &inner.name,
+            path: &path,
+        };
+        self.authorize(&realm, &change, inner.owner_signature.as_deref())
+            .await?;
+        serialized_feed(Server::add_to_feed(self, &realm, &inner.name, &path).await)
+    }
+
+    async fn remove_from_feed(
+        &self,
+        request: Request<FeedItem>,
+    ) -> Result<Response<SerializedFeed>, Status> {
+        require(&request, Role::Publisher)?;
+        let realm = self.request_realm(&request).await?;
+        let inner = request.into_inner();
+        let path = PathBuf::from(inner.item_path);
+        let change = RealmChange::RemoveFromFeed {
+            realm: &realm,
+            feed: &inner.name,
+            path: &path,
+        };
+        self.authorize(&realm, &change, inner.owner_signature.as_deref())
+            .await?;
+        serialized_feed(Server::remove_from_feed(self, &realm, &inner.name, &path).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use distd_core::chunk_storage::hashmap_storage::HashMapStorage;
+    use distd_core::feed::Feed;
+    use tokio::sync::mpsc;
+    use tonic::Code;
+
+    use super::ClientUuidExtension;
+    use crate::realm;
+    use crate::Server;
+
+    #[tokio::test]
+    async fn updates_end_with_the_session() {
+        let server = Server::<HashMapStorage>::default();
+        let default = realm::Name::from(realm::DEFAULT);
+        let addr = ([127, 0, 0, 1], 1234).into();
+        let uuid = server
+            .register_client("c".into(), addr, None, None, default.clone(), [1; 32])
+            .await
+            .unwrap();
+        let (_, expires) = server.issue_token(&uuid).unwrap();
+        let session = ClientUuidExtension { uuid, expires };
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let push = server
+            .clone()
+            .push_updates(default.clone(), None, Some(session.clone()), tx);
+        tokio::spawn(push);
+        assert!(rx.recv().await.unwrap().is_ok());
+        server.expose_feed(&default, Feed::new("a")).await.unwrap();
+        assert!(rx.recv().await.unwrap().is_ok());
+
+        // Revoked clients get nothing more
+        server.revoke_client(&default, &uuid).await.unwrap();
+        server.expose_feed(&default, Feed::new("b")).await.unwrap();
+        let status = rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(rx.recv().await.is_none());
+
+        // Nor do expired tokens, even without changes
+        let (tx, mut rx) = mpsc::channel(16);
+        let stale = ClientUuidExtension {
+            expires: 0,
+            ..session
+        };
+        tokio::spawn(server.clone().push_updates(default, None, Some(stale), tx));
+        assert!(rx.recv().await.unwrap().is_ok());
+        let status = rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(rx.recv().await.is_none());
+    }
 }
@@ -2,44 +2,110 @@
 #![feature(array_chunks)]
 #![feature(iterator_try_collect)]
 
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
 use distd_core::chunk_storage::hashmap_storage::HashMapStorage;
+#[cfg(feature = "redb")]
+use distd_core::chunk_storage::redb::RedbStorage;
+use distd_core::chunk_storage::ChunkStorage;
 use distd_core::feed::Feed;
+use distd_core::signature::encode_public_key;
 
+use crate::access::AccessControl;
 use crate::client::Client;
+use crate::error::Server as ServerError;
+use crate::persistence::Persistence;
 use crate::server::Server;
+use crate::settings::{Backend, Settings};
 
+pub mod access;
+pub mod auth;
 pub mod client;
 pub mod error;
-pub mod rest_api;
 pub mod grpc;
+pub mod persistence;
+pub mod realm;
+pub mod rest_api;
 pub mod server;
+pub mod settings;
+pub mod tracker;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), ServerError> {
+    let settings = Settings::new("ServerSettings")?;
+
     tracing_subscriber::fmt()
         .with_target(false)
         .compact()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(tracing::Level::from_str(&settings.log.level).unwrap_or(tracing::Level::INFO))
         .init();
 
     tracing::info!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    tracing::debug!("Settings: {settings:?}");
+
+    let persistence = Persistence::new(settings.persistence.path.clone())?;
+
+    match settings.storage.backend {
+        Backend::HashMap => {
+            tracing::warn!("Using in-memory chunk storage, chunks will be lost on restart");
+            serve(
+                &settings,
+                Server::restore(persistence, HashMapStorage::default())?,
+            )
+            .await
+        }
+        #[cfg(feature = "redb")]
+        Backend::Redb => {
+            tracing::info!(
+                "Using redb chunk storage at '{}'",
+                settings.storage.path.to_string_lossy()
+            );
+            let storage = RedbStorage::new(&settings.storage.path).map_err(|e| {
+                tracing::error!("Cannot open redb storage: {e}");
+                ServerError::UnknownDataStore
+            })?;
+            serve(&settings, Server::restore(persistence, storage)?).await
+        }
+    }
+}
+
+/// Expose the gRPC and HTTP services of `server`
+async fn serve<T>(settings: &Settings, mut server: Server<T>) -> Result<(), ServerError>
+where
+    T: ChunkStorage + Sync + Send + Debug + 'static,
+{
+    server.keep_revisions = settings.retention.keep_revisions;
+    server.peer_ttl = Duration::from_secs(settings.tracker.peer_ttl);
+    server.token_ttl = Duration::from_secs(settings.auth.token_ttl);
+    server.access = Arc::new(AccessControl::new(
+        &settings.auth.api_keys,
+        settings.auth.anonymous,
+    ));
+    if settings.auth.api_keys.is_empty() {
+        tracing::warn!("No API keys configured, publishing and administration are not available");
+    }
+    tracing::info!(
+        "Server public key: '{}'",
+        encode_public_key(server.public_key())
+    );
 
-    let server: Server<HashMapStorage> = Server::default();
+    // Already there after a restart
     let feed = Feed::new("A feed");
-    server.expose_feed(feed).await.unwrap();
+    server.expose_feed(&realm::DEFAULT.into(), feed).await.ok();
 
     let app = rest_api::make_app(server.clone());
 
-    let addr_grpc = "[::1]:50051".parse().unwrap();
-    tokio::spawn( tonic::transport::Server::builder()
-        .add_service(distd_core::proto::distd_server::DistdServer::new(server))
-        .serve(addr_grpc));
+    let addr_grpc = settings.server.grpc_addr;
+    tokio::spawn(server.make_grcp_service().await?.serve(addr_grpc));
     tracing::info!("listening on {} for gRPC", addr_grpc);
 
-    // run our app with hyper, listening globally on port 3000
-    let addr = "0.0.0.0:3000";
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let addr = settings.server.http_addr;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
     tracing::info!("listening on {} for HTTP", addr);
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app).await?;
+    Ok(())
 }
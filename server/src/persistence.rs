@@ -0,0 +1,198 @@
+//! Server state persistence
+//!
+//! Everything needed to resume serving clients after a restart is kept in a directory: a bitcode-serialized
+//! snapshot of the server state and the PKCS#8 server key pair.
+//! Chunks are not handled here, as they're the business of the `ChunkStorage` backend.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use distd_core::signature::PublicKey;
+use ring::{rand, signature::Ed25519KeyPair};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::client::Client;
+use crate::error::Server as ServerError;
+use crate::realm::{Name as RealmName, Realm};
+
+/// Snapshot of the server state kept across restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Realms, along with their items, feeds and revisions
+    pub realms: BTreeMap<RealmName, Realm>,
+    /// Registered clients
+    pub clients: BTreeMap<Uuid, Client>,
+    /// Uuids accepted by the gRPC interceptor
+    pub uuids: HashSet<Uuid>,
+    /// Public keys of revoked clients
+    pub revoked: HashSet<PublicKey>,
+}
+
+/// Directory where the server state is persisted
+#[derive(Debug)]
+pub struct Persistence {
+    path: PathBuf,
+    /// Serializes writes, so that a stale snapshot never overwrites a newer one
+    lock: Mutex<()>,
+}
+
+impl Persistence {
+    /// Use `path` as persistence directory, creating it if needed
+    pub fn new(path: PathBuf) -> Result<Self, ServerError> {
+        fs::create_dir_all(&path)?;
+        tracing::info!("Persisting server state in '{}'", path.to_string_lossy());
+        Ok(Self {
+            path,
+            lock: Mutex::default(),
+        })
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.path.join("state.bin")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.path.join("server.pkcs8")
+    }
+
+    /// Load the PKCS#8 key pair, generating and storing a new one on first start
+    pub fn load_or_generate_key(&self) -> Result<Vec<u8>, ServerError> {
+        let path = self.key_path();
+        if path.exists() {
+            return Ok(fs::read(path)?);
+        }
+
+        tracing::info!("Generating new server key pair");
+        let rng = rand::SystemRandom::new();
+        let pkcs8_bytes =
+            Ed25519KeyPair::generate_pkcs8(&rng).map_err(|_| ServerError::KeyGeneration)?;
+
+        // Private key, only readable by the owner
+        File::options()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&path)?
+            .write_all(pkcs8_bytes.as_ref())?;
+        Ok(pkcs8_bytes.as_ref().to_vec())
+    }
+
+    /// Load last persisted state, an empty one is returned if there is none
+    pub fn load_state(&self) -> Result<State, ServerError> {
+        let path = self.state_path();
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let state: State = bitcode::deserialize(&fs::read(path)?)?;
+        tracing::info!(
+            "Loaded {} realms, {} items, {} feeds and {} clients",
+            state.realms.len(),
+            state
+                .realms
+                .values()
+                .map(|realm| realm.metadata.items.len())
+                .sum::<usize>(),
+            state
+                .realms
+                .values()
+                .map(|realm| realm.metadata.feeds.len())
+                .sum::<usize>(),
+            state.clients.len()
+        );
+        Ok(state)
+    }
+
+    /// Store the state `snapshot` resolves to, replacing the previous one atomically
+    ///
+    /// The snapshot is only taken once previous writes are over, so that it's never older than the stored one.
+    pub async fn store_state<F>(&self, snapshot: F) -> Result<(), ServerError>
+    where
+        F: Future<Output = State>,
+    {
+        let _guard = self.lock.lock().await;
+        let buf = bitcode::serialize(&snapshot.await)?;
+        let path = self.state_path();
+        tokio::task::spawn_blocking(move || write_atomic(&path, &buf))
+            .await
+            .map_err(io::Error::from)?
+    }
+}
+
+/// Write to a temporary file and rename it over `path`, so that a crash never leaves a truncated file around
+fn write_atomic(path: &Path, buf: &[u8]) -> Result<(), ServerError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+
+    use distd_core::feed::Feed;
+
+    use super::*;
+
+    fn temp_persistence() -> Persistence {
+        Persistence::new(std::env::temp_dir().join(Uuid::new_v4().to_string())).unwrap()
+    }
+
+    #[test]
+    fn key_is_generated_once() {
+        let persistence = temp_persistence();
+        let key = persistence.load_or_generate_key().unwrap();
+        assert!(Ed25519KeyPair::from_pkcs8(&key).is_ok());
+        assert_eq!(key, persistence.load_or_generate_key().unwrap());
+    }
+
+    #[tokio::test]
+    async fn state_roundtrip() {
+        let persistence = temp_persistence();
+        assert!(persistence.load_state().unwrap().clients.is_empty());
+
+        let uuid = Uuid::new_v4();
+        let mut state = State::default();
+        let mut realm = Realm::new("realm".to_string(), "owner".to_string(), Some([1; 32]));
+        realm
+            .metadata
+            .feeds
+            .insert("feed".to_string(), Feed::new("feed"));
+        state.realms.insert(realm.name.clone(), realm);
+        state.clients.insert(
+            uuid,
+            Client {
+                name: "client".to_string(),
+                addr: SocketAddr::from(([127, 0, 0, 1], 1234)),
+                uuid,
+                realm: "realm".to_string(),
+                public_key: [2; 32],
+                version: None,
+                last_heartbeat: SystemTime::now(),
+                holdings: HashSet::new(),
+                peer_port: None,
+            },
+        );
+        state.uuids.insert(uuid);
+        state.revoked.insert([3; 32]);
+        persistence.store_state(async { state }).await.unwrap();
+
+        let loaded = persistence.load_state().unwrap();
+        let realm = &loaded.realms["realm"];
+        assert_eq!(realm.owner_pkey, Some([1; 32]));
+        assert!(realm.metadata.feeds.contains_key("feed"));
+        assert_eq!(loaded.clients.get(&uuid).unwrap().name, "client");
+        assert_eq!(loaded.clients.get(&uuid).unwrap().public_key, [2; 32]);
+        assert!(loaded.uuids.contains(&uuid));
+        assert!(loaded.revoked.contains(&[3; 32]));
+    }
+}
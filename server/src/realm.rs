@@ -1,8 +1,116 @@
+//! Realms, namespaces sharing a single server
+//!
+//! Every realm has its own items, feeds and clients: clients register into a realm, the default one unless they ask
+//! for another, and only ever see and get what's in their realm. Storage is shared among realms, so that chunks are
+//! deduplicated across them.
 
-#[derive(Debug)]
-struct Realm {
-    name: ArrayString<256>,
-    owner: String,
-    owner_pkey: String,
+use distd_core::signature::{self, PublicKey, RealmChange};
+use distd_core::unique_name::UniqueName;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Server as ServerError;
+use crate::server::InternalMetadata;
+
+pub type Name = UniqueName;
+
+/// Realm of clients not asking for a specific one, open to anyone
+pub const DEFAULT: &str = signature::DEFAULT_REALM;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Realm {
+    pub name: Name,
+
+    /// Who the realm belongs to, informative only
+    pub owner: String,
+
+    /// Ed25519 public key of the owner, publishing into the realm and changing its feeds need a signature made with
+    /// its key pair
+    ///
+    /// Anyone allowed into a realm without a key may publish into it.
+    pub owner_pkey: Option<PublicKey>,
+
+    /// Items, feeds and revisions of the realm
+    pub metadata: InternalMetadata,
+}
+
+impl Realm {
+    #[must_use]
+    pub fn new(name: Name, owner: String, owner_pkey: Option<PublicKey>) -> Self {
+        Self {
+            name,
+            owner,
+            owner_pkey,
+            metadata: InternalMetadata::default(),
+        }
+    }
+
+    /// Check that `signature` of `change` was made by the owner, if the realm has an owner key
+    ///
+    /// The nonce the change was signed for must be one handed out by the server, which `take` tells and takes: a
+    /// signature is good only once.
+    pub fn authorize<F>(
+        &self,
+        change: &RealmChange<'_>,
+        signature: Option<&[u8]>,
+        take: F,
+    ) -> Result<(), ServerError>
+    where
+        F: FnOnce(&[u8]) -> bool,
+    {
+        let Some(owner_pkey) = &self.owner_pkey else {
+            return Ok(());
+        };
+        signature
+            .ok_or(signature::Error::Missing)
+            .and_then(|signature| change.verify(owner_pkey, signature))
+            .and_then(|nonce| take(nonce).then_some(()).ok_or(signature::Error::Stale))
+            .map_err(|e| ServerError::Unauthorized(self.name.clone(), e))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use distd_core::auth::Challenges;
+    use distd_core::signature::RealmChange;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::Realm;
+
+    #[test]
+    fn owner_authorizes_changes() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let owner_pkey = key_pair.public_key().as_ref().try_into().unwrap();
+        let challenges = Challenges::default();
+        let take = |nonce: &[u8]| challenges.take(nonce).is_ok();
+        let create = |realm| RealmChange::CreateFeed { realm, feed: "f" };
+        let sign = |realm| {
+            create(realm)
+                .sign(&key_pair, &challenges.issue().unwrap())
+                .unwrap()
+        };
+
+        let open = Realm::new("open".into(), String::new(), None);
+        assert!(open.authorize(&create("open"), None, take).is_ok());
+
+        let owned = Realm::new("owned".into(), "team".into(), Some(owner_pkey));
+        let signature = sign("owned");
+        assert!(owned
+            .authorize(&create("open"), Some(&signature), take)
+            .is_err());
+        assert!(owned
+            .authorize(&create("owned"), Some(&signature), take)
+            .is_ok());
+        assert!(owned.authorize(&create("owned"), None, take).is_err());
+
+        // Signatures can't be replayed, nor made for nonces the server didn't hand out
+        assert!(owned
+            .authorize(&create("owned"), Some(&signature), take)
+            .is_err());
+        let made_up = create("owned").sign(&key_pair, &[0; 32]).unwrap();
+        assert!(owned
+            .authorize(&create("owned"), Some(&made_up), take)
+            .is_err());
+    }
+}
@@ -4,42 +4,220 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use axum::{
+    async_trait,
     extract::{
-        connect_info::IntoMakeServiceWithConnectInfo, ConnectInfo, DefaultBodyLimit, Multipart,
-        Path, Query, State,
+        connect_info::IntoMakeServiceWithConnectInfo, ConnectInfo, DefaultBodyLimit, Extension,
+        FromRequestParts, MatchedPath, Multipart, Path, Query, Request, State,
     },
-    http::StatusCode,
-    response::IntoResponse,
-    routing::get, //, post},
-    Json,
-    Router,
+    http::{header, request::Parts, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
+use tokio_stream::StreamExt;
 use tower_http::{
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
     trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
     LatencyUnit,
 };
 
 use distd_core::{
-    chunk_storage::ChunkStorage,
+    chunk_storage::{gc::Report as GcReport, stats::Usage, ChunkStorage, Stats},
     feed::{Feed, Name as FeedName},
     hash::Hash,
-    metadata::Server as ServerMetadata,
+    metadata::{Item as ItemMetadata, Server as ServerMetadata},
+    signature::{decode_public_key, decode_signature, encode_nonce, RealmChange, Signature},
     utils::serde::empty_string_as_none,
     version::Version,
 };
 
+use crate::access::{self, Error as AccessError, Grant, Role};
+use crate::error::Server as ServerError;
+use crate::realm::{Name as RealmName, Realm};
 use crate::Client;
 use crate::Server as RawServer;
 
 type Server<T> = Arc<RawServer<T>>;
 
+/// Status code for a failed server operation
+fn status_code(e: &ServerError) -> StatusCode {
+    match e {
+        ServerError::UnknownFeed(_)
+        | ServerError::UnknownItem(_)
+        | ServerError::UnknownRealm(_)
+        | ServerError::UnknownClient(_) => StatusCode::NOT_FOUND,
+        ServerError::FeedExists(_) | ServerError::RealmExists(_) => StatusCode::CONFLICT,
+        ServerError::ItemReception(_) => StatusCode::BAD_REQUEST,
+        ServerError::Unauthorized(..)
+        | ServerError::Access(AccessError::Forbidden(_) | AccessError::OtherRealm(_)) => {
+            StatusCode::FORBIDDEN
+        }
+        ServerError::Authentication(_)
+        | ServerError::Access(AccessError::UnknownKey | AccessError::Unauthenticated(_)) => {
+            StatusCode::UNAUTHORIZED
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Least role needed to call the route at `path` with `method`, see `access`
+fn required_role(method: &Method, path: &str) -> Role {
+    let read = method == Method::GET || method == Method::HEAD;
+    match (path, read) {
+        ("/" | "/version", _) => Role::None,
+        (
+            "/clients" | "/clients/:uuid" | "/chunks" | "/chunks/size-sum" | "/chunks/stats"
+            | "/chunks/gc",
+            true,
+        )
+        | ("/clients/:uuid" | "/chunks/gc" | "/realms", false) => Role::Admin,
+        // Clients register themselves, only admins may look at them
+        ("/clients", false) | (_, true) => Role::Reader,
+        _ => Role::Publisher,
+    }
+}
+
+/// Refuse requests not granted the role needed for the route they're for, the `Grant` of the others is added to
+/// their extensions
+///
+/// # Errors
+/// Returns `StatusCode::UNAUTHORIZED` if the API key is unknown, or missing and anonymous requests don't have the
+/// needed role, `StatusCode::FORBIDDEN` if the API key doesn't grant it
+async fn authorize<T>(
+    State(server): State<Server<T>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("", MatchedPath::as_str);
+    let required = required_role(request.method(), path);
+    let credentials = request
+        .headers()
+        .get(access::HEADER)
+        .map(|credentials| credentials.to_str())
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let grant = match server.access.authorize(credentials, required) {
+        Ok(grant) => grant,
+        Err(e) => {
+            tracing::debug!("Refusing {} {path}: {e}", request.method());
+            return Err(status_code(&e.into()));
+        }
+    };
+    request.extensions_mut().insert(grant);
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize, Serialize)]
+struct RealmObj {
+    /// Realm to work in, the one the API key is bound to if missing
+    pub realm: Option<RealmName>,
+}
+
+/// Realm a request works in, see `access::Grant::realm`
+///
+/// Requests asking for another realm than the one their API key is bound to are refused with
+/// `StatusCode::FORBIDDEN`.
+struct RequestRealm(RealmName);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestRealm
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, StatusCode> {
+        let Query(requested) = Query::<RealmObj>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let grant = parts
+            .extensions
+            .get::<Grant>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        grant
+            .realm(requested.realm.as_ref())
+            .map(Self)
+            .map_err(|e| status_code(&e.into()))
+    }
+}
+
+/// Requests working on what's shared among realms, refused with `StatusCode::FORBIDDEN` when their API key is bound
+/// to a realm, see `access::Grant::global`
+struct Global;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Global
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, StatusCode> {
+        parts
+            .extensions
+            .get::<Grant>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?
+            .global()
+            .map(|()| Self)
+            .map_err(|e| status_code(&e.into()))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct SignatureObj {
+    /// Base64-encoded signature of the change by the realm owner, needed if the realm has an owner key
+    ///
+    /// It's made for a nonce from `POST /challenge` and is good only once, see `RealmChange::sign`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub signature: Option<String>,
+}
+
+/// Check that the owner of `realm`, if it has one, signed `change` with `signature`
+///
+/// # Errors
+/// Returns `StatusCode::BAD_REQUEST` if the signature is not valid base64
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and `change` is not signed with it
+async fn authorize_change<T>(
+    server: &Server<T>,
+    realm: &RealmName,
+    change: &RealmChange<'_>,
+    signature: Option<String>,
+) -> Result<(), StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let signature = decode(signature)?;
+    server
+        .authorize_change(realm, change, signature.as_deref())
+        .await
+        .map_err(|e| status_code(&e))
+}
+
+/// Decode an optional base64-encoded signature
+fn decode(signature: Option<String>) -> Result<Option<Signature>, StatusCode> {
+    signature
+        .map(|signature| decode_signature(&signature))
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
 #[derive(Deserialize, Serialize)]
 struct ClientPostObj {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub version: Option<Version>,
     pub name: String,
     pub uuid: Option<String>,
-    //pub realm: Option<Realm>,
+    /// Realm to register into, the one the API key is bound to if missing
+    pub realm: Option<RealmName>,
+    /// Base64-encoded Ed25519 public key of the client
+    pub public_key: String,
 }
 
 /// Register a client
@@ -50,23 +228,36 @@ struct ClientPostObj {
 ///
 /// The version is optional and can be used to identify the client version
 /// The name is mandatory and should be unique
+/// The realm is optional, clients not asking for one are registered into the realm of the API key
+/// The public key is mandatory, the client is bound to it. Session tokens are only handed out over gRPC, to clients
+/// proving they hold the matching key pair.
 ///
 /// # Errors
-/// If the name is already in use, the server will return a 409 status code
+/// Returns `StatusCode::BAD_REQUEST` if the public key is not a valid base64-encoded Ed25519 key
+/// Returns `StatusCode::FORBIDDEN` if the API key is bound to another realm
+/// If the name is already in use, the realm unknown or not the one the client registered into, or the key revoked or
+/// not the one the client registered with, the server will return a 409 status code
 async fn register_client<T>(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(grant): Extension<Grant>,
     Query(client): Query<ClientPostObj>,
     State(server): State<Server<T>>,
 ) -> Result<impl IntoResponse, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default + Debug,
+    T: ChunkStorage + Sync + Send + Debug,
 {
+    let public_key = decode_public_key(&client.public_key).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let realm = grant
+        .realm(client.realm.as_ref())
+        .map_err(|e| status_code(&e.into()))?;
     server
         .register_client(
             client.name,
             addr,
             client.version,
             client.uuid.and_then(|s| Uuid::from_str(&s).ok()),
+            realm,
+            public_key,
         )
         .await
         .map(|uuid| uuid.to_string())
@@ -78,10 +269,30 @@ async fn version() -> &'static str {
     env!("CARGO_PKG_VERSION")
 }
 
-/// Get all clients
-async fn get_clients<T>(State(server): State<Server<T>>) -> impl IntoResponse
+/// Hand out a challenge nonce, for the realm owner to sign a change for
+///
+/// Returns the base64-encoded nonce, good once and for a short while only.
+///
+/// # Errors
+/// Returns `StatusCode::SERVICE_UNAVAILABLE` if too many challenges were handed out lately
+async fn issue_challenge<T>(State(server): State<Server<T>>) -> Result<String, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
+{
+    server
+        .challenges
+        .issue()
+        .map(|nonce| encode_nonce(&nonce))
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Get all clients of a realm
+async fn get_clients<T>(
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> impl IntoResponse
+where
+    T: ChunkStorage + Sync + Send,
 {
     Json(
         server
@@ -89,18 +300,20 @@ where
             .read()
             .await
             .values()
+            .filter(|client| client.realm == realm)
             .cloned()
             .collect::<Vec<Client>>(),
     )
 }
 
-/// Get one client
+/// Get one client of a realm
 async fn get_one_client<T>(
     Path(uuid): Path<String>,
+    RequestRealm(realm): RequestRealm,
     State(server): State<Server<T>>,
 ) -> Result<Json<Client>, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
 {
     let uuid = Uuid::from_str(&uuid).ok().ok_or(StatusCode::BAD_REQUEST)?;
 
@@ -109,15 +322,39 @@ where
         .read()
         .await
         .get(&uuid)
+        .filter(|client| client.realm == realm)
         .cloned()
         .map(Json)
         .ok_or(StatusCode::NOT_FOUND)
 }
 
+/// Revoke a client, its session tokens are refused and its key cannot be used to register again
+///
+/// The API key the client registered with still works: rotate it too, or whoever holds it may register a new client.
+///
+/// # Errors
+/// Returns `StatusCode::BAD_REQUEST` if the uuid is not valid
+/// Returns `StatusCode::NOT_FOUND` if there is no client with the requested uuid in the realm
+async fn revoke_client<T>(
+    Path(uuid): Path<String>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Client>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let uuid = Uuid::from_str(&uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+    server
+        .revoke_client(&realm, &uuid)
+        .await
+        .map(Json)
+        .map_err(|e| status_code(&e))
+}
+
 /// Get all chunks
-async fn get_chunks<T>(State(server): State<Server<T>>) -> impl IntoResponse
+async fn get_chunks<T>(_: Global, State(server): State<Server<T>>) -> impl IntoResponse
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
 {
     Json(
         server
@@ -132,62 +369,231 @@ where
 }
 
 /// Get sum of all chunks sizes
-async fn get_chunks_size_sum<T>(State(server): State<Server<T>>) -> impl IntoResponse
+async fn get_chunks_size_sum<T>(_: Global, State(server): State<Server<T>>) -> impl IntoResponse
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
 {
     Json(server.storage.read().await.size())
 }
 
-/// Get all feeds
-async fn get_feeds<T>(State(server): State<Server<T>>) -> impl IntoResponse
+#[derive(Deserialize, Serialize)]
+struct StatsObj {
+    pub storage: Stats,
+    /// Usage of every item revision
+    pub usage: Usage,
+}
+
+/// Get storage statistics along with items usage and deduplication, over all realms
+async fn get_chunks_stats<T>(_: Global, State(server): State<Server<T>>) -> impl IntoResponse
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
 {
-    Json(
+    let storage = server.storage.read().await.stats();
+    let usage = Usage::of(
         server
-            .metadata
+            .realms
             .read()
             .await
-            .feeds
             .values()
-            .cloned()
-            .collect::<Vec<Feed>>(),
-    )
+            .flat_map(|realm| realm.metadata.items.values())
+            .flat_map(|revisions| revisions.values()),
+    );
+    Json(StatsObj { storage, usage })
+}
+
+/// Report what a garbage collection would reclaim, without removing anything
+async fn get_garbage<T>(
+    _: Global,
+    State(server): State<Server<T>>,
+) -> Result<Json<GcReport>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .collect_garbage(true)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Drop expired item revisions and unreferenced chunks
+async fn collect_garbage<T>(
+    _: Global,
+    State(server): State<Server<T>>,
+) -> Result<Json<GcReport>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .collect_garbage(false)
+        .await
+        .map(Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Deserialize, Serialize)]
+struct RealmInfo {
+    pub name: RealmName,
+    pub owner: String,
+    /// Whether publishing into the realm needs a signature by its owner
+    pub owned: bool,
 }
 
-/// Get all items
-async fn get_items<T>(State(server): State<Server<T>>) -> impl IntoResponse
+/// Get all realms
+async fn get_realms<T>(State(server): State<Server<T>>) -> impl IntoResponse
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
 {
     Json(
         server
-            .metadata
+            .realms
             .read()
             .await
-            .items
-            .keys()
-            .cloned()
-            .map(|x| x.to_string_lossy().into())
-            .collect::<Vec<String>>(),
+            .values()
+            .map(|realm| RealmInfo {
+                name: realm.name.clone(),
+                owner: realm.owner.clone(),
+                owned: realm.owner_pkey.is_some(),
+            })
+            .collect::<Vec<RealmInfo>>(),
     )
 }
 
+#[derive(Deserialize, Serialize)]
+struct RealmPostObj {
+    pub name: RealmName,
+    pub owner: String,
+    /// Base64-encoded Ed25519 public key of the owner, anyone may publish into the realm if missing
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub owner_pkey: Option<String>,
+}
+
+/// Create an empty realm
+///
+/// # Errors
+/// Returns `StatusCode::BAD_REQUEST` if the owner public key is not a valid base64-encoded Ed25519 key
+/// Returns `StatusCode::CONFLICT` if a realm with the same name already exists
+async fn create_realm<T>(
+    _: Global,
+    Query(realm): Query<RealmPostObj>,
+    State(server): State<Server<T>>,
+) -> Result<Json<RealmName>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let owner_pkey = realm
+        .owner_pkey
+        .map(|key| decode_public_key(&key))
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    server
+        .create_realm(Realm::new(realm.name, realm.owner, owner_pkey))
+        .await
+        .map(Json)
+        .map_err(|e| status_code(&e))
+}
+
+/// Get all feeds of a realm
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist
+async fn get_feeds<T>(
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Vec<Feed>>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .metadata(&realm)
+        .await
+        .map(|metadata| Json(metadata.feeds.values().cloned().collect()))
+        .map_err(|e| status_code(&e))
+}
+
+/// Get all items of a realm
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist
+async fn get_items<T>(
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Vec<String>>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .metadata(&realm)
+        .await
+        .map(|metadata| {
+            Json(
+                metadata
+                    .items
+                    .keys()
+                    .map(|x| x.to_string_lossy().into())
+                    .collect(),
+            )
+        })
+        .map_err(|e| status_code(&e))
+}
+
 #[derive(Deserialize, Serialize)]
 struct ItemGetObj {
     pub path: PathBuf,
+    /// Specific revision to get, latest if missing
+    pub revision: Option<u32>,
 }
 
-/// Get one item
+/// Get one item, optionally at a specific revision
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist
 async fn get_one_item<T>(
     Query(item): Query<ItemGetObj>,
+    RequestRealm(realm): RequestRealm,
     State(server): State<Server<T>>,
-) -> impl IntoResponse
+) -> Result<impl IntoResponse, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send + Debug,
 {
-    Json(server.metadata.read().await.items.get(&item.path).cloned())
+    server
+        .metadata(&realm)
+        .await
+        .map(|metadata| Json(metadata.item(&item.path, item.revision).cloned()))
+        .map_err(|e| status_code(&e))
+}
+
+#[derive(Deserialize, Serialize)]
+struct RevisionsGetObj {
+    pub path: PathBuf,
+}
+
+/// Get metadata of every revision of an item, oldest first
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist or there is no item at the requested path in it
+async fn get_item_revisions<T>(
+    Query(item): Query<RevisionsGetObj>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Vec<ItemMetadata>>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .metadata(&realm)
+        .await
+        .map_err(|e| status_code(&e))?
+        .items
+        .get(&item.path)
+        .map(|revisions| {
+            revisions
+                .values()
+                .map(|item| item.metadata.clone())
+                .collect()
+        })
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
 }
 
 #[derive(Deserialize, Serialize)]
@@ -197,69 +603,282 @@ struct ItemPostObj {
     pub description: Option<String>,
     pub path: PathBuf,
     pub name: String,
+    /// Feed to publish the item into
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub feed: Option<FeedName>,
+    /// Base64-encoded signature of `RealmChange::Publish` by the realm owner, the root being the one of the item data
+    /// chunked as the server does, needed if the realm has an owner key
+    ///
+    /// It's made for a nonce from `POST /challenge`, which must be used before it expires, see `RealmChange::sign`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub signature: Option<String>,
 }
 
-/// Publish an item
+/// Publish an item, optionally into a feed
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm or the requested feed doesn't exist
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and the item is not signed with it, or the API key
+/// is bound to another realm
+/// Returns `StatusCode::BAD_REQUEST` if the multipart body is malformed or the item data cannot be read
 async fn publish_item<T>(
     Query(item_data): Query<ItemPostObj>,
+    RequestRealm(realm): RequestRealm,
     State(server): State<Server<T>>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default + Debug,
+    T: ChunkStorage + Sync + Send + Debug,
 {
-    while let Some(field) = multipart
-        .next_field()
-        .await
-        .expect("Failed to get next field!")
-    {
-        if field.name().unwrap() != "item" {
+    while let Some(field) = multipart.next_field().await.map_err(|e| {
+        tracing::debug!("Malformed multipart body: {e}");
+        StatusCode::BAD_REQUEST
+    })? {
+        if field.name() != Some("item") {
             continue;
         }
+        let signature = decode(item_data.signature)?;
+        // Stored as it's uploaded, never buffered as a whole
+        let data = field.map(|data| {
+            data.inspect_err(|e| tracing::warn!("Cannot read item field: {e}"))
+                .map_err(std::io::Error::other)
+        });
         let res = server
             .publish_item(
+                &realm,
                 item_data.name,
                 item_data.path,
                 item_data.description,
-                field
-                    .bytes()
-                    .await
-                    .inspect_err(|e| tracing::warn!("Cannot extract bytes from item field: {e}"))
-                    .map_err(|_| StatusCode::BAD_REQUEST)?,
+                data,
+                signature.as_deref(),
+                item_data.feed,
             )
             .await;
         let res = res.map(|x| x.metadata);
         tracing::debug!("{:?}", res);
-        return res.map(Json).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        return res.map(Json).map_err(|e| status_code(&e));
     }
     Err(StatusCode::BAD_REQUEST)
 }
 
 /// Get one feed
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist
 async fn get_one_feed<T>(
     Path(name): Path<FeedName>,
+    RequestRealm(realm): RequestRealm,
     State(server): State<Server<T>>,
-) -> impl IntoResponse
+) -> Result<impl IntoResponse, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .metadata(&realm)
+        .await
+        .map(|metadata| Json(metadata.feeds.get(&name).cloned()))
+        .map_err(|e| status_code(&e))
+}
+
+#[derive(Deserialize, Serialize)]
+struct FeedPostObj {
+    pub name: FeedName,
+}
+
+/// Create an empty feed
+///
+/// # Errors
+/// Returns `StatusCode::CONFLICT` if a feed with the same name already exists
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and the change is not signed with it
+async fn create_feed<T>(
+    Query(feed): Query<FeedPostObj>,
+    Query(signed): Query<SignatureObj>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Feed>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let change = RealmChange::CreateFeed {
+        realm: &realm,
+        feed: &feed.name,
+    };
+    authorize_change(&server, &realm, &change, signed.signature).await?;
+    let feed = Feed::new(&feed.name);
+    server
+        .expose_feed(&realm, feed.clone())
+        .await
+        .map(|_| Json(feed))
+        .map_err(|e| status_code(&e))
+}
+
+#[derive(Deserialize, Serialize)]
+struct FeedPutObj {
+    /// New name of the feed
+    pub name: FeedName,
+}
+
+/// Rename a feed
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if there is no feed with the requested name
+/// Returns `StatusCode::CONFLICT` if the new name is already used by another feed
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and the change is not signed with it
+async fn rename_feed<T>(
+    Path(name): Path<FeedName>,
+    Query(feed): Query<FeedPutObj>,
+    Query(signed): Query<SignatureObj>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Feed>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let change = RealmChange::RenameFeed {
+        realm: &realm,
+        feed: &name,
+        new_name: &feed.name,
+    };
+    authorize_change(&server, &realm, &change, signed.signature).await?;
+    server
+        .rename_feed(&realm, &name, feed.name)
+        .await
+        .map(Json)
+        .map_err(|e| status_code(&e))
+}
+
+/// Delete a feed, its items are kept
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if there is no feed with the requested name
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and the change is not signed with it
+async fn delete_feed<T>(
+    Path(name): Path<FeedName>,
+    Query(signed): Query<SignatureObj>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Feed>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let change = RealmChange::DeleteFeed {
+        realm: &realm,
+        feed: &name,
+    };
+    authorize_change(&server, &realm, &change, signed.signature).await?;
+    server
+        .remove_feed(&realm, &name)
+        .await
+        .map(Json)
+        .map_err(|e| status_code(&e))
+}
+
+#[derive(Deserialize, Serialize)]
+struct FeedItemObj {
+    pub path: PathBuf,
+}
+
+/// Add the latest revision of an item to a feed, which then follows its new revisions
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if either the feed or the item doesn't exist
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and the change is not signed with it
+async fn add_feed_item<T>(
+    Path(name): Path<FeedName>,
+    Query(item): Query<FeedItemObj>,
+    Query(signed): Query<SignatureObj>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Feed>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    let change = RealmChange::AddToFeed {
+        realm: &realm,
+        feed: &name,
+        path: &item.path,
+    };
+    authorize_change(&server, &realm, &change, signed.signature).await?;
+    server
+        .add_to_feed(&realm, &name, &item.path)
+        .await
+        .map(Json)
+        .map_err(|e| status_code(&e))
+}
+
+/// Remove an item from a feed, the item itself is kept
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the feed doesn't exist or doesn't have the item
+/// Returns `StatusCode::FORBIDDEN` if the realm has an owner key and the change is not signed with it
+async fn remove_feed_item<T>(
+    Path(name): Path<FeedName>,
+    Query(item): Query<FeedItemObj>,
+    Query(signed): Query<SignatureObj>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Feed>, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send + Debug,
 {
-    Json(server.metadata.read().await.feeds.get(&name).cloned())
+    let change = RealmChange::RemoveFromFeed {
+        realm: &realm,
+        feed: &name,
+        path: &item.path,
+    };
+    authorize_change(&server, &realm, &change, signed.signature).await?;
+    server
+        .remove_from_feed(&realm, &name, &item.path)
+        .await
+        .map(Json)
+        .map_err(|e| status_code(&e))
+}
+
+/// Get usage and deduplication of the items in a feed
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist or there is no feed with the requested name in it
+async fn get_feed_stats<T>(
+    Path(name): Path<FeedName>,
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<Usage>, StatusCode>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    server
+        .metadata(&realm)
+        .await
+        .map_err(|e| status_code(&e))?
+        .feeds
+        .get(&name)
+        .map(|feed| Json(Usage::of(feed.paths.values())))
+        .ok_or(StatusCode::NOT_FOUND)
 }
 
 /// Download data associated with an hash
-/// This is a simple wrapper around `ChunkStorage::get`
+/// This is a simple wrapper around `ChunkStorage::get`, limited to the hashes of the items of the realm
 ///
 /// # Errors
 /// Returns `StatusCode::BAD_REQUEST` if the hash is not a valid `Hash`
-/// Returns `StatusCode::NOT_FOUND` if the hash is not found in the storage
+/// Returns `StatusCode::NOT_FOUND` if the hash is not found in the storage or in the realm
 async fn get_chunk<T>(
     Path(hash): Path<String>,
+    RequestRealm(realm): RequestRealm,
     State(server): State<Server<T>>,
 ) -> Result<impl IntoResponse, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send + Debug,
 {
     let hash = Hash::from_str(hash.as_str()).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let reachable = server
+        .reachable(&realm)
+        .await
+        .map_err(|e| status_code(&e))?;
+    if !reachable.contains(&hash) {
+        return Err(StatusCode::NOT_FOUND);
+    }
     server
         .storage
         .read()
@@ -274,34 +893,62 @@ struct TransferGetObj {
     got: String,
 }
 
-/// Download data associated with an hash-tree from its root
-async fn get_metadata<T>(State(server): State<Server<T>>) -> impl IntoResponse
+/// Get the metadata of a realm, as served to its clients
+///
+/// # Errors
+/// Returns `StatusCode::NOT_FOUND` if the realm doesn't exist
+async fn get_metadata<T>(
+    RequestRealm(realm): RequestRealm,
+    State(server): State<Server<T>>,
+) -> Result<Json<ServerMetadata>, StatusCode>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send + Debug,
 {
-    let metadata = (*server.metadata.read().await).clone();
-    Json(ServerMetadata::from(metadata))
+    let metadata = server
+        .metadata(&realm)
+        .await
+        .map_err(|e| status_code(&e))?
+        .clone();
+    Ok(Json(ServerMetadata::from(metadata)))
 }
 
-/// Create a new `axum::Router` with all the routes
+/// Create a new `axum::Router` with all the routes, each one needing the role given by `required_role`
 pub fn make_app<T>(server: RawServer<T>) -> IntoMakeServiceWithConnectInfo<Router, SocketAddr>
 where
-    T: ChunkStorage + Sync + Send + Default + Debug + 'static,
+    T: ChunkStorage + Sync + Send + Debug + 'static,
 {
+    let server = Arc::new(server);
     Router::new()
         .route("/", get(version))
         .route("/version", get(version))
+        .route("/challenge", post(issue_challenge))
         .route("/clients", get(get_clients).post(register_client))
-        .route("/clients/:uuid", get(get_one_client))
+        .route("/clients/:uuid", get(get_one_client).delete(revoke_client))
+        .route("/realms", get(get_realms).post(create_realm))
         .route("/items/all", get(get_items))
         .route("/items", get(get_one_item).post(publish_item))
+        .route("/items/revisions", get(get_item_revisions))
         .route("/chunks", get(get_chunks))
         .route("/chunks/size-sum", get(get_chunks_size_sum))
+        .route("/chunks/stats", get(get_chunks_stats))
+        .route("/chunks/gc", get(get_garbage).post(collect_garbage))
         .route("/chunks/get/:hash", get(get_chunk))
-        .route("/feeds", get(get_feeds))
-        .route("/feeds/:feed_name", get(get_one_feed))
+        .route("/feeds", get(get_feeds).post(create_feed))
+        .route(
+            "/feeds/:feed_name",
+            get(get_one_feed).put(rename_feed).delete(delete_feed),
+        )
+        .route(
+            "/feeds/:feed_name/items",
+            post(add_feed_item).delete(remove_feed_item),
+        )
+        .route("/feeds/:feed_name/stats", get(get_feed_stats))
         .route("/metadata", get(get_metadata))
-        .with_state(Arc::new(server))
+        .route_layer(middleware::from_fn_with_state(
+            server.clone(),
+            authorize::<T>,
+        ))
+        .with_state(server)
         .layer(DefaultBodyLimit::max(1024 * 1024 * 1024 * 48))
         .layer(
             TraceLayer::new_for_http()
@@ -313,5 +960,9 @@ where
                         .latency_unit(LatencyUnit::Micros),
                 ),
         )
+        // API keys are left out of traced headers
+        .layer(SetSensitiveRequestHeadersLayer::new([
+            header::AUTHORIZATION,
+        ]))
         .into_make_service_with_connect_info::<SocketAddr>()
 }
@@ -1,80 +1,181 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::SystemTime;
-
-use axum::body::Bytes;
-use distd_core::chunk_storage::ChunkStorage;
-use distd_core::item::{Item, Name as ItemName};
-use distd_core::metadata::Server as ServerMetadata;
-use distd_core::utils::grpc::uuid_to_metadata;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use distd_core::attributes::Attributes;
+use distd_core::auth::Challenges;
+use distd_core::chunk_storage::{gc, ChunkStorage, Node, Staging, StorageError};
+use distd_core::chunker::{Chunking, FastCdc};
+use distd_core::item::{Item, Kind as ItemKind, Name as ItemName};
+use distd_core::metadata::{Item as ItemMetadata, Server as ServerMetadata};
+use distd_core::signature::Signature;
+use distd_core::signature::{encode_public_key, split_nonce, PublicKey, RealmChange};
 use ring::error::KeyRejected;
-use ring::pkcs8::Document;
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use ring::{
     rand,
     signature::{self},
 };
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, RwLock, RwLockMappedWriteGuard, RwLockReadGuard, RwLockWriteGuard};
+use tokio_stream::{Stream, StreamExt};
 use tracing::span;
 use uuid::Uuid;
 
+use crate::access::AccessControl;
 use crate::client::{Client, Name as ClientName};
 use crate::error::Server as ServerError;
-use crate::grpc::UuidAuthInterceptor;
+use crate::grpc::TokenAuthInterceptor;
+use crate::persistence::{Persistence, State};
+use crate::realm::{self, Name as RealmName, Realm};
 use distd_core::feed::{Feed, Name as FeedName};
-use distd_core::hash::hash as do_hash;
+use distd_core::hash::{Hash, TreeBuilder};
 use distd_core::version::Version;
 
 /// Data structure used internally by server, may be converted to `ServerMetadata`
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InternalMetadata {
     // TODO
     // server version
     pub version: Version,
     // Feed map
     pub feeds: HashMap<FeedName, Feed>,
-    // Item map
-    pub items: HashMap<PathBuf, Item>,
+    // Item map, every revision of each item by revision number
+    pub items: HashMap<PathBuf, BTreeMap<u32, Item>>,
+}
+
+impl InternalMetadata {
+    /// Get the latest revision of the item at `path`
+    #[must_use]
+    pub fn latest(&self, path: &Path) -> Option<&Item> {
+        self.items.get(path)?.values().next_back()
+    }
+
+    /// Get the item at `path`, at a specific revision or the latest one if `revision` is `None`
+    #[must_use]
+    pub fn item(&self, path: &Path, revision: Option<u32>) -> Option<&Item> {
+        match revision {
+            Some(revision) => self.items.get(path)?.get(&revision),
+            None => self.latest(path),
+        }
+    }
 }
 
 impl From<InternalMetadata> for ServerMetadata {
     fn from(value: InternalMetadata) -> Self {
         Self {
             version: value.version,
-            feeds: value.feeds,
             items: value
+                .items
+                .keys()
+                .filter_map(|path| Some((path.clone(), value.latest(path)?.metadata.clone())))
+                .collect(),
+            revisions: value
                 .items
                 .iter()
-                .map(|x| (x.0.clone(), x.1.metadata.clone()))
+                .map(|(path, revisions)| {
+                    (
+                        path.clone(),
+                        revisions
+                            .iter()
+                            .map(|(revision, item)| (*revision, item.metadata.clone()))
+                            .collect(),
+                    )
+                })
                 .collect(),
+            feeds: value.feeds,
         }
     }
 }
 
+/// Hashes reachable from the items of a realm, along with the metadata generation they were computed at
+type Reachable = (u64, Arc<HashSet<Hash>>);
+
 /// distd Server
 ///
 /// Server signature is used to check replicated data among clients when shared p2p,
 /// Note that this is different from an eventual "build" signature.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Server<T>
 where
-    T: ChunkStorage + Sync + Send + Default,
+    T: ChunkStorage + Sync + Send,
 {
-    key_pair: Arc<Ed25519KeyPair>, // needs server restart to be changed
-    uuid_nonce: String,            // needs server restart to be changed
+    pub(crate) key_pair: Arc<Ed25519KeyPair>, // needs server restart to be changed
+    uuid_nonce: String,                       // needs server restart to be changed
 
-    /// global server metadata
-    pub metadata: Arc<RwLock<InternalMetadata>>,
+    /// Realms, each with its own metadata, the default one is always there
+    pub realms: Arc<RwLock<BTreeMap<RealmName, Realm>>>,
     /// A storage implementing `ChunkStorage`, basically a key-value database of some sort
     pub storage: Arc<RwLock<T>>,
     /// Client map
     pub clients: Arc<RwLock<BTreeMap<Uuid, Client>>>,
 
-    /// gRPC interceptor for uuids check
-    pub uuid_interceptor: UuidAuthInterceptor,
+    /// gRPC interceptor checking session tokens
+    pub token_interceptor: TokenAuthInterceptor,
+
+    /// Challenges handed out to registering clients
+    pub challenges: Arc<Challenges>,
+
+    /// Public keys of revoked clients, which cannot register again
+    pub revoked: Arc<RwLock<HashSet<PublicKey>>>,
+
+    /// How long session tokens are valid for
+    pub token_ttl: Duration,
+
+    /// API keys and the roles they grant to REST and gRPC requests
+    pub access: Arc<AccessControl>,
+
+    /// Chunker used to compute hash-trees of newly published items
+    pub chunking: Chunking,
+
+    /// How many revisions of each item to keep, all of them if `None`
+    pub keep_revisions: Option<NonZeroUsize>,
+
+    /// Clients not sending a heartbeat for this long are not handed out as peers anymore
+    pub peer_ttl: Duration,
+
+    /// Where to persist server state, if anywhere
+    persistence: Option<Arc<Persistence>>,
+
+    /// Nodes of items being received, kept by garbage collection until the items are in metadata, see `Pins`
+    pending: Arc<Mutex<HashMap<Hash, usize>>>,
+
+    /// Hashes reachable from the items of each realm, along with the generation they were computed at
+    reachable: Arc<Mutex<HashMap<RealmName, Reachable>>>,
+
+    /// Metadata generation, increased on every change
+    generation: Arc<watch::Sender<u64>>,
+}
+
+impl<T> Clone for Server<T>
+where
+    T: ChunkStorage + Sync + Send,
+{
+    fn clone(&self) -> Self {
+        Self {
+            key_pair: self.key_pair.clone(),
+            uuid_nonce: self.uuid_nonce.clone(),
+            realms: self.realms.clone(),
+            storage: self.storage.clone(),
+            clients: self.clients.clone(),
+            token_interceptor: self.token_interceptor.clone(),
+            challenges: self.challenges.clone(),
+            revoked: self.revoked.clone(),
+            token_ttl: self.token_ttl,
+            access: self.access.clone(),
+            chunking: self.chunking,
+            keep_revisions: self.keep_revisions,
+            peer_ttl: self.peer_ttl,
+            persistence: self.persistence.clone(),
+            pending: self.pending.clone(),
+            reachable: self.reachable.clone(),
+            generation: self.generation.clone(),
+        }
+    }
 }
 
 impl<T> Default for Server<T>
@@ -92,38 +193,201 @@ where
         let key_pair = signature::Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap();
 
         Self {
+            token_interceptor: TokenAuthInterceptor::new(&key_pair),
             key_pair: Arc::new(key_pair),
             uuid_nonce,
-            metadata: Arc::new(RwLock::new(InternalMetadata::default())),
+            realms: Arc::new(RwLock::new(with_default_realm(BTreeMap::new()))),
             clients: Arc::new(RwLock::new(BTreeMap::<Uuid, Client>::new())),
             storage: Arc::default(),
-            uuid_interceptor: UuidAuthInterceptor::default(),
+            challenges: Arc::default(),
+            revoked: Arc::default(),
+            token_ttl: DEFAULT_TOKEN_TTL,
+            access: Arc::default(),
+            chunking: Chunking::FastCdc(FastCdc::default()),
+            keep_revisions: None,
+            peer_ttl: DEFAULT_PEER_TTL,
+            persistence: None,
+            pending: Arc::default(),
+            reachable: Arc::default(),
+            generation: first_generation(),
         }
     }
 }
 
+/// Default `Server::peer_ttl`, a few missed heartbeats
+pub const DEFAULT_PEER_TTL: Duration = Duration::from_secs(90);
+
+/// Default `Server::token_ttl`, clients renew their tokens well before they expire
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_mins(15);
+
 #[derive(Debug)]
 pub struct RegisterError;
 
+/// Nodes pinned against garbage collection by an item being received, in `Server::pending`
+///
+/// Pins are released when dropped, leaving the nodes to garbage collection unless the item made it to metadata.
+struct Pins {
+    pending: Arc<Mutex<HashMap<Hash, usize>>>,
+    hashes: Vec<Hash>,
+}
+
+impl Pins {
+    /// Pin `hashes` instead of the ones pinned so far
+    ///
+    /// Call while holding the storage lock the nodes were stored with, so that no collection runs in between.
+    fn replace<'a, I>(&mut self, hashes: I)
+    where
+        I: IntoIterator<Item = &'a Hash>,
+    {
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        let old = std::mem::replace(&mut self.hashes, hashes.into_iter().copied().collect());
+        for hash in &self.hashes {
+            *pending.entry(*hash).or_default() += 1;
+        }
+        release(&mut pending, &old);
+    }
+}
+
+impl Drop for Pins {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.pending.lock() {
+            release(&mut pending, &self.hashes);
+        }
+    }
+}
+
+fn release(pending: &mut HashMap<Hash, usize>, hashes: &[Hash]) {
+    for hash in hashes {
+        if let Some(count) = pending.get_mut(hash) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(hash);
+            }
+        }
+    }
+}
+
+/// Add the default realm to `realms`, if not there yet
+fn with_default_realm(mut realms: BTreeMap<RealmName, Realm>) -> BTreeMap<RealmName, Realm> {
+    realms
+        .entry(realm::DEFAULT.into())
+        .or_insert_with(|| Realm::new(realm::DEFAULT.into(), String::new(), None));
+    realms
+}
+
+/// Generations are not persisted, they start from the server start time so that they're not reused after a restart
+fn first_generation() -> Arc<watch::Sender<u64>> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    Arc::new(watch::Sender::new(
+        u64::try_from(now.as_nanos()).unwrap_or_default(),
+    ))
+}
+
 impl<T> Server<T>
 where
-    T: ChunkStorage + Sync + Send + Default + Debug,
+    T: ChunkStorage + Sync + Send + Debug,
 {
-    /// Create a new server instance, with a specific key pair and metadata
-    pub fn new(pkcs8_bytes: &Document, metadata: InternalMetadata) -> Result<Self, KeyRejected> {
-        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref())?;
+    /// Create a new server instance, with a specific key pair, realms and storage
+    ///
+    /// The default realm is added to `realms` if missing.
+    pub fn new(
+        pkcs8_bytes: &[u8],
+        realms: BTreeMap<RealmName, Realm>,
+        storage: T,
+    ) -> Result<Self, KeyRejected> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8_bytes)?;
         Ok(Self {
+            token_interceptor: TokenAuthInterceptor::new(&key_pair),
             key_pair: Arc::new(key_pair),
-            uuid_nonce: blake3::hash(pkcs8_bytes.as_ref()).to_string(),
-            metadata: Arc::new(RwLock::new(metadata)),
-            ..Default::default()
+            uuid_nonce: blake3::hash(pkcs8_bytes).to_string(),
+            realms: Arc::new(RwLock::new(with_default_realm(realms))),
+            storage: Arc::new(RwLock::new(storage)),
+            clients: Arc::default(),
+            challenges: Arc::default(),
+            revoked: Arc::default(),
+            token_ttl: DEFAULT_TOKEN_TTL,
+            access: Arc::default(),
+            chunking: Chunking::FastCdc(FastCdc::default()),
+            keep_revisions: None,
+            peer_ttl: DEFAULT_PEER_TTL,
+            persistence: None,
+            pending: Arc::default(),
+            reachable: Arc::default(),
+            generation: first_generation(),
         })
     }
 
-    /// Register a new client
+    /// Restore a server from its persisted state, using `storage` for chunks
+    ///
+    /// On first start a new key pair is generated and stored along with an empty state.
+    /// Any later change to the server state is persisted again.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn restore(persistence: Persistence, storage: T) -> Result<Self, ServerError> {
+        let key = persistence.load_or_generate_key()?;
+        let mut state = persistence.load_state()?;
+
+        let mut server = Self::new(&key, BTreeMap::new(), storage).map_err(ServerError::Key)?;
+        // Items may have been signed by an older version, over other data
+        for realm in state.realms.values_mut() {
+            server.sign_items(&mut realm.metadata)?;
+        }
+        server.realms = Arc::new(RwLock::new(with_default_realm(state.realms)));
+        server.clients = Arc::new(RwLock::new(state.clients));
+        server
+            .token_interceptor
+            .uuids
+            .write()
+            .unwrap()
+            .extend(state.uuids);
+        server.revoked = Arc::new(RwLock::new(state.revoked));
+        server.persistence = Some(Arc::new(persistence));
+        Ok(server)
+    }
+
+    /// Sign again every item of `metadata`, feeds included
+    fn sign_items(&self, metadata: &mut InternalMetadata) -> Result<(), ServerError> {
+        let items = metadata.items.values_mut().flat_map(BTreeMap::values_mut);
+        let in_feeds = metadata
+            .feeds
+            .values_mut()
+            .flat_map(|feed| feed.paths.values_mut());
+        for item in items.chain(in_feeds) {
+            item.metadata.signature = Some(self.sign(&item.metadata.signed_data()?));
+        }
+        Ok(())
+    }
+
+    /// Persist server state, if a persistence directory was provided
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn persist(&self) -> Result<(), ServerError> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(());
+        };
+        persistence
+            .store_state(async {
+                let uuids = self.token_interceptor.uuids.read().unwrap().clone();
+                State {
+                    realms: self.realms.read().await.clone(),
+                    clients: self.clients.read().await.clone(),
+                    uuids,
+                    revoked: self.revoked.read().await.clone(),
+                }
+            })
+            .await
+    }
+
+    /// Register a new client into `realm`, bound to `public_key`
     ///
     /// This function will insert a new client into the clients map.
-    /// The clients map key will be a UUID generated from the client name, the server nonce and the client address.
+    /// The clients map key will be a UUID generated from the client name, the server nonce, the client address and
+    /// its key.
+    /// Clients already registered stay in the realm they registered into and bound to the key they registered with,
+    /// asking for another realm or using another key is an error. Revoked keys are refused.
+    /// Proving that the client holds `public_key` is up to the caller, see `Server::authenticate`.
     #[allow(clippy::missing_panics_doc)]
     pub async fn register_client(
         &self,
@@ -131,6 +395,8 @@ where
         addr: SocketAddr,
         version: Option<Version>,
         uuid: Option<Uuid>,
+        realm: RealmName,
+        public_key: PublicKey,
     ) -> Result<Uuid, RegisterError> {
         // tracing span
         let span = span!(tracing::Level::INFO, "register_client");
@@ -140,8 +406,19 @@ where
         let nonced_name = name.clone() + &self.uuid_nonce + &addr.to_string();
         tracing::debug!("Client nonced name: '{}'", nonced_name);
 
+        if self.revoked.read().await.contains(&public_key) {
+            tracing::warn!("Client \"{name}\"@{addr} used a revoked key");
+            return Err(RegisterError);
+        }
+
         if let Some(u) = uuid {
-            if self.clients.read().await.contains_key(&u) {
+            if self
+                .clients
+                .read()
+                .await
+                .get(&u)
+                .is_some_and(|client| client.realm == realm && client.public_key == public_key)
+            {
                 tracing::info!(
                     "Got existing uuid '{}' from \"{}\"@{}",
                     u.to_string(),
@@ -159,9 +436,14 @@ where
                 Err(RegisterError)
             }
         } else {
+            if !self.realms.read().await.contains_key(&realm) {
+                tracing::warn!("Client \"{name}\"@{addr} asked for unknown realm '{realm}'");
+                return Err(RegisterError);
+            }
+            let nonced_name = nonced_name + &realm + &encode_public_key(&public_key);
             let uuid = Uuid::new_v5(&Uuid::NAMESPACE_URL, nonced_name.as_bytes());
             tracing::info!(
-                "Assigned new client uuid '{}' to \"{}\"@{}",
+                "Assigned new client uuid '{}' to \"{}\"@{} in realm '{realm}'",
                 uuid.to_string(),
                 name,
                 addr
@@ -170,18 +452,19 @@ where
                 addr,
                 name,
                 uuid,
+                realm,
+                public_key,
                 version,
                 last_heartbeat: SystemTime::now(),
+                holdings: HashSet::new(),
+                peer_port: None,
             };
 
             // Add uuid to valid list in interceptor
-            self.uuid_interceptor
-                .uuids
-                .write()
-                .unwrap()
-                .insert(uuid_to_metadata(&uuid));
+            self.token_interceptor.uuids.write().unwrap().insert(uuid);
 
-            self.clients
+            let uuid = self
+                .clients
                 .write()
                 .await
                 .try_insert(client.uuid, client)
@@ -189,81 +472,1412 @@ where
                 .cloned()
                 .ok()
                 .map(|client| client.uuid)
-                .ok_or(RegisterError)
+                .ok_or(RegisterError)?;
+
+            if let Err(e) = self.persist().await {
+                tracing::error!("Cannot persist newly registered client: {e}");
+            }
+            Ok(uuid)
         }
     }
 
-    #[allow(clippy::missing_panics_doc)]
-    pub async fn expose_feed(&self, feed: Feed) -> Result<FeedName, RegisterError> {
-        self.metadata
-            .write()
+    /// Add a new realm, whose name must not be used by another one
+    pub async fn create_realm(&self, realm: Realm) -> Result<RealmName, ServerError> {
+        let name = {
+            let mut realms = self.realms.write().await;
+            if realms.contains_key(&realm.name) {
+                return Err(ServerError::RealmExists(realm.name));
+            }
+            tracing::info!("New realm '{}' owned by '{}'", realm.name, realm.owner);
+            let name = realm.name.clone();
+            realms.insert(name.clone(), realm);
+            name
+        };
+        self.persist().await?;
+        Ok(name)
+    }
+
+    /// Metadata of `realm`, locked for reading
+    pub async fn metadata(
+        &self,
+        realm: &RealmName,
+    ) -> Result<RwLockReadGuard<'_, InternalMetadata>, ServerError> {
+        RwLockReadGuard::try_map(self.realms.read().await, |realms| {
+            realms.get(realm).map(|realm| &realm.metadata)
+        })
+        .map_err(|_| ServerError::UnknownRealm(realm.clone()))
+    }
+
+    /// Metadata of `realm`, locked for writing
+    async fn metadata_mut(
+        &self,
+        realm: &RealmName,
+    ) -> Result<RwLockMappedWriteGuard<'_, InternalMetadata>, ServerError> {
+        RwLockWriteGuard::try_map(self.realms.write().await, |realms| {
+            realms.get_mut(realm).map(|realm| &mut realm.metadata)
+        })
+        .map_err(|_| ServerError::UnknownRealm(realm.clone()))
+    }
+
+    /// Realm a client registered into
+    pub async fn client_realm(&self, uuid: &Uuid) -> Result<RealmName, ServerError> {
+        self.clients
+            .read()
             .await
-            .feeds
-            .try_insert(feed.name.clone(), feed)
-            .ok()
-            .cloned()
-            .map(|feed| feed.name.clone())
-            .ok_or(RegisterError)
+            .get(uuid)
+            .map(|client| client.realm.clone())
+            .ok_or(ServerError::UnknownClient(*uuid))
+    }
+
+    /// Check that the owner of `realm`, if it has one, made `signature` of `change`
+    pub async fn authorize_change(
+        &self,
+        realm: &RealmName,
+        change: &RealmChange<'_>,
+        signature: Option<&[u8]>,
+    ) -> Result<(), ServerError> {
+        self.realms
+            .read()
+            .await
+            .get(realm)
+            .ok_or_else(|| ServerError::UnknownRealm(realm.clone()))?
+            .authorize(change, signature, |nonce| {
+                self.challenges.take(nonce).is_ok()
+            })
     }
 
-    /// Publish a new item
+    /// Hashes of the nodes reachable from the items of `realm`, the only ones its clients may learn about
     ///
-    /// This function will insert the item into the storage and the metadata map.
-    /// The item will be inserted into the metadata map using the path as key.
+    /// Storage is shared among realms, hashes of other realms are neither served nor told to be held. The set is
+    /// computed again once metadata changed.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn reachable(&self, realm: &RealmName) -> Result<Arc<HashSet<Hash>>, ServerError> {
+        let generation = *self.generation.borrow();
+        if let Some((computed, hashes)) = self.reachable.lock().unwrap().get(realm) {
+            if *computed == generation {
+                return Ok(hashes.clone());
+            }
+        }
+
+        let roots: Vec<Hash> = self
+            .metadata(realm)
+            .await?
+            .items
+            .values()
+            .flat_map(BTreeMap::values)
+            .map(|item| *item.root())
+            .collect();
+        let mut stack: Vec<Arc<Node>> = {
+            let storage = self.storage.read().await;
+            roots.iter().filter_map(|root| storage.get(root)).collect()
+        };
+        let mut hashes = HashSet::new();
+        while let Some(node) = stack.pop() {
+            if hashes.insert(*node.hash()) {
+                if let Some((left, right)) = node.children() {
+                    stack.extend([left.clone(), right.clone()]);
+                }
+            }
+        }
+
+        let hashes = Arc::new(hashes);
+        self.reachable
+            .lock()
+            .unwrap()
+            .insert(realm.clone(), (generation, hashes.clone()));
+        Ok(hashes)
+    }
+
+    /// Add `feed` to `realm`, its name must not be used by another feed of the realm
+    pub async fn expose_feed(
+        &self,
+        realm: &RealmName,
+        feed: Feed,
+    ) -> Result<FeedName, ServerError> {
+        let name = {
+            let mut metadata = self.metadata_mut(realm).await?;
+            if metadata.feeds.contains_key(&feed.name) {
+                return Err(ServerError::FeedExists(feed.name));
+            }
+            let name = feed.name.clone();
+            metadata.feeds.insert(name.clone(), feed);
+            self.metadata_changed();
+            name
+        };
+
+        if let Err(e) = self.persist().await {
+            tracing::error!("Cannot persist newly exposed feed: {e}");
+        }
+        Ok(name)
+    }
+
+    /// Add the latest revision of the item at `path` to the feed `name`, the feed then follows its new revisions
+    pub async fn add_to_feed(
+        &self,
+        realm: &RealmName,
+        name: &FeedName,
+        path: &Path,
+    ) -> Result<Feed, ServerError> {
+        self.update_feeds(realm, |metadata| {
+            let item = metadata
+                .latest(path)
+                .cloned()
+                .ok_or_else(|| ServerError::UnknownItem(path.to_path_buf()))?;
+            let feed = metadata
+                .feeds
+                .get_mut(name)
+                .ok_or_else(|| ServerError::UnknownFeed(name.clone()))?;
+            feed.paths.insert(path.to_path_buf(), item);
+            Ok(feed.clone())
+        })
+        .await
+    }
+
+    /// Remove the item at `path` from the feed `name`, the item itself is kept
+    pub async fn remove_from_feed(
+        &self,
+        realm: &RealmName,
+        name: &FeedName,
+        path: &Path,
+    ) -> Result<Feed, ServerError> {
+        self.update_feeds(realm, |metadata| {
+            let feed = metadata
+                .feeds
+                .get_mut(name)
+                .ok_or_else(|| ServerError::UnknownFeed(name.clone()))?;
+            feed.paths
+                .remove(path)
+                .ok_or_else(|| ServerError::UnknownItem(path.to_path_buf()))?;
+            Ok(feed.clone())
+        })
+        .await
+    }
+
+    /// Rename the feed `name` to `new_name`, which must not be used by another feed
+    pub async fn rename_feed(
+        &self,
+        realm: &RealmName,
+        name: &FeedName,
+        new_name: FeedName,
+    ) -> Result<Feed, ServerError> {
+        self.update_feeds(realm, |metadata| {
+            if metadata.feeds.contains_key(&new_name) {
+                return Err(ServerError::FeedExists(new_name));
+            }
+            let mut feed = metadata
+                .feeds
+                .remove(name)
+                .ok_or_else(|| ServerError::UnknownFeed(name.clone()))?;
+            feed.name.clone_from(&new_name);
+            metadata.feeds.insert(new_name, feed.clone());
+            Ok(feed)
+        })
+        .await
+    }
+
+    /// Delete the feed `name`, returning it, its items are kept
+    pub async fn remove_feed(
+        &self,
+        realm: &RealmName,
+        name: &FeedName,
+    ) -> Result<Feed, ServerError> {
+        self.update_feeds(realm, |metadata| {
+            metadata
+                .feeds
+                .remove(name)
+                .ok_or_else(|| ServerError::UnknownFeed(name.clone()))
+        })
+        .await
+    }
+
+    /// Apply `f` to the metadata of `realm` and persist the change, returning the feed it returns
+    async fn update_feeds<F>(&self, realm: &RealmName, f: F) -> Result<Feed, ServerError>
+    where
+        F: FnOnce(&mut InternalMetadata) -> Result<Feed, ServerError>,
+    {
+        let feed = {
+            let mut metadata = self.metadata_mut(realm).await?;
+            let feed = f(&mut metadata)?;
+            self.metadata_changed();
+            feed
+        };
+        self.persist().await?;
+        Ok(feed)
+    }
+
+    /// Start a new metadata generation, notifying anyone following updates
+    ///
+    /// Call while still holding the metadata write lock, so that a generation read along with metadata always
+    /// matches it.
+    fn metadata_changed(&self) {
+        self.generation.send_modify(|generation| *generation += 1);
+    }
+
+    /// Follow metadata generations, the current one is marked as seen
+    #[must_use]
+    pub fn generations(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
+    /// Publish a new item into `realm`, whose content is read from `file` piece by piece
+    ///
+    /// This function will insert the item into the storage and the metadata map of the realm.
+    /// The item will be inserted into the metadata map using the path as key, and into `feed` if provided.
+    /// If the realm has an owner, `signature` must be its signature of the `RealmChange::Publish` of the item, whose
+    /// root is computed with `Server::chunking`, for a challenge nonce taken before the upload starts. Content is stored as it comes, so that it's never held in memory as
+    /// a whole: when publishing is not authorized, the nodes already stored are left to garbage collection.
+    /// Storage is only locked to store each piece, the nodes stored so far being pinned until the item is in metadata:
+    /// a slow upload doesn't hold anyone else up.
     ///
     /// # Panics
     ///
     /// Conversion of paths to UTF-8 may panic on some OSes (Windows for sure)
     #[allow(clippy::missing_panics_doc)]
-    pub async fn publish_item(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn publish_item<S, B>(
         &self,
+        realm: &RealmName,
         name: ItemName,
         path: PathBuf,
         description: Option<String>,
-        file: Bytes,
-    ) -> Result<Item, ServerError> {
-        // Get last revision, if any. 0 otherwise
-        let revision = self
-            .metadata
+        mut file: S,
+        signature: Option<&[u8]>,
+        feed: Option<FeedName>,
+    ) -> Result<Item, ServerError>
+    where
+        S: Stream<Item = std::io::Result<B>> + Unpin + Send,
+        B: AsRef<[u8]>,
+    {
+        self.check_feed(realm, feed.as_ref()).await?;
+        // The nonce is taken before the upload, which may well last longer than it's good for
+        let taken = signature
+            .and_then(split_nonce)
+            .and_then(|(nonce, _)| self.challenges.take(nonce).ok());
+
+        let reception = |e: distd_core::error::Error| ServerError::ItemReception(Box::new(e));
+        let mut staging = Staging::default();
+        let mut pins = self.pins();
+        let mut builder = TreeBuilder::new(&mut staging, &self.chunking);
+        while let Some(data) = file.next().await {
+            let data = data.map_err(|e| reception(e.into()))?;
+            builder.update(data.as_ref()).map_err(reception)?;
+            self.stage(builder.hasher(), &mut pins, Staging::commit)
+                .await?;
+        }
+        builder.finish().map_err(reception)?;
+        self.stage(&mut staging, &mut pins, Staging::commit).await?;
+        let root = staging.root().ok_or(ServerError::ChunkInsertError)?;
+        let change = RealmChange::Publish {
+            realm,
+            path: &path,
+            root: root.hash(),
+            feed: feed.as_deref(),
+        };
+        self.realms
             .read()
             .await
-            .items
-            .get(&path)
-            .map(|i| i.metadata.revision + 1)
-            .unwrap_or_default();
+            .get(realm)
+            .ok_or_else(|| ServerError::UnknownRealm(realm.clone()))?
+            .authorize(&change, signature, |nonce| {
+                taken.is_some_and(|taken| taken == nonce)
+            })?;
 
         // Check if already exists and if so just return the old one
-        // This is doing duplicated hashing calculations, may be improved
-        let root = &do_hash(&file);
-        if let Some(old) = self.metadata.read().await.items.get(&path) {
-            if old.metadata.name == name
-                && old.metadata.path == path
-                && old.metadata.description == description
-                && &old.metadata.root.hash == root
-            {
-                return Ok(old.clone());
+        if let Some(old) = self
+            .unchanged(
+                realm,
+                &name,
+                &path,
+                description.as_deref(),
+                root.hash(),
+                self.chunking,
+                &Attributes::default(),
+                &ItemKind::File,
+            )
+            .await
+        {
+            if let Some(feed) = &feed {
+                self.add_to_feed(realm, feed, &old.metadata.path).await?;
             }
+            return Ok(old);
         }
 
-        // Create item and return it
-        let item = self
-            .storage
-            .write()
+        // Create item, sign it and return it
+        let item = Item::new(name, path, 0, description, self.chunking, &root);
+        self.add_item(realm, item, feed.as_ref()).await
+    }
+
+    /// Publish an item uploaded by a client into `realm`, whose nodes are received from `stream`
+    ///
+    /// Only nodes missing from storage need to be sent, any other subtree can be `Skipped` as long as it's reachable
+    /// from `realm` or was received earlier in the upload: content of other realms can't be linked by guessing its
+    /// hashes. The revision is assigned here, the rest of `target` is kept as is and the received tree must have its
+    /// root.
+    /// If `feed` is provided, the item is added to it as well.
+    /// Storage is only locked to store each node, the ones received so far being pinned until the item is in
+    /// metadata: a slow upload doesn't hold anyone else up. Items with a chunking outside sane limits are refused,
+    /// as every client installing them would follow it.
+    pub async fn receive_item<S>(
+        &self,
+        realm: &RealmName,
+        target: ItemMetadata,
+        feed: Option<FeedName>,
+        mut stream: S,
+    ) -> Result<Item, ServerError>
+    where
+        S: Stream<Item = Node> + Unpin + Send,
+    {
+        target
+            .chunking
+            .check()
+            .map_err(|e| ServerError::ItemReception(Box::new(e.into())))?;
+        self.check_feed(realm, feed.as_ref()).await?;
+
+        if let Some(old) = self
+            .unchanged(
+                realm,
+                &target.name,
+                &target.path,
+                target.description.as_deref(),
+                &target.root.hash,
+                target.chunking,
+                &target.attributes,
+                &target.kind,
+            )
             .await
-            .create_item(name, path, revision, description, file)
-            .ok_or(ServerError::ChunkInsertError)?;
+        {
+            // Nothing to receive, the item may still need to be added to the feed
+            if let Some(feed) = &feed {
+                self.add_to_feed(realm, feed, &old.metadata.path).await?;
+            }
+            return Ok(old);
+        }
 
-        self.metadata
-            .write()
+        let reachable = self.reachable(realm).await?;
+        let mut received = HashSet::new();
+        let mut staging = Staging::default();
+        let mut pins = self.pins();
+        while let Some(node) = stream.next().await {
+            let linked = match &node {
+                Node::Parent { left, right, .. } => vec![*left.hash(), *right.hash()],
+                Node::Skipped { hash, .. } => vec![*hash],
+                Node::Stored { .. } => vec![],
+            };
+            if let Some(hash) = linked
+                .into_iter()
+                .find(|hash| !reachable.contains(hash) && !received.contains(hash))
+            {
+                let e = StorageError::MissingNode(hash);
+                return Err(ServerError::ItemReception(Box::new(e.into())));
+            }
+            received.insert(*node.hash());
+            self.stage(&mut staging, &mut pins, |staging, storage| {
+                staging.receive(storage, &node)
+            })
+            .await?;
+        }
+        let item = staging
+            .into_item(target)
+            .map_err(|e| ServerError::ItemReception(Box::new(e.into())))?;
+        self.add_item(realm, item, feed.as_ref()).await
+    }
+
+    /// Pin nodes of an item being received against garbage collection, until the returned pins are dropped
+    fn pins(&self) -> Pins {
+        Pins {
+            pending: self.pending.clone(),
+            hashes: vec![],
+        }
+    }
+
+    /// Apply `f` to `staging` and storage, locked just for that, then pin the frontier of `staging`
+    async fn stage<F>(
+        &self,
+        staging: &mut Staging,
+        pins: &mut Pins,
+        f: F,
+    ) -> Result<(), ServerError>
+    where
+        F: FnOnce(&mut Staging, &mut T) -> Result<(), StorageError>,
+    {
+        let mut storage = self.storage.write().await;
+        f(staging, &mut storage).map_err(|e| ServerError::ItemReception(Box::new(e.into())))?;
+        pins.replace(staging.frontier());
+        Ok(())
+    }
+
+    /// Check that `realm` exists and has `feed`, if any, before publishing into it
+    async fn check_feed(
+        &self,
+        realm: &RealmName,
+        feed: Option<&FeedName>,
+    ) -> Result<(), ServerError> {
+        let metadata = self.metadata(realm).await?;
+        match feed {
+            Some(feed) if !metadata.feeds.contains_key(feed) => {
+                Err(ServerError::UnknownFeed(feed.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Latest item at `path` of `realm`, if publishing the given metadata and content wouldn't change it
+    #[allow(clippy::too_many_arguments)]
+    async fn unchanged(
+        &self,
+        realm: &RealmName,
+        name: &ItemName,
+        path: &Path,
+        description: Option<&str>,
+        root: &Hash,
+        chunking: Chunking,
+        attributes: &Attributes,
+        kind: &ItemKind,
+    ) -> Option<Item> {
+        self.metadata(realm)
             .await
-            .items
-            .insert(item.metadata.path.clone(), item.clone());
+            .ok()?
+            .latest(path)
+            .filter(|old| {
+                &old.metadata.name == name
+                    && old.metadata.description.as_deref() == description
+                    && &old.metadata.root.hash == root
+                    && old.metadata.chunking == chunking
+                    && &old.metadata.attributes == attributes
+                    && &old.metadata.kind == kind
+            })
+            .cloned()
+    }
+
+    /// Sign a newly stored item and add it to the metadata of `realm`, and to `feed` if provided
+    ///
+    /// The item gets the revision following the latest one at its path, 0 if none, assigned along with the metadata
+    /// change so that concurrent uploads never get the same one. Feeds already listing the item path are moved to the
+    /// new revision. Nodes of the item must be kept from garbage collection until then, e.g. pinned.
+    /// Garbage is only collected when the new revision makes an older one at the same path exceed `keep_revisions`.
+    async fn add_item(
+        &self,
+        realm: &RealmName,
+        mut item: Item,
+        feed: Option<&FeedName>,
+    ) -> Result<Item, ServerError> {
+        let keep = self.keep_revisions.map_or(usize::MAX, NonZeroUsize::get);
+        let expired = {
+            let mut metadata = self.metadata_mut(realm).await?;
+            item.metadata.revision = metadata
+                .latest(&item.metadata.path)
+                .map_or(0, |latest| latest.metadata.revision + 1);
+            item.metadata.signature = Some(self.sign(&item.metadata.signed_data()?));
+
+            // Previous revisions are kept, so that clients can still pin them or diff from them
+            let revisions = metadata
+                .items
+                .entry(item.metadata.path.clone())
+                .or_default();
+            revisions.insert(item.metadata.revision, item.clone());
+            let expired = revisions.len() > keep;
+            for (name, entries) in &mut metadata.feeds {
+                if Some(name) == feed || entries.paths.contains_key(&item.metadata.path) {
+                    entries
+                        .paths
+                        .insert(item.metadata.path.clone(), item.clone());
+                }
+            }
+            self.metadata_changed();
+            expired
+        };
+
+        if expired {
+            self.collect_garbage(false).await?;
+        } else {
+            self.persist().await?;
+        }
 
         Ok(item)
     }
 
+    /// Drop item revisions exceeding `keep_revisions`, then any node not referenced by the retained ones
+    ///
+    /// Storage is shared among realms, revisions of every realm are retained, as well as the nodes of items being
+    /// received. On a dry run nothing is changed, the report tells what would be reclaimed.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<gc::Report, ServerError> {
+        // Nodes are pinned while storage is locked, which is then locked before anything else
+        let mut storage = self.storage.write().await;
+        let mut realms = self.realms.write().await;
+
+        let keep = self.keep_revisions.map_or(usize::MAX, NonZeroUsize::get);
+        let mut roots: Vec<Hash> = self.pending.lock().unwrap().keys().copied().collect();
+        let mut changed = false;
+        let items = realms
+            .values_mut()
+            .flat_map(|realm| realm.metadata.items.values_mut());
+        for revisions in items {
+            let expired: Vec<u32> = revisions
+                .keys()
+                .take(revisions.len().saturating_sub(keep))
+                .copied()
+                .collect();
+            if !dry_run {
+                for revision in &expired {
+                    revisions.remove(revision);
+                }
+                changed |= !expired.is_empty();
+            }
+            roots.extend(
+                revisions
+                    .iter()
+                    .filter(|(revision, _)| !expired.contains(revision))
+                    .map(|(_, item)| *item.root()),
+            );
+        }
+
+        let report = storage.collect_garbage(&roots, dry_run);
+        if changed {
+            self.metadata_changed();
+        }
+        drop(realms);
+        drop(storage);
+
+        if !dry_run {
+            self.persist().await?;
+        }
+        Ok(report)
+    }
+
+    /// Record the hashes a client advertised to hold, replacing any previous record if `replace` is set
+    ///
+    /// Only hashes of the items of the client realm are recorded, returns whether all of them were known.
+    /// Holdings are used in transfers when a client doesn't provide any hash, they're not persisted on their
+    /// own as clients advertise them again when they start.
+    pub async fn record_holdings(
+        &self,
+        uuid: &Uuid,
+        hashes: Vec<Hash>,
+        replace: bool,
+    ) -> Result<bool, ServerError> {
+        let count = hashes.len();
+        let reachable = self.reachable(&self.client_realm(uuid).await?).await?;
+        let known: Vec<Hash> = hashes
+            .into_iter()
+            .filter(|hash| reachable.contains(hash))
+            .collect();
+        tracing::debug!(
+            "Client {uuid} holds {} known hashes out of {count}",
+            known.len()
+        );
+
+        let mut clients = self.clients.write().await;
+        let client = clients
+            .get_mut(uuid)
+            .ok_or(ServerError::UnknownClient(*uuid))?;
+        if replace {
+            client.holdings.clear();
+        }
+        let all_known = known.len() == count;
+        client.holdings.extend(known);
+        client.last_heartbeat = SystemTime::now();
+        Ok(all_known)
+    }
+
+    /// Sign `data` with the server key pair
+    #[must_use]
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        self.key_pair.sign(data).as_ref().to_vec()
+    }
+
     /// Get the public key of the server
     #[must_use] pub fn public_key(&self) -> &[u8] {
         self.key_pair.public_key().as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use axum::body::Bytes;
+    use distd_core::chunk_storage::{hashmap_storage::HashMapStorage, ChunkStorage, Node};
+    use distd_core::chunker::{Chunking, FixedSize};
+    use distd_core::feed::Feed;
+    use distd_core::hash::{hash, hash_with, Hash};
+    use distd_core::item::Item;
+    use distd_core::metadata::{Item as ItemMetadata, Server as ServerMetadata};
+    use distd_core::signature::RealmChange;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::collections::HashSet;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_stream::Stream;
+    use uuid::Uuid;
+
+    use super::Server;
+    use crate::error::Server as ServerError;
+    use crate::realm::{self, Realm};
+
+    /// Content of an item uploaded in a single piece
+    fn content(data: Vec<u8>) -> impl Stream<Item = std::io::Result<Bytes>> + Unpin + Send {
+        tokio_stream::once(Ok(Bytes::from(data)))
+    }
+
+    #[tokio::test]
+    async fn publish_keeps_revisions() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        let path = PathBuf::from("a/b");
+
+        let first = server
+            .publish_item(
+                &realm,
+                "x".into(),
+                path.clone(),
+                None,
+                content(vec![1u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        // Publishing the same content again doesn't create a new revision
+        let same = server
+            .publish_item(
+                &realm,
+                "x".into(),
+                path.clone(),
+                None,
+                content(vec![1u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(first, same);
+        let second = server
+            .publish_item(
+                &realm,
+                "x".into(),
+                path.clone(),
+                None,
+                content(vec![2u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(first.metadata.revision, 0);
+        assert_eq!(second.metadata.revision, 1);
+
+        let public_key = server.public_key().try_into().unwrap();
+        assert!(first.metadata.verify(&public_key).is_ok());
+        assert!(second.metadata.verify(&public_key).is_ok());
+
+        let metadata = server.metadata(&realm).await.unwrap();
+        assert_eq!(metadata.latest(&path), Some(&second));
+        assert_eq!(metadata.item(&path, Some(0)), Some(&first));
+
+        let metadata = ServerMetadata::from(metadata.clone());
+        assert_eq!(metadata.items.get(&path), Some(&second.metadata));
+        assert_eq!(metadata.revisions[&path].len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retention_drops_old_revisions() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let mut server = Server::<HashMapStorage>::default();
+        let path = PathBuf::from("a/b");
+        for i in 0..3u8 {
+            server
+                .publish_item(
+                    &realm,
+                    "x".into(),
+                    path.clone(),
+                    None,
+                    content(vec![i; 5000]),
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        let first = server
+            .metadata(&realm)
+            .await
+            .unwrap()
+            .item(&path, Some(0))
+            .cloned()
+            .unwrap();
+
+        // Without a retention policy nothing is reclaimable
+        assert_eq!(server.collect_garbage(true).await.unwrap().nodes, 0);
+
+        server.keep_revisions = NonZeroUsize::new(2);
+        let report = server.collect_garbage(true).await.unwrap();
+        assert!(report.bytes > 0);
+        assert!(server.storage.read().await.contains(first.root()));
+        assert_eq!(server.metadata(&realm).await.unwrap().items[&path].len(), 3);
+
+        // Publishing at another path doesn't expire anything, nothing is collected
+        server
+            .publish_item(
+                &realm,
+                "y".into(),
+                "c".into(),
+                None,
+                content(vec![9u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(server.metadata(&realm).await.unwrap().items[&path].len(), 3);
+        assert!(server.storage.read().await.contains(first.root()));
+
+        // Publishing applies the retention policy
+        let last = server
+            .publish_item(
+                &realm,
+                "x".into(),
+                path.clone(),
+                None,
+                content(vec![3u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let metadata = server.metadata(&realm).await.unwrap();
+        assert_eq!(
+            metadata.items[&path].keys().copied().collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(!server.storage.read().await.contains(first.root()));
+        assert!(server.storage.read().await.contains(last.root()));
+    }
+
+    #[tokio::test]
+    async fn receive_uploaded_delta() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        let path = PathBuf::from("a/b");
+
+        // Pseudo-random data, for content-defined chunks to differ
+        let mut x = 0x2545_f491_4f6c_dd1d_u64;
+        let mut data: Vec<u8> = (0..1 << 22)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x.to_le_bytes()[0]
+            })
+            .collect();
+        let first = server
+            .publish_item(
+                &realm,
+                "x".into(),
+                path.clone(),
+                None,
+                content(data.clone()),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Chunk a changed copy as a client would, then send only what the server is missing
+        data[1 << 21] ^= 1;
+        let root = HashMapStorage::default()
+            .insert_with(Bytes::from(data), &server.chunking)
+            .unwrap();
+        let target = Item::new("x".into(), path.clone(), 0, None, server.chunking, &root);
+        let hashes: Vec<Hash> = root.all_hashes().into_iter().collect();
+        let (missing, held): (Vec<Hash>, Vec<Hash>) = {
+            let storage = server.storage.read().await;
+            hashes.into_iter().partition(|hash| !storage.contains(hash))
+        };
+        let nodes: Vec<Node> = root
+            .clone()
+            .find_negotiated_diff(&held, &missing)
+            .map(|node| (*node).clone())
+            .collect();
+        let sent = nodes
+            .iter()
+            .filter(|node| matches!(node, Node::Stored { .. }))
+            .count();
+        assert!(sent > 0 && sent < first.chunks.len());
+
+        let item = server
+            .receive_item(
+                &realm,
+                target.metadata.clone(),
+                None,
+                tokio_stream::iter(nodes),
+            )
+            .await
+            .unwrap();
+        assert_eq!(item.metadata.revision, 1);
+        assert_eq!(item.root(), root.hash());
+        assert_eq!(
+            server.metadata(&realm).await.unwrap().latest(&path),
+            Some(&item)
+        );
+
+        // A tree not matching the declared root is refused
+        assert!(server
+            .receive_item(
+                &realm,
+                first.metadata,
+                None,
+                tokio_stream::iter(vec![(*root).clone()])
+            )
+            .await
+            .is_err());
+        // As is publishing into a feed that doesn't exist
+        assert!(server
+            .receive_item(
+                &realm,
+                target.metadata.clone(),
+                Some("f".into()),
+                tokio_stream::empty()
+            )
+            .await
+            .is_err());
+        // Or with chunks clients cannot be expected to hold
+        let mut huge = target.metadata;
+        huge.chunking = Chunking::Fixed(FixedSize { size: u32::MAX });
+        assert!(matches!(
+            server
+                .receive_item(&realm, huge, None, tokio_stream::empty())
+                .await,
+            Err(ServerError::ItemReception(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn receive_skipping_other_realms() {
+        let (a, b) = (realm::Name::from("a"), realm::Name::from("b"));
+        let server = Server::<HashMapStorage>::default();
+        for realm in [&a, &b] {
+            server
+                .create_realm(Realm::new(realm.clone(), String::new(), None))
+                .await
+                .unwrap();
+        }
+        let (target, nodes) = upload(&server, 7);
+        server
+            .receive_item(&b, target.clone(), None, tokio_stream::iter(nodes))
+            .await
+            .unwrap();
+
+        // The whole tree is in storage, but not reachable from realm "a"
+        let skipped = Node::Skipped {
+            hash: target.root.hash,
+            size: target.root.size,
+        };
+        assert!(matches!(
+            server
+                .receive_item(
+                    &a,
+                    target.clone(),
+                    None,
+                    tokio_stream::iter([skipped.clone()])
+                )
+                .await,
+            Err(ServerError::ItemReception(_))
+        ));
+        assert!(server
+            .metadata(&a)
+            .await
+            .unwrap()
+            .latest(&target.path)
+            .is_none());
+
+        // Realm "b" can skip it all
+        let mut target = target;
+        target.description = Some("same content".into());
+        server
+            .receive_item(&b, target, None, tokio_stream::iter([skipped]))
+            .await
+            .unwrap();
+    }
+
+    /// Target and nodes of an item uploaded by a client, with pseudo-random content from `seed`
+    fn upload(server: &Server<HashMapStorage>, mut seed: u64) -> (ItemMetadata, Vec<Node>) {
+        let data: Vec<u8> = (0..1 << 20)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed.to_le_bytes()[0]
+            })
+            .collect();
+        let root = HashMapStorage::default()
+            .insert_with(Bytes::from(data), &server.chunking)
+            .unwrap();
+        let target = Item::new("x".into(), "a".into(), 0, None, server.chunking, &root);
+        let nodes = root.find_diff(&[]).map(|node| (*node).clone()).collect();
+        (target.metadata, nodes)
+    }
+
+    #[tokio::test]
+    async fn receive_without_holding_storage() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+
+        // Half of the nodes are sent, the upload then stalls
+        let (target, nodes) = upload(&server, 1);
+        let (tx, rx) = mpsc::channel(nodes.len());
+        let (head, tail) = nodes.split_at(nodes.len() / 2);
+        for node in head {
+            tx.send(node.clone()).await.unwrap();
+        }
+        let receiving = server.receive_item(&realm, target.clone(), None, ReceiverStream::new(rx));
+        tokio::pin!(receiving);
+        let stalled = Duration::from_millis(50);
+        assert!(tokio::time::timeout(stalled, &mut receiving).await.is_err());
+
+        // Storage is not held meanwhile, and nodes received so far are kept by garbage collection
+        assert_eq!(server.collect_garbage(false).await.unwrap().nodes, 0);
+        assert!(!server.storage.read().await.nodes().is_empty());
+
+        for node in tail {
+            tx.send(node.clone()).await.unwrap();
+        }
+        drop(tx);
+        let item = receiving.await.unwrap();
+        assert_eq!(item.metadata.root, target.root);
+        assert!(server.storage.read().await.contains(&target.root.hash));
+        assert_eq!(server.collect_garbage(false).await.unwrap().nodes, 0);
+    }
+
+    #[tokio::test]
+    async fn publish_without_holding_storage() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        // Small chunks, for pieces to be stored before the whole content is there
+        let server = Server::<HashMapStorage> {
+            chunking: Chunking::Fixed(FixedSize { size: 1024 }),
+            ..Server::default()
+        };
+        let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+
+        // Half of the content is uploaded, the upload then stalls
+        let (tx, rx) = mpsc::channel(4);
+        let (head, tail) = data.split_at(data.len() / 2);
+        tx.send(Ok(Bytes::copy_from_slice(head))).await.unwrap();
+        let publishing = server.publish_item(
+            &realm,
+            "x".into(),
+            "a".into(),
+            None,
+            ReceiverStream::new(rx),
+            None,
+            None,
+        );
+        tokio::pin!(publishing);
+        let stalled = Duration::from_millis(50);
+        assert!(tokio::time::timeout(stalled, &mut publishing)
+            .await
+            .is_err());
+
+        // Storage is not held meanwhile, and what's been stored so far is kept by garbage collection
+        assert!(!server.storage.read().await.nodes().is_empty());
+        assert_eq!(server.collect_garbage(false).await.unwrap().nodes, 0);
+
+        tx.send(Ok(Bytes::copy_from_slice(tail))).await.unwrap();
+        drop(tx);
+        let item = publishing.await.unwrap();
+        assert_eq!(item.metadata.root.hash, hash_with(&data, &server.chunking));
+        let root = server.storage.read().await.get(item.root()).unwrap();
+        assert_eq!(root.clone_data(), data);
+        assert_eq!(server.collect_garbage(false).await.unwrap().nodes, 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_get_their_own_revision() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+
+        // Both uploads to the same path are in progress at the same time
+        let mut senders = vec![];
+        let mut uploads = vec![];
+        for seed in [1, 2] {
+            let (target, nodes) = upload(&server, seed);
+            let (tx, rx) = mpsc::channel(nodes.len());
+            for node in nodes {
+                tx.send(node).await.unwrap();
+            }
+            senders.push(tx);
+            let mut receiving =
+                Box::pin(server.receive_item(&realm, target, None, ReceiverStream::new(rx)));
+            let stalled = Duration::from_millis(50);
+            assert!(tokio::time::timeout(stalled, &mut receiving).await.is_err());
+            uploads.push(receiving);
+        }
+        drop(senders);
+
+        let mut revisions = vec![];
+        for receiving in uploads {
+            revisions.push(receiving.await.unwrap().metadata.revision);
+        }
+        assert_eq!(revisions, vec![0, 1]);
+        assert_eq!(
+            server.metadata(&realm).await.unwrap().items[Path::new("a")].len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn feed_management() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        let (a, b) = (PathBuf::from("a"), PathBuf::from("b"));
+        server.expose_feed(&realm, Feed::new("f")).await.unwrap();
+        assert!(server.expose_feed(&realm, Feed::new("f")).await.is_err());
+
+        // Publishing into a feed, or adding an item later
+        let a0 = server
+            .publish_item(
+                &realm,
+                "a".into(),
+                a.clone(),
+                None,
+                content(vec![1u8; 5000]),
+                None,
+                Some("f".into()),
+            )
+            .await
+            .unwrap();
+        server
+            .publish_item(
+                &realm,
+                "b".into(),
+                b.clone(),
+                None,
+                content(vec![2u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let feed = server.add_to_feed(&realm, &"f".into(), &b).await.unwrap();
+        assert_eq!(feed.paths.keys().collect::<Vec<_>>(), vec![&a, &b]);
+        assert_eq!(feed.paths[&a], a0);
+        assert!(server
+            .add_to_feed(&realm, &"f".into(), Path::new("c"))
+            .await
+            .is_err());
+        assert!(server
+            .publish_item(
+                &realm,
+                "c".into(),
+                "c".into(),
+                None,
+                content(vec![3u8; 5000]),
+                None,
+                Some("g".into())
+            )
+            .await
+            .is_err());
+
+        // Feeds follow new revisions of their items
+        let a1 = server
+            .publish_item(
+                &realm,
+                "a".into(),
+                a.clone(),
+                None,
+                content(vec![3u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            server.metadata(&realm).await.unwrap().feeds["f"].paths[&a],
+            a1
+        );
+
+        let feed = server
+            .remove_from_feed(&realm, &"f".into(), &b)
+            .await
+            .unwrap();
+        assert!(!feed.paths.contains_key(&b));
+        assert!(server
+            .remove_from_feed(&realm, &"f".into(), &b)
+            .await
+            .is_err());
+        assert!(server.metadata(&realm).await.unwrap().latest(&b).is_some());
+
+        server.expose_feed(&realm, Feed::new("g")).await.unwrap();
+        assert!(server
+            .rename_feed(&realm, &"f".into(), "g".into())
+            .await
+            .is_err());
+        let feed = server
+            .rename_feed(&realm, &"f".into(), "h".into())
+            .await
+            .unwrap();
+        assert_eq!(feed.name, "h");
+        assert_eq!(server.remove_feed(&realm, &"h".into()).await.unwrap(), feed);
+        assert!(server.remove_feed(&realm, &"h".into()).await.is_err());
+        assert_eq!(
+            server
+                .metadata(&realm)
+                .await
+                .unwrap()
+                .feeds
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["g"]
+        );
+    }
+
+    #[tokio::test]
+    async fn feed_creation_errors() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        server.expose_feed(&realm, Feed::new("f")).await.unwrap();
+        assert!(matches!(
+            server.expose_feed(&realm, Feed::new("f")).await,
+            Err(ServerError::FeedExists(_))
+        ));
+        assert!(matches!(
+            server.expose_feed(&"none".into(), Feed::new("f")).await,
+            Err(ServerError::UnknownRealm(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn metadata_generations() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        let mut generations = server.generations();
+        let first = *generations.borrow();
+
+        server
+            .publish_item(
+                &realm,
+                "x".into(),
+                "a".into(),
+                None,
+                content(vec![1u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(generations.has_changed().unwrap());
+        assert_eq!(*generations.borrow_and_update(), first + 1);
+
+        // Nothing changes when publishing the same item again, or on a dry run
+        server
+            .publish_item(
+                &realm,
+                "x".into(),
+                "a".into(),
+                None,
+                content(vec![1u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        server.collect_garbage(true).await.unwrap();
+        assert!(!generations.has_changed().unwrap());
+
+        server.expose_feed(&realm, Feed::new("f")).await.unwrap();
+        server
+            .add_to_feed(&realm, &"f".into(), Path::new("a"))
+            .await
+            .unwrap();
+        assert_eq!(*generations.borrow_and_update(), first + 3);
+    }
+
+    #[tokio::test]
+    async fn record_client_holdings() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        let item = server
+            .publish_item(
+                &realm,
+                "x".into(),
+                "a".into(),
+                None,
+                content(vec![1u8; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let uuid = server
+            .register_client(
+                "c".into(),
+                ([127, 0, 0, 1], 1234).into(),
+                None,
+                None,
+                realm,
+                [0; 32],
+            )
+            .await
+            .unwrap();
+
+        let known: Vec<Hash> = item.all_hashes().into_iter().collect();
+        assert!(server
+            .record_holdings(&uuid, known.clone(), true)
+            .await
+            .unwrap());
+        // Unknown hashes are reported and dropped
+        let unknown = hash(b"unknown");
+        assert!(!server
+            .record_holdings(&uuid, vec![unknown], false)
+            .await
+            .unwrap());
+        assert_eq!(
+            server.clients.read().await[&uuid].holdings,
+            HashSet::from_iter(known)
+        );
+
+        assert!(server.record_holdings(&uuid, vec![], true).await.unwrap());
+        assert!(server.clients.read().await[&uuid].holdings.is_empty());
+        assert!(server
+            .record_holdings(&Uuid::nil(), vec![], true)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn publish_needs_owner_signature() {
+        let server = Server::<HashMapStorage>::default();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let owner_pkey = key_pair.public_key().as_ref().try_into().unwrap();
+        let realm = server
+            .create_realm(Realm::new("owned".into(), "team".into(), Some(owner_pkey)))
+            .await
+            .unwrap();
+
+        // Content uploaded in pieces, signed through the root of its tree
+        let data = vec![5u8; 5000];
+        let pieces = || {
+            let pieces: Vec<_> = data
+                .chunks(1000)
+                .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+                .collect();
+            tokio_stream::iter(pieces)
+        };
+        let publish = |path: &'static str, signature: Option<Vec<u8>>| {
+            let server = &server;
+            let realm = &realm;
+            let pieces = pieces();
+            async move {
+                server
+                    .publish_item(
+                        realm,
+                        "x".into(),
+                        path.into(),
+                        None,
+                        pieces,
+                        signature.as_deref(),
+                        None,
+                    )
+                    .await
+            }
+        };
+        assert!(publish("a", None).await.is_err());
+        let of_data = key_pair.sign(&data).as_ref().to_vec();
+        assert!(publish("a", Some(of_data)).await.is_err());
+        let root = hash_with(&data, &server.chunking);
+        let change = RealmChange::Publish {
+            realm: &realm,
+            path: Path::new("a"),
+            root: &root,
+            feed: None,
+        };
+        let sign = || {
+            change
+                .sign(&key_pair, &server.challenges.issue().unwrap())
+                .unwrap()
+        };
+
+        // Not to be replayed at another path, nor at all
+        assert!(publish("b", Some(sign())).await.is_err());
+        let signature = sign();
+        let item = publish("a", Some(signature.clone())).await.unwrap();
+        assert_eq!(item.metadata.root.hash, root);
+        assert_eq!(server.metadata(&realm).await.unwrap().items.len(), 1);
+        assert!(publish("a", Some(signature)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn realms_are_scoped() {
+        let realm = realm::Name::from(realm::DEFAULT);
+        let server = Server::<HashMapStorage>::default();
+        let other = server
+            .create_realm(Realm::new("other".into(), "team".into(), None))
+            .await
+            .unwrap();
+        assert!(server
+            .create_realm(Realm::new("other".into(), String::new(), None))
+            .await
+            .is_err());
+
+        // Same path in both realms, each with its own revisions
+        let path = PathBuf::from("a");
+        for (realm, byte) in [(&realm, 1u8), (&other, 2u8), (&other, 3u8)] {
+            server
+                .publish_item(
+                    realm,
+                    "x".into(),
+                    path.clone(),
+                    None,
+                    content(vec![byte; 5000]),
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(server.metadata(&realm).await.unwrap().items[&path].len(), 1);
+        assert_eq!(server.metadata(&other).await.unwrap().items[&path].len(), 2);
+        assert!(server.metadata(&"none".into()).await.is_err());
+
+        // Clients of a realm only learn about hashes of its own items, even after they change
+        let ours = hash_with(&[1; 5000], &server.chunking);
+        let theirs = hash_with(&[3; 5000], &server.chunking);
+        let reachable = server.reachable(&realm).await.unwrap();
+        assert!(reachable.contains(&ours) && !reachable.contains(&theirs));
+        server
+            .publish_item(
+                &realm,
+                "x".into(),
+                "b".into(),
+                None,
+                content(vec![4; 5000]),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let reachable = server.reachable(&realm).await.unwrap();
+        assert!(reachable.contains(&hash_with(&[4; 5000], &server.chunking)));
+        assert!(server.reachable(&"none".into()).await.is_err());
+
+        // Clients stay in the realm they registered into
+        let addr = ([127, 0, 0, 1], 1234).into();
+        let uuid = server
+            .register_client("c".into(), addr, None, None, other.clone(), [0; 32])
+            .await
+            .unwrap();
+        assert_eq!(server.client_realm(&uuid).await.unwrap(), other);
+        assert!(server
+            .register_client("c".into(), addr, None, Some(uuid), realm, [0; 32])
+            .await
+            .is_err());
+        assert!(server
+            .register_client("c".into(), addr, None, None, "none".into(), [0; 32])
+            .await
+            .is_err());
+    }
+}
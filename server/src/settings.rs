@@ -0,0 +1,149 @@
+use config::{Config, Environment, File};
+use serde::Deserialize;
+use std::{net::SocketAddr, num::NonZeroUsize, path::PathBuf};
+
+use crate::access::{ApiKey, Role};
+use crate::error::Server as ServerError;
+
+pub use distd_core::utils::settings::cache_dir;
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Server {
+    /// Address to listen on for gRPC requests
+    pub grpc_addr: SocketAddr,
+
+    /// Address to listen on for HTTP (REST API) requests
+    pub http_addr: SocketAddr,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Log {
+    pub level: String,
+}
+
+/// Backend used to store chunks
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// In-memory storage, chunks are lost on restart
+    HashMap,
+    /// redb database at `Storage::path`
+    #[cfg(feature = "redb")]
+    Redb,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Storage {
+    pub backend: Backend,
+
+    /// The path to the storage database, if the backend requires one
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Persistence {
+    /// The path to the directory where server state and key are kept
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[allow(unused)]
+pub struct Retention {
+    /// How many revisions of each item to keep, older ones and their chunks are dropped on publish
+    pub keep_revisions: Option<NonZeroUsize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Tracker {
+    /// Seconds without a heartbeat after which a client is not handed out as a peer anymore
+    pub peer_ttl: u64,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self {
+            peer_ttl: crate::server::DEFAULT_PEER_TTL.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Auth {
+    /// Seconds client session tokens are valid for, clients renew them before they expire
+    pub token_ttl: u64,
+
+    /// API keys granting roles to REST and gRPC requests, see `access`
+    pub api_keys: Vec<ApiKey>,
+
+    /// Role of requests without an API key
+    pub anonymous: Role,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            token_ttl: crate::server::DEFAULT_TOKEN_TTL.as_secs(),
+            api_keys: Vec::new(),
+            anonymous: Role::Reader,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Settings {
+    pub server: Server,
+    pub log: Log,
+    pub storage: Storage,
+    pub persistence: Persistence,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub tracker: Tracker,
+    #[serde(default)]
+    pub auth: Auth,
+}
+
+impl Settings {
+    /// Load settings from `config_file` (optional) and from the environment, on top of sensible defaults
+    pub fn new(config_file: &str) -> Result<Self, ServerError> {
+        let state_dir = cache_dir().join("server");
+
+        let s = Config::builder()
+            .set_default("server.grpc_addr", "[::1]:50051")?
+            .set_default("server.http_addr", "0.0.0.0:3000")?
+            .set_default("log.level", "INFO")?
+            .set_default("storage.backend", default_backend())?
+            .set_default(
+                "storage.path",
+                state_dir.join("chunks.redb").to_string_lossy().to_string(),
+            )?
+            .set_default("persistence.path", state_dir.to_string_lossy().to_string())?
+            // Merge in the main configuration file, if any
+            .add_source(File::with_name(config_file).required(false))
+            // Add in settings from the environment (with a prefix of DISTD)
+            .add_source(
+                Environment::with_prefix("distd")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?;
+
+        s.try_deserialize().map_err(ServerError::InvalidConfig)
+    }
+}
+
+/// Persistent backend when available, in-memory otherwise
+const fn default_backend() -> &'static str {
+    if cfg!(feature = "redb") {
+        "redb"
+    } else {
+        "hashmap"
+    }
+}
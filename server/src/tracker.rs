@@ -0,0 +1,266 @@
+//! Tracking clients serving held items to each other, see `distd_core::peer`
+//!
+//! Clients report the UDP port they serve peers on with their heartbeats, and advertise the hashes they hold with
+//! `AdvHashes`. Any client may then ask for the live peers holding an item, so that it doesn't need to get the
+//! whole item from the server.
+
+use std::cmp::Reverse;
+use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+use distd_core::chunk_storage::ChunkStorage;
+use distd_core::hash::Hash;
+use uuid::Uuid;
+
+use crate::error::Server as ServerError;
+use crate::Server;
+
+/// How many peers are handed out when the client doesn't ask for a specific number
+pub const DEFAULT_PEERS: usize = 16;
+
+/// A client serving all or part of an item to other clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Address the client serves held items on
+    pub addr: SocketAddr,
+
+    /// Whether the client holds the whole item
+    pub complete: bool,
+}
+
+impl<T> Server<T>
+where
+    T: ChunkStorage + Sync + Send + Debug,
+{
+    /// Record a heartbeat from a client, along with the port it serves peers on if any
+    ///
+    /// `addr` is the address the heartbeat came from, if known, as clients may have moved since they registered.
+    pub async fn heartbeat(
+        &self,
+        uuid: &Uuid,
+        addr: Option<SocketAddr>,
+        peer_port: Option<u16>,
+    ) -> Result<(), ServerError> {
+        let mut clients = self.clients.write().await;
+        let client = clients
+            .get_mut(uuid)
+            .ok_or(ServerError::UnknownClient(*uuid))?;
+        if let Some(addr) = addr {
+            client.addr = addr;
+        }
+        client.peer_port = peer_port;
+        client.last_heartbeat = SystemTime::now();
+        Ok(())
+    }
+
+    /// Live peers holding all or part of the item with `root`, for the client `requester`
+    ///
+    /// Peers are the clients of the same realm serving peers which sent a heartbeat within `peer_ttl`, the ones
+    /// that didn't are expired. Holdings are the hashes recorded with `record_holdings`: a peer holding the root holds the whole
+    /// item, one holding any other node of its tree holds part of it.
+    /// Peers closest to the requester come first, closeness being the length of the common prefix of their IP
+    /// addresses, then the ones holding the whole item, then the ones heard of most recently.
+    pub async fn peers(
+        &self,
+        root: &Hash,
+        requester: &Uuid,
+        limit: usize,
+    ) -> Result<Vec<Peer>, ServerError> {
+        // Items of other realms are unknown to the requester
+        let realm = self.client_realm(requester).await?;
+        if !self.reachable(&realm).await?.contains(root) {
+            return Err(ServerError::UnknownHash(*root));
+        }
+        let tree = self
+            .storage
+            .read()
+            .await
+            .get(root)
+            .ok_or(ServerError::UnknownHash(*root))?
+            .all_hashes();
+
+        let mut clients = self.clients.write().await;
+        self.expire_peers(clients.values_mut());
+        let (from, realm) = clients
+            .get(requester)
+            .map(|client| (client.addr.ip(), &client.realm))
+            .ok_or(ServerError::UnknownClient(*requester))?;
+
+        let mut peers: Vec<_> = clients
+            .values()
+            .filter(|client| client.uuid != *requester && client.realm == *realm)
+            .filter_map(|client| {
+                let port = client.peer_port?;
+                let complete = client.holdings.contains(root);
+                (complete || !client.holdings.is_disjoint(&tree)).then(|| {
+                    let peer = Peer {
+                        addr: SocketAddr::new(client.addr.ip(), port),
+                        complete,
+                    };
+                    (peer, client.last_heartbeat)
+                })
+            })
+            .collect();
+        peers.sort_by_key(|(peer, heartbeat)| {
+            (
+                Reverse(common_prefix(from, peer.addr.ip())),
+                Reverse(peer.complete),
+                Reverse(*heartbeat),
+            )
+        });
+        tracing::debug!("{} peers hold {root}", peers.len());
+
+        Ok(peers
+            .into_iter()
+            .take(limit)
+            .map(|(peer, _)| peer)
+            .collect())
+    }
+
+    /// Stop handing out the clients which didn't send a heartbeat within `peer_ttl`
+    ///
+    /// They're handed out again as soon as they send a new one.
+    fn expire_peers<'a>(&self, clients: impl Iterator<Item = &'a mut crate::client::Client>) {
+        let now = SystemTime::now();
+        for client in clients.filter(|client| client.peer_port.is_some()) {
+            let stale = now
+                .duration_since(client.last_heartbeat)
+                .is_ok_and(|elapsed| elapsed > self.peer_ttl);
+            if stale {
+                tracing::info!("Client {} stopped sending heartbeats", client.uuid);
+                client.peer_port = None;
+            }
+        }
+    }
+}
+
+/// Length in bits of the common prefix of two addresses, a rough measure of their locality
+///
+/// Addresses of different families have nothing in common, IPv4-mapped IPv6 addresses are taken as IPv4.
+fn common_prefix(a: IpAddr, b: IpAddr) -> u32 {
+    match (a.to_canonical(), b.to_canonical()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a) ^ u32::from(b)).leading_zeros(),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (u128::from(a) ^ u128::from(b)).leading_zeros(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+
+    use axum::body::Bytes;
+    use distd_core::chunk_storage::hashmap_storage::HashMapStorage;
+    use distd_core::chunker::{Chunking, FixedSize};
+    use distd_core::hash::hash;
+    use uuid::Uuid;
+
+    use super::{common_prefix, Peer};
+    use crate::realm;
+    use crate::Server;
+
+    #[test]
+    fn address_locality() {
+        let ip = |s: &str| s.parse().unwrap();
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("10.0.0.1")), 32);
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("10.0.1.1")), 23);
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("::ffff:10.0.0.3")), 30);
+        assert_eq!(common_prefix(ip("10.0.0.1"), ip("fd00::1")), 0);
+        assert_eq!(common_prefix(ip("fd00::1"), ip("fd00::2")), 126);
+    }
+
+    #[tokio::test]
+    async fn hand_out_live_peers() {
+        let mut server = Server::<HashMapStorage>::default();
+        server.chunking = Chunking::Fixed(FixedSize { size: 1024 });
+        let item = server
+            .publish_item(
+                &realm::DEFAULT.into(),
+                "x".into(),
+                "a".into(),
+                None,
+                tokio_stream::once(Ok(Bytes::from(vec![1u8; 5000]))),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        let root = *item.root();
+
+        let mut uuids = vec![];
+        for (name, ip) in [
+            ("requester", [10, 0, 0, 1]),
+            ("far", [192, 168, 0, 1]),
+            ("near", [10, 0, 0, 2]),
+            ("partial", [10, 0, 0, 3]),
+            ("silent", [10, 0, 0, 4]),
+            ("stale", [10, 0, 0, 5]),
+        ] {
+            let addr = SocketAddr::from((ip, 1234));
+            let uuid = server
+                .register_client(
+                    name.into(),
+                    addr,
+                    None,
+                    None,
+                    realm::DEFAULT.into(),
+                    [0; 32],
+                )
+                .await
+                .unwrap();
+            server.heartbeat(&uuid, None, Some(4000)).await.unwrap();
+            uuids.push(uuid);
+        }
+        let [requester, far, near, partial, silent, stale] = uuids[..] else {
+            unreachable!()
+        };
+        for uuid in [requester, far, near, silent, stale] {
+            server
+                .record_holdings(&uuid, vec![root], true)
+                .await
+                .unwrap();
+        }
+        let chunk = item.chunks[0].hash;
+        server
+            .record_holdings(&partial, vec![chunk], true)
+            .await
+            .unwrap();
+        // Not serving peers anymore
+        server.heartbeat(&silent, None, None).await.unwrap();
+        server
+            .clients
+            .write()
+            .await
+            .get_mut(&stale)
+            .unwrap()
+            .last_heartbeat = SystemTime::now() - server.peer_ttl * 2;
+
+        let peers = server.peers(&root, &requester, 10).await.unwrap();
+        let peer = |ip: [u8; 4], complete| Peer {
+            addr: (ip, 4000).into(),
+            complete,
+        };
+        assert_eq!(
+            peers,
+            vec![
+                peer([10, 0, 0, 2], true),
+                peer([10, 0, 0, 3], false),
+                peer([192, 168, 0, 1], true),
+            ]
+        );
+        assert!(server.clients.read().await[&stale].peer_port.is_none());
+        assert_eq!(server.peers(&root, &requester, 1).await.unwrap().len(), 1);
+
+        // A heartbeat brings stale peers back
+        server.heartbeat(&stale, None, Some(4000)).await.unwrap();
+        assert_eq!(server.peers(&root, &requester, 10).await.unwrap().len(), 4);
+
+        assert!(server
+            .peers(&hash(b"unknown"), &requester, 10)
+            .await
+            .is_err());
+        assert!(server.peers(&root, &Uuid::nil(), 10).await.is_err());
+    }
+}